@@ -0,0 +1,150 @@
+//! Broadcast efficiency at the scale of the Gossip Glomers efficiency
+//! challenge: `cargo bench --bench broadcast [-- FLAGS]`, with the same
+//! flags as the broadcast binary, e.g. `-- --topology tree`, and its
+//! defaults otherwise.
+//!
+//! Runs 25 nodes in the simulator with 100ms between any two, feeding them
+//! 100 ops a second for 20 seconds, half broadcasts and half reads, with
+//! `BENCH_DROP` of the messages between nodes lost (none by default).
+//! `BENCH_LATENCY` draws the time between nodes from a distribution
+//! instead, e.g. `lognormal:100:0.5`; see [`Latency::from_name`]. As
+//! Maelstrom measures it, a broadcast's latency is from the client sending
+//! it until the last node has its value. Messages
+//! and bytes per op come from the nodes' own `stats`; compare gossip modes
+//! with `-- --gossip-mode push`, `-- --gossip-mode push-pull` and
+//! `-- --gossip-mode rumor --gossip-ms 100`.
+//!
+//! Like Maelstrom, it offers the nodes a grid topology, which
+//! `-- --topology maelstrom` keeps.
+//!
+//! Once every value has spread, each node is read a last time and the
+//! history put to [`check_broadcast`], failing the run with the ops at
+//! fault if it doesn't hold. `BENCH_HISTORY` names a file to write the
+//! history to as JSON lines.
+
+use maelle::builder::NodeBuilder;
+use maelle::checker::check_broadcast;
+use maelle::node::{Node, Workload, random_u64};
+use maelle::protocol::{AdminPayload, NodeId, Payload};
+use maelle::runtime::env_or;
+use maelle::sim::{Latency, Link, Sim, seed_from_env};
+use maelle::topology;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const TICK: Duration = Duration::from_millis(10);
+
+fn main() -> anyhow::Result<()> {
+    let nodes = env_or("BENCH_NODES", 25);
+    let seconds = env_or("BENCH_SECONDS", 20);
+    let latency = match std::env::var("BENCH_LATENCY") {
+        Ok(name) => Latency::from_name(&name)
+            .ok_or_else(|| anyhow::anyhow!("unknown latency {:?}", name))?,
+        Err(_) => Latency::Fixed(Duration::from_millis(env_or("BENCH_LATENCY_MS", 100))),
+    };
+    // `cargo bench` passes `--bench` along with whatever follows `--`.
+    let args = std::env::args().skip(1).filter(|arg| arg != "--bench");
+    let config = NodeBuilder::for_workload(Workload::Broadcast)
+        .args(args)
+        .build()?;
+    let ids: Vec<String> = (0..nodes).map(|i| format!("n{}", i)).collect();
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    let mut sim = Sim::new(&ids, seed_from_env(), |ctx| {
+        Node::with_config(ctx, config.clone())
+    });
+    sim.latency = latency;
+    let drop = env_or("BENCH_DROP", 0.0);
+    if drop > 0.0 {
+        for a in &ids {
+            for b in &ids {
+                let link = Link {
+                    drop,
+                    ..Link::default()
+                };
+                sim.link(a, b, link);
+            }
+        }
+    }
+    // Maelstrom's default.
+    let node_ids: Vec<NodeId> = ids.iter().map(|id| NodeId::from(*id)).collect();
+    let topology = topology::adjacency(&node_ids, &topology::grid(ids.len()));
+    for id in &ids {
+        let topology = Payload::Topology {
+            topology: topology.clone(),
+        };
+        sim.request(id, topology, Duration::from_secs(1))?;
+    }
+
+    let mut pending: BTreeMap<u64, Duration> = BTreeMap::new();
+    let mut latencies = Vec::new();
+    let mut next_value = 0;
+    let ticks = seconds * 1000 / TICK.as_millis() as u64;
+    for tick in 0.. {
+        if tick >= ticks && pending.is_empty() {
+            break;
+        }
+        if tick >= ticks + 10_000 / TICK.as_millis() as u64 {
+            anyhow::bail!("{} values never reached every node", pending.len());
+        }
+        if tick < ticks {
+            let dest = ids[random_u64() as usize % ids.len()];
+            if random_u64().is_multiple_of(2) {
+                next_value += 1;
+                pending.insert(next_value, sim.now());
+                sim.send(
+                    dest,
+                    Payload::Broadcast {
+                        message: next_value.into(),
+                        stamp: None,
+                    },
+                )?;
+            } else {
+                sim.send(dest, Payload::Read { key: None })?;
+            }
+        }
+        sim.run_for(TICK);
+        while sim.recv().is_some() {}
+        pending.retain(|value, sent_at| {
+            let value = Value::from(*value);
+            let everywhere = ids.iter().all(|id| {
+                sim.node(id)
+                    .is_some_and(|node| node.messages.contains(&value))
+            });
+            if everywhere {
+                latencies.push(sim.now() - *sent_at);
+            }
+            !everywhere
+        });
+    }
+
+    for id in &ids {
+        sim.request(id, Payload::Read { key: None }, Duration::from_secs(1))?;
+    }
+    if let Ok(path) = std::env::var("BENCH_HISTORY") {
+        sim.history()
+            .write_jsonl(std::io::BufWriter::new(std::fs::File::create(path)?))?;
+    }
+    check_broadcast(sim.history())
+        .map_err(|violation| anyhow::anyhow!("{} (seed {})", violation, sim.seed()))?;
+
+    let reply = sim.request(ids[0], AdminPayload::Stats, Duration::from_secs(1))?;
+    let stats = match reply.parse_body::<AdminPayload>()?.body.payload {
+        AdminPayload::StatsOk { stats } => stats,
+        _ => anyhow::bail!("no stats from {}", ids[0]),
+    };
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_millis();
+    println!(
+        "{} nodes, {} ops: {:.2} msgs/op, {:.0} bytes/op, latency p50 {}ms p95 {}ms p99 {}ms max {}ms",
+        nodes,
+        stats.client_ops,
+        stats.messages_per_op,
+        stats.bytes_sent as f64 / stats.client_ops.max(1) as f64,
+        percentile(50),
+        percentile(95),
+        percentile(99),
+        percentile(100),
+    );
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! Echo throughput with and without write coalescing: `cargo bench`.
+//!
+//! Each run feeds one node a burst of echo requests and times how long it
+//! takes to write every reply to `/dev/null`, so the cost measured is the
+//! dispatcher plus one `write_all` per line or per coalesced batch.
+
+use maelle::protocol::{Message, Payload};
+use maelle::runtime::{Coalesce, Context, Handler, Output, env_or, run_with};
+use std::time::{Duration, Instant};
+
+struct Echo;
+
+impl Handler for Echo {
+    type Payload = Payload;
+
+    fn handle(&mut self, ctx: &mut Context, m: Message<Payload>) -> anyhow::Result<()> {
+        match m.body.payload {
+            Payload::Echo { echo } => ctx.reply(Payload::EchoOk { echo }),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn input(requests: usize) -> Vec<std::io::Result<String>> {
+    let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":0,"node_id":"n1","node_ids":["n1"]}}"#;
+    std::iter::once(init.to_string())
+        .chain((1..=requests).map(|i| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"echo":"hello {}"}}}}"#,
+                i, i
+            )
+        }))
+        .map(Ok)
+        .collect()
+}
+
+fn run(requests: usize, coalesce: Option<Coalesce>) -> anyhow::Result<Duration> {
+    let input = input(requests);
+    let sink = std::fs::OpenOptions::new().write(true).open("/dev/null")?;
+    let started = Instant::now();
+    run_with(
+        input.into_iter(),
+        Output::to_writer_with(sink, coalesce),
+        |_| Echo,
+    )?;
+    Ok(started.elapsed())
+}
+
+fn main() -> anyhow::Result<()> {
+    let requests = env_or("BENCH_REQUESTS", 200_000);
+    let coalesce = Coalesce {
+        bytes: env_or("MAELLE_COALESCE_BYTES", 16 * 1024),
+        window: Duration::from_millis(env_or("MAELLE_COALESCE_MS", 1)),
+    };
+    for (name, coalesce) in [("per_line", None), ("coalesced", Some(coalesce))] {
+        let elapsed = run(requests, coalesce)?;
+        println!(
+            "{:>10}: {} echoes in {:?} ({:.0}/s)",
+            name,
+            requests,
+            elapsed,
+            requests as f64 / elapsed.as_secs_f64()
+        );
+    }
+    Ok(())
+}
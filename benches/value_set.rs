@@ -0,0 +1,62 @@
+//! Memory held by the broadcast store, and the size of its sync digest, for
+//! a dense run of integer values: `cargo bench --bench value_set`.
+//!
+//! Compares [`ValueSet`] against the plain `Vec<Value>` it would take to
+//! hold the same values, counting bytes with a wrapping allocator.
+
+use maelle::node::ValueSet;
+use maelle::runtime::env_or;
+use serde_json::Value;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Bytes still allocated once `build` has run, and what it built.
+fn measure<T>(build: impl FnOnce() -> T) -> (usize, T) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let built = build();
+    (ALLOCATED.load(Ordering::Relaxed) - before, built)
+}
+
+fn main() -> anyhow::Result<()> {
+    let values: u64 = env_or("BENCH_VALUES", 1_000_000);
+    let (vec_bytes, vec) = measure(|| (0..values).map(Value::from).collect::<Vec<_>>());
+    let (set_bytes, set) = measure(|| {
+        let mut set = ValueSet::default();
+        for n in 0..values {
+            set.insert(n.into());
+        }
+        set
+    });
+    let vec_digest = serde_json::to_vec(&vec)?.len();
+    let ranges: Vec<(u64, u64)> = set.ranges().collect();
+    let set_digest = serde_json::to_vec(&ranges)?.len();
+    println!("{} dense values", values);
+    println!(
+        "{:>10}: {:>12} bytes held, {:>12} byte digest",
+        "vec", vec_bytes, vec_digest
+    );
+    println!(
+        "{:>10}: {:>12} bytes held, {:>12} byte digest",
+        "value_set", set_bytes, set_digest
+    );
+    Ok(())
+}
@@ -0,0 +1,24 @@
+//! Arbitrary bytes, as one input line, through a node's parsing and
+//! dispatch. Whatever comes in, the node answers or logs it; a panic that
+//! escapes is a bug.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use maelle::node::{Node, Workload};
+use maelle::sim::Sim;
+use std::cell::RefCell;
+
+thread_local! {
+    // Built once: a fresh node per input would spend the run on setup.
+    static SIM: RefCell<Sim<Node>> =
+        RefCell::new(Sim::new(&["n1"], 0, |ctx| Node::new(ctx, Workload::Broadcast)));
+}
+
+fuzz_target!(|data: &[u8]| {
+    let line = String::from_utf8_lossy(data);
+    SIM.with(|sim| {
+        let mut sim = sim.borrow_mut();
+        sim.deliver_line("n1", &line);
+        while sim.recv().is_some() {}
+    });
+});
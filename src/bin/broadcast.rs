@@ -0,0 +1,12 @@
+//! The `broadcast` challenge, with a tree topology and batched fan-out by
+//! default.
+//! Takes the same flags as `maelle`.
+
+use maelle::builder::NodeBuilder;
+use maelle::node::Workload;
+
+fn main() -> anyhow::Result<()> {
+    NodeBuilder::for_workload(Workload::Broadcast)
+        .args(std::env::args().skip(1))
+        .build_and_run(std::io::stdin(), std::io::stdout())
+}
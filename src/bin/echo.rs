@@ -0,0 +1,38 @@
+//! A lean echo-only node, built on the library with its own payload type.
+
+use maelle::protocol::{Kind, Message};
+use maelle::runtime::{Context, Handler};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum EchoPayload {
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+impl Kind for EchoPayload {
+    fn kind(&self) -> &'static str {
+        match self {
+            EchoPayload::Echo { .. } => "echo",
+            EchoPayload::EchoOk { .. } => "echo_ok",
+        }
+    }
+}
+
+struct Echo;
+
+impl Handler for Echo {
+    type Payload = EchoPayload;
+
+    fn handle(&mut self, ctx: &mut Context, m: Message<EchoPayload>) -> anyhow::Result<()> {
+        match m.body.payload {
+            EchoPayload::Echo { echo } => ctx.reply(EchoPayload::EchoOk { echo }),
+            EchoPayload::EchoOk { .. } => Ok(()),
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    maelle::runtime::run(std::io::stdin(), std::io::stdout(), |_| Echo)
+}
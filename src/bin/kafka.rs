@@ -0,0 +1,11 @@
+//! The `kafka` challenge, with its logs kept in Maelstrom's kv services.
+//! Takes the same flags as `maelle`.
+
+use maelle::builder::NodeBuilder;
+use maelle::node::Workload;
+
+fn main() -> anyhow::Result<()> {
+    NodeBuilder::for_workload(Workload::KvKafka)
+        .args(std::env::args().skip(1))
+        .build_and_run(std::io::stdin(), std::io::stdout())
+}
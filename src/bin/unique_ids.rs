@@ -0,0 +1,11 @@
+//! The `unique-ids` challenge: ids from the snowflake strategy by default.
+//! Takes the same flags as `maelle`.
+
+use maelle::builder::NodeBuilder;
+use maelle::node::Workload;
+
+fn main() -> anyhow::Result<()> {
+    NodeBuilder::for_workload(Workload::UniqueIds)
+        .args(std::env::args().skip(1))
+        .build_and_run(std::io::stdin(), std::io::stdout())
+}
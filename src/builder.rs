@@ -0,0 +1,526 @@
+//! Sets a [`Node`] up in code or from command-line flags rather than only
+//! through the environment, then runs it. Whatever isn't set keeps the
+//! value the environment gives it, so `NodeBuilder::new()` on its own runs
+//! exactly the node the plain binary always has.
+//!
+//! Every setting is checked before the init handshake: a bad one fails
+//! [`NodeBuilder::build_and_run`] up front instead of the node mid-run.
+
+use crate::log::{self, Level};
+use crate::node::{
+    GossipMode, IdStrategy, Node, NodeConfig, Pacing, RetryPolicy, TopologyMode, Workload,
+};
+use crate::protocol::NodeId;
+use crate::record::Recorder;
+use crate::runtime::{JsonStream, Output, env_or, run_with};
+use crate::topology;
+use std::{
+    collections::HashMap,
+    io::{BufReader, Read, Write},
+    time::Duration,
+};
+
+/// What `--help` prints.
+pub const USAGE: &str = "\
+usage: maelle [WORKLOAD] [FLAGS]
+       maelle topology [--nodes N] [FLAGS]
+
+Runs a Maelstrom node on stdin and stdout. Anything not set here comes
+from the MAELLE_* environment variables. `topology` instead prints the
+topology --topology derives for N nodes (5 by default), n0 on, as a
+Graphviz graph.
+
+workloads:
+  echo, unique-ids, broadcast, g-counter, pn-counter, kv-counter,
+  kv-kafka, g-set, or-set, lin-kv, sharded-kv
+
+flags for one workload, refused under any other:
+  --id-strategy NAME     unique-ids: counter, snowflake, uuid-v4, uuid-v7
+  --topology NAME        broadcast: maelstrom, tree, star, clusters, grid,
+                         ring[:CHORDS], random[:DEGREE[:SEED]]
+  --cluster-size N       broadcast: clusters of N nodes
+  --batch-ms MS          broadcast: how long fan-out is held to batch
+  --tier-batch-ms MS     broadcast: the same between cluster representatives
+  --ack-delay-ms MS      broadcast: how long acks wait to ride on other sends
+  --read-chunk N         broadcast: split reads into read_oks of N values
+  --dump-topology PATH   broadcast: write the topology to PATH as DOT,
+                         {id} in it replaced by the node's id
+  --gossip-mode NAME     broadcast: push, push-pull, rumor
+  --rumor-rounds K       broadcast: rumor rounds a value is pushed for
+  --rumor-fanout N       broadcast: neighbors a rumor round pushes to
+  --sync-ms MS           broadcast: anti-entropy interval
+  --gossip-ms MS         broadcast, counters, g-set, or-set: gossip interval
+  --gossip-rtt-multiple X
+                         the same: gossip each neighbor every X of its
+                         round trip
+  --gossip-floor-ms MS   the same: the shortest interval that paces gossip to
+  --gossip-ceiling-ms MS the same: the longest
+
+flags for every workload:
+  --retry-base-ms MS     first retry delay for unacked sends
+  --wal PATH             write-ahead log to recover state from
+  --record PATH          append a session recording
+  --log-level LEVEL      off, error, warn, info, debug
+  --workload NAME        the workload, as a flag
+  -h, --help             print this and exit
+";
+
+pub struct NodeBuilder {
+    config: NodeConfig,
+    record: Option<String>,
+    replay: Option<String>,
+    /// `Some(None)` turns logging off.
+    log_level: Option<Option<Level>>,
+    help: bool,
+    /// Set by the `topology` subcommand: how many nodes to print the
+    /// derived topology of, without running one.
+    topology_nodes: Option<usize>,
+    /// Flags given that only some workloads take, checked on build
+    /// against the workload finally chosen.
+    scoped: Vec<String>,
+    /// What was wrong with the settings, reported all at once on build.
+    errors: Vec<String>,
+}
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl NodeBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: NodeConfig::from_env(Workload::from_env()),
+            record: std::env::var("MAELLE_RECORD").ok(),
+            replay: std::env::var("MAELLE_REPLAY").ok(),
+            log_level: None,
+            help: false,
+            topology_nodes: None,
+            scoped: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+    /// A builder for `workload` tuned for its Gossip Glomers challenge:
+    /// snowflake ids for `unique-ids`, and for `broadcast` clusters of 5
+    /// with fan-out batched and acks piggybacked to cut messages per op
+    /// (see `benches/broadcast.rs`).
+    /// Each of those gives way to its `MAELLE_*` variable when that's set,
+    /// and to flags as ever.
+    pub fn for_workload(workload: Workload) -> Self {
+        let unset = |name| std::env::var_os(name).is_none();
+        let mut builder = Self::new().workload(workload);
+        match workload {
+            Workload::UniqueIds if unset("MAELLE_ID_STRATEGY") => {
+                builder = builder.id_strategy(IdStrategy::Snowflake);
+            }
+            Workload::Broadcast => {
+                if unset("MAELLE_TOPOLOGY") {
+                    let size = env_or("MAELLE_CLUSTER_SIZE", 5).max(1);
+                    builder = builder.topology(TopologyMode::Clusters { size });
+                }
+                if unset("MAELLE_BATCH_MS") {
+                    builder = builder.batch_window(Duration::from_millis(100));
+                }
+                if unset("MAELLE_ACK_DELAY_MS") {
+                    builder = builder.ack_delay(Duration::from_millis(50));
+                }
+            }
+            _ => {}
+        }
+        builder
+    }
+    pub fn workload(mut self, workload: Workload) -> Self {
+        self.config.workload = workload;
+        self
+    }
+    pub fn id_strategy(mut self, id_strategy: IdStrategy) -> Self {
+        self.config.id_strategy = id_strategy;
+        self
+    }
+    pub fn topology(mut self, topology: TopologyMode) -> Self {
+        if topology == (TopologyMode::Tree { fanout: 0 }) {
+            self.errors
+                .push("tree topology needs a fanout of at least 1".into());
+        }
+        if topology == (TopologyMode::Clusters { size: 0 }) {
+            self.errors
+                .push("clusters need a size of at least 1".into());
+        }
+        if let TopologyMode::Random { degree: 0 | 1, .. } = topology {
+            self.errors
+                .push("random topology needs a degree of at least 2".into());
+        }
+        self.config.topology_mode = topology;
+        self
+    }
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        if policy.base.is_zero() {
+            self.errors.push("retry base must be positive".into());
+        }
+        if policy.multiplier < 1.0 {
+            self.errors
+                .push("retry multiplier must be at least 1".into());
+        }
+        if !(0.0..=1.0).contains(&policy.jitter) {
+            self.errors.push("retry jitter must be within 0..=1".into());
+        }
+        self.config.retry_policy = policy;
+        self
+    }
+    pub fn gossip_interval(mut self, interval: Duration) -> Self {
+        if interval.is_zero() {
+            self.errors.push("gossip interval must be positive".into());
+        }
+        self.config.gossip_interval = interval;
+        self
+    }
+    pub fn gossip_mode(mut self, mode: GossipMode) -> Self {
+        if let GossipMode::Rumor { rounds, fanout } = mode {
+            if rounds == 0 {
+                self.errors.push("rumors need at least 1 round".into());
+            }
+            if fanout == 0 {
+                self.errors
+                    .push("rumors need a fanout of at least 1".into());
+            }
+        }
+        self.config.gossip_mode = mode;
+        self
+    }
+    /// Paces gossip and retries per neighbor by round-trip time; see
+    /// [`Pacing`].
+    pub fn pacing(mut self, pacing: Pacing) -> Self {
+        if !pacing.rtt_multiple.is_finite() || pacing.rtt_multiple <= 0.0 {
+            self.errors
+                .push("gossip rtt multiple must be positive".into());
+        }
+        if pacing.floor.is_zero() {
+            self.errors.push("gossip floor must be positive".into());
+        }
+        self.config.pacing = Some(pacing);
+        self
+    }
+    fn paced(&self) -> Pacing {
+        self.config.pacing.unwrap_or_else(Pacing::from_env)
+    }
+    pub fn sync_interval(mut self, interval: Duration) -> Self {
+        if interval.is_zero() {
+            self.errors
+                .push("anti-entropy interval must be positive".into());
+        }
+        self.config.sync_interval = interval;
+        self
+    }
+    /// How long broadcast fan-out is held to go out in batches; zero sends
+    /// each value as it arrives.
+    pub fn batch_window(mut self, window: Duration) -> Self {
+        self.config.batch_window = window;
+        self
+    }
+    /// The batch window between cluster representatives, under
+    /// [`TopologyMode::Clusters`].
+    pub fn tier_window(mut self, window: Duration) -> Self {
+        self.config.tier_window = Some(window);
+        self
+    }
+    /// How long acks to other nodes are held to go out along with the next
+    /// batch or gossip their way; zero acks each at once.
+    pub fn ack_delay(mut self, delay: Duration) -> Self {
+        self.config.ack_delay = delay;
+        self
+    }
+    /// Splits client reads of broadcast values into `read_ok`s of at most
+    /// `size` values each; zero, as stock Maelstrom expects, answers in one.
+    pub fn read_chunk(mut self, size: usize) -> Self {
+        self.config.read_chunk = size;
+        self
+    }
+    /// Writes the topology to `path` as a Graphviz graph each time it's
+    /// set, along with the round trips measured so far.
+    pub fn dump_topology(mut self, path: impl Into<String>) -> Self {
+        self.config.dump_topology = Some(path.into());
+        self
+    }
+    pub fn wal(mut self, path: impl Into<String>) -> Self {
+        self.config.wal = Some(path.into());
+        self
+    }
+    /// Appends a [session recording](crate::record) to `path`.
+    pub fn record(mut self, path: impl Into<String>) -> Self {
+        self.record = Some(path.into());
+        self
+    }
+    /// `None` turns logging off; otherwise as `MAELLE_LOG`.
+    pub fn log_level(mut self, level: Option<Level>) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+    /// Applies command-line arguments: a workload, then flags, e.g.
+    /// `g-set --gossip-ms 200`. A flag's value may also follow an `=`.
+    /// Flags that only some workloads take (see [`USAGE`]) fail the build
+    /// under any other, rather than being silently ignored.
+    ///
+    /// Parsed by hand rather than with clap: the crate builds with serde,
+    /// serde_json and anyhow alone, and the flags are all `--name value`.
+    pub fn args(mut self, args: impl IntoIterator<Item = String>) -> Self {
+        let mut args = args.into_iter().peekable();
+        if let Some(name) = args.next_if(|arg| !arg.starts_with('-')) {
+            self = match Workload::from_name(&name) {
+                None if name == "topology" => {
+                    self.topology_nodes = Some(5);
+                    self
+                }
+                Some(workload) => self.workload(workload),
+                None => {
+                    self.errors
+                        .push(format!("workload {:?} is unknown or not built in", name));
+                    self
+                }
+            };
+        }
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                self.help = true;
+                continue;
+            }
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), value.to_string()),
+                None => match args.next() {
+                    Some(value) => (arg, value),
+                    None => {
+                        self.errors.push(format!("{} needs a value", arg));
+                        break;
+                    }
+                },
+            };
+            if scope(&flag).is_some() {
+                self.scoped.push(flag.clone());
+            }
+            self = match flag.as_str() {
+                "--workload" => match Workload::from_name(&value) {
+                    Some(workload) => self.workload(workload),
+                    None => self.invalid(&flag, &value),
+                },
+                "--id-strategy" => match IdStrategy::from_name(&value) {
+                    Some(strategy) => self.id_strategy(strategy),
+                    None => self.invalid(&flag, &value),
+                },
+                "--topology" => match TopologyMode::from_name(&value) {
+                    Some(topology) => self.topology(topology),
+                    None => self.invalid(&flag, &value),
+                },
+                "--gossip-ms" => match value.parse() {
+                    Ok(ms) => self.gossip_interval(Duration::from_millis(ms)),
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--gossip-mode" => match GossipMode::from_name(&value) {
+                    Some(mode) => self.gossip_mode(mode),
+                    None => self.invalid(&flag, &value),
+                },
+                "--rumor-rounds" => match value.parse() {
+                    Ok(rounds) => {
+                        let mode = self.config.gossip_mode.with_rounds(rounds);
+                        self.gossip_mode(mode)
+                    }
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--rumor-fanout" => match value.parse() {
+                    Ok(fanout) => {
+                        let mode = self.config.gossip_mode.with_fanout(fanout);
+                        self.gossip_mode(mode)
+                    }
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--gossip-rtt-multiple" => match value.parse() {
+                    Ok(rtt_multiple) => {
+                        let pacing = Pacing {
+                            rtt_multiple,
+                            ..self.paced()
+                        };
+                        self.pacing(pacing)
+                    }
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--gossip-floor-ms" => match value.parse() {
+                    Ok(ms) => {
+                        let pacing = Pacing {
+                            floor: Duration::from_millis(ms),
+                            ..self.paced()
+                        };
+                        self.pacing(pacing)
+                    }
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--gossip-ceiling-ms" => match value.parse() {
+                    Ok(ms) => {
+                        let pacing = Pacing {
+                            ceiling: Duration::from_millis(ms),
+                            ..self.paced()
+                        };
+                        self.pacing(pacing)
+                    }
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--sync-ms" => match value.parse() {
+                    Ok(ms) => self.sync_interval(Duration::from_millis(ms)),
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--cluster-size" => match value.parse() {
+                    Ok(size) => self.topology(TopologyMode::Clusters { size }),
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--batch-ms" => match value.parse() {
+                    Ok(ms) => self.batch_window(Duration::from_millis(ms)),
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--tier-batch-ms" => match value.parse() {
+                    Ok(ms) => self.tier_window(Duration::from_millis(ms)),
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--ack-delay-ms" => match value.parse() {
+                    Ok(ms) => self.ack_delay(Duration::from_millis(ms)),
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--read-chunk" => match value.parse() {
+                    Ok(size) => self.read_chunk(size),
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--dump-topology" => self.dump_topology(value),
+                "--nodes" if self.topology_nodes.is_some() => match value.parse() {
+                    Ok(nodes) if nodes > 0 => {
+                        self.topology_nodes = Some(nodes);
+                        self
+                    }
+                    _ => self.invalid(&flag, &value),
+                },
+                "--retry-base-ms" => match value.parse() {
+                    Ok(ms) => {
+                        let policy = RetryPolicy {
+                            base: Duration::from_millis(ms),
+                            ..self.config.retry_policy
+                        };
+                        self.retry_policy(policy)
+                    }
+                    Err(_) => self.invalid(&flag, &value),
+                },
+                "--wal" => self.wal(value),
+                "--record" => self.record(value),
+                "--log-level" => match value.as_str() {
+                    "off" => self.log_level(None),
+                    name => match Level::from_name(name) {
+                        Some(level) => self.log_level(Some(level)),
+                        None => self.invalid(&flag, &value),
+                    },
+                },
+                _ => {
+                    self.errors
+                        .push(format!("unknown flag {} (see --help)", flag));
+                    self
+                }
+            };
+        }
+        self
+    }
+    fn invalid(mut self, flag: &str, value: &str) -> Self {
+        self.errors.push(format!("invalid {} {:?}", flag, value));
+        self
+    }
+    /// The node's settings, once they've all checked out.
+    pub fn build(mut self) -> anyhow::Result<NodeConfig> {
+        // Checked here, as the floor and ceiling may be set either way round.
+        if let Some(pacing) = self.config.pacing {
+            if pacing.floor > pacing.ceiling {
+                self.errors
+                    .push("gossip floor must not exceed its ceiling".into());
+            }
+        }
+        // The `topology` subcommand runs no workload; its flags are the
+        // topology's.
+        if self.topology_nodes.is_none() {
+            for flag in &self.scoped {
+                if let Some((workloads, names)) = scope(flag) {
+                    if !workloads.contains(&self.config.workload) {
+                        self.errors
+                            .push(format!("{} only applies to {}", flag, names));
+                    }
+                }
+            }
+        }
+        if !self.errors.is_empty() {
+            anyhow::bail!("invalid configuration: {}", self.errors.join("; "));
+        }
+        Ok(self.config)
+    }
+    /// Builds the node and runs it on `stdin` and `stdout`, init handshake
+    /// included; see [`run_with`]. With a replay set (`MAELLE_REPLAY`),
+    /// that's run instead. Asked for `--help`, prints [`USAGE`] to `stdout`
+    /// and returns without reading anything, as it does the topology for
+    /// the `topology` subcommand.
+    pub fn build_and_run(
+        mut self,
+        stdin: impl Read + Send + 'static,
+        mut stdout: impl Write + Send + 'static,
+    ) -> anyhow::Result<()> {
+        if self.help {
+            stdout.write_all(USAGE.as_bytes())?;
+            return Ok(());
+        }
+        if let Some(level) = self.log_level.take() {
+            log::set_max_level(level);
+        }
+        let record = self.record.take();
+        let replay = self.replay.take();
+        if let Some(nodes) = self.topology_nodes {
+            let config = self.build()?;
+            return print_topology(config.topology_mode, nodes, stdout);
+        }
+        let config = self.build()?;
+        let make = move |ctx: &_| Node::with_config(ctx, config);
+        if let Some(path) = replay {
+            return crate::record::replay(path, stdout, make);
+        }
+        let mut output = Output::to_writer(stdout);
+        if let Some(path) = record {
+            let recorder = Recorder::create(&path)
+                .map_err(|e| anyhow::anyhow!("can't record to {}: {}", path, e))?;
+            output = output.recording(recorder);
+        }
+        run_with(JsonStream::new(BufReader::new(stdin)), output, make)
+    }
+}
+
+/// The workloads a flag applies to, and their names as [`USAGE`] gives
+/// them, for flags that don't apply to every workload.
+fn scope(flag: &str) -> Option<(&'static [Workload], &'static str)> {
+    const GOSSIPING: &[Workload] = &[
+        Workload::Broadcast,
+        Workload::Counter,
+        Workload::GSet,
+        Workload::OrSet,
+    ];
+    match flag {
+        "--id-strategy" => Some((&[Workload::UniqueIds], "unique-ids")),
+        "--topology" | "--cluster-size" | "--batch-ms" | "--tier-batch-ms" | "--ack-delay-ms"
+        | "--read-chunk" | "--dump-topology" | "--gossip-mode" | "--rumor-rounds"
+        | "--rumor-fanout" | "--sync-ms" => Some((&[Workload::Broadcast], "broadcast")),
+        "--gossip-ms" | "--gossip-rtt-multiple" | "--gossip-floor-ms" | "--gossip-ceiling-ms" => {
+            Some((GOSSIPING, "broadcast, counters, g-set and or-set"))
+        }
+        _ => None,
+    }
+}
+
+/// The topology `mode` derives for `nodes` nodes named as Maelstrom names
+/// them, as a Graphviz graph on `stdout`.
+fn print_topology(mode: TopologyMode, nodes: usize, mut stdout: impl Write) -> anyhow::Result<()> {
+    let ids: Vec<NodeId> = (0..nodes)
+        .map(|i| NodeId::from(format!("n{}", i)))
+        .collect();
+    let Some(topology) = mode.derive(&ids) else {
+        anyhow::bail!(
+            "the maelstrom topology only comes from maelstrom; pick another with --topology"
+        );
+    };
+    let dot = topology::to_dot(&ids, &topology, None, &HashMap::new());
+    stdout.write_all(dot.as_bytes())?;
+    Ok(())
+}
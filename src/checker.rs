@@ -0,0 +1,284 @@
+//! Client histories and checks over them. [`Sim`](crate::sim::Sim) keeps a
+//! [`History`] of every request its client makes and every reply to one,
+//! as Jepsen-style `invoke` and `ok` (or `fail`) ops, which
+//! [`History::write_jsonl`] writes out a line each for other checkers.
+//!
+//! The checks here read a node's final value from the last read answered
+//! by it, so a run should end with a read of every node once it's quiet.
+//! A failed check returns the ops that show it, to print.
+
+use crate::protocol::{AdminPayload, ErrorCode, MsgId, NodeId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OpKind {
+    Invoke,
+    Ok,
+    /// Answered with an error.
+    Fail,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Op {
+    #[serde(rename = "type")]
+    pub kind: OpKind,
+    /// Nanoseconds of virtual time since the run started.
+    pub time: u64,
+    /// The node the request went to.
+    pub node: NodeId,
+    pub msg_id: MsgId,
+    /// The request's type, for its replies too.
+    pub f: String,
+    /// The request's or reply's fields, less its type. A chunked read has
+    /// an `ok` for each chunk.
+    pub value: Value,
+}
+
+/// What the client did and was told, in the order it happened.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    ops: Vec<Op>,
+    /// Each request's node and type, for its replies.
+    invoked: HashMap<MsgId, (NodeId, String)>,
+}
+impl History {
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+    /// Records a request to `node`; admin ones are left out.
+    pub fn invoke(&mut self, time: Duration, node: &NodeId, msg_id: MsgId, payload: &Value) {
+        let (f, value) = split_type(payload);
+        if AdminPayload::TYPES.contains(&f.as_str()) {
+            return;
+        }
+        self.invoked.insert(msg_id, (node.clone(), f.clone()));
+        self.ops.push(Op {
+            kind: OpKind::Invoke,
+            time: time.as_nanos() as u64,
+            node: node.clone(),
+            msg_id,
+            f,
+            value,
+        });
+    }
+    /// Records a reply to the request `in_reply_to`, if it's one recorded.
+    pub fn complete(&mut self, time: Duration, in_reply_to: MsgId, payload: &Value) {
+        let Some((node, f)) = self.invoked.get(&in_reply_to) else {
+            return;
+        };
+        let (kind, value) = split_type(payload);
+        let kind = if kind == "error" {
+            OpKind::Fail
+        } else {
+            OpKind::Ok
+        };
+        self.ops.push(Op {
+            kind,
+            time: time.as_nanos() as u64,
+            node: node.clone(),
+            msg_id: in_reply_to,
+            f: f.clone(),
+            value,
+        });
+    }
+    /// Writes each op as a line of JSON.
+    pub fn write_jsonl(&self, mut out: impl Write) -> anyhow::Result<()> {
+        for op in &self.ops {
+            serde_json::to_writer(&mut out, op)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+    /// The ops of the one request `msg_id`.
+    fn request(&self, msg_id: MsgId) -> Vec<Op> {
+        self.ops
+            .iter()
+            .filter(|op| op.msg_id == msg_id)
+            .cloned()
+            .collect()
+    }
+    /// The `ok`s of requests of type `f`, merged by request, in the order
+    /// their last reply came; `merge` folds a chunk into what came before.
+    fn completed(
+        &self,
+        f: &str,
+        merge: impl Fn(&mut Value, &Value),
+    ) -> Vec<(MsgId, &NodeId, Value)> {
+        let mut completed: Vec<(MsgId, &NodeId, Value)> = Vec::new();
+        for op in &self.ops {
+            if op.kind != OpKind::Ok || op.f != f {
+                continue;
+            }
+            match completed
+                .iter()
+                .position(|(msg_id, ..)| *msg_id == op.msg_id)
+            {
+                Some(i) => {
+                    let (msg_id, node, mut value) = completed.remove(i);
+                    merge(&mut value, &op.value);
+                    completed.push((msg_id, node, value));
+                }
+                None => completed.push((op.msg_id, &op.node, op.value.clone())),
+            }
+        }
+        completed
+    }
+    /// The last read `ok` from each node, by node.
+    fn final_reads(&self, merge: impl Fn(&mut Value, &Value)) -> BTreeMap<&NodeId, (MsgId, Value)> {
+        self.completed("read", merge)
+            .into_iter()
+            .map(|(msg_id, node, value)| (node, (msg_id, value)))
+            .collect()
+    }
+}
+
+/// A payload's `type`, and the rest of it.
+fn split_type(payload: &Value) -> (String, Value) {
+    let mut value = payload.clone();
+    let f = value
+        .as_object_mut()
+        .and_then(|fields| fields.remove("type"))
+        .and_then(|f| f.as_str().map(str::to_string))
+        .unwrap_or_default();
+    (f, value)
+}
+
+/// A check that failed: why, and the ops that show it.
+#[derive(Debug)]
+pub struct Violation {
+    pub reason: String,
+    pub ops: Vec<Op>,
+}
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)?;
+        for op in &self.ops {
+            write!(f, "\n  {}", serde_json::to_string(op).unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for Violation {}
+
+/// Every broadcast acknowledged is in every node's final read, and no read
+/// has a value that was never broadcast.
+pub fn check_broadcast(history: &History) -> Result<(), Violation> {
+    let key = Value::to_string;
+    let mut broadcast: BTreeMap<String, MsgId> = BTreeMap::new();
+    let mut acked: BTreeSet<MsgId> = BTreeSet::new();
+    for op in history.ops.iter().filter(|op| op.f == "broadcast") {
+        match op.kind {
+            OpKind::Invoke => {
+                broadcast.insert(key(&op.value["message"]), op.msg_id);
+            }
+            OpKind::Ok => {
+                acked.insert(op.msg_id);
+            }
+            OpKind::Fail => {}
+        }
+    }
+    let merge = |read: &mut Value, chunk: &Value| {
+        if let (Some(read), Some(chunk)) = (
+            read["messages"].as_array_mut(),
+            chunk["messages"].as_array(),
+        ) {
+            read.extend(chunk.iter().cloned());
+        }
+    };
+    let read_values = |read: &Value| -> BTreeSet<String> {
+        read["messages"]
+            .as_array()
+            .map(|messages| messages.iter().map(key).collect())
+            .unwrap_or_default()
+    };
+    for (msg_id, _, read) in history.completed("read", merge) {
+        if let Some(value) = read_values(&read)
+            .into_iter()
+            .find(|value| !broadcast.contains_key(value))
+        {
+            return Err(Violation {
+                reason: format!("read {} has {}, which was never broadcast", msg_id, value),
+                ops: history.request(msg_id),
+            });
+        }
+    }
+    for (node, (read_id, read)) in history.final_reads(merge) {
+        let seen = read_values(&read);
+        let missing = broadcast
+            .iter()
+            .find(|(value, msg_id)| acked.contains(msg_id) && !seen.contains(*value));
+        if let Some((value, msg_id)) = missing {
+            let mut ops = history.request(*msg_id);
+            ops.extend(history.request(read_id));
+            return Err(Violation {
+                reason: format!(
+                    "{}'s final read lacks {}, acknowledged as broadcast",
+                    node, value
+                ),
+                ops,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Whether an error leaves it open if the request took effect: a timeout
+/// or a crash, as against a definite refusal.
+fn is_indefinite(error: &Value) -> bool {
+    let code = error["code"].as_u64().map(|code| code as usize);
+    matches!(
+        code.and_then(ErrorCode::from_code),
+        Some(ErrorCode::Timeout | ErrorCode::Crash)
+    )
+}
+
+/// Every node's final read of the counter is the sum of the acknowledged
+/// deltas, give or take those never answered or failed indefinitely, which
+/// may or may not count.
+pub fn check_counter(history: &History) -> Result<(), Violation> {
+    let mut acked = 0i64;
+    // What the deltas never answered could add to, and take from, the sum.
+    let mut unsure: BTreeMap<MsgId, i64> = BTreeMap::new();
+    for op in history.ops.iter().filter(|op| op.f == "add") {
+        match op.kind {
+            OpKind::Invoke => {
+                unsure.insert(op.msg_id, op.value["delta"].as_i64().unwrap_or(0));
+            }
+            OpKind::Ok => {
+                acked = acked.wrapping_add(unsure.remove(&op.msg_id).unwrap_or(0));
+            }
+            // A timeout or crash may have happened after the add took.
+            OpKind::Fail if is_indefinite(&op.value) => {}
+            OpKind::Fail => {
+                unsure.remove(&op.msg_id);
+            }
+        }
+    }
+    let low = acked.wrapping_add(unsure.values().filter(|delta| **delta < 0).sum::<i64>());
+    let high = acked.wrapping_add(unsure.values().filter(|delta| **delta > 0).sum::<i64>());
+    for (node, (read_id, read)) in history.final_reads(|_, _| {}) {
+        let value = read["value"].as_i64();
+        if value.is_some_and(|value| (low..=high).contains(&value)) {
+            continue;
+        }
+        let expected = if low == high {
+            format!("{}", low)
+        } else {
+            format!("within {}..={}", low, high)
+        };
+        return Err(Violation {
+            reason: format!(
+                "{}'s final read is {}, not {}",
+                node, read["value"], expected
+            ),
+            ops: history.request(read_id),
+        });
+    }
+    Ok(())
+}
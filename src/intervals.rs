@@ -0,0 +1,100 @@
+//! Sets of integers kept as sorted, non-overlapping ranges. Broadcast
+//! values are mostly dense runs of integers, which this stores (and sends
+//! in digests) as a handful of `[start, end]` pairs instead of one entry
+//! per value.
+
+use std::collections::BTreeMap;
+
+/// Ranges by start, each inclusive of its end. Ranges that touch are merged
+/// as they're inserted, so there's never a gap of zero between two.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct IntervalSet {
+    ranges: BTreeMap<u64, u64>,
+    len: usize,
+}
+impl IntervalSet {
+    pub fn insert(&mut self, n: u64) -> bool {
+        self.insert_range(n, n) > 0
+    }
+    /// Adds every value in `start..=end`, returning how many weren't there.
+    pub fn insert_range(&mut self, start: u64, end: u64) -> usize {
+        if start > end {
+            return 0;
+        }
+        let (mut merged_start, mut merged_end) = (start, end);
+        let mut covered = 0;
+        let touching: Vec<(u64, u64)> = self
+            .ranges
+            .range(..=end.saturating_add(1))
+            .rev()
+            .take_while(|(_, e)| e.saturating_add(1) >= start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in touching {
+            self.ranges.remove(&s);
+            covered += size(s.max(start), e.min(end));
+            merged_start = merged_start.min(s);
+            merged_end = merged_end.max(e);
+        }
+        self.ranges.insert(merged_start, merged_end);
+        let added = size(start, end) - covered;
+        self.len += added;
+        added
+    }
+    pub fn contains(&self, n: u64) -> bool {
+        self.ranges
+            .range(..=n)
+            .next_back()
+            .is_some_and(|(_, end)| *end >= n)
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The ranges, lowest first, each as `(start, end)` inclusive.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(start, end)| (*start, *end))
+    }
+    /// Every value, lowest first.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ranges().flat_map(|(start, end)| start..=end)
+    }
+    /// The parts of `start..=end` that aren't in the set.
+    pub fn gaps(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        if start > end {
+            return gaps;
+        }
+        let from = self
+            .ranges
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(s, _)| *s);
+        let mut next = Some(start);
+        for (s, e) in self.ranges.range(from..=end) {
+            let Some(at) = next else {
+                break;
+            };
+            if *s > at {
+                gaps.push((at, s - 1));
+            }
+            if *e >= at {
+                next = e.checked_add(1);
+            }
+        }
+        if let Some(at) = next.filter(|at| *at <= end) {
+            gaps.push((at, end));
+        }
+        gaps
+    }
+}
+
+/// How many values `start..=end` holds, `start <= end`.
+fn size(start: u64, end: u64) -> usize {
+    if start > end {
+        return 0;
+    }
+    (end - start) as usize + 1
+}
@@ -0,0 +1,286 @@
+//! Client for Maelstrom's key-value services (`seq-kv`, `lin-kv`).
+
+use crate::protocol::{ErrorCode, Message, NodeId, Payload};
+use crate::runtime::{Context, Handler, RpcError};
+use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
+
+#[derive(Debug)]
+pub enum KvError {
+    KeyNotFound(String),
+    CasMismatch(String),
+    Service { code: usize, text: String },
+    Timeout,
+    Unexpected,
+    Send(String),
+}
+impl std::fmt::Display for KvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::KeyNotFound(text) => write!(f, "key not found: {}", text),
+            KvError::CasMismatch(text) => write!(f, "cas precondition failed: {}", text),
+            KvError::Service { code, text } => write!(f, "kv error {}: {}", code, text),
+            KvError::Timeout => write!(f, "timed out waiting for kv reply"),
+            KvError::Unexpected => write!(f, "unexpected kv reply"),
+            KvError::Send(text) => write!(f, "failed to send kv request: {}", text),
+        }
+    }
+}
+impl std::error::Error for KvError {}
+
+const KV_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub const SEQ_KV: &str = "seq-kv";
+pub const LIN_KV: &str = "lin-kv";
+
+/// Each request comes in two forms: one that blocks the dispatcher until
+/// the reply comes, and a `_then` one that carries on with a continuation
+/// instead (see [`Context::rpc_then`]), which handlers want.
+#[derive(Clone)]
+pub struct KvClient {
+    service: NodeId,
+}
+impl KvClient {
+    pub fn new(service: &str) -> Self {
+        Self {
+            service: service.into(),
+        }
+    }
+    fn request(&self, ctx: &Context, payload: Payload) -> Result<Payload, KvError> {
+        kv_reply(ctx.rpc_with_timeout(&self.service, payload, KV_TIMEOUT))
+    }
+    fn request_then<H: Handler>(
+        &self,
+        ctx: &Context,
+        payload: Payload,
+        then: impl FnOnce(&mut H, &mut Context, Result<Payload, KvError>) -> anyhow::Result<()>
+        + Send
+        + 'static,
+    ) -> anyhow::Result<()> {
+        ctx.rpc_then(&self.service, payload, KV_TIMEOUT, |handler, ctx, reply| {
+            then(handler, ctx, kv_reply(reply))
+        })
+    }
+    pub fn read(&self, ctx: &Context, key: &str) -> Result<Value, KvError> {
+        let reply = self.request(
+            ctx,
+            Payload::Read {
+                key: Some(key.into()),
+            },
+        )?;
+        match reply {
+            Payload::ReadOk { value, .. } => Ok(value.unwrap_or(Value::Null)),
+            _ => Err(KvError::Unexpected),
+        }
+    }
+    pub fn read_then<H: Handler>(
+        &self,
+        ctx: &Context,
+        key: &str,
+        then: impl FnOnce(&mut H, &mut Context, Result<Value, KvError>) -> anyhow::Result<()>
+        + Send
+        + 'static,
+    ) -> anyhow::Result<()> {
+        let read = Payload::Read {
+            key: Some(key.into()),
+        };
+        self.request_then(ctx, read, |handler, ctx, reply| {
+            let value = reply.and_then(|reply| match reply {
+                Payload::ReadOk { value, .. } => Ok(value.unwrap_or(Value::Null)),
+                _ => Err(KvError::Unexpected),
+            });
+            then(handler, ctx, value)
+        })
+    }
+    pub fn write(&self, ctx: &Context, key: &str, value: Value) -> Result<(), KvError> {
+        let reply = self.request(
+            ctx,
+            Payload::Write {
+                key: key.into(),
+                value,
+            },
+        )?;
+        match reply {
+            Payload::WriteOk => Ok(()),
+            _ => Err(KvError::Unexpected),
+        }
+    }
+    pub fn cas(
+        &self,
+        ctx: &Context,
+        key: &str,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError> {
+        let reply = self.request(
+            ctx,
+            Payload::Cas {
+                key: key.into(),
+                from,
+                to,
+                create_if_not_exists,
+            },
+        )?;
+        match reply {
+            Payload::CasOk => Ok(()),
+            _ => Err(KvError::Unexpected),
+        }
+    }
+    pub fn cas_then<H: Handler>(
+        &self,
+        ctx: &Context,
+        key: &str,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+        then: impl FnOnce(&mut H, &mut Context, Result<(), KvError>) -> anyhow::Result<()>
+        + Send
+        + 'static,
+    ) -> anyhow::Result<()> {
+        let cas = Payload::Cas {
+            key: key.into(),
+            from,
+            to,
+            create_if_not_exists,
+        };
+        self.request_then(ctx, cas, |handler, ctx, reply| {
+            let done = reply.and_then(|reply| match reply {
+                Payload::CasOk => Ok(()),
+                _ => Err(KvError::Unexpected),
+            });
+            then(handler, ctx, done)
+        })
+    }
+    /// Read-modify-write loop: applies `f` to the current value (`None` if the
+    /// key doesn't exist yet) and retries whenever the cas loses a race.
+    pub fn update(
+        &self,
+        ctx: &Context,
+        key: &str,
+        mut f: impl FnMut(Option<&Value>) -> Value,
+    ) -> Result<Value, KvError> {
+        loop {
+            let current = match self.read(ctx, key) {
+                Ok(value) => Some(value),
+                Err(KvError::KeyNotFound(_)) => None,
+                Err(e) => return Err(e),
+            };
+            let next = f(current.as_ref());
+            let from = current.clone().unwrap_or(Value::Null);
+            match self.cas(ctx, key, from, next.clone(), current.is_none()) {
+                Ok(()) => return Ok(next),
+                Err(KvError::CasMismatch(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /// [`KvClient::update`] without blocking: each read and cas waits on the
+    /// dispatcher, and `then` gets the value written in the end.
+    pub fn update_then<H: Handler>(
+        &self,
+        ctx: &Context,
+        key: &str,
+        mut f: impl FnMut(Option<&Value>) -> Value + Send + 'static,
+        then: impl FnOnce(&mut H, &mut Context, Result<Value, KvError>) -> anyhow::Result<()>
+        + Send
+        + 'static,
+    ) -> anyhow::Result<()> {
+        let kv = self.clone();
+        let owned = key.to_string();
+        self.read_then(ctx, key, move |handler, ctx, current| {
+            let current = match current {
+                Ok(value) => Some(value),
+                Err(KvError::KeyNotFound(_)) => None,
+                Err(e) => return then(handler, ctx, Err(e)),
+            };
+            let next = f(current.as_ref());
+            let from = current.clone().unwrap_or(Value::Null);
+            let create = current.is_none();
+            let retry = kv.clone();
+            kv.cas_then(
+                ctx,
+                &owned.clone(),
+                from,
+                next.clone(),
+                create,
+                move |handler, ctx, done| match done {
+                    Ok(()) => then(handler, ctx, Ok(next)),
+                    Err(KvError::CasMismatch(_)) => retry.update_then(ctx, &owned, f, then),
+                    Err(e) => then(handler, ctx, Err(e)),
+                },
+            )
+        })
+    }
+}
+
+/// A kv service's reply as the payload it answered with, or the error.
+fn kv_reply(reply: Result<Message<Value>, RpcError>) -> Result<Payload, KvError> {
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(RpcError::Timeout) => return Err(KvError::Timeout),
+        Err(RpcError::Send(e)) => return Err(KvError::Send(e.to_string())),
+        Err(RpcError::Error { code, text }) => {
+            return Err(match ErrorCode::from_code(code) {
+                Some(ErrorCode::KeyDoesNotExist) => KvError::KeyNotFound(text),
+                Some(ErrorCode::PreconditionFailed) => KvError::CasMismatch(text),
+                _ => KvError::Service { code, text },
+            });
+        }
+    };
+    reply
+        .parse_body::<Payload>()
+        .map(|reply| reply.body.payload)
+        .map_err(|_| KvError::Unexpected)
+}
+
+/// A kv service's contents, by serialized key.
+pub type KvStore = HashMap<String, Value>;
+
+/// Applies one kv request to `store` and returns the reply Maelstrom's kv
+/// services would give.
+pub fn apply(store: &mut KvStore, request: &Payload) -> Payload {
+    match request {
+        Payload::Read { key: Some(key) } => match store.get(&key.to_string()) {
+            Some(value) => Payload::ReadOk {
+                messages: None,
+                value: Some(value.clone()),
+                chunk: None,
+                total_chunks: None,
+            },
+            None => error(ErrorCode::KeyDoesNotExist, "key does not exist"),
+        },
+        Payload::Write { key, value } => {
+            store.insert(key.to_string(), value.clone());
+            Payload::WriteOk
+        }
+        Payload::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        } => match store.get(&key.to_string()) {
+            None if *create_if_not_exists => {
+                store.insert(key.to_string(), to.clone());
+                Payload::CasOk
+            }
+            None => error(ErrorCode::KeyDoesNotExist, "key does not exist"),
+            Some(current) if current == from => {
+                store.insert(key.to_string(), to.clone());
+                Payload::CasOk
+            }
+            Some(current) => error(
+                ErrorCode::PreconditionFailed,
+                format!("expected {}, but had {}", from, current),
+            ),
+        },
+        _ => error(ErrorCode::NotSupported, "not a kv operation"),
+    }
+}
+
+pub fn error(code: ErrorCode, text: impl Into<String>) -> Payload {
+    Payload::Error {
+        code: code as usize,
+        text: text.into(),
+    }
+}
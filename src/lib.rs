@@ -0,0 +1,19 @@
+//! A Maelstrom node: the message types, node state, and the runtime that
+//! drives them, usable from other binaries.
+
+pub mod builder;
+pub mod checker;
+pub mod intervals;
+pub mod kv;
+pub mod log;
+pub mod middleware;
+pub mod node;
+pub mod protocol;
+pub mod raft;
+pub mod record;
+pub mod ring;
+pub mod runtime;
+pub mod sim;
+pub mod testnet;
+pub mod topology;
+pub mod wal;
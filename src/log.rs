@@ -0,0 +1,124 @@
+//! Levelled `key=value` logging to stderr, one line per event so runs can be
+//! grepped and lined up with the Maelstrom log. stdout is the protocol
+//! channel, so nothing here ever touches it.
+//!
+//! The level comes from `MAELLE_LOG` (`off`, `error`, `warn`, `info`,
+//! `debug`; default `info`). Messages in and out are logged at `debug`.
+//! While a handler works on a traced request, its lines carry `trace=`.
+
+use std::cell::RefCell;
+use std::fmt::{Display, Write as _};
+use std::io::Write as _;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            _ => return None,
+        })
+    }
+}
+
+static MAX_LEVEL: OnceLock<Option<Level>> = OnceLock::new();
+static NODE: OnceLock<String> = OnceLock::new();
+
+thread_local! {
+    static TRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn max_level() -> Option<Level> {
+    *MAX_LEVEL.get_or_init(|| match std::env::var("MAELLE_LOG").as_deref() {
+        Ok("off") => None,
+        Ok(name) => Some(Level::from_name(name).unwrap_or(Level::Info)),
+        Err(_) => Some(Level::Info),
+    })
+}
+
+/// Sets the level in place of `MAELLE_LOG`, `None` being `off`. Returns
+/// false, changing nothing, once a line has been logged.
+pub fn set_max_level(level: Option<Level>) -> bool {
+    MAX_LEVEL.set(level).is_ok()
+}
+
+pub fn enabled(level: Level) -> bool {
+    max_level().is_some_and(|max| level <= max)
+}
+
+/// Tags every later line with this node's id.
+pub fn set_node(node_id: &str) {
+    let _ = NODE.set(node_id.to_string());
+}
+
+/// Tags this thread's later lines, and its outgoing messages, with `trace`.
+pub fn set_trace(trace: Option<String>) {
+    TRACE.with(|current| *current.borrow_mut() = trace);
+}
+
+/// The trace this thread is working under, if any.
+pub fn trace() -> Option<String> {
+    TRACE.with(|current| current.borrow().clone())
+}
+
+/// Writes one line; use [`log!`](crate::log!) rather than calling this
+/// directly so disabled levels cost nothing.
+pub fn write(level: Level, event: &str, fields: &[(&str, &dyn Display)]) {
+    let mut line = format!("level={}", level.name());
+    if let Some(node) = NODE.get() {
+        let _ = write!(line, " node={}", node);
+    }
+    if let Some(trace) = trace() {
+        let _ = write!(line, " trace={}", trace);
+    }
+    let _ = write!(line, " event={}", event);
+    for (key, value) in fields {
+        // Lets callers name a field after a keyword, as in `r#type = ..`.
+        let key = key.trim_start_matches("r#");
+        let value = value.to_string();
+        if value.is_empty() || value.contains([' ', '=', '"', '\n']) {
+            let _ = write!(line, " {}={:?}", key, value);
+        } else {
+            let _ = write!(line, " {}={}", key, value);
+        }
+    }
+    line.push('\n');
+    let _ = std::io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// Shows an optional field as its value, or `-` when absent.
+pub fn opt<T: Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+/// `log!(Warn, "retry", dest = dest, msg_id = id)` logs `event=retry` with
+/// the given fields if the level is enabled.
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $event:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::log::enabled($crate::log::Level::$level) {
+            $crate::log::write(
+                $crate::log::Level::$level,
+                $event,
+                &[$((stringify!($key), &$value as &dyn std::fmt::Display)),*],
+            );
+        }
+    };
+}
@@ -0,0 +1,71 @@
+//! Cross-cutting behavior run around every workload message, so logging,
+//! metrics, dedup and the like don't have to be written into each handler.
+//!
+//! Every [`Context`] starts out with [`Logging`] and [`Metrics`]; more are
+//! added with [`Context::add_middleware`], e.g. from the closure that builds
+//! the handler. `before` runs in registration order and `after` in reverse,
+//! and only for middlewares whose `before` ran. Init and admin messages
+//! don't go through the chain.
+
+use crate::log;
+use crate::protocol::Message;
+use crate::runtime::{Context, Stats, request_msg_id};
+use serde_json::Value;
+use std::{ops::ControlFlow, time::Instant};
+
+/// What handling a message came to.
+pub type HandlerResult = anyhow::Result<()>;
+
+pub trait Middleware: Send + 'static {
+    /// Runs before the handler. `Break` stops here: neither later
+    /// middlewares nor the handler see the message, and the payload, if
+    /// there is one, is sent as the reply.
+    fn before(&mut self, _ctx: &Context, _m: &Message<Value>) -> ControlFlow<Option<Value>> {
+        ControlFlow::Continue(())
+    }
+
+    /// Runs once the message has been handled, or answered by a `before`.
+    fn after(&mut self, _ctx: &Context, _m: &Message<Value>, _result: &HandlerResult) {}
+}
+
+/// Logs each message handled and how long it took, at `debug`.
+#[derive(Default)]
+pub struct Logging {
+    started: Option<Instant>,
+}
+impl Middleware for Logging {
+    fn before(&mut self, _: &Context, _: &Message<Value>) -> ControlFlow<Option<Value>> {
+        self.started = Some(Instant::now());
+        ControlFlow::Continue(())
+    }
+
+    fn after(&mut self, _: &Context, m: &Message<Value>, result: &HandlerResult) {
+        let elapsed = self.started.take().map_or(0, |at| at.elapsed().as_micros());
+        log!(
+            Debug,
+            "handled",
+            src = m.src,
+            r#type = m
+                .body
+                .payload
+                .get("type")
+                .and_then(Value::as_str)
+                .unwrap_or("-"),
+            msg_id = log::opt(m.body.msg_id),
+            ok = result.is_ok(),
+            elapsed_us = elapsed,
+        );
+    }
+}
+
+/// Counts client requests for the efficiency stats.
+#[derive(Default)]
+pub struct Metrics;
+impl Middleware for Metrics {
+    fn before(&mut self, ctx: &Context, m: &Message<Value>) -> ControlFlow<Option<Value>> {
+        if m.src.is_client() && request_msg_id(&m.body).is_some() {
+            Stats::incr(&ctx.stats().client_ops);
+        }
+        ControlFlow::Continue(())
+    }
+}
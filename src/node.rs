@@ -0,0 +1,3337 @@
+//! The node: its state, the workload data structures it is built from, and
+//! the `Handler` implementation that drives them.
+
+use crate::intervals::IntervalSet;
+use crate::kv::{self, KvClient, KvError, KvStore, SEQ_KV};
+use crate::log;
+#[cfg(feature = "broadcast")]
+use crate::protocol::Digest;
+use crate::protocol::{
+    Body, ErrorCode, ErrorReply, Kind, Message, MsgId, NodeId, Operation, Payload, RegisterWrite,
+    Stamp,
+};
+use crate::raft::{Raft, raft_tick};
+use crate::ring::Ring;
+use crate::runtime::{Clock, Context, Handler, Output, Period, Stats, Task, env_or, pending_ttl};
+use crate::topology;
+use crate::wal::{self, Wal, WalEntry};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Workload {
+    /// Only `echo`, which every workload answers anyway.
+    Echo,
+    /// Only `generate`, likewise.
+    UniqueIds,
+    Broadcast,
+    Counter,
+    KvCounter,
+    KvKafka,
+    GSet,
+    OrSet,
+    LinKv,
+    /// `lin-kv` without replication: each key lives only on the node the
+    /// ring assigns it to, and every other node forwards to that one.
+    ShardedKv,
+}
+impl Workload {
+    pub fn from_env() -> Self {
+        std::env::var("MAELLE_WORKLOAD")
+            .ok()
+            .and_then(|name| Self::from_name(&name))
+            .unwrap_or(if cfg!(feature = "broadcast") {
+                Workload::Broadcast
+            } else {
+                Workload::Echo
+            })
+    }
+    /// The workload Maelstrom calls `name`, e.g. `g-counter`, unless it
+    /// isn't [built in](Workload::is_built).
+    pub fn from_name(name: &str) -> Option<Self> {
+        let workload = match name {
+            "echo" => Workload::Echo,
+            "unique-ids" => Workload::UniqueIds,
+            "broadcast" => Workload::Broadcast,
+            "g-counter" | "pn-counter" | "counter" => Workload::Counter,
+            "kv-counter" => Workload::KvCounter,
+            "kv-kafka" => Workload::KvKafka,
+            "g-set" => Workload::GSet,
+            "or-set" => Workload::OrSet,
+            "lin-kv" => Workload::LinKv,
+            "sharded-kv" => Workload::ShardedKv,
+            _ => return None,
+        };
+        workload.is_built().then_some(workload)
+    }
+    /// Whether this build has the cargo feature the workload's messages
+    /// are behind.
+    pub fn is_built(self) -> bool {
+        match self {
+            Workload::Broadcast => cfg!(feature = "broadcast"),
+            Workload::Counter | Workload::KvCounter | Workload::GSet | Workload::OrSet => {
+                cfg!(feature = "counter")
+            }
+            Workload::KvKafka => cfg!(feature = "kafka"),
+            Workload::Echo | Workload::UniqueIds | Workload::LinKv | Workload::ShardedKv => true,
+        }
+    }
+}
+
+/// A pn-counter built from two grow-only counters, each tracked per node.
+/// Only a node's own totals change locally; replicas gossip their whole
+/// state and [merge](PnCounter::merge) what they hear, so they converge.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct PnCounter {
+    pub increments: HashMap<String, u64>,
+    pub decrements: HashMap<String, u64>,
+}
+impl PnCounter {
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        let counter = if delta >= 0 {
+            &mut self.increments
+        } else {
+            &mut self.decrements
+        };
+        let total = counter.entry(node_id.to_string()).or_default();
+        *total = total.wrapping_add(delta.unsigned_abs());
+    }
+    /// Keeps each node's larger totals: both only ever grow, so the larger
+    /// is the later.
+    pub fn merge(&mut self, other: PnCounter) {
+        for (ours, theirs) in [
+            (&mut self.increments, other.increments),
+            (&mut self.decrements, other.decrements),
+        ] {
+            for (node, total) in theirs {
+                let ours = ours.entry(node).or_default();
+                *ours = (*ours).max(total);
+            }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.increments.is_empty() && self.decrements.is_empty()
+    }
+    /// Wraps like an i64 on overflow rather than panicking.
+    pub fn value(&self) -> i64 {
+        let sum =
+            |totals: &HashMap<String, u64>| totals.values().fold(0u64, |a, b| a.wrapping_add(*b));
+        (sum(&self.increments) as i64).wrapping_sub(sum(&self.decrements) as i64)
+    }
+}
+
+/// Exponential backoff for retransmissions: the n-th retry waits
+/// `base * multiplier^n`, capped at `max_interval`, spread by +/- `jitter`.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub base: Duration,
+    pub multiplier: f64,
+    pub max_interval: Duration,
+    pub jitter: f64,
+}
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            base: Duration::from_millis(env_or("MAELLE_RETRY_BASE_MS", 500)),
+            multiplier: env_or("MAELLE_RETRY_MULTIPLIER", 2.0),
+            max_interval: Duration::from_millis(env_or("MAELLE_RETRY_MAX_MS", 5000)),
+            jitter: env_or("MAELLE_RETRY_JITTER", 0.1),
+        }
+    }
+    /// `random` is a sample in [0, 1) used to pick the jitter offset.
+    pub fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let backoff = self.base.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let capped = backoff.min(self.max_interval.as_secs_f64());
+        let jittered = capped * (1.0 + self.jitter * (2.0 * random - 1.0));
+        Duration::from_secs_f64(jittered.max(0.0))
+    }
+    pub fn next_delay(&self, attempt: u32) -> Duration {
+        self.delay(attempt, (random_u64() >> 11) as f64 / (1u64 << 53) as f64)
+    }
+}
+
+/// Gossip and retries paced by each neighbor's smoothed round-trip time:
+/// the neighbor is gossiped to every `rtt_multiple` of it, and its retries
+/// back off from that, kept within `floor..=ceiling`. Neighbors with no
+/// estimate yet keep the fixed intervals.
+#[derive(Clone, Copy, Debug)]
+pub struct Pacing {
+    pub rtt_multiple: f64,
+    pub floor: Duration,
+    pub ceiling: Duration,
+}
+impl Pacing {
+    pub fn from_env() -> Self {
+        Self {
+            rtt_multiple: env_or("MAELLE_GOSSIP_RTT_MULTIPLE", 4.0),
+            floor: Duration::from_millis(env_or("MAELLE_GOSSIP_FLOOR_MS", 50)),
+            ceiling: Duration::from_millis(env_or("MAELLE_GOSSIP_CEILING_MS", 2000)),
+        }
+    }
+    pub fn interval(&self, rtt: Duration) -> Duration {
+        Duration::try_from_secs_f64(rtt.as_secs_f64() * self.rtt_multiple)
+            .unwrap_or(self.ceiling)
+            .min(self.ceiling)
+            .max(self.floor)
+    }
+}
+
+/// A token bucket for background traffic: `rate` sends a second on
+/// average, up to `burst` at once. A zero rate leaves it unlimited.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    pub rate: f64,
+    pub burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            refilled_at: now,
+        }
+    }
+    pub fn from_env(now: Instant) -> Self {
+        Self::new(
+            env_or("MAELLE_RATE_LIMIT", 0.0),
+            env_or("MAELLE_RATE_BURST", 100.0),
+            now,
+        )
+    }
+    pub fn is_limited(&self) -> bool {
+        self.rate > 0.0
+    }
+    /// Takes a token if one has accrued by `now`.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        if !self.is_limited() {
+            return true;
+        }
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst.max(1.0));
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// A send the [`RateLimiter`] held back, waiting its turn.
+pub struct Deferred {
+    pub dest: NodeId,
+    pub body: Body,
+    pub trace: Option<String>,
+    /// Whether a callback waited on it when it was held back; if that's
+    /// gone by its turn, it was answered in the meantime and isn't sent.
+    pub tracked: bool,
+}
+
+pub enum Callback {
+    /// An unacknowledged message, resent with its original msg_id until the
+    /// ack arrives.
+    Pending {
+        dest: NodeId,
+        body: Payload,
+        /// When it was first sent, to expire it by.
+        created: Instant,
+        sent_at: Instant,
+        attempts: u32,
+        retry_at: Instant,
+        /// The request it was first sent for, carried by the retries too.
+        trace: Option<String>,
+    },
+    /// Gossip isn't retried, but its ack tells us what the peer now has.
+    Gossip {
+        dest: NodeId,
+        messages: Vec<Value>,
+        sent_at: Instant,
+    },
+    /// A client request passed on to another node, whose answer goes back
+    /// to `client` as the reply to `msg_id`.
+    Relay {
+        client: NodeId,
+        msg_id: MsgId,
+        sent_at: Instant,
+    },
+}
+
+/// A last-writer-wins register; versions are (lamport clock, origin node) so
+/// replicas applying the same writes in any order converge.
+pub struct Register {
+    pub value: Value,
+    pub version: (usize, NodeId),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdStrategy {
+    Counter,
+    Snowflake,
+    UuidV4,
+    UuidV7,
+}
+impl IdStrategy {
+    pub fn from_env() -> Self {
+        std::env::var("MAELLE_ID_STRATEGY")
+            .ok()
+            .and_then(|name| Self::from_name(&name))
+            .unwrap_or(IdStrategy::Counter)
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "counter" => IdStrategy::Counter,
+            "snowflake" => IdStrategy::Snowflake,
+            "uuid" | "uuid-v4" => IdStrategy::UuidV4,
+            "uuid-v7" => IdStrategy::UuidV7,
+            _ => return None,
+        })
+    }
+}
+
+/// How broadcast gossip rounds go. `Push` sends every live neighbor the
+/// values it hasn't been seen to have. `PushPull` trades [`Digest`]s with
+/// one random neighbor and has each side send only what the other's lacks.
+/// `Rumor` pushes a value to `fanout` random live neighbors a round, only
+/// for the first `rounds` rounds after learning it; after that it's cold
+/// and left to anti-entropy, unless a neighbor pulls it and heats it again.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GossipMode {
+    Push,
+    PushPull,
+    Rumor { rounds: usize, fanout: usize },
+}
+impl GossipMode {
+    pub fn from_env() -> Self {
+        std::env::var("MAELLE_GOSSIP_MODE")
+            .ok()
+            .and_then(|name| Self::from_name(&name))
+            .unwrap_or(GossipMode::Push)
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "push" => GossipMode::Push,
+            "push-pull" => GossipMode::PushPull,
+            "rumor" => Self::rumor(),
+            _ => return None,
+        })
+    }
+    /// `Rumor` as `MAELLE_RUMOR_ROUNDS` and `MAELLE_RUMOR_FANOUT` set it.
+    pub fn rumor() -> Self {
+        GossipMode::Rumor {
+            rounds: env_or("MAELLE_RUMOR_ROUNDS", 3),
+            fanout: env_or("MAELLE_RUMOR_FANOUT", 2),
+        }
+    }
+    /// `Rumor` with `rounds`, keeping this one's fanout if it's `Rumor`.
+    pub fn with_rounds(self, rounds: usize) -> Self {
+        match self {
+            GossipMode::Rumor { fanout, .. } => GossipMode::Rumor { rounds, fanout },
+            _ => Self::rumor().with_rounds(rounds),
+        }
+    }
+    /// `Rumor` with `fanout`, keeping this one's rounds if it's `Rumor`.
+    pub fn with_fanout(self, fanout: usize) -> Self {
+        match self {
+            GossipMode::Rumor { rounds, .. } => GossipMode::Rumor { rounds, fanout },
+            _ => Self::rumor().with_fanout(fanout),
+        }
+    }
+}
+
+thread_local! {
+    static SEEDED: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+}
+
+/// Makes [`random_u64`] on this thread a deterministic sequence from `seed`,
+/// for simulations that need to replay exactly.
+pub fn seed_random(seed: u64) {
+    SEEDED.with(|state| state.set(Some(seed)));
+}
+
+pub fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    if let Some(state) = SEEDED.with(|state| state.get()) {
+        // splitmix64
+        let state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        SEEDED.with(|seeded| seeded.set(Some(state)));
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        return z ^ (z >> 31);
+    }
+    // Every RandomState carries fresh keys, which is plenty for ids and jitter.
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0),
+    );
+    hasher.finish()
+}
+
+pub fn format_uuid(bits: u128) -> String {
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+pub fn uuid_v4() -> String {
+    let bits = ((random_u64() as u128) << 64) | random_u64() as u128;
+    let bits = (bits & !(0xf << 76) & !(0b11 << 62)) | (0x4 << 76) | (0b10 << 62);
+    format_uuid(bits)
+}
+
+pub fn uuid_v7(unix_ms: u64) -> String {
+    let random = ((random_u64() as u128) << 64) | random_u64() as u128;
+    let bits = ((unix_ms as u128 & 0xffff_ffff_ffff) << 80)
+        | (0x7 << 76)
+        | (random & (0xfff << 64))
+        | (0b10 << 62)
+        | (random & ((1 << 62) - 1));
+    format_uuid(bits)
+}
+
+pub const SNOWFLAKE_EPOCH_MS: u64 = 1_577_836_800_000;
+pub const SNOWFLAKE_NODE_BITS: u32 = 10;
+pub const SNOWFLAKE_SEQUENCE_BITS: u32 = 12;
+pub const SNOWFLAKE_SEQUENCE_MASK: u64 = (1 << SNOWFLAKE_SEQUENCE_BITS) - 1;
+
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Packs a millisecond timestamp, node index, and per-millisecond sequence
+/// into a u64. Timestamps never move backwards even if the clock does.
+#[derive(Debug)]
+pub struct Snowflake {
+    pub node_index: u64,
+    pub last_ms: u64,
+    pub sequence: u64,
+}
+impl Snowflake {
+    pub fn new(node_index: usize) -> Self {
+        Self {
+            node_index: node_index as u64 & ((1 << SNOWFLAKE_NODE_BITS) - 1),
+            last_ms: 0,
+            sequence: 0,
+        }
+    }
+    pub fn next(&mut self, mut clock: impl FnMut() -> u64) -> u64 {
+        let mut now = clock().max(self.last_ms);
+        if now == self.last_ms {
+            self.sequence = (self.sequence + 1) & SNOWFLAKE_SEQUENCE_MASK;
+            if self.sequence == 0 {
+                now = loop {
+                    let t = clock();
+                    if t > self.last_ms {
+                        break t;
+                    }
+                    if t < self.last_ms {
+                        // The clock is behind us; borrow the next millisecond
+                        // rather than spinning until it catches up.
+                        break self.last_ms + 1;
+                    }
+                    std::hint::spin_loop();
+                };
+            }
+        } else {
+            self.sequence = 0;
+        }
+        self.last_ms = now;
+        (now.saturating_sub(SNOWFLAKE_EPOCH_MS) << (SNOWFLAKE_NODE_BITS + SNOWFLAKE_SEQUENCE_BITS))
+            | (self.node_index << SNOWFLAKE_SEQUENCE_BITS)
+            | self.sequence
+    }
+}
+
+/// Where the broadcast neighbors come from: the topology Maelstrom sends, or
+/// a structure every node derives identically from the sorted `node_ids`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TopologyMode {
+    Maelstrom,
+    Tree {
+        fanout: usize,
+    },
+    Star,
+    /// Consecutive runs of `size` ids form clusters, each represented by
+    /// its first. Representatives are all linked to one another, and the
+    /// rest only to their own representative.
+    Clusters {
+        size: usize,
+    },
+    /// A square grid, as Maelstrom's own default.
+    Grid,
+    /// A cycle with `chords` shortcuts from each node; see [`topology::ring`].
+    Ring {
+        chords: usize,
+    },
+    /// A random connected graph of `degree` neighbors apiece, the same
+    /// on every node for the same `seed`.
+    Random {
+        degree: usize,
+        seed: u64,
+    },
+}
+impl TopologyMode {
+    pub fn from_env() -> Self {
+        std::env::var("MAELLE_TOPOLOGY")
+            .ok()
+            .and_then(|name| Self::from_name(&name))
+            .unwrap_or(TopologyMode::Maelstrom)
+    }
+    /// `maelstrom`, `star`, `tree` with `MAELLE_TREE_FANOUT` children per
+    /// node, `clusters` of `MAELLE_CLUSTER_SIZE` nodes, `grid`, `ring:K`
+    /// with K chords, or `random:D:SEED` with D neighbors apiece. Left off,
+    /// K, D and SEED come from `MAELLE_RING_CHORDS`, `MAELLE_RANDOM_DEGREE`
+    /// and `MAELLE_RANDOM_SEED`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let mut parts = name.split(':');
+        let kind = parts.next()?;
+        // A part given but not a number spoils the name.
+        let mut arg = |var: &str, default: u64| match parts.next() {
+            Some(value) => value.parse().ok(),
+            None => Some(env_or(var, default)),
+        };
+        let mode = match kind {
+            "ring" => TopologyMode::Ring {
+                chords: arg("MAELLE_RING_CHORDS", 2)? as usize,
+            },
+            "random" => TopologyMode::Random {
+                degree: arg("MAELLE_RANDOM_DEGREE", 4)? as usize,
+                seed: arg("MAELLE_RANDOM_SEED", 0)?,
+            },
+            _ if kind == name => return Self::from_plain_name(name),
+            _ => return None,
+        };
+        parts.next().is_none().then_some(mode)
+    }
+    fn from_plain_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "maelstrom" => TopologyMode::Maelstrom,
+            "grid" => TopologyMode::Grid,
+            "tree" => TopologyMode::Tree {
+                fanout: env_or("MAELLE_TREE_FANOUT", 4).max(1),
+            },
+            "star" => TopologyMode::Star,
+            "clusters" => TopologyMode::Clusters {
+                size: env_or("MAELLE_CLUSTER_SIZE", 5).max(1),
+            },
+            _ => return None,
+        })
+    }
+    /// The nodes `id` reaches across clusters: the other representatives,
+    /// if it's one. Empty for every other mode.
+    pub fn tier_peers(&self, id: &NodeId, node_ids: &[NodeId]) -> HashSet<NodeId> {
+        let TopologyMode::Clusters { size } = *self else {
+            return HashSet::new();
+        };
+        let mut ids = node_ids.to_vec();
+        ids.sort();
+        let representatives: HashSet<NodeId> = ids.into_iter().step_by(size.max(1)).collect();
+        if !representatives.contains(id) {
+            return HashSet::new();
+        }
+        representatives.into_iter().filter(|n| n != id).collect()
+    }
+    pub fn derive(&self, node_ids: &[NodeId]) -> Option<HashMap<NodeId, Vec<NodeId>>> {
+        let mut ids = node_ids.to_vec();
+        ids.sort();
+        let n = ids.len();
+        let edges = match *self {
+            TopologyMode::Maelstrom => return None,
+            TopologyMode::Tree { fanout } => topology::tree(n, fanout),
+            TopologyMode::Star => topology::star(n),
+            TopologyMode::Clusters { size } => topology::clusters(n, size),
+            TopologyMode::Grid => topology::grid(n),
+            TopologyMode::Ring { chords } => topology::ring(n, chords),
+            TopologyMode::Random { degree, seed } => topology::random_regular(n, degree, seed),
+        };
+        Some(topology::adjacency(&ids, &edges))
+    }
+}
+
+/// Total order used for every value list we return: numbers first, in
+/// numeric order, then everything else by canonical serialization.
+pub fn value_order(a: &Value, b: &Value) -> std::cmp::Ordering {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(x), Some(y)) => x
+            .total_cmp(&y)
+            .then_with(|| a.to_string().cmp(&b.to_string())),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+/// Leader election by heartbeat. The leader of the highest term wins, and
+/// within a term the lowest id; everyone starts out following the lowest id
+/// in term 0. A node that hasn't heard from its leader within a randomized
+/// timeout starts the next term as leader itself. There are no votes, so a
+/// node cut off from the leader alone still takes over once it's back.
+#[derive(Debug)]
+pub struct Election {
+    pub term: usize,
+    pub leader: NodeId,
+    pub last_heard: Instant,
+    pub timeout: Duration,
+}
+
+/// How broadcast values spread. `Total` trades the flood for a global order:
+/// the lowest node id numbers every value and sends it to everyone, and
+/// `read` returns values in that order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BroadcastOrder {
+    Eventual,
+    Total,
+}
+impl BroadcastOrder {
+    pub fn from_env() -> Self {
+        match std::env::var("MAELLE_BROADCAST_ORDER").as_deref() {
+            Ok("total") => BroadcastOrder::Total,
+            _ => BroadcastOrder::Eventual,
+        }
+    }
+}
+
+/// What a node knows of the total order.
+#[derive(Default, Debug)]
+pub struct TotalOrder {
+    /// Values in sequence order, up to the first gap.
+    pub delivered: Vec<Value>,
+    /// Values that arrived after a gap, by seq, until it fills.
+    pub buffered: BTreeMap<usize, Value>,
+    /// On the sequencer, every value numbered so far.
+    pub sequenced: ValueSet,
+}
+
+/// What the failure detector makes of a neighbor.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Alive,
+    Suspected,
+}
+
+/// When a neighbor was last heard from, and how many probes in a row it
+/// has left unanswered since.
+#[derive(Debug)]
+pub struct PeerHealth {
+    pub last_ack: Instant,
+    pub suspicion: u32,
+}
+
+/// Replies already sent to clients' mutations, so a request delivered again
+/// is answered again instead of applied again. Keeps the last `per_client`
+/// of each client's.
+#[derive(Debug)]
+pub struct Dedup {
+    pub per_client: usize,
+    replies: HashMap<NodeId, VecDeque<(MsgId, Payload)>>,
+}
+impl Dedup {
+    pub fn new(per_client: usize) -> Self {
+        Self {
+            per_client,
+            replies: HashMap::new(),
+        }
+    }
+    pub fn get(&self, client: &str, msg_id: MsgId) -> Option<&Payload> {
+        self.replies
+            .get(client)?
+            .iter()
+            .find(|(id, _)| *id == msg_id)
+            .map(|(_, reply)| reply)
+    }
+    pub fn insert(&mut self, client: &NodeId, msg_id: MsgId, reply: Payload) {
+        if self.per_client == 0 {
+            return;
+        }
+        let replies = self.replies.entry(client.clone()).or_default();
+        replies.push_back((msg_id, reply));
+        while replies.len() > self.per_client {
+            replies.pop_front();
+        }
+    }
+}
+
+/// The stamped values seen from one origin, and how far they run without a
+/// gap.
+#[derive(Default, Debug)]
+pub struct OriginLog {
+    pub values: BTreeMap<usize, Value>,
+    /// Every seq up to and including this one has been seen.
+    pub contiguous: usize,
+    /// Set while a pull for this origin is unanswered, so a gap is only
+    /// asked for once.
+    pub pulling: bool,
+}
+
+/// A JSON value ordered by [`value_order`].
+#[derive(Clone, Debug)]
+struct Ordered(Value);
+impl PartialEq for Ordered {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+impl Eq for Ordered {}
+impl PartialOrd for Ordered {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Ordered {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        value_order(&self.0, &other.0)
+    }
+}
+
+/// A set of JSON values, equal only if they serialize the same, so `1` and
+/// `1.0` stay distinct. Kept in [`value_order`] as values arrive, so reads
+/// never sort and iteration is the same on every run. Non-negative integers,
+/// which is what most workloads send, are kept as ranges in an
+/// [`IntervalSet`]; everything else one by one.
+#[derive(Default, Clone, Debug)]
+pub struct ValueSet {
+    ints: IntervalSet,
+    others: BTreeSet<Ordered>,
+}
+impl ValueSet {
+    pub fn insert(&mut self, value: Value) -> bool {
+        match value.as_u64() {
+            Some(n) => self.ints.insert(n),
+            None => self.others.insert(Ordered(value)),
+        }
+    }
+    /// Adds every integer in `start..=end`, returning how many were new.
+    pub fn insert_range(&mut self, start: u64, end: u64) -> usize {
+        self.ints.insert_range(start, end)
+    }
+    pub fn contains(&self, value: &Value) -> bool {
+        match value.as_u64() {
+            Some(n) => self.ints.contains(n),
+            None => self.others.contains(&Ordered(value.clone())),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ints.is_empty() && self.others.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.ints.len() + self.others.len()
+    }
+    /// The values in [`value_order`].
+    pub fn iter(&self) -> impl Iterator<Item = Value> + '_ {
+        let mut ints = self.ints.iter().map(Value::from).peekable();
+        let mut others = self.others.iter().map(|value| value.0.clone()).peekable();
+        std::iter::from_fn(move || match (ints.peek(), others.peek()) {
+            (Some(int), Some(other)) if value_order(int, other).is_gt() => others.next(),
+            (Some(_), _) => ints.next(),
+            (None, _) => others.next(),
+        })
+    }
+    pub fn sorted(&self) -> Vec<Value> {
+        self.iter().collect()
+    }
+    /// The integers, as inclusive ranges lowest first.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ints.ranges()
+    }
+    /// Everything that isn't kept in [`ValueSet::ranges`].
+    pub fn others(&self) -> impl Iterator<Item = &Value> {
+        self.others.iter().map(|value| &value.0)
+    }
+    /// The integers in `start..=end` that aren't in the set, as ranges.
+    pub fn gaps(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        self.ints.gaps(start, end)
+    }
+    #[cfg(feature = "broadcast")]
+    pub fn digest(&self) -> Digest {
+        Digest {
+            count: self.len(),
+            ranges: self.ranges().collect(),
+            others: self.others().cloned().collect(),
+        }
+    }
+}
+
+/// An observed-remove set: every add carries a unique tag and a remove only
+/// tombstones the tags it has observed, so a concurrent re-add wins.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct OrSet {
+    pub adds: HashMap<String, (Value, HashSet<String>)>,
+    pub tombstones: HashSet<String>,
+}
+impl OrSet {
+    pub fn add(&mut self, element: Value, tag: String) {
+        self.adds
+            .entry(element.to_string())
+            .or_insert_with(|| (element, HashSet::new()))
+            .1
+            .insert(tag);
+    }
+    pub fn remove(&mut self, element: &Value) {
+        if let Some((_, tags)) = self.adds.get(&element.to_string()) {
+            self.tombstones.extend(tags.iter().cloned());
+        }
+    }
+    pub fn merge(&mut self, other: OrSet) {
+        for (key, (element, tags)) in other.adds {
+            self.adds
+                .entry(key)
+                .or_insert_with(|| (element, HashSet::new()))
+                .1
+                .extend(tags);
+        }
+        self.tombstones.extend(other.tombstones);
+    }
+    pub fn read(&self) -> Vec<Value> {
+        let mut elements = self
+            .adds
+            .values()
+            .filter(|(_, tags)| tags.iter().any(|tag| !self.tombstones.contains(tag)))
+            .map(|(element, _)| element.clone())
+            .collect::<Vec<_>>();
+        elements.sort_by(value_order);
+        elements
+    }
+}
+
+pub struct Node {
+    pub id: NodeId,
+    pub node_ids: Vec<NodeId>,
+    pub workload: Workload,
+    pub msg_ids: Arc<AtomicU64>,
+    pub output: Output,
+    pub time: Arc<dyn Clock>,
+    pub unique_ids: AtomicUsize,
+    pub id_strategy: IdStrategy,
+    pub snowflake: Snowflake,
+    pub topology: HashMap<NodeId, Vec<NodeId>>,
+    pub topology_mode: TopologyMode,
+    pub topology_fallback: bool,
+    /// Where to write the topology as DOT whenever it's set.
+    pub dump_topology: Option<String>,
+    pub messages: ValueSet,
+    pub broadcast_order: BroadcastOrder,
+    pub total_order: TotalOrder,
+    pub election: Election,
+    pub raft: Raft,
+    pub ring: Ring,
+    /// The keys this node owns under [`Workload::ShardedKv`].
+    pub shard: KvStore,
+    /// How many values clients have broadcast to this node, i.e. the last
+    /// seq it stamped.
+    pub next_seq: usize,
+    pub origins: HashMap<NodeId, OriginLog>,
+    /// The stamp of each value that arrived with one, by serialization.
+    pub stamps: HashMap<String, Stamp>,
+    pub known: HashMap<NodeId, ValueSet>,
+    pub health: HashMap<NodeId, PeerHealth>,
+    /// Unanswered probes after which a neighbor is suspected.
+    pub suspect_after: u32,
+    pub batch_window: Period,
+    /// How long acks to other nodes are held for something to ride on;
+    /// zero replies at once.
+    pub ack_delay: Duration,
+    /// Acks held for each node, oldest first.
+    pub acks: HashMap<NodeId, Vec<MsgId>>,
+    /// Most broadcast values in one `read_ok` to a client; zero sends
+    /// them all in one.
+    pub read_chunk: usize,
+    /// Under [`TopologyMode::Clusters`], the other representatives if
+    /// this node is one, whose batches go out every `tier_window` instead.
+    pub tier_peers: HashSet<NodeId>,
+    pub tier_window: Period,
+    pub outbox: HashMap<NodeId, Vec<Value>>,
+    pub counter: PnCounter,
+    pub logs: HashMap<String, Vec<usize>>,
+    pub committed: HashMap<String, usize>,
+    pub elements: ValueSet,
+    pub or_set: OrSet,
+    pub registers: HashMap<usize, Register>,
+    pub clock: usize,
+    pub callbacks: HashMap<MsgId, Callback>,
+    pub dedup: Dedup,
+    pub wal: Option<Wal>,
+    pub retry_policy: RetryPolicy,
+    pub limiter: RateLimiter,
+    pub gossip_mode: GossipMode,
+    pub gossip_interval: Duration,
+    /// How often the gossip timer ticks: `gossip_interval`, or under
+    /// `pacing` as often as any neighbor can be due.
+    pub gossip_tick: Period,
+    pub pacing: Option<Pacing>,
+    /// Smoothed round-trip times to neighbors, from their acks.
+    pub rtt: HashMap<NodeId, Duration>,
+    /// Under `pacing`, when each neighbor is next gossiped to, and when
+    /// the next round is for the modes that pick neighbors at random.
+    pub next_gossip: HashMap<NodeId, Instant>,
+    pub next_round: Option<Instant>,
+    /// Under [`GossipMode::Rumor`], the values still being pushed, each
+    /// with the rounds it has left.
+    pub rumors: Vec<(Value, usize)>,
+    /// Background sends the limiter held back, oldest first.
+    pub deferred: VecDeque<Deferred>,
+    /// How many sends the limiter has ever held back.
+    pub deferred_total: u64,
+    /// How often `deferred` is drained; zero while the limiter is off and
+    /// nothing is held.
+    pub drain_tick: Period,
+    pub timers: Vec<(Period, Task<Node>)>,
+}
+/// The settings a [`Node`] starts with; see [`crate::builder`] for setting
+/// them other than from the environment.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    pub workload: Workload,
+    pub id_strategy: IdStrategy,
+    pub topology_mode: TopologyMode,
+    pub retry_policy: RetryPolicy,
+    pub gossip_interval: Duration,
+    pub gossip_mode: GossipMode,
+    /// Per-neighbor intervals from round-trip times instead of the fixed
+    /// ones; set by `MAELLE_GOSSIP_RTT_MULTIPLE`.
+    pub pacing: Option<Pacing>,
+    pub sync_interval: Duration,
+    /// How often the [`Election`] leader heartbeats, and followers check on
+    /// it; zero, the default, leaves it off.
+    pub heartbeat_interval: Duration,
+    pub batch_window: Duration,
+    /// The batch window between cluster representatives; `None` for the
+    /// same as `batch_window`.
+    pub tier_window: Option<Duration>,
+    pub ack_delay: Duration,
+    /// Splits client reads of broadcast values into `read_ok`s of this
+    /// many, numbered by `chunk` and `total_chunks`; stock Maelstrom
+    /// expects one, so zero, the default, keeps it at that.
+    pub read_chunk: usize,
+    pub wal: Option<String>,
+    /// Where to write the topology as DOT whenever it's set, `{id}` in it
+    /// replaced by the node's; see [`topology::to_dot`].
+    pub dump_topology: Option<String>,
+}
+impl NodeConfig {
+    pub fn from_env(workload: Workload) -> Self {
+        Self {
+            workload,
+            id_strategy: IdStrategy::from_env(),
+            topology_mode: TopologyMode::from_env(),
+            retry_policy: RetryPolicy::from_env(),
+            gossip_interval: gossip_interval(),
+            gossip_mode: GossipMode::from_env(),
+            pacing: std::env::var_os("MAELLE_GOSSIP_RTT_MULTIPLE")
+                .is_some()
+                .then(Pacing::from_env),
+            sync_interval: sync_interval(),
+            heartbeat_interval: heartbeat_interval(),
+            batch_window: batch_window(),
+            tier_window: tier_window(),
+            ack_delay: ack_delay(),
+            read_chunk: env_or("MAELLE_READ_CHUNK", 0),
+            wal: std::env::var("MAELLE_WAL").ok(),
+            dump_topology: std::env::var("MAELLE_DUMP_TOPOLOGY").ok(),
+        }
+    }
+}
+
+impl Node {
+    pub fn new(ctx: &Context, workload: Workload) -> Self {
+        Self::with_config(ctx, NodeConfig::from_env(workload))
+    }
+    pub fn with_config(ctx: &Context, config: NodeConfig) -> Self {
+        let node_index = ctx
+            .node_ids
+            .iter()
+            .position(|n| *n == ctx.node_id)
+            .unwrap_or(0);
+        let mut node = Self {
+            id: ctx.node_id.clone(),
+            node_ids: ctx.node_ids.clone(),
+            workload: config.workload,
+            msg_ids: ctx.msg_ids(),
+            output: ctx.output(),
+            time: ctx.clock(),
+            unique_ids: AtomicUsize::new(0),
+            id_strategy: config.id_strategy,
+            snowflake: Snowflake::new(node_index),
+            topology: HashMap::new(),
+            topology_mode: config.topology_mode,
+            topology_fallback: env_or("MAELLE_TOPOLOGY_FALLBACK", true),
+            dump_topology: config.dump_topology,
+            messages: ValueSet::default(),
+            broadcast_order: BroadcastOrder::from_env(),
+            total_order: TotalOrder::default(),
+            election: Election {
+                term: 0,
+                leader: ctx.node_ids.iter().min().unwrap_or(&ctx.node_id).clone(),
+                last_heard: ctx.now(),
+                timeout: election_timeout(),
+            },
+            raft: Raft::new(ctx.now()),
+            ring: Ring::new(&ctx.node_ids, env_or("MAELLE_VNODES", 64)),
+            shard: KvStore::new(),
+            next_seq: 0,
+            origins: HashMap::new(),
+            stamps: HashMap::new(),
+            known: HashMap::new(),
+            health: HashMap::new(),
+            suspect_after: env_or("MAELLE_SUSPECT_AFTER", 3),
+            batch_window: config.batch_window.into(),
+            ack_delay: config.ack_delay,
+            acks: HashMap::new(),
+            read_chunk: config.read_chunk,
+            tier_peers: config.topology_mode.tier_peers(&ctx.node_id, &ctx.node_ids),
+            tier_window: config.tier_window.unwrap_or(config.batch_window).into(),
+            outbox: HashMap::new(),
+            counter: PnCounter::default(),
+            logs: HashMap::new(),
+            committed: HashMap::new(),
+            elements: ValueSet::default(),
+            or_set: OrSet::default(),
+            registers: HashMap::new(),
+            clock: 0,
+            callbacks: HashMap::new(),
+            dedup: Dedup::new(env_or("MAELLE_DEDUP_PER_CLIENT", 256)),
+            wal: None,
+            retry_policy: config.retry_policy,
+            limiter: RateLimiter::from_env(ctx.now()),
+            gossip_mode: config.gossip_mode,
+            gossip_interval: config.gossip_interval,
+            gossip_tick: gossip_tick(config.pacing, config.gossip_interval).into(),
+            pacing: config.pacing,
+            rtt: HashMap::new(),
+            next_gossip: HashMap::new(),
+            next_round: None,
+            rumors: Vec::new(),
+            deferred: VecDeque::new(),
+            deferred_total: 0,
+            drain_tick: Period::new(Duration::ZERO),
+            timers: Vec::new(),
+        };
+        node.every(Duration::from_millis(100), retry_pending);
+        node.every(node.gossip_tick.clone(), gossip);
+        #[cfg(feature = "broadcast")]
+        node.every(config.sync_interval, anti_entropy);
+        #[cfg(feature = "broadcast")]
+        node.every(node.batch_window.clone(), flush_outbox);
+        #[cfg(feature = "broadcast")]
+        node.every(node.ack_delay, flush_acks);
+        #[cfg(feature = "broadcast")]
+        if !node.tier_peers.is_empty() {
+            node.every(node.tier_window.clone(), flush_tier_outbox);
+        }
+        node.every(config.heartbeat_interval, heartbeat);
+        node.every(raft_interval(), raft_tick);
+        node.every(ping_interval(), probe_neighbors);
+        if node.limiter.is_limited() {
+            node.drain_tick.set(DRAIN_INTERVAL);
+        }
+        node.every(node.drain_tick.clone(), drain_deferred);
+        if let Some(path) = &config.wal {
+            node.open_wal(path);
+        }
+        #[cfg(feature = "broadcast")]
+        if node.workload == Workload::Broadcast && node.messages.is_empty() {
+            node.catch_up();
+        }
+        node
+    }
+    /// Asks every neighbor for what it has, so a node restarted without its
+    /// state doesn't wait on gossip and anti-entropy to refill it. Reads are
+    /// served meanwhile from whatever has arrived. On a fresh cluster the
+    /// answers are just empty.
+    #[cfg(feature = "broadcast")]
+    fn catch_up(&mut self) {
+        for n in self.neighbors() {
+            let body = Body::request(self.next_msg_id(), Payload::CatchUpRequest);
+            if let Err(e) = self.output.send(&self.id, &n, body) {
+                log!(Warn, "catch_up_failed", peer = n, error = e);
+            }
+        }
+    }
+    /// Replays the log at `path`, if there is one, then appends to it.
+    /// Without a usable log the node runs on, just not durably.
+    fn open_wal(&mut self, path: &str) {
+        let entries = match wal::load(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log!(Error, "wal_unreadable", path = path, error = e);
+                return;
+            }
+        };
+        log!(Info, "wal_replay", path = path, entries = entries.len());
+        for entry in entries {
+            self.restore(entry);
+        }
+        let fsync_every = Duration::from_millis(env_or("MAELLE_WAL_FSYNC_MS", 100));
+        match Wal::open(path, fsync_every) {
+            Ok(wal) => self.wal = Some(wal),
+            Err(e) => log!(Error, "wal_unwritable", path = path, error = e),
+        }
+    }
+    /// Applies one logged change; applying it again changes nothing.
+    pub fn restore(&mut self, entry: WalEntry) {
+        match entry {
+            WalEntry::Message { value } => {
+                self.messages.insert(value);
+            }
+            WalEntry::Element { value } => {
+                self.elements.insert(value);
+            }
+            WalEntry::Counter {
+                node,
+                increments,
+                decrements,
+            } => {
+                self.counter.increments.insert(node.clone(), increments);
+                self.counter.decrements.insert(node, decrements);
+            }
+            WalEntry::Append { key, offset, msg } => {
+                let log = self.logs.entry(key).or_default();
+                match offset.cmp(&log.len()) {
+                    std::cmp::Ordering::Less => log[offset] = msg,
+                    std::cmp::Ordering::Equal => log.push(msg),
+                    std::cmp::Ordering::Greater => log!(Warn, "wal_gap", offset = offset),
+                }
+            }
+            WalEntry::Commit { key, offset } => {
+                self.commit(HashMap::from([(key, offset)]));
+            }
+        }
+    }
+    fn log_change(&self, entry: impl FnOnce() -> WalEntry) {
+        if let Some(wal) = &self.wal {
+            wal.append(&entry());
+        }
+    }
+    /// Adds a broadcast value, returning whether it's new.
+    pub fn insert_message(&mut self, value: Value) -> bool {
+        if self.messages.contains(&value) {
+            return false;
+        }
+        self.log_change(|| WalEntry::Message {
+            value: value.clone(),
+        });
+        if let GossipMode::Rumor { rounds, .. } = self.gossip_mode {
+            self.rumors.push((value.clone(), rounds));
+        }
+        self.messages.insert(value)
+    }
+    /// Under [`GossipMode::Rumor`], has `value` pushed for another full
+    /// set of rounds, hot or cold.
+    pub fn heat(&mut self, value: &Value) {
+        let GossipMode::Rumor { rounds, .. } = self.gossip_mode else {
+            return;
+        };
+        match self.rumors.iter_mut().find(|(hot, _)| hot == value) {
+            Some((_, left)) => *left = rounds,
+            None => self.rumors.push((value.clone(), rounds)),
+        }
+    }
+    pub fn insert_element(&mut self, value: Value) -> bool {
+        if self.elements.contains(&value) {
+            return false;
+        }
+        self.log_change(|| WalEntry::Element {
+            value: value.clone(),
+        });
+        self.elements.insert(value)
+    }
+    pub fn add_to_counter(&mut self, delta: i64) {
+        let node = self.id.to_string();
+        self.counter.add(&node, delta);
+        self.log_change(|| WalEntry::Counter {
+            increments: self.counter.increments.get(&node).copied().unwrap_or(0),
+            decrements: self.counter.decrements.get(&node).copied().unwrap_or(0),
+            node,
+        });
+    }
+    /// Registers `task` to run every `period`; a zero period idles it.
+    /// Only takes effect before the runtime starts, though the period can
+    /// be changed at any time.
+    pub fn every(&mut self, period: impl Into<Period>, task: Task<Node>) {
+        self.timers.push((period.into(), task));
+    }
+    /// Allocates from the counter shared with [`Context`], so ids never
+    /// collide with the ones the runtime hands out.
+    pub fn next_msg_id(&self) -> MsgId {
+        MsgId(self.msg_ids.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+    pub fn gen_unique_id(&self) -> String {
+        let n = self.unique_ids.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{}", self.id, n)
+    }
+    pub fn gen_id(&mut self) -> String {
+        match self.id_strategy {
+            IdStrategy::Counter => self.gen_unique_id(),
+            IdStrategy::Snowflake => self.snowflake.next(|| self.time.unix_ms()).to_string(),
+            IdStrategy::UuidV4 => uuid_v4(),
+            IdStrategy::UuidV7 => uuid_v7(self.time.unix_ms()),
+        }
+    }
+    pub fn append(&mut self, key: String, msg: usize) -> usize {
+        let log = self.logs.entry(key.clone()).or_default();
+        log.push(msg);
+        let offset = log.len() - 1;
+        self.log_change(|| WalEntry::Append { key, offset, msg });
+        offset
+    }
+    pub fn poll(&self, offsets: HashMap<String, usize>) -> HashMap<String, Vec<[usize; 2]>> {
+        offsets
+            .into_iter()
+            .map(|(key, from)| {
+                let entries = self
+                    .logs
+                    .get(&key)
+                    .map(|log| {
+                        log.iter()
+                            .enumerate()
+                            .skip(from)
+                            .map(|(offset, msg)| [offset, *msg])
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                (key, entries)
+            })
+            .collect()
+    }
+    pub fn commit(&mut self, offsets: HashMap<String, usize>) {
+        for (key, offset) in offsets {
+            if self.committed.get(&key).is_some_and(|&c| c >= offset) {
+                continue;
+            }
+            self.committed.insert(key.clone(), offset);
+            self.log_change(|| WalEntry::Commit { key, offset });
+        }
+    }
+    pub fn committed_offsets(&self, keys: Vec<String>) -> HashMap<String, usize> {
+        keys.into_iter()
+            .filter_map(|key| self.committed.get(&key).map(|offset| (key, *offset)))
+            .collect()
+    }
+    /// Our neighbors from the topology. Without an entry for us (no
+    /// `topology` yet, or a map that omits our id) this falls back to every
+    /// other node, unless `MAELLE_TOPOLOGY_FALLBACK=false` asks for none.
+    pub fn neighbors(&self) -> Vec<NodeId> {
+        match self.topology.get(&self.id) {
+            Some(neighbors) => neighbors.clone(),
+            None if self.topology_fallback => self
+                .node_ids
+                .iter()
+                .filter(|n| **n != self.id)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Acks `msg_id` from `src` with `reply`, unless `src` is a node and
+    /// acks are held: then it waits up to `ack_delay` to ride on the next
+    /// batch or gossip to `src`, and goes in an `acks` message otherwise.
+    /// Either way the sender's callback is settled as for a reply.
+    #[cfg(feature = "broadcast")]
+    pub fn ack(
+        &mut self,
+        ctx: &mut Context,
+        src: &NodeId,
+        msg_id: Option<MsgId>,
+        reply: Payload,
+    ) -> anyhow::Result<()> {
+        match msg_id {
+            Some(msg_id) if !self.ack_delay.is_zero() && self.node_ids.contains(src) => {
+                self.acks.entry(src.clone()).or_default().push(msg_id);
+                Ok(())
+            }
+            _ => ctx.reply(reply),
+        }
+    }
+    /// The acks held for `dest`, to send along with something else.
+    pub fn take_acks(&mut self, dest: &NodeId) -> Vec<MsgId> {
+        self.acks.remove(dest).unwrap_or_default()
+    }
+    /// Settles the callbacks `acks` answer, as their replies would.
+    pub fn acknowledge_all(&mut self, acks: Vec<MsgId>) {
+        for msg_id in acks {
+            self.acknowledge(msg_id);
+        }
+    }
+    /// Sends each node's held acks on their own.
+    #[cfg(feature = "broadcast")]
+    pub fn flush_acks(&mut self) -> anyhow::Result<()> {
+        let mut held: Vec<_> = self.acks.drain().collect();
+        held.sort_by(|a, b| a.0.cmp(&b.0));
+        for (dest, acks) in held {
+            self.output
+                .send(&self.id, &dest, Body::new(Payload::Acks { acks }))?;
+        }
+        Ok(())
+    }
+    /// Replies to a client's mutation, remembering the reply in case the
+    /// same request arrives again.
+    pub fn reply_once(
+        &mut self,
+        ctx: &mut Context,
+        src: &NodeId,
+        msg_id: Option<MsgId>,
+        reply: Payload,
+    ) -> anyhow::Result<()> {
+        if let Some(msg_id) = msg_id.filter(|_| !self.node_ids.iter().any(|n| n == src)) {
+            self.dedup.insert(src, msg_id, reply.clone());
+        }
+        ctx.reply(reply)
+    }
+    /// Passes a client's request on to `dest`, whose answer goes back to the
+    /// client as the reply to its own msg_id, without blocking on it.
+    pub fn relay(
+        &mut self,
+        src: &NodeId,
+        msg_id: Option<MsgId>,
+        dest: &NodeId,
+        op: Payload,
+    ) -> anyhow::Result<()> {
+        let relay_id = self.next_msg_id();
+        let Some(msg_id) = msg_id else {
+            // Nobody to answer, so nothing to remember.
+            return self.output.send(&self.id, dest, Body::new(op));
+        };
+        self.callbacks.insert(
+            relay_id,
+            Callback::Relay {
+                client: src.clone(),
+                msg_id,
+                sent_at: self.time.now(),
+            },
+        );
+        self.output
+            .send(&self.id, dest, Body::request(relay_id, op))
+    }
+    /// Neighbors the failure detector doesn't suspect. Gossip rounds pick
+    /// only from these. Fan-out still reaches a suspected neighbor, since it
+    /// may only be slow, but batched up for the next probe rather than sent
+    /// and retried a value at a time (see [`Node::forward`]).
+    pub fn alive_neighbors(&self) -> Vec<NodeId> {
+        self.neighbors()
+            .into_iter()
+            .filter(|n| self.health_of(n) == Health::Alive)
+            .collect()
+    }
+    pub fn health_of(&self, peer: &str) -> Health {
+        match self.health.get(peer) {
+            Some(peer) if peer.suspicion >= self.suspect_after => Health::Suspected,
+            _ => Health::Alive,
+        }
+    }
+    /// Folds a round trip to `peer` into its estimate, weighting the new
+    /// sample an eighth as TCP does.
+    pub fn observe_rtt(&mut self, peer: &NodeId, sample: Duration) {
+        if !self.node_ids.contains(peer) {
+            return;
+        }
+        let rtt = self.rtt.entry(peer.clone()).or_insert(sample);
+        *rtt = (*rtt * 7 + sample) / 8;
+    }
+    /// How often `peer` is gossiped to.
+    pub fn gossip_interval_for(&self, peer: &str) -> Duration {
+        match (self.pacing, self.rtt.get(peer)) {
+            (Some(pacing), Some(rtt)) => pacing.interval(*rtt),
+            _ => self.gossip_interval,
+        }
+    }
+    /// The retry policy for sends to `peer`, backing off from its gossip
+    /// interval under pacing.
+    pub fn retry_policy_for(&self, peer: &str) -> RetryPolicy {
+        match (self.pacing, self.rtt.get(peer)) {
+            (Some(pacing), Some(rtt)) => RetryPolicy {
+                base: pacing.interval(*rtt),
+                ..self.retry_policy
+            },
+            _ => self.retry_policy,
+        }
+    }
+    /// The live neighbors due a gossip round, each then scheduled for its
+    /// next one; without pacing, all of them.
+    pub fn gossip_due(&mut self) -> Vec<NodeId> {
+        let neighbors = self.alive_neighbors();
+        if self.pacing.is_none() {
+            return neighbors;
+        }
+        let now = self.time.now();
+        let due: Vec<NodeId> = neighbors
+            .into_iter()
+            .filter(|n| self.next_gossip.get(n).is_none_or(|at| now >= *at))
+            .collect();
+        for n in due.iter() {
+            let at = now + self.gossip_interval_for(n);
+            self.next_gossip.insert(n.clone(), at);
+        }
+        due
+    }
+    /// Whether a round of the gossip modes that pick neighbors at random
+    /// is due; under pacing those keep the fixed interval.
+    pub fn round_due(&mut self) -> bool {
+        if self.pacing.is_none() {
+            return true;
+        }
+        let now = self.time.now();
+        if self.next_round.is_some_and(|at| now < at) {
+            return false;
+        }
+        self.next_round = Some(now + self.gossip_interval);
+        true
+    }
+    /// Notes that `peer` is alive. If it was suspected, its pending
+    /// messages are resent right away rather than at their backed-off time,
+    /// and what was held for its next probe goes now.
+    pub fn heard_from(&mut self, peer: &NodeId) -> anyhow::Result<()> {
+        if !self.node_ids.iter().any(|n| n == peer) {
+            return Ok(());
+        }
+        let was = self.health_of(peer);
+        let now = self.time.now();
+        let health = self.health.entry(peer.clone()).or_insert(PeerHealth {
+            last_ack: now,
+            suspicion: 0,
+        });
+        health.last_ack = now;
+        health.suspicion = 0;
+        if was == Health::Alive {
+            return Ok(());
+        }
+        log!(Info, "peer_recovered", peer = peer);
+        for callback in self.callbacks.values_mut() {
+            if let Callback::Pending { dest, retry_at, .. } = callback {
+                if dest == peer {
+                    *retry_at = now;
+                }
+            }
+        }
+        #[cfg(feature = "broadcast")]
+        if let Some(messages) = self.outbox.remove(peer) {
+            self.send_batch(peer.clone(), messages)?;
+        }
+        Ok(())
+    }
+    /// Stores any new messages and forwards them to every neighbor except
+    /// `from`, either immediately or through the per-neighbor batch outbox.
+    #[cfg(feature = "broadcast")]
+    pub fn disseminate(&mut self, from: &str, messages: Vec<Value>) -> anyhow::Result<()> {
+        let fresh: Vec<Value> = messages
+            .into_iter()
+            .filter(|message| self.insert_message(message.clone()))
+            .collect();
+        self.forward(from, &fresh)
+    }
+    /// Sends `messages` on to every neighbor except `from` that isn't known
+    /// to have them, as [`Node::disseminate`] does new ones; a suspected
+    /// neighbor's are held in its outbox for the next probe. Under
+    /// [`GossipMode::Rumor`] they're heated instead.
+    #[cfg(feature = "broadcast")]
+    pub fn forward(&mut self, from: &str, messages: &[Value]) -> anyhow::Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        if matches!(self.gossip_mode, GossipMode::Rumor { .. }) {
+            for message in messages {
+                self.heat(message);
+            }
+            return Ok(());
+        }
+        for n in self.neighbors() {
+            if n == from {
+                continue;
+            }
+            let suspected = self.health_of(&n) == Health::Suspected;
+            let known = self.known.get(&n);
+            let fresh: Vec<Value> = messages
+                .iter()
+                .filter(|message| !known.is_some_and(|known| known.contains(message)))
+                .cloned()
+                .collect();
+            let window = if self.tier_peers.contains(&n) {
+                self.tier_window.get()
+            } else {
+                self.batch_window.get()
+            };
+            if window.is_zero() && !suspected {
+                for message in fresh.iter() {
+                    let body = Payload::Broadcast {
+                        message: message.clone(),
+                        stamp: self.stamp_of(message),
+                    };
+                    self.send_tracked(n.clone(), body)?;
+                }
+            } else {
+                self.outbox
+                    .entry(n)
+                    .or_default()
+                    .extend(fresh.iter().cloned());
+            }
+        }
+        Ok(())
+    }
+    /// Writes the topology to `dump_topology`, if set, over whatever was
+    /// there; `{id}` in the path stands for this node's id, so nodes
+    /// started alike write apart. Failing to is only logged.
+    #[cfg(feature = "broadcast")]
+    fn write_topology(&self) {
+        let (Some(path), Some(dot)) = (&self.dump_topology, self.topology_dot()) else {
+            return;
+        };
+        let path = path.replace("{id}", &self.id.to_string());
+        if let Err(e) = std::fs::write(&path, dot) {
+            log!(Warn, "topology_unwritable", path = path, error = e);
+        }
+    }
+    /// Moves to `topology` as it may change mid-run, keeping what's known
+    /// of the neighbors that stay. New neighbors are taken to know nothing
+    /// and sent everything; whatever was batched or unacked for dropped
+    /// ones goes out again by the routes that are left.
+    #[cfg(feature = "broadcast")]
+    pub fn set_topology(&mut self, topology: HashMap<NodeId, Vec<NodeId>>) -> anyhow::Result<()> {
+        let before: HashSet<NodeId> = self.neighbors().into_iter().collect();
+        self.topology = topology;
+        self.write_topology();
+        let after: HashSet<NodeId> = self.neighbors().into_iter().collect();
+        // Sorted, so simulations replay alike.
+        let mut added: Vec<NodeId> = after.difference(&before).cloned().collect();
+        added.sort();
+        let mut removed: Vec<NodeId> = before.difference(&after).cloned().collect();
+        removed.sort();
+        if added.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        log!(
+            Info,
+            "topology_changed",
+            added = added.len(),
+            removed = removed.len()
+        );
+        let mut stranded = ValueSet::default();
+        for n in removed.iter() {
+            self.known.remove(n);
+            self.health.remove(n);
+            self.next_gossip.remove(n);
+            for message in self.outbox.remove(n).unwrap_or_default() {
+                stranded.insert(message);
+            }
+        }
+        let mut retired: Vec<MsgId> = self
+            .callbacks
+            .iter()
+            .filter(|(_, callback)| match callback {
+                Callback::Pending {
+                    dest,
+                    body: Payload::Broadcast { .. } | Payload::BroadcastMany { .. },
+                    ..
+                }
+                | Callback::Gossip { dest, .. } => removed.contains(dest),
+                _ => false,
+            })
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        retired.sort();
+        for msg_id in retired {
+            let messages = match self.callbacks.remove(&msg_id) {
+                Some(Callback::Pending {
+                    body: Payload::Broadcast { message, .. },
+                    ..
+                }) => vec![message],
+                Some(Callback::Pending {
+                    body: Payload::BroadcastMany { messages, .. },
+                    ..
+                })
+                | Some(Callback::Gossip { messages, .. }) => messages,
+                _ => Vec::new(),
+            };
+            for message in messages {
+                stranded.insert(message);
+            }
+        }
+        if self.workload != Workload::Broadcast {
+            return Ok(());
+        }
+        let messages = self.messages.sorted();
+        for n in added {
+            self.known.remove(&n);
+            for chunk in messages.chunks(catch_up_chunk().max(1)) {
+                let body = Payload::BroadcastMany {
+                    messages: chunk.to_vec(),
+                    stamps: self.stamps_of(chunk),
+                    acks: Vec::new(),
+                };
+                self.send_tracked(n.clone(), body)?;
+            }
+        }
+        self.forward(&self.id.clone(), &stranded.sorted())
+    }
+    pub fn stamp_of(&self, value: &Value) -> Option<Stamp> {
+        self.stamps.get(&value.to_string()).cloned()
+    }
+    /// Stamps for `values` as carried by `BroadcastMany` and `Gossip`.
+    pub fn stamps_of(&self, values: &[Value]) -> Vec<Option<Stamp>> {
+        let stamps: Vec<Option<Stamp>> = values.iter().map(|value| self.stamp_of(value)).collect();
+        if stamps.iter().all(Option::is_none) {
+            return Vec::new();
+        }
+        stamps
+    }
+    /// Takes note of the stamps `values` arrived with from `from`, and asks
+    /// `from` for whatever is missing before them.
+    #[cfg(feature = "broadcast")]
+    pub fn note_stamps(
+        &mut self,
+        from: &NodeId,
+        values: &[Value],
+        stamps: Vec<Option<Stamp>>,
+    ) -> anyhow::Result<()> {
+        let mut origins = BTreeSet::new();
+        for (value, stamp) in values.iter().zip(stamps) {
+            if let Some((origin, seq)) = stamp {
+                self.note_stamp(value, (origin.clone(), seq));
+                origins.insert(origin);
+            }
+        }
+        for origin in origins {
+            self.pull_gap(from, origin)?;
+        }
+        Ok(())
+    }
+    #[cfg(feature = "broadcast")]
+    fn note_stamp(&mut self, value: &Value, (origin, seq): Stamp) {
+        self.stamps
+            .entry(value.to_string())
+            .or_insert_with(|| (origin.clone(), seq));
+        let log = self.origins.entry(origin).or_default();
+        log.values.insert(seq, value.clone());
+        while log.values.contains_key(&(log.contiguous + 1)) {
+            log.contiguous += 1;
+        }
+    }
+    /// Pulls from `peer` the values of `origin` after the first gap, unless
+    /// there's none or a pull is already out.
+    #[cfg(feature = "broadcast")]
+    fn pull_gap(&mut self, peer: &NodeId, origin: NodeId) -> anyhow::Result<()> {
+        if *peer == self.id || !self.node_ids.contains(peer) {
+            return Ok(());
+        }
+        let Some(log) = self.origins.get_mut(&origin) else {
+            return Ok(());
+        };
+        let gap = log
+            .values
+            .keys()
+            .next_back()
+            .is_some_and(|&last| last > log.contiguous);
+        if !gap || log.pulling {
+            return Ok(());
+        }
+        log.pulling = true;
+        let from_seq = log.contiguous + 1;
+        log!(
+            Info,
+            "pull",
+            peer = peer,
+            origin = origin,
+            from_seq = from_seq
+        );
+        self.send_tracked(peer.clone(), Payload::Pull { origin, from_seq })
+    }
+    /// The node that orders broadcasts under [`BroadcastOrder::Total`].
+    pub fn sequencer(&self) -> &NodeId {
+        self.node_ids.iter().min().unwrap_or(&self.id)
+    }
+    /// Numbers `message` and sends it to every other node, unless it
+    /// already has a number. Only the sequencer does this.
+    #[cfg(feature = "broadcast")]
+    pub fn sequence(&mut self, message: Value) -> anyhow::Result<()> {
+        if !self.total_order.sequenced.insert(message.clone()) {
+            return Ok(());
+        }
+        let seq = self.total_order.sequenced.len();
+        for n in self.node_ids.clone() {
+            if n == self.id {
+                continue;
+            }
+            let body = Payload::Sequenced {
+                seq,
+                message: message.clone(),
+            };
+            self.send_tracked(n, body)?;
+        }
+        self.deliver_sequenced(seq, message);
+        Ok(())
+    }
+    /// Delivers the `seq`th value once every one before it has been.
+    pub fn deliver_sequenced(&mut self, seq: usize, message: Value) {
+        let order = &mut self.total_order;
+        if seq <= order.delivered.len() {
+            return;
+        }
+        order.buffered.insert(seq, message);
+        while let Some(message) = order.buffered.remove(&(order.delivered.len() + 1)) {
+            order.delivered.push(message);
+        }
+    }
+    /// Records that `peer` has these messages, so we stop sending them to it.
+    pub fn mark_known(&mut self, peer: &NodeId, messages: impl IntoIterator<Item = Value>) {
+        if !self.node_ids.contains(peer) {
+            return;
+        }
+        let known = self.known.entry(peer.clone()).or_default();
+        for message in messages {
+            known.insert(message);
+        }
+    }
+    /// As [`Node::mark_known`], for integer ranges from a sync digest.
+    pub fn mark_known_ranges(&mut self, peer: &NodeId, ranges: &[(u64, u64)]) {
+        if ranges.is_empty() || !self.node_ids.contains(peer) {
+            return;
+        }
+        let known = self.known.entry(peer.clone()).or_default();
+        for &(start, end) in ranges {
+            known.insert_range(start, end);
+        }
+    }
+    /// What `peer` lacks going by its `digest`, less anything already on
+    /// its way there, which is then marked known to it too.
+    #[cfg(feature = "broadcast")]
+    pub fn fill_for(&mut self, peer: &NodeId, digest: &Digest) -> Vec<Value> {
+        self.mark_known(peer, digest.others.iter().cloned());
+        self.mark_known_ranges(peer, &digest.ranges);
+        let known = self.known.get(peer);
+        let fill: Vec<Value> = self
+            .messages
+            .iter()
+            .filter(|message| !known.is_some_and(|known| known.contains(message)))
+            .collect();
+        self.mark_known(peer, fill.iter().cloned());
+        log!(
+            Debug,
+            "push_pull",
+            peer = peer,
+            theirs = digest.count,
+            ours = self.messages.len(),
+            sending = fill.len(),
+        );
+        fill
+    }
+    /// Clears the callback for an acked message, returning whether one existed.
+    pub fn acknowledge(&mut self, in_reply_to: MsgId) -> bool {
+        let callback = self.callbacks.remove(&in_reply_to);
+        // Only first attempts time cleanly: an ack after a retry could be
+        // for either send.
+        match &callback {
+            Some(Callback::Gossip { dest, sent_at, .. })
+            | Some(Callback::Pending {
+                dest,
+                sent_at,
+                attempts: 0,
+                ..
+            }) => {
+                let sample = self.time.now().saturating_duration_since(*sent_at);
+                self.observe_rtt(dest, sample);
+            }
+            _ => {}
+        }
+        let (dest, messages) = match callback {
+            #[cfg(feature = "broadcast")]
+            Some(Callback::Pending {
+                dest,
+                body: Payload::Broadcast { message, .. },
+                ..
+            }) => (dest, vec![message]),
+            #[cfg(feature = "broadcast")]
+            Some(Callback::Pending {
+                dest,
+                body: Payload::BroadcastMany { messages, .. },
+                ..
+            }) => (dest, messages),
+            Some(Callback::Gossip { dest, messages, .. }) => (dest, messages),
+            Some(_) => return true,
+            None => return false,
+        };
+        self.mark_known(&dest, messages);
+        true
+    }
+    /// Sends what's batched for the cluster representatives this one
+    /// reaches (`tier`), or for everyone else.
+    #[cfg(feature = "broadcast")]
+    pub fn flush_outbox(&mut self, tier: bool) -> anyhow::Result<()> {
+        // A suspected neighbor's wait for its next probe.
+        let suspected: HashSet<NodeId> = self
+            .outbox
+            .keys()
+            .filter(|dest| self.health_of(dest) == Health::Suspected)
+            .cloned()
+            .collect();
+        let mut outbox: Vec<_> = self
+            .outbox
+            .extract_if(|dest, _| {
+                self.tier_peers.contains(dest) == tier && !suspected.contains(dest)
+            })
+            .collect();
+        // A stable order keeps simulations replayable.
+        outbox.sort_by(|a, b| a.0.cmp(&b.0));
+        for (dest, messages) in outbox {
+            self.send_batch(dest, messages)?;
+        }
+        Ok(())
+    }
+    /// Sends `messages` to `dest` as one tracked `broadcast_many`, with
+    /// whatever acks are held for it.
+    #[cfg(feature = "broadcast")]
+    fn send_batch(&mut self, dest: NodeId, messages: Vec<Value>) -> anyhow::Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let stamps = self.stamps_of(&messages);
+        // Retries go without the acks; they only need to arrive once.
+        let tracked = Payload::BroadcastMany {
+            messages: messages.clone(),
+            stamps: stamps.clone(),
+            acks: Vec::new(),
+        };
+        let msg_id = self.track(dest.clone(), tracked);
+        let acks = self.take_acks(&dest);
+        let body = Payload::BroadcastMany {
+            messages,
+            stamps,
+            acks,
+        };
+        self.output
+            .send(&self.id, &dest, Body::request(msg_id, body))
+    }
+    /// Sends gossip, retries and anti-entropy through the rate limiter.
+    /// Whatever it holds back waits in `deferred`, behind anything held
+    /// back before it, for the drain timer.
+    pub fn send_limited(&mut self, dest: NodeId, body: Body) -> anyhow::Result<()> {
+        if self.deferred.is_empty() && self.limiter.try_acquire(self.time.now()) {
+            return self.output.send(&self.id, &dest, body);
+        }
+        self.deferred_total += 1;
+        let tracked = body
+            .msg_id
+            .is_some_and(|id| self.callbacks.contains_key(&id));
+        self.deferred.push_back(Deferred {
+            dest,
+            body,
+            trace: log::trace(),
+            tracked,
+        });
+        Ok(())
+    }
+    pub fn send_tracked(&mut self, dest: NodeId, body: Payload) -> anyhow::Result<()> {
+        let msg_id = self.track(dest.clone(), body.clone());
+        self.output
+            .send(&self.id, &dest, Body::request(msg_id, body))
+    }
+    /// Registers `body` to `dest` for retries until acked, returning the
+    /// msg_id to send it with.
+    pub fn track(&mut self, dest: NodeId, body: Payload) -> MsgId {
+        let msg_id = self.next_msg_id();
+        self.callbacks.insert(
+            msg_id,
+            Callback::Pending {
+                retry_at: self.time.now() + self.retry_policy_for(&dest).next_delay(0),
+                dest,
+                body,
+                created: self.time.now(),
+                sent_at: self.time.now(),
+                attempts: 0,
+                trace: log::trace(),
+            },
+        );
+        msg_id
+    }
+    /// Applies a txn with read-committed semantics: writes are buffered and
+    /// only published to the shared registers once the whole txn has run.
+    pub fn apply_txn(
+        &mut self,
+        txn: Vec<Operation>,
+    ) -> anyhow::Result<(Vec<Operation>, Vec<RegisterWrite>)> {
+        let mut buffered: HashMap<usize, Value> = HashMap::new();
+        let txn = txn
+            .into_iter()
+            .map(|(op, key, value)| match op.as_str() {
+                "r" => {
+                    let value = buffered
+                        .get(&key)
+                        .or_else(|| self.registers.get(&key).map(|r| &r.value))
+                        .cloned();
+                    Ok((op, key, value))
+                }
+                "w" => {
+                    buffered.insert(key, value.clone().unwrap_or(Value::Null));
+                    Ok((op, key, value))
+                }
+                _ => Err(ErrorReply::new(
+                    ErrorCode::NotSupported,
+                    format!("unknown txn operation {}", op),
+                )
+                .into()),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        self.clock = self.clock.saturating_add(1);
+        let version = (self.clock, self.id.clone());
+        let writes: Vec<RegisterWrite> = buffered.into_iter().collect();
+        for (key, value) in writes.iter() {
+            self.write_register(*key, value.clone(), version.clone());
+        }
+        Ok((txn, writes))
+    }
+    pub fn write_register(&mut self, key: usize, value: Value, version: (usize, NodeId)) {
+        match self.registers.get(&key) {
+            Some(current) if current.version >= version => (),
+            _ => {
+                self.registers.insert(key, Register { value, version });
+            }
+        }
+    }
+    pub fn apply_replicated(&mut self, origin: NodeId, clock: usize, writes: Vec<RegisterWrite>) {
+        self.clock = self.clock.max(clock);
+        for (key, value) in writes {
+            self.write_register(key, value, (clock, origin.clone()));
+        }
+    }
+    #[cfg(feature = "txn")]
+    pub fn replicate(&mut self, writes: Vec<RegisterWrite>) -> anyhow::Result<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let clock = self.clock;
+        let peers: Vec<NodeId> = self
+            .node_ids
+            .iter()
+            .filter(|n| **n != self.id)
+            .cloned()
+            .collect();
+        for dest in peers {
+            let body = Payload::Replicate {
+                clock,
+                writes: writes.clone(),
+            };
+            self.send_tracked(dest, body)?;
+        }
+        Ok(())
+    }
+}
+
+const COUNTER_KEY: &str = "counter";
+
+fn counter_kv() -> KvClient {
+    let service = std::env::var("MAELLE_COUNTER_KV").unwrap_or_else(|_| SEQ_KV.to_string());
+    KvClient::new(&service)
+}
+
+/// Answers a `read` with the counter from the kv service, once it has it.
+fn read_kv_counter(ctx: &Context) -> anyhow::Result<()> {
+    counter_kv().read_then(ctx, COUNTER_KEY, |_: &mut Node, ctx, value| {
+        let value = match value {
+            Ok(value) => value.as_i64().unwrap_or(0),
+            Err(KvError::KeyNotFound(_)) => 0,
+            Err(e) => return Err(e.into()),
+        };
+        ctx.reply(Payload::ReadOk {
+            messages: None,
+            value: Some(value.into()),
+            chunk: None,
+            total_chunks: None,
+        })
+    })
+}
+
+/// Adds `delta` to the counter in the kv service, and then answers the
+/// `add` from `src`.
+#[cfg(feature = "counter")]
+fn add_kv_counter(
+    ctx: &Context,
+    src: NodeId,
+    msg_id: Option<MsgId>,
+    delta: i64,
+) -> anyhow::Result<()> {
+    let add = move |current: Option<&Value>| {
+        let current = current.and_then(Value::as_i64).unwrap_or(0);
+        current.wrapping_add(delta).into()
+    };
+    counter_kv().update_then(ctx, COUNTER_KEY, add, move |node: &mut Node, ctx, done| {
+        done?;
+        node.reply_once(ctx, &src, msg_id, Payload::AddOk)
+    })
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_kv_append(ctx: &Context, key: &str, msg: usize) -> anyhow::Result<()> {
+    let append = move |current: Option<&Value>| {
+        let mut log: Vec<usize> = current
+            .and_then(|log| serde_json::from_value(log.clone()).ok())
+            .unwrap_or_default();
+        log.push(msg);
+        log.into()
+    };
+    let log = format!("log-{}", key);
+    KvClient::new(kv::LIN_KV).update_then(ctx, &log, append, |_: &mut Node, ctx, log| {
+        let len = log?.as_array().map(Vec::len).unwrap_or(0);
+        ctx.reply(Payload::SendOk {
+            offset: len.saturating_sub(1),
+        })
+    })
+}
+
+/// Reads the logs of `offsets`' keys one after another, and answers the
+/// `poll` with `msgs` once the last is in.
+#[cfg(feature = "kafka")]
+fn kafka_kv_poll(
+    ctx: &Context,
+    mut offsets: Vec<(String, usize)>,
+    mut msgs: HashMap<String, Vec<[usize; 2]>>,
+) -> anyhow::Result<()> {
+    let Some((key, from)) = offsets.pop() else {
+        return ctx.reply(Payload::PollOk { msgs });
+    };
+    let log = format!("log-{}", key);
+    KvClient::new(kv::LIN_KV).read_then(ctx, &log, move |_: &mut Node, ctx, log| {
+        let log: Vec<usize> = match log {
+            Ok(log) => serde_json::from_value(log)?,
+            Err(KvError::KeyNotFound(_)) => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let entries = log
+            .into_iter()
+            .enumerate()
+            .skip(from)
+            .map(|(offset, msg)| [offset, msg])
+            .collect();
+        msgs.insert(key, entries);
+        kafka_kv_poll(ctx, offsets, msgs)
+    })
+}
+
+/// Raises each of `offsets`' committed offsets in turn, never lowering
+/// one, and then answers the `commit_offsets`.
+#[cfg(feature = "kafka")]
+fn kafka_kv_commit(ctx: &Context, mut offsets: Vec<(String, usize)>) -> anyhow::Result<()> {
+    let Some((key, offset)) = offsets.pop() else {
+        return ctx.reply(Payload::CommitOffsetsOk);
+    };
+    let raise = move |current: Option<&Value>| {
+        let current = current.and_then(Value::as_u64).unwrap_or(0) as usize;
+        current.max(offset).into()
+    };
+    let commit = format!("commit-{}", key);
+    KvClient::new(kv::LIN_KV).update_then(ctx, &commit, raise, |_: &mut Node, ctx, done| {
+        done?;
+        kafka_kv_commit(ctx, offsets)
+    })
+}
+
+/// Reads the committed offsets of `keys` one after another, and answers
+/// the `list_committed_offsets` with `offsets` once the last is in.
+#[cfg(feature = "kafka")]
+fn kafka_kv_committed(
+    ctx: &Context,
+    mut keys: Vec<String>,
+    mut offsets: HashMap<String, usize>,
+) -> anyhow::Result<()> {
+    let Some(key) = keys.pop() else {
+        return ctx.reply(Payload::ListCommittedOffsetsOk { offsets });
+    };
+    let commit = format!("commit-{}", key);
+    KvClient::new(kv::LIN_KV).read_then(ctx, &commit, move |_: &mut Node, ctx, offset| {
+        match offset {
+            Ok(offset) => {
+                offsets.insert(key, serde_json::from_value(offset)?);
+            }
+            Err(KvError::KeyNotFound(_)) => (),
+            Err(e) => return Err(e.into()),
+        }
+        kafka_kv_committed(ctx, keys, offsets)
+    })
+}
+
+impl Handler for Node {
+    type Payload = Payload;
+
+    fn handle(&mut self, ctx: &mut Context, m: Message) -> anyhow::Result<()> {
+        let in_reply_to = m.body.in_reply_to;
+        self.heard_from(&m.src)?;
+        if let Some(Callback::Relay { client, msg_id, .. }) = in_reply_to
+            .filter(|id| matches!(self.callbacks.get(id), Some(Callback::Relay { .. })))
+            .and_then(|id| self.callbacks.remove(&id))
+        {
+            if !matches!(m.body.payload, Payload::ReadOk { .. }) {
+                self.dedup.insert(&client, msg_id, m.body.payload.clone());
+            }
+            let body = Body {
+                msg_id: Some(self.next_msg_id()),
+                in_reply_to: Some(msg_id),
+                payload: m.body.payload,
+            };
+            return self.output.send(&self.id, &client, body);
+        }
+        if let Some(reply) = m.body.msg_id.and_then(|id| self.dedup.get(&m.src, id)) {
+            log!(
+                Info,
+                "duplicate_request",
+                src = m.src,
+                msg_id = log::opt(m.body.msg_id)
+            );
+            return ctx.reply(reply.clone());
+        }
+        match m.body.payload {
+            Payload::Echo { echo } => {
+                ctx.reply(Payload::EchoOk { echo })?;
+            }
+            Payload::EchoOk { .. } => (),
+            Payload::Generate => {
+                ctx.reply(Payload::GenerateOk { id: self.gen_id() })?;
+            }
+            Payload::GenerateOk { .. } => (),
+            op @ (Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. })
+                if self.workload == Workload::LinKv =>
+            {
+                self.raft_submit(&m.src, m.body.msg_id, op)?;
+            }
+            op @ (Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. })
+                if self.workload == Workload::ShardedKv =>
+            {
+                let key = match &op {
+                    Payload::Read { key: Some(key) }
+                    | Payload::Write { key, .. }
+                    | Payload::Cas { key, .. } => key.to_string(),
+                    _ => {
+                        return Err(ErrorReply::new(
+                            ErrorCode::MalformedRequest,
+                            "read without key",
+                        )
+                        .into());
+                    }
+                };
+                let owner = self.ring.owner(&key).unwrap_or(&self.id).clone();
+                // A node only forwards to the owner, so anything a node
+                // sends is applied here whatever the ring says.
+                if owner == self.id || self.node_ids.contains(&m.src) {
+                    let reply = kv::apply(&mut self.shard, &op);
+                    if matches!(op, Payload::Read { .. }) {
+                        ctx.reply(reply)?;
+                    } else {
+                        self.reply_once(ctx, &m.src, m.body.msg_id, reply)?;
+                    }
+                } else {
+                    self.relay(&m.src, m.body.msg_id, &owner, op)?;
+                }
+            }
+            payload @ (Payload::RequestVote { .. }
+            | Payload::RequestVoteOk { .. }
+            | Payload::AppendEntries { .. }
+            | Payload::AppendEntriesOk { .. }) => {
+                self.handle_raft(ctx, &m.src, payload)?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::Topology { topology } => {
+                let topology = self
+                    .topology_mode
+                    .derive(&self.node_ids)
+                    .unwrap_or(topology);
+                self.set_topology(topology)?;
+                if !self.topology.contains_key(&self.id) {
+                    log!(
+                        Warn,
+                        "topology_missing_self",
+                        fallback = if self.topology_fallback {
+                            "all_nodes"
+                        } else {
+                            "none"
+                        },
+                    );
+                }
+                ctx.reply(Payload::TopologyOk)?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::TopologyOk => (),
+            #[cfg(feature = "broadcast")]
+            Payload::Broadcast { message, .. } if self.broadcast_order == BroadcastOrder::Total => {
+                if self.id == *self.sequencer() {
+                    self.sequence(message)?;
+                } else {
+                    let sequencer = self.sequencer().clone();
+                    self.send_tracked(sequencer, Payload::Sequence { message })?;
+                }
+                self.reply_once(ctx, &m.src, m.body.msg_id, Payload::BroadcastOk)?;
+            }
+            Payload::Ping => {
+                ctx.reply(Payload::PingOk)?;
+            }
+            Payload::PingOk => (),
+            Payload::Heartbeat { term, leader } => {
+                let election = &mut self.election;
+                if term > election.term || (term == election.term && leader < election.leader) {
+                    log!(Info, "new_leader", leader = leader, term = term);
+                    election.term = term;
+                    election.leader = leader.clone();
+                }
+                if term == election.term && leader == election.leader {
+                    election.last_heard = self.time.now();
+                    election.timeout = election_timeout();
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::Sequence { message } => {
+                self.sequence(message)?;
+                ctx.reply(Payload::SequenceOk)?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::Sequenced { seq, message } => {
+                self.deliver_sequenced(seq, message);
+                ctx.reply(Payload::SequencedOk)?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::SequenceOk | Payload::SequencedOk => {
+                if let Some(id) = in_reply_to {
+                    self.acknowledge(id);
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::Broadcast { message, stamp } => {
+                let from_client = !self.node_ids.contains(&m.src);
+                let stamp = match stamp {
+                    None if from_client && !self.messages.contains(&message) => {
+                        self.next_seq += 1;
+                        Some((self.id.clone(), self.next_seq))
+                    }
+                    stamp => stamp,
+                };
+                self.note_stamps(&m.src, std::slice::from_ref(&message), vec![stamp])?;
+                if !self.messages.contains(&message) {
+                    self.mark_known(&m.src, [message.clone()]);
+                    self.disseminate(&m.src, vec![message])?;
+                };
+                if from_client {
+                    self.reply_once(ctx, &m.src, m.body.msg_id, Payload::BroadcastOk)?;
+                } else {
+                    self.ack(ctx, &m.src, m.body.msg_id, Payload::BroadcastOk)?;
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::BroadcastMany {
+                messages,
+                stamps,
+                acks,
+            } => {
+                self.acknowledge_all(acks);
+                self.note_stamps(&m.src, &messages, stamps)?;
+                self.mark_known(&m.src, messages.iter().cloned());
+                self.disseminate(&m.src, messages)?;
+                self.ack(ctx, &m.src, m.body.msg_id, Payload::BroadcastManyOk)?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::Acks { acks } => self.acknowledge_all(acks),
+            #[cfg(feature = "broadcast")]
+            Payload::BroadcastManyOk => {
+                if let Some(id) = in_reply_to {
+                    self.acknowledge(id);
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::BroadcastOk => {
+                // Duplicate or late acks (e.g. after a retry already succeeded)
+                // are expected; there is nothing left to do for them.
+                if !in_reply_to.is_some_and(|id| self.acknowledge(id)) {
+                    log!(
+                        Warn,
+                        "unknown_callback",
+                        src = m.src,
+                        in_reply_to = log::opt(in_reply_to),
+                    );
+                }
+            }
+            Payload::Read { .. } if self.workload == Workload::Broadcast => {
+                // Other nodes always get chunks; clients only when asked.
+                let chunk_size = match self.read_chunk {
+                    0 if self.node_ids.contains(&m.src) => catch_up_chunk(),
+                    size => size,
+                };
+                if self.workload == Workload::Broadcast
+                    && self.broadcast_order == BroadcastOrder::Total
+                {
+                    let delivered = &self.total_order.delivered;
+                    read_messages(ctx, delivered.iter().cloned(), delivered.len(), chunk_size)?;
+                } else {
+                    read_messages(ctx, self.messages.iter(), self.messages.len(), chunk_size)?;
+                }
+            }
+            Payload::Read { .. } if self.workload == Workload::KvCounter => read_kv_counter(ctx)?,
+            Payload::Read { .. } => {
+                let (messages, value) = match self.workload {
+                    Workload::Counter => (None, Some(self.counter.value().into())),
+                    Workload::GSet => (None, Some(self.elements.sorted().into())),
+                    Workload::OrSet => (None, Some(self.or_set.read().into())),
+                    // Served through the Raft log or the shard, or
+                    // streamed from the message set, above.
+                    Workload::LinKv | Workload::ShardedKv | Workload::Broadcast => (None, None),
+                    // Answered from the kv service, above.
+                    Workload::KvCounter => (None, None),
+                    Workload::Echo | Workload::UniqueIds | Workload::KvKafka => (None, None),
+                };
+                ctx.reply(Payload::ReadOk {
+                    messages,
+                    value,
+                    chunk: None,
+                    total_chunks: None,
+                })?;
+            }
+            #[cfg(feature = "counter")]
+            Payload::Add { delta, .. } if self.workload == Workload::KvCounter => {
+                add_kv_counter(ctx, m.src.clone(), m.body.msg_id, delta)?;
+            }
+            #[cfg(feature = "counter")]
+            Payload::Add { delta, element } => {
+                match self.workload {
+                    Workload::Counter => self.add_to_counter(delta),
+                    Workload::GSet => {
+                        let element = element.ok_or_else(|| {
+                            ErrorReply::new(ErrorCode::MalformedRequest, "add without element")
+                        })?;
+                        self.insert_element(element);
+                    }
+                    Workload::OrSet => {
+                        let element = element.ok_or_else(|| {
+                            ErrorReply::new(ErrorCode::MalformedRequest, "add without element")
+                        })?;
+                        let tag = self.gen_unique_id();
+                        self.or_set.add(element, tag);
+                    }
+                    _ => (),
+                }
+                self.reply_once(ctx, &m.src, m.body.msg_id, Payload::AddOk)?;
+            }
+            #[cfg(feature = "counter")]
+            Payload::AddOk => (),
+            #[cfg(feature = "kafka")]
+            Payload::Send { key, msg } if self.workload == Workload::KvKafka => {
+                kafka_kv_append(ctx, &key, msg)?;
+            }
+            #[cfg(feature = "kafka")]
+            Payload::Send { key, msg } => {
+                let offset = self.append(key, msg);
+                ctx.reply(Payload::SendOk { offset })?;
+            }
+            #[cfg(feature = "kafka")]
+            Payload::Poll { offsets } if self.workload == Workload::KvKafka => {
+                kafka_kv_poll(ctx, offsets.into_iter().collect(), HashMap::new())?;
+            }
+            #[cfg(feature = "kafka")]
+            Payload::Poll { offsets } => {
+                let msgs = self.poll(offsets);
+                ctx.reply(Payload::PollOk { msgs })?;
+            }
+            #[cfg(feature = "kafka")]
+            Payload::CommitOffsets { offsets } if self.workload == Workload::KvKafka => {
+                kafka_kv_commit(ctx, offsets.into_iter().collect())?;
+            }
+            #[cfg(feature = "kafka")]
+            Payload::CommitOffsets { offsets } => {
+                self.commit(offsets);
+                ctx.reply(Payload::CommitOffsetsOk)?;
+            }
+            #[cfg(feature = "kafka")]
+            Payload::ListCommittedOffsets { keys } if self.workload == Workload::KvKafka => {
+                kafka_kv_committed(ctx, keys, HashMap::new())?;
+            }
+            #[cfg(feature = "kafka")]
+            Payload::ListCommittedOffsets { keys } => {
+                let offsets = self.committed_offsets(keys);
+                ctx.reply(Payload::ListCommittedOffsetsOk { offsets })?;
+            }
+            #[cfg(feature = "txn")]
+            Payload::Txn { txn } => {
+                let (txn, writes) = self.apply_txn(txn)?;
+                ctx.reply(Payload::TxnOk { txn })?;
+                self.replicate(writes)?;
+            }
+            #[cfg(feature = "txn")]
+            Payload::Replicate { clock, writes } => {
+                self.apply_replicated(m.src.clone(), clock, writes);
+                ctx.reply(Payload::ReplicateOk)?;
+            }
+            #[cfg(feature = "txn")]
+            Payload::ReplicateOk => {
+                if let Some(id) = in_reply_to {
+                    self.callbacks.remove(&id);
+                }
+            }
+            #[cfg(feature = "counter")]
+            Payload::Remove { element } => {
+                self.or_set.remove(&element);
+                ctx.reply(Payload::RemoveOk)?;
+            }
+            #[cfg(feature = "counter")]
+            Payload::RemoveOk => (),
+            #[cfg(feature = "counter")]
+            Payload::OrSetGossip { state } => {
+                self.or_set.merge(state);
+            }
+            #[cfg(feature = "counter")]
+            Payload::CounterGossip { state } => {
+                self.counter.merge(state);
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::Gossip {
+                messages,
+                stamps,
+                acks,
+            } => {
+                self.acknowledge_all(acks);
+                self.note_stamps(&m.src, &messages, stamps)?;
+                self.mark_known(&m.src, messages.iter().cloned());
+                for message in messages {
+                    self.insert_message(message);
+                }
+                self.ack(ctx, &m.src, m.body.msg_id, Payload::GossipOk)?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::GossipOk => {
+                if let Some(id) = in_reply_to {
+                    self.acknowledge(id);
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::GossipDigest { digest } => {
+                let messages = self.fill_for(&m.src, &digest);
+                let stamps = self.stamps_of(&messages);
+                ctx.reply(Payload::GossipDigestOk {
+                    messages,
+                    stamps,
+                    digest: self.messages.digest(),
+                })?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::GossipDigestOk {
+                messages,
+                stamps,
+                digest,
+            } => {
+                self.note_stamps(&m.src, &messages, stamps)?;
+                self.mark_known(&m.src, messages.iter().cloned());
+                for message in messages {
+                    self.insert_message(message);
+                }
+                let messages = self.fill_for(&m.src, &digest);
+                if !messages.is_empty() {
+                    let stamps = self.stamps_of(&messages);
+                    ctx.reply(Payload::GossipFill { messages, stamps })?;
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::GossipFill { messages, stamps } => {
+                self.note_stamps(&m.src, &messages, stamps)?;
+                self.mark_known(&m.src, messages.iter().cloned());
+                for message in messages {
+                    self.insert_message(message);
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::Pull { origin, from_seq } => {
+                let values: Vec<(usize, Value)> = self
+                    .origins
+                    .get(&origin)
+                    .map(|log| {
+                        log.values
+                            .range(from_seq..)
+                            .map(|(seq, value)| (*seq, value.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                for (_, value) in values.iter() {
+                    self.heat(value);
+                }
+                ctx.reply(Payload::PullOk { origin, values })?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::PullOk { origin, values } => {
+                if let Some(id) = in_reply_to {
+                    self.acknowledge(id);
+                }
+                if let Some(log) = self.origins.get_mut(&origin) {
+                    log.pulling = false;
+                }
+                // Whatever is still missing is asked for again when the
+                // next value from this origin shows up.
+                let (seqs, values): (Vec<usize>, Vec<Value>) = values.into_iter().unzip();
+                for (value, seq) in values.iter().zip(seqs) {
+                    self.note_stamp(value, (origin.clone(), seq));
+                }
+                self.mark_known(&m.src, values.iter().cloned());
+                self.disseminate(&m.src, values)?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::SyncRequest { have, have_ranges } => {
+                let have_set = {
+                    let mut set = ValueSet::default();
+                    for value in have.iter() {
+                        set.insert(value.clone());
+                    }
+                    for &(start, end) in have_ranges.iter() {
+                        set.insert_range(start, end);
+                    }
+                    set
+                };
+                let missing: Vec<Value> = self
+                    .messages
+                    .iter()
+                    .filter(|message| !have_set.contains(message))
+                    .take(sync_digest_size())
+                    .collect();
+                for value in missing.iter() {
+                    self.heat(value);
+                }
+                self.mark_known(&m.src, have.iter().cloned());
+                self.mark_known_ranges(&m.src, &have_ranges);
+                for value in have {
+                    self.insert_message(value);
+                }
+                for (start, end) in have_ranges {
+                    for (from, to) in self.messages.gaps(start, end) {
+                        for n in from..=to {
+                            self.insert_message(n.into());
+                        }
+                    }
+                }
+                ctx.reply(Payload::SyncResponse { missing })?;
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::SyncResponse { missing, .. } => {
+                self.mark_known(&m.src, missing.iter().cloned());
+                for value in missing {
+                    self.insert_message(value);
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::CatchUpRequest => {
+                let messages = self.messages.sorted();
+                let mut chunks: Vec<&[Value]> = messages.chunks(catch_up_chunk().max(1)).collect();
+                if chunks.is_empty() {
+                    chunks.push(&[]);
+                }
+                for (i, chunk) in chunks.iter().enumerate() {
+                    ctx.reply(Payload::CatchUpResponse {
+                        messages: chunk.to_vec(),
+                        stamps: self.stamps_of(chunk),
+                        more: i + 1 < chunks.len(),
+                    })?;
+                }
+            }
+            #[cfg(feature = "broadcast")]
+            Payload::CatchUpResponse {
+                messages,
+                stamps,
+                more,
+            } => {
+                self.note_stamps(&m.src, &messages, stamps)?;
+                self.mark_known(&m.src, messages.iter().cloned());
+                let count = messages.len();
+                for message in messages {
+                    self.insert_message(message);
+                }
+                log!(
+                    Debug,
+                    "caught_up",
+                    peer = m.src,
+                    messages = count,
+                    more = more
+                );
+            }
+            #[cfg(feature = "counter")]
+            Payload::SetGossip { elements } => {
+                for element in elements {
+                    self.insert_element(element);
+                }
+            }
+            // Another node's message set, coming over in chunks.
+            #[cfg(feature = "broadcast")]
+            Payload::ReadOk {
+                messages: Some(messages),
+                chunk,
+                total_chunks,
+                ..
+            } if self.workload == Workload::Broadcast && self.node_ids.contains(&m.src) => {
+                self.mark_known(&m.src, messages.iter().cloned());
+                for message in messages {
+                    self.insert_message(message);
+                }
+                log!(
+                    Debug,
+                    "read_chunk",
+                    peer = m.src,
+                    chunk = log::opt(chunk),
+                    total_chunks = log::opt(total_chunks),
+                );
+            }
+            Payload::ReadOk { .. } => (),
+            Payload::Error { code, text } => log!(
+                Warn,
+                "error_reply",
+                src = m.src,
+                in_reply_to = log::opt(in_reply_to),
+                code = code,
+                text = text,
+            ),
+            _ => anyhow::bail!("invalid message received"),
+        };
+        Ok(())
+    }
+
+    fn periodic(&self) -> Vec<(Period, Task<Self>)> {
+        self.timers.clone()
+    }
+
+    /// Broadcast's, with this node's own neighbors as it uses them, e.g.
+    /// everyone under `topology_fallback`.
+    fn topology_dot(&self) -> Option<String> {
+        if self.workload != Workload::Broadcast {
+            return None;
+        }
+        let mut topology = self.topology.clone();
+        topology.insert(self.id.clone(), self.neighbors());
+        Some(topology::to_dot(
+            &self.node_ids,
+            &topology,
+            Some(&self.id),
+            &self.rtt,
+        ))
+    }
+
+    fn configure(
+        &mut self,
+        settings: &HashMap<String, Value>,
+    ) -> anyhow::Result<BTreeMap<String, Value>> {
+        // All are checked before any is applied, so a bad request changes
+        // nothing.
+        let settings: BTreeMap<&String, Setting> = settings
+            .iter()
+            .map(|(key, value)| Ok((key, Setting::parse(key, value)?)))
+            .collect::<anyhow::Result<_>>()?;
+        let mut applied = BTreeMap::new();
+        for (key, setting) in settings {
+            let value = match setting {
+                Setting::Gossip(interval) => {
+                    self.gossip_interval = interval;
+                    self.gossip_tick.set(gossip_tick(self.pacing, interval));
+                    json!(interval.as_millis() as u64)
+                }
+                Setting::Batch { tier, window } => {
+                    let period = if tier {
+                        &self.tier_window
+                    } else {
+                        &self.batch_window
+                    };
+                    period.set(window);
+                    // Nothing queues up any more to be flushed later.
+                    #[cfg(feature = "broadcast")]
+                    if window.is_zero() {
+                        self.flush_outbox(tier)?;
+                    }
+                    json!(window.as_millis() as u64)
+                }
+                Setting::RetryBase(base) => {
+                    self.retry_policy.base = base;
+                    json!(base.as_millis() as u64)
+                }
+                Setting::RateLimit(rate) => {
+                    self.limiter.rate = rate;
+                    if self.limiter.is_limited() {
+                        self.drain_tick.set(DRAIN_INTERVAL);
+                    }
+                    json!(rate)
+                }
+                Setting::RateBurst(burst) => {
+                    self.limiter.burst = burst;
+                    json!(burst)
+                }
+            };
+            applied.insert(key.clone(), value);
+        }
+        Ok(applied)
+    }
+
+    fn stats(&self) -> Value {
+        json!({
+            "pending_callbacks": self.callbacks.len(),
+            "messages": self.messages.len(),
+            "elements": self.elements.len(),
+            "outbox": self.outbox.values().map(Vec::len).sum::<usize>(),
+            "deferred_queue": self.deferred.len(),
+            "deferred": self.deferred_total,
+            "rumors": self.rumors.len(),
+            "rtt": self
+                .rtt
+                .iter()
+                .map(|(peer, rtt)| {
+                    let interval = self.gossip_interval_for(peer);
+                    (
+                        peer.clone(),
+                        json!({
+                            "rtt_ms": rtt.as_secs_f64() * 1000.0,
+                            "gossip_ms": interval.as_secs_f64() * 1000.0,
+                        }),
+                    )
+                })
+                .collect::<BTreeMap<_, _>>(),
+            "health": self
+                .health
+                .keys()
+                .map(|peer| (peer.clone(), self.health_of(peer)))
+                .collect::<BTreeMap<_, _>>(),
+        })
+    }
+
+    fn dump_state(&self) -> Value {
+        let pending: Vec<Value> = self
+            .callbacks
+            .iter()
+            .map(|(msg_id, callback)| match callback {
+                Callback::Pending {
+                    dest,
+                    body,
+                    attempts,
+                    ..
+                } => json!({
+                    "msg_id": msg_id,
+                    "dest": dest,
+                    "attempts": attempts,
+                    "body": body,
+                }),
+                Callback::Gossip { dest, messages, .. } => json!({
+                    "msg_id": msg_id,
+                    "dest": dest,
+                    "gossip": messages.len(),
+                }),
+                Callback::Relay {
+                    client,
+                    msg_id: for_msg_id,
+                    ..
+                } => json!({
+                    "msg_id": msg_id,
+                    "relay_for": client,
+                    "client_msg_id": for_msg_id,
+                }),
+            })
+            .collect();
+        json!({
+            "workload": format!("{:?}", self.workload),
+            "topology": self.topology,
+            "neighbors": self.neighbors(),
+            "messages": self.messages.sorted(),
+            "sequenced": self.total_order.delivered,
+            "leader": self.election.leader,
+            "term": self.election.term,
+            "buffered": self.total_order.buffered.len(),
+            "contiguous": self
+                .origins
+                .iter()
+                .map(|(origin, log)| (origin.clone(), log.contiguous))
+                .collect::<BTreeMap<_, _>>(),
+            "elements": self.elements.sorted(),
+            "counter": self.counter.value(),
+            "pending": pending,
+        })
+    }
+
+    fn leader(&self) -> Option<NodeId> {
+        match self.workload {
+            Workload::LinKv => self.raft.leader.clone(),
+            _ => Some(self.election.leader.clone()),
+        }
+    }
+
+    fn quiescent(&self) -> bool {
+        self.callbacks.is_empty()
+            && self.outbox.values().all(Vec::is_empty)
+            && self.deferred.is_empty()
+    }
+}
+
+/// How long a relayed request waits on its answer before it's forgotten;
+/// the client will have timed out by then.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn retry_pending(node: &mut Node, ctx: &mut Context) -> anyhow::Result<()> {
+    let now = ctx.now();
+    let ttl = pending_ttl();
+    node.callbacks.retain(|msg_id, callback| {
+        let keep = match callback {
+            Callback::Relay { sent_at, .. } => {
+                now.saturating_duration_since(*sent_at) < RELAY_TIMEOUT
+            }
+            // Nothing else repairs a replica that misses a transaction's
+            // writes, so those are retried for as long as it takes.
+            #[cfg(feature = "txn")]
+            Callback::Pending {
+                body: Payload::Replicate { .. },
+                ..
+            } => true,
+            Callback::Pending { created: at, .. } | Callback::Gossip { sent_at: at, .. } => {
+                ttl.is_zero() || now.saturating_duration_since(*at) < ttl
+            }
+        };
+        if !keep {
+            log_expired(*msg_id, callback);
+        }
+        keep
+    });
+    let mut due = Vec::new();
+    let policy = node.retry_policy;
+    let paced: HashMap<NodeId, RetryPolicy> = node
+        .rtt
+        .keys()
+        .map(|peer| (peer.clone(), node.retry_policy_for(peer)))
+        .collect();
+    let suspected: HashSet<NodeId> = node
+        .health
+        .keys()
+        .filter(|peer| node.health_of(peer) == Health::Suspected)
+        .cloned()
+        .collect();
+    let deferred: HashSet<MsgId> = node.deferred.iter().filter_map(|d| d.body.msg_id).collect();
+    for (msg_id, callback) in node.callbacks.iter_mut() {
+        if let Callback::Pending {
+            dest,
+            body,
+            sent_at,
+            attempts,
+            retry_at,
+            trace,
+            ..
+        } = callback
+        {
+            let policy = paced.get(dest).copied().unwrap_or(policy);
+            if now >= *retry_at && (suspected.contains(dest) || deferred.contains(msg_id)) {
+                // Held until the peer answers a probe (see `heard_from`), or
+                // until the limiter lets the last attempt out.
+                *retry_at = now + policy.next_delay(*attempts);
+            } else if now >= *retry_at {
+                *attempts += 1;
+                *sent_at = now;
+                *retry_at = now + policy.next_delay(*attempts);
+                let body = Body::request(*msg_id, body.clone());
+                due.push((dest.clone(), body, trace.clone()));
+            }
+        }
+    }
+    due.sort_by_key(|(_, body, _)| body.msg_id);
+    for (dest, body, trace) in due {
+        ctx.in_trace(trace, || {
+            Stats::incr(&ctx.stats().retries);
+            log!(Warn, "retry", dest = dest, msg_id = log::opt(body.msg_id));
+            node.send_limited(dest, body)
+        })?;
+    }
+    Ok(())
+}
+
+/// How often held-back sends are retried while a rate limit is set.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Under `pacing` the gossip timer only checks who's due, as often as any
+/// neighbor can be.
+fn gossip_tick(pacing: Option<Pacing>, interval: Duration) -> Duration {
+    match pacing {
+        Some(pacing) => pacing.floor.min(interval),
+        None => interval,
+    }
+}
+
+/// A setting a `configure` request can change, checked.
+enum Setting {
+    /// `gossip_ms`
+    Gossip(Duration),
+    /// `batch_ms`, or `tier_batch_ms` for the `tier` window.
+    Batch { tier: bool, window: Duration },
+    /// `retry_base_ms`
+    RetryBase(Duration),
+    /// `rate_limit`, sends a second; zero turns the limiter off.
+    RateLimit(f64),
+    /// `rate_burst`
+    RateBurst(f64),
+}
+impl Setting {
+    fn parse(key: &str, value: &Value) -> anyhow::Result<Self> {
+        let malformed = |what: &str| {
+            ErrorReply::new(
+                ErrorCode::MalformedRequest,
+                format!("{} must be {}", key, what),
+            )
+        };
+        let millis = || {
+            value
+                .as_u64()
+                .map(Duration::from_millis)
+                .ok_or_else(|| malformed("a whole number of milliseconds"))
+        };
+        let positive_millis = || {
+            millis()
+                .ok()
+                .filter(|interval| !interval.is_zero())
+                .ok_or_else(|| malformed("a positive number of milliseconds"))
+        };
+        let rate = || {
+            value
+                .as_f64()
+                .filter(|rate| rate.is_finite() && *rate >= 0.0)
+                .ok_or_else(|| malformed("a non-negative number"))
+        };
+        let setting = match key {
+            "gossip_ms" => Self::Gossip(positive_millis()?),
+            "batch_ms" => Self::Batch {
+                tier: false,
+                window: millis()?,
+            },
+            "tier_batch_ms" => Self::Batch {
+                tier: true,
+                window: millis()?,
+            },
+            "retry_base_ms" => Self::RetryBase(positive_millis()?),
+            "rate_limit" => Self::RateLimit(rate()?),
+            "rate_burst" => Self::RateBurst(rate()?),
+            _ => {
+                return Err(ErrorReply::new(
+                    ErrorCode::NotSupported,
+                    format!("unknown setting {:?}", key),
+                )
+                .into());
+            }
+        };
+        Ok(setting)
+    }
+}
+
+/// Sends held-back messages, oldest first, for as long as the limiter has
+/// tokens for them.
+fn drain_deferred(node: &mut Node, ctx: &mut Context) -> anyhow::Result<()> {
+    let now = ctx.now();
+    while let Some(next) = node.deferred.pop_front() {
+        let answered = next.tracked
+            && !next
+                .body
+                .msg_id
+                .is_some_and(|id| node.callbacks.contains_key(&id));
+        if answered {
+            continue;
+        }
+        if !node.limiter.try_acquire(now) {
+            node.deferred.push_front(next);
+            break;
+        }
+        let Deferred {
+            dest, body, trace, ..
+        } = next;
+        ctx.in_trace(trace, || node.output.send(&node.id, &dest, body))?;
+    }
+    // The limiter was turned off and what it held is out.
+    if !node.limiter.is_limited() && node.deferred.is_empty() {
+        node.drain_tick.set(Duration::ZERO);
+    }
+    Ok(())
+}
+
+/// Logs what an expired callback was waiting on. Whatever it carried is
+/// left to gossip and anti-entropy.
+fn log_expired(msg_id: MsgId, callback: &Callback) {
+    match callback {
+        Callback::Pending {
+            dest,
+            body,
+            attempts,
+            ..
+        } => log!(
+            Warn,
+            "callback_expired",
+            dest = dest,
+            msg_id = msg_id,
+            attempts = attempts,
+            body = serde_json::to_string(body).unwrap_or_default(),
+        ),
+        Callback::Gossip { dest, messages, .. } => log!(
+            Info,
+            "gossip_unacked",
+            dest = dest,
+            msg_id = msg_id,
+            messages = messages.len(),
+        ),
+        Callback::Relay { .. } => (),
+    }
+}
+
+pub fn batch_window() -> Duration {
+    Duration::from_millis(env_or("MAELLE_BATCH_MS", 0))
+}
+
+/// How long acks wait to be piggybacked, from `MAELLE_ACK_DELAY_MS`.
+pub fn ack_delay() -> Duration {
+    Duration::from_millis(env_or("MAELLE_ACK_DELAY_MS", 0))
+}
+
+/// The batch window between cluster representatives, from
+/// `MAELLE_TIER_BATCH_MS`; unset, it's the same as within a cluster.
+pub fn tier_window() -> Option<Duration> {
+    std::env::var("MAELLE_TIER_BATCH_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis)
+}
+
+/// How often the leader sends heartbeats, from `MAELLE_HEARTBEAT_MS`; off by
+/// default, since only workloads that need a leader should pay for them.
+fn heartbeat_interval() -> Duration {
+    Duration::from_millis(env_or("MAELLE_HEARTBEAT_MS", 0))
+}
+
+/// Somewhere between one and two `MAELLE_ELECTION_MS`, drawn afresh each
+/// time so that followers rarely time out together.
+pub(crate) fn election_timeout() -> Duration {
+    let base = env_or("MAELLE_ELECTION_MS", 1000u64);
+    Duration::from_millis(base + random_u64() % base.max(1))
+}
+
+/// How often a Raft leader sends `append_entries`, from
+/// `MAELLE_RAFT_TICK_MS`; it has to be well under the election timeout.
+fn raft_interval() -> Duration {
+    Duration::from_millis(env_or("MAELLE_RAFT_TICK_MS", 100))
+}
+
+/// Sends heartbeats if this node leads, or takes over if the leader has
+/// gone quiet.
+fn heartbeat(node: &mut Node, ctx: &mut Context) -> anyhow::Result<()> {
+    let election = &mut node.election;
+    if election.leader != node.id
+        && ctx.now().saturating_duration_since(election.last_heard) >= election.timeout
+    {
+        election.term += 1;
+        election.leader = node.id.clone();
+        log!(Info, "elected", term = election.term);
+    }
+    if election.leader != node.id {
+        return Ok(());
+    }
+    let body = Payload::Heartbeat {
+        term: election.term,
+        leader: node.id.clone(),
+    };
+    for n in node.node_ids.iter().filter(|n| **n != node.id) {
+        node.output.send(&node.id, n, Body::new(body.clone()))?;
+    }
+    Ok(())
+}
+
+/// How often quiet neighbors are probed, from `MAELLE_PING_MS`.
+fn ping_interval() -> Duration {
+    Duration::from_millis(env_or("MAELLE_PING_MS", 1000))
+}
+
+/// Pings every neighbor not heard from within the last interval, and
+/// counts it one step further toward suspected. Any message from a peer,
+/// not only a `ping_ok`, clears its suspicion. A suspected neighbor's
+/// held broadcasts go out alongside, so a dead one costs a batch a probe
+/// rather than a send and its retries for every value.
+fn probe_neighbors(node: &mut Node, ctx: &mut Context) -> anyhow::Result<()> {
+    if !matches!(
+        node.workload,
+        Workload::Broadcast | Workload::GSet | Workload::OrSet
+    ) {
+        return Ok(());
+    }
+    let now = ctx.now();
+    let interval = ping_interval();
+    for n in node.neighbors() {
+        let health = node.health.entry(n.clone()).or_insert(PeerHealth {
+            last_ack: now,
+            suspicion: 0,
+        });
+        if now.saturating_duration_since(health.last_ack) < interval {
+            continue;
+        }
+        health.suspicion += 1;
+        if health.suspicion == node.suspect_after {
+            log!(Warn, "peer_suspected", peer = n);
+        }
+        let body = Body::request(node.next_msg_id(), Payload::Ping);
+        node.output.send(&node.id, &n, body)?;
+        #[cfg(feature = "broadcast")]
+        if node.health_of(&n) == Health::Suspected {
+            if let Some(messages) = node.outbox.remove(&n) {
+                node.send_batch(n.clone(), messages)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn gossip_interval() -> Duration {
+    Duration::from_millis(env_or("MAELLE_GOSSIP_MS", 500))
+}
+
+fn gossip(node: &mut Node, ctx: &mut Context) -> anyhow::Result<()> {
+    let at_random = node.workload == Workload::Broadcast && node.gossip_mode != GossipMode::Push;
+    if at_random && !node.round_due() {
+        return Ok(());
+    }
+    Stats::incr(&ctx.stats().gossip_rounds);
+    match node.workload {
+        #[cfg(feature = "broadcast")]
+        Workload::Broadcast if node.gossip_mode == GossipMode::PushPull => gossip_digest(node),
+        #[cfg(feature = "broadcast")]
+        Workload::Broadcast if matches!(node.gossip_mode, GossipMode::Rumor { .. }) => {
+            gossip_rumors(node)
+        }
+        #[cfg(feature = "broadcast")]
+        Workload::Broadcast => gossip_messages(node),
+        #[cfg(feature = "counter")]
+        Workload::Counter | Workload::GSet | Workload::OrSet => gossip_state(node),
+        _ => Ok(()),
+    }
+}
+
+/// Sends each live neighbor the messages it hasn't been seen to have yet.
+#[cfg(feature = "broadcast")]
+fn gossip_messages(node: &mut Node) -> anyhow::Result<()> {
+    for n in node.gossip_due() {
+        let known = node.known.get(&n);
+        let messages: Vec<Value> = node
+            .messages
+            .iter()
+            .filter(|message| !known.is_some_and(|known| known.contains(message)))
+            .collect();
+        if !messages.is_empty() {
+            send_gossip(node, n, messages)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends `n` a `gossip` of `messages`, marked known to it once acked.
+#[cfg(feature = "broadcast")]
+fn send_gossip(node: &mut Node, n: NodeId, messages: Vec<Value>) -> anyhow::Result<()> {
+    let msg_id = node.next_msg_id();
+    node.callbacks.insert(
+        msg_id,
+        Callback::Gossip {
+            dest: n.clone(),
+            messages: messages.clone(),
+            sent_at: node.time.now(),
+        },
+    );
+    let stamps = node.stamps_of(&messages);
+    // Held back by the limiter, acks could outlast the callback they're
+    // for, and never go out.
+    let acks = if node.limiter.is_limited() {
+        Vec::new()
+    } else {
+        node.take_acks(&n)
+    };
+    let body = Payload::Gossip {
+        messages,
+        stamps,
+        acks,
+    };
+    node.send_limited(n, Body::request(msg_id, body))
+}
+
+/// Pushes the hot values to `fanout` random live neighbors, then counts
+/// the round against each of them, dropping those with none left.
+#[cfg(feature = "broadcast")]
+fn gossip_rumors(node: &mut Node) -> anyhow::Result<()> {
+    let GossipMode::Rumor { fanout, .. } = node.gossip_mode else {
+        return Ok(());
+    };
+    let mut neighbors = node.alive_neighbors();
+    if node.rumors.is_empty() || neighbors.is_empty() {
+        return Ok(());
+    }
+    let fanout = fanout.min(neighbors.len());
+    for i in 0..fanout {
+        let j = i + random_u64() as usize % (neighbors.len() - i);
+        neighbors.swap(i, j);
+    }
+    neighbors.truncate(fanout);
+    for n in neighbors {
+        let known = node.known.get(&n);
+        let messages: Vec<Value> = node
+            .rumors
+            .iter()
+            .map(|(value, _)| value)
+            .filter(|value| !known.is_some_and(|known| known.contains(value)))
+            .cloned()
+            .collect();
+        if !messages.is_empty() {
+            send_gossip(node, n, messages)?;
+        }
+    }
+    node.rumors.retain_mut(|(_, left)| {
+        *left = left.saturating_sub(1);
+        *left > 0
+    });
+    Ok(())
+}
+
+/// Opens a push-pull round with one random live neighbor.
+#[cfg(feature = "broadcast")]
+fn gossip_digest(node: &mut Node) -> anyhow::Result<()> {
+    let neighbors = node.alive_neighbors();
+    if neighbors.is_empty() {
+        return Ok(());
+    }
+    let peer = neighbors[random_u64() as usize % neighbors.len()].clone();
+    let digest = node.messages.digest();
+    let body = Body::request(node.next_msg_id(), Payload::GossipDigest { digest });
+    node.send_limited(peer, body)
+}
+
+/// Serializes the values an iterator yields as a JSON array, without
+/// collecting them first; it's used up by the first serialization.
+struct Streamed<I>(std::cell::Cell<Option<I>>);
+impl<I: Iterator<Item = Value>> Serialize for Streamed<I> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.take().into_iter().flatten())
+    }
+}
+
+/// A `read_ok` of broadcast values, as [`Payload::ReadOk`] is on the wire.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+#[serde(bound(serialize = "I: Iterator<Item = Value>"))]
+struct MessagesOk<I> {
+    messages: Streamed<I>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_chunks: Option<usize>,
+}
+impl<I> Kind for MessagesOk<I> {
+    fn kind(&self) -> &'static str {
+        "read_ok"
+    }
+}
+
+/// Answers a read with the `len` values from `values`: in one `read_ok`,
+/// or with a `chunk_size` in as many as it takes, each numbered.
+fn read_messages(
+    ctx: &mut Context,
+    mut values: impl Iterator<Item = Value>,
+    len: usize,
+    chunk_size: usize,
+) -> anyhow::Result<()> {
+    if chunk_size == 0 {
+        return ctx.reply(MessagesOk {
+            messages: Streamed(Some(values).into()),
+            chunk: None,
+            total_chunks: None,
+        });
+    }
+    let total_chunks = len.div_ceil(chunk_size).max(1);
+    for chunk in 0..total_chunks {
+        ctx.reply(MessagesOk {
+            messages: Streamed(Some(values.by_ref().take(chunk_size)).into()),
+            chunk: Some(chunk),
+            total_chunks: Some(total_chunks),
+        })?;
+    }
+    Ok(())
+}
+
+fn sync_interval() -> Duration {
+    Duration::from_millis(env_or("MAELLE_SYNC_MS", 2000))
+}
+
+#[cfg(feature = "broadcast")]
+fn sync_digest_size() -> usize {
+    env_or("MAELLE_SYNC_DIGEST", usize::MAX)
+}
+
+/// Low-frequency full-state exchange with one random neighbor, repairing
+/// divergence that retries and gossip can't see (e.g. after a restart).
+#[cfg(feature = "broadcast")]
+fn anti_entropy(node: &mut Node, _: &mut Context) -> anyhow::Result<()> {
+    let neighbors = node.neighbors();
+    if node.workload != Workload::Broadcast || neighbors.is_empty() {
+        return Ok(());
+    }
+    let peer = neighbors[random_u64() as usize % neighbors.len()].clone();
+    let digest_size = sync_digest_size();
+    let (have, have_ranges) = if node.messages.len() > digest_size {
+        // A random window of values, so over time every one gets compared.
+        let skip = random_u64() as usize % (node.messages.len() - digest_size + 1);
+        let have = node.messages.iter().skip(skip).take(digest_size).collect();
+        (have, Vec::new())
+    } else {
+        let have = node.messages.others().cloned().collect();
+        (have, node.messages.ranges().collect())
+    };
+    let body = Body::request(
+        node.next_msg_id(),
+        Payload::SyncRequest { have, have_ranges },
+    );
+    node.send_limited(peer, body)
+}
+
+/// Most values in one `catch_up_response`, or in one `read_ok` to another
+/// node, from `MAELLE_CATCH_UP_CHUNK`.
+fn catch_up_chunk() -> usize {
+    env_or("MAELLE_CATCH_UP_CHUNK", 1000)
+}
+
+#[cfg(feature = "broadcast")]
+fn flush_outbox(node: &mut Node, _: &mut Context) -> anyhow::Result<()> {
+    node.flush_outbox(false)
+}
+
+#[cfg(feature = "broadcast")]
+fn flush_tier_outbox(node: &mut Node, _: &mut Context) -> anyhow::Result<()> {
+    node.flush_outbox(true)
+}
+
+#[cfg(feature = "broadcast")]
+fn flush_acks(node: &mut Node, _: &mut Context) -> anyhow::Result<()> {
+    node.flush_acks()
+}
+
+/// Sends each live neighbor this node's whole counter or set, to merge.
+#[cfg(feature = "counter")]
+fn gossip_state(node: &mut Node) -> anyhow::Result<()> {
+    let body = match node.workload {
+        Workload::Counter if !node.counter.is_empty() => Payload::CounterGossip {
+            state: node.counter.clone(),
+        },
+        Workload::GSet if !node.elements.is_empty() => Payload::SetGossip {
+            elements: node.elements.iter().collect(),
+        },
+        Workload::OrSet if !node.or_set.adds.is_empty() => Payload::OrSetGossip {
+            state: node.or_set.clone(),
+        },
+        _ => return Ok(()),
+    };
+    for n in node.gossip_due() {
+        node.send_limited(n, Body::new(body.clone()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,999 @@
+//! Wire types: the JSON messages exchanged with Maelstrom and other nodes.
+
+#[cfg(feature = "counter")]
+use crate::node::{OrSet, PnCounter};
+use crate::raft::LogEntry;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Who a message is from or to: a node (`n1`), a client (`c3`) or a
+/// Maelstrom service (`lin-kv`). Serialized as the bare string.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct NodeId(pub String);
+impl NodeId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    /// Maelstrom's clients are `c1`, `c2`, ...
+    pub fn is_client(&self) -> bool {
+        self.0.starts_with('c')
+    }
+    /// And its nodes `n1`, `n2`, ...
+    pub fn is_node(&self) -> bool {
+        self.0.starts_with('n')
+    }
+}
+impl std::ops::Deref for NodeId {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+impl std::borrow::Borrow<str> for NodeId {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl From<&str> for NodeId {
+    fn from(id: &str) -> Self {
+        Self(id.to_string())
+    }
+}
+impl From<String> for NodeId {
+    fn from(id: String) -> Self {
+        Self(id)
+    }
+}
+impl PartialEq<str> for NodeId {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+impl PartialEq<&str> for NodeId {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// A body's `msg_id`, or the `in_reply_to` that answers one. Serialized as
+/// the bare number.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct MsgId(pub u64);
+impl std::fmt::Display for MsgId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A micro-operation of a txn, e.g. `["r", 1, null]` or `["w", 1, 5]`.
+pub type Operation = (String, usize, Option<Value>);
+/// A register write as replicated between nodes.
+pub type RegisterWrite = (usize, Value);
+/// Where a broadcast value entered the cluster: the node a client sent it
+/// to, and that node's count of values taken so far.
+pub type Stamp = (NodeId, usize);
+
+/// What a node has of the broadcast values, in little more than the room
+/// its integer ranges take: how many there are, those ranges, and every
+/// value that isn't an integer as it is.
+#[cfg(feature = "broadcast")]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Digest {
+    pub count: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<(u64, u64)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub others: Vec<Value>,
+}
+
+/// The `type` a payload goes out as, which the runtime counts and logs
+/// sends by without looking at the encoded line.
+pub trait Kind {
+    fn kind(&self) -> &'static str;
+}
+
+/// A payload built as JSON: its `type` field, or `unknown`.
+impl Kind for Value {
+    fn kind(&self) -> &'static str {
+        match self.get("type").and_then(Value::as_str) {
+            Some(kind) => intern(kind),
+            None => "unknown",
+        }
+    }
+}
+
+/// How many distinct types [`intern`] keeps before calling the rest `other`,
+/// so a sender making up types can't grow it without end.
+const MAX_KINDS: usize = 256;
+
+/// `kind` as a `&'static str`, leaked the first time it's seen. Each thread
+/// remembers the ones it has looked up, so it's only locked for new ones.
+pub fn intern(kind: &str) -> &'static str {
+    thread_local! {
+        static SEEN: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+    }
+    static ALL: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    if let Some(kind) = SEEN.with(|seen| seen.borrow().get(kind).copied()) {
+        return kind;
+    }
+    let Ok(mut all) = ALL.lock() else {
+        return "other";
+    };
+    let interned = match all.get(kind) {
+        Some(interned) => *interned,
+        None if all.len() >= MAX_KINDS => return "other",
+        None => {
+            let interned: &'static str = Box::leak(kind.into());
+            all.insert(interned);
+            interned
+        }
+    };
+    SEEN.with(|seen| seen.borrow_mut().insert(interned));
+    interned
+}
+
+/// The startup handshake, shared by every workload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum InitPayload {
+    Init {
+        node_id: NodeId,
+        node_ids: Vec<NodeId>,
+    },
+    InitOk,
+}
+impl Kind for InitPayload {
+    fn kind(&self) -> &'static str {
+        match self {
+            InitPayload::Init { .. } => "init",
+            InitPayload::InitOk => "init_ok",
+        }
+    }
+}
+
+/// Everything the built-in [`Node`](crate::node::Node) workloads speak.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Echo {
+        echo: String,
+    },
+    EchoOk {
+        echo: String,
+    },
+    Generate,
+    GenerateOk {
+        id: String,
+    },
+    #[cfg(feature = "broadcast")]
+    Topology {
+        topology: HashMap<NodeId, Vec<NodeId>>,
+    },
+    #[cfg(feature = "broadcast")]
+    TopologyOk,
+    #[cfg(feature = "broadcast")]
+    Broadcast {
+        message: Value,
+        /// Only ever set between nodes.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stamp: Option<Stamp>,
+    },
+    #[cfg(feature = "broadcast")]
+    BroadcastOk,
+    #[cfg(feature = "broadcast")]
+    BroadcastMany {
+        messages: Vec<Value>,
+        /// `stamps[i]` is the stamp of `messages[i]`, if it has one; empty
+        /// when none do.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        stamps: Vec<Option<Stamp>>,
+        /// The sender's acks of the receiver's msg_ids, riding along
+        /// instead of going as their own replies.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        acks: Vec<MsgId>,
+    },
+    #[cfg(feature = "broadcast")]
+    BroadcastManyOk,
+    /// Held acks that found nothing to ride on in time.
+    #[cfg(feature = "broadcast")]
+    Acks {
+        acks: Vec<MsgId>,
+    },
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<Value>,
+    },
+    ReadOk {
+        /// Deduplicated and sorted by `value_order`, or in sequence order
+        /// under total-order broadcast.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        messages: Option<Vec<Value>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<Value>,
+        /// Set when `messages` is split over several replies: this one's
+        /// index, from 0, of `total_chunks`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        total_chunks: Option<usize>,
+    },
+    #[cfg(feature = "counter")]
+    Add {
+        #[serde(default)]
+        delta: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        element: Option<Value>,
+    },
+    #[cfg(feature = "counter")]
+    AddOk,
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    #[cfg(feature = "kafka")]
+    Send {
+        key: String,
+        msg: usize,
+    },
+    #[cfg(feature = "kafka")]
+    SendOk {
+        offset: usize,
+    },
+    #[cfg(feature = "kafka")]
+    Poll {
+        offsets: HashMap<String, usize>,
+    },
+    #[cfg(feature = "kafka")]
+    PollOk {
+        msgs: HashMap<String, Vec<[usize; 2]>>,
+    },
+    #[cfg(feature = "kafka")]
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+    },
+    #[cfg(feature = "kafka")]
+    CommitOffsetsOk,
+    #[cfg(feature = "kafka")]
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    #[cfg(feature = "kafka")]
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+    #[cfg(feature = "txn")]
+    Txn {
+        txn: Vec<Operation>,
+    },
+    #[cfg(feature = "txn")]
+    TxnOk {
+        txn: Vec<Operation>,
+    },
+    #[cfg(feature = "txn")]
+    Replicate {
+        clock: usize,
+        writes: Vec<RegisterWrite>,
+    },
+    #[cfg(feature = "txn")]
+    ReplicateOk,
+    #[cfg(feature = "counter")]
+    Remove {
+        element: Value,
+    },
+    #[cfg(feature = "counter")]
+    RemoveOk,
+    #[cfg(feature = "broadcast")]
+    Gossip {
+        messages: Vec<Value>,
+        /// As in `BroadcastMany`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        stamps: Vec<Option<Stamp>>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        acks: Vec<MsgId>,
+    },
+    #[cfg(feature = "broadcast")]
+    GossipOk,
+    /// Opens a push-pull round: what the sender has.
+    #[cfg(feature = "broadcast")]
+    GossipDigest {
+        digest: Digest,
+    },
+    /// What the digest showed the sender lacks, and what this node has in
+    /// turn.
+    #[cfg(feature = "broadcast")]
+    GossipDigestOk {
+        messages: Vec<Value>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        stamps: Vec<Option<Stamp>>,
+        digest: Digest,
+    },
+    /// Closes the round with what the answering node lacks.
+    #[cfg(feature = "broadcast")]
+    GossipFill {
+        messages: Vec<Value>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        stamps: Vec<Option<Stamp>>,
+    },
+    RequestVote {
+        term: usize,
+        candidate: NodeId,
+        last_log_index: usize,
+        last_log_term: usize,
+    },
+    RequestVoteOk {
+        term: usize,
+        granted: bool,
+    },
+    AppendEntries {
+        term: usize,
+        leader: NodeId,
+        prev_log_index: usize,
+        prev_log_term: usize,
+        entries: Vec<LogEntry>,
+        leader_commit: usize,
+    },
+    AppendEntriesOk {
+        term: usize,
+        success: bool,
+        /// On success the follower's last matching index; otherwise where
+        /// the leader should back up to.
+        last_index: usize,
+    },
+    /// Sent by the leader of `term` to every other node.
+    Heartbeat {
+        term: usize,
+        leader: NodeId,
+    },
+    /// Asks a neighbor that has gone quiet whether it's still there.
+    Ping,
+    PingOk,
+    /// Hands a client's broadcast to the sequencer to be ordered.
+    #[cfg(feature = "broadcast")]
+    Sequence {
+        message: Value,
+    },
+    #[cfg(feature = "broadcast")]
+    SequenceOk,
+    /// The sequencer's decision that `message` is the `seq`th value.
+    #[cfg(feature = "broadcast")]
+    Sequenced {
+        seq: usize,
+        message: Value,
+    },
+    #[cfg(feature = "broadcast")]
+    SequencedOk,
+    /// Asks for every value stamped by `origin` from `from_seq` on.
+    #[cfg(feature = "broadcast")]
+    Pull {
+        origin: NodeId,
+        from_seq: usize,
+    },
+    #[cfg(feature = "broadcast")]
+    PullOk {
+        origin: NodeId,
+        values: Vec<(usize, Value)>,
+    },
+    #[cfg(feature = "broadcast")]
+    SyncRequest {
+        have: Vec<Value>,
+        /// Integer values as inclusive `[start, end]` ranges; `have` then
+        /// carries only the rest.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        have_ranges: Vec<(u64, u64)>,
+    },
+    #[cfg(feature = "broadcast")]
+    SyncResponse {
+        missing: Vec<Value>,
+    },
+    /// Asks a neighbor for every value it has, from a node starting out
+    /// with none.
+    #[cfg(feature = "broadcast")]
+    CatchUpRequest,
+    /// One chunk of the answer; `more` says another follows.
+    #[cfg(feature = "broadcast")]
+    CatchUpResponse {
+        messages: Vec<Value>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        stamps: Vec<Option<Stamp>>,
+        more: bool,
+    },
+    #[cfg(feature = "counter")]
+    SetGossip {
+        elements: Vec<Value>,
+    },
+    #[cfg(feature = "counter")]
+    OrSetGossip {
+        state: OrSet,
+    },
+    #[cfg(feature = "counter")]
+    CounterGossip {
+        state: PnCounter,
+    },
+    Error {
+        code: usize,
+        #[serde(default)]
+        text: String,
+    },
+}
+impl Kind for Payload {
+    fn kind(&self) -> &'static str {
+        match self {
+            Payload::Echo { .. } => "echo",
+            Payload::EchoOk { .. } => "echo_ok",
+            Payload::Generate => "generate",
+            Payload::GenerateOk { .. } => "generate_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::Topology { .. } => "topology",
+            #[cfg(feature = "broadcast")]
+            Payload::TopologyOk => "topology_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::Broadcast { .. } => "broadcast",
+            #[cfg(feature = "broadcast")]
+            Payload::BroadcastOk => "broadcast_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::BroadcastMany { .. } => "broadcast_many",
+            #[cfg(feature = "broadcast")]
+            Payload::BroadcastManyOk => "broadcast_many_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::Acks { .. } => "acks",
+            Payload::Read { .. } => "read",
+            Payload::ReadOk { .. } => "read_ok",
+            #[cfg(feature = "counter")]
+            Payload::Add { .. } => "add",
+            #[cfg(feature = "counter")]
+            Payload::AddOk => "add_ok",
+            Payload::Write { .. } => "write",
+            Payload::WriteOk => "write_ok",
+            Payload::Cas { .. } => "cas",
+            Payload::CasOk => "cas_ok",
+            #[cfg(feature = "kafka")]
+            Payload::Send { .. } => "send",
+            #[cfg(feature = "kafka")]
+            Payload::SendOk { .. } => "send_ok",
+            #[cfg(feature = "kafka")]
+            Payload::Poll { .. } => "poll",
+            #[cfg(feature = "kafka")]
+            Payload::PollOk { .. } => "poll_ok",
+            #[cfg(feature = "kafka")]
+            Payload::CommitOffsets { .. } => "commit_offsets",
+            #[cfg(feature = "kafka")]
+            Payload::CommitOffsetsOk => "commit_offsets_ok",
+            #[cfg(feature = "kafka")]
+            Payload::ListCommittedOffsets { .. } => "list_committed_offsets",
+            #[cfg(feature = "kafka")]
+            Payload::ListCommittedOffsetsOk { .. } => "list_committed_offsets_ok",
+            #[cfg(feature = "txn")]
+            Payload::Txn { .. } => "txn",
+            #[cfg(feature = "txn")]
+            Payload::TxnOk { .. } => "txn_ok",
+            #[cfg(feature = "txn")]
+            Payload::Replicate { .. } => "replicate",
+            #[cfg(feature = "txn")]
+            Payload::ReplicateOk => "replicate_ok",
+            #[cfg(feature = "counter")]
+            Payload::Remove { .. } => "remove",
+            #[cfg(feature = "counter")]
+            Payload::RemoveOk => "remove_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::Gossip { .. } => "gossip",
+            #[cfg(feature = "broadcast")]
+            Payload::GossipOk => "gossip_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::GossipDigest { .. } => "gossip_digest",
+            #[cfg(feature = "broadcast")]
+            Payload::GossipDigestOk { .. } => "gossip_digest_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::GossipFill { .. } => "gossip_fill",
+            Payload::RequestVote { .. } => "request_vote",
+            Payload::RequestVoteOk { .. } => "request_vote_ok",
+            Payload::AppendEntries { .. } => "append_entries",
+            Payload::AppendEntriesOk { .. } => "append_entries_ok",
+            Payload::Heartbeat { .. } => "heartbeat",
+            Payload::Ping => "ping",
+            Payload::PingOk => "ping_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::Sequence { .. } => "sequence",
+            #[cfg(feature = "broadcast")]
+            Payload::SequenceOk => "sequence_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::Sequenced { .. } => "sequenced",
+            #[cfg(feature = "broadcast")]
+            Payload::SequencedOk => "sequenced_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::Pull { .. } => "pull",
+            #[cfg(feature = "broadcast")]
+            Payload::PullOk { .. } => "pull_ok",
+            #[cfg(feature = "broadcast")]
+            Payload::SyncRequest { .. } => "sync_request",
+            #[cfg(feature = "broadcast")]
+            Payload::SyncResponse { .. } => "sync_response",
+            #[cfg(feature = "broadcast")]
+            Payload::CatchUpRequest => "catch_up_request",
+            #[cfg(feature = "broadcast")]
+            Payload::CatchUpResponse { .. } => "catch_up_response",
+            #[cfg(feature = "counter")]
+            Payload::SetGossip { .. } => "set_gossip",
+            #[cfg(feature = "counter")]
+            Payload::OrSetGossip { .. } => "or_set_gossip",
+            #[cfg(feature = "counter")]
+            Payload::CounterGossip { .. } => "counter_gossip",
+            Payload::Error { .. } => "error",
+        }
+    }
+}
+
+/// Introspection requests the runtime answers itself, whatever the workload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum AdminPayload {
+    Stats,
+    StatsOk {
+        stats: StatsSnapshot,
+    },
+    DumpState,
+    DumpStateOk {
+        state: Value,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        vclock: BTreeMap<String, usize>,
+    },
+    Leader,
+    LeaderOk {
+        leader: Option<NodeId>,
+    },
+    /// Changes settings while the node runs; see
+    /// [`Handler::configure`](crate::runtime::Handler::configure).
+    Configure {
+        settings: HashMap<String, Value>,
+    },
+    /// The settings as applied.
+    ConfigureOk {
+        settings: BTreeMap<String, Value>,
+    },
+    /// Breaks this node's outbound link to a peer; see [`Fault`].
+    InjectFault {
+        #[serde(flatten)]
+        fault: Fault,
+    },
+    /// The faults in effect once it's applied.
+    InjectFaultOk {
+        faults: Vec<Fault>,
+    },
+    DumpTopology,
+    /// The topology as the node sees it, as a Graphviz graph.
+    DumpTopologyOk {
+        dot: String,
+    },
+}
+impl Kind for AdminPayload {
+    fn kind(&self) -> &'static str {
+        match self {
+            AdminPayload::Stats => "stats",
+            AdminPayload::StatsOk { .. } => "stats_ok",
+            AdminPayload::DumpState => "dump_state",
+            AdminPayload::DumpStateOk { .. } => "dump_state_ok",
+            AdminPayload::Leader => "leader",
+            AdminPayload::LeaderOk { .. } => "leader_ok",
+            AdminPayload::Configure { .. } => "configure",
+            AdminPayload::ConfigureOk { .. } => "configure_ok",
+            AdminPayload::InjectFault { .. } => "inject_fault",
+            AdminPayload::InjectFaultOk { .. } => "inject_fault_ok",
+            AdminPayload::DumpTopology => "dump_topology",
+            AdminPayload::DumpTopologyOk { .. } => "dump_topology_ok",
+        }
+    }
+}
+impl AdminPayload {
+    pub const TYPES: [&'static str; 12] = [
+        "stats",
+        "stats_ok",
+        "dump_state",
+        "dump_state_ok",
+        "leader",
+        "leader_ok",
+        "configure",
+        "configure_ok",
+        "inject_fault",
+        "inject_fault_ok",
+        "dump_topology",
+        "dump_topology_ok",
+    ];
+}
+
+/// Trouble an `inject_fault` request makes for what a node sends, below
+/// its handler, which carries on as if the network were at fault.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "mode")]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// Discards the next `count` messages to `peer`, on top of any still
+    /// to be discarded.
+    DropNext { count: usize, peer: NodeId },
+    /// Holds each message to `peer` for `ms` before it's written, in
+    /// order; zero stops holding them.
+    Delay { peer: NodeId, ms: u64 },
+    /// Lifts every fault, writing out whatever is held.
+    Clear,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsSnapshot {
+    pub client_ops: u64,
+    pub messages_sent: u64,
+    #[serde(default)]
+    pub bytes_sent: u64,
+    pub retries: u64,
+    pub gossip_rounds: u64,
+    pub messages_per_op: f64,
+    /// Messages by `type`, admin traffic excluded.
+    #[serde(default)]
+    pub received: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub sent: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub pending_rpcs: usize,
+    /// Messages read from stdin and still waiting to be handled.
+    #[serde(default)]
+    pub inbox_depth: usize,
+    #[serde(default)]
+    pub uptime_ms: u64,
+    /// Whatever the workload reports about itself.
+    #[serde(default)]
+    pub handler: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message<P = Payload> {
+    pub src: NodeId,
+    pub dest: NodeId,
+    pub body: Body<P>,
+}
+impl<P> Message<P> {
+    /// The addressing and ids of this message, without its payload.
+    pub fn headers(&self) -> Message<()> {
+        Message {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: Body {
+                msg_id: self.body.msg_id,
+                in_reply_to: self.body.in_reply_to,
+                payload: (),
+            },
+        }
+    }
+    /// Addresses `payload` back to this message's sender, taking a fresh
+    /// msg_id from `next_id` and answering this message's msg_id.
+    pub fn into_reply<Q>(self, next_id: &mut impl FnMut() -> MsgId, payload: Q) -> Message<Q> {
+        Message {
+            src: self.dest,
+            dest: self.src,
+            body: Body {
+                msg_id: Some(next_id()),
+                in_reply_to: self.body.msg_id,
+                payload,
+            },
+        }
+    }
+}
+impl Message<Value> {
+    /// Converts the body into a workload's payload type. Borrows, so the
+    /// original is still around to log if it doesn't fit.
+    pub fn parse_body<P: DeserializeOwned>(&self) -> Result<Message<P>, BodyError> {
+        let payload = P::deserialize(&self.body.payload).map_err(|e| {
+            match self.body.payload.get("type").and_then(Value::as_str) {
+                // Internally tagged enums report an unrecognized tag this way.
+                Some(kind) if e.to_string().starts_with("unknown variant") => {
+                    BodyError::UnknownType(kind.to_string())
+                }
+                _ => BodyError::Malformed(e),
+            }
+        })?;
+        Ok(Message {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: Body {
+                msg_id: self.body.msg_id,
+                in_reply_to: self.body.in_reply_to,
+                payload,
+            },
+        })
+    }
+}
+
+/// A message body: the ids every Maelstrom body may carry, and the
+/// workload-specific rest (including its `type`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Body<P = Payload> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<MsgId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<MsgId>,
+    #[serde(flatten)]
+    pub payload: P,
+}
+impl<P> Body<P> {
+    pub fn new(payload: P) -> Self {
+        Self {
+            msg_id: None,
+            in_reply_to: None,
+            payload,
+        }
+    }
+    pub fn request(msg_id: MsgId, payload: P) -> Self {
+        Self {
+            msg_id: Some(msg_id),
+            ..Self::new(payload)
+        }
+    }
+}
+
+/// Why a message body doesn't fit the payload type it was parsed into.
+#[derive(Debug)]
+pub enum BodyError {
+    UnknownType(String),
+    Malformed(serde_json::Error),
+}
+impl BodyError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            BodyError::UnknownType(_) => ErrorCode::NotSupported,
+            BodyError::Malformed(_) => ErrorCode::MalformedRequest,
+        }
+    }
+}
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyError::UnknownType(kind) => write!(f, "unsupported message type {}", kind),
+            BodyError::Malformed(e) => write!(f, "malformed message body: {}", e),
+        }
+    }
+}
+impl std::error::Error for BodyError {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    Timeout = 0,
+    NodeNotFound = 1,
+    NotSupported = 10,
+    TemporarilyUnavailable = 11,
+    MalformedRequest = 12,
+    Crash = 13,
+    Abort = 14,
+    KeyDoesNotExist = 20,
+    KeyAlreadyExists = 21,
+    PreconditionFailed = 22,
+    TxnConflict = 30,
+}
+impl ErrorCode {
+    pub fn from_code(code: usize) -> Option<Self> {
+        let code = match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            _ => return None,
+        };
+        Some(code)
+    }
+}
+
+/// An error a handler wants reported back to the requester with a specific
+/// Maelstrom error code; any other handler error is reported as a crash.
+#[derive(Debug)]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub text: String,
+}
+impl ErrorReply {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+}
+impl std::fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.text)
+    }
+}
+impl std::error::Error for ErrorReply {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// `payload` as a reply, msg_id 7, to a request from `c1` with msg_id 3.
+    fn reply<P: Serialize>(payload: P) -> Value {
+        let request = Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n1"),
+            body: Body::request(MsgId(3), ()),
+        };
+        serde_json::to_value(request.into_reply(&mut || MsgId(7), payload)).unwrap()
+    }
+
+    /// The reply's body has `fields` on top of its type and ids, and the
+    /// type is the payload's [`Kind`].
+    fn assert_reply<P: Serialize + Kind>(payload: P, kind: &str, fields: Value) {
+        assert_eq!(payload.kind(), kind);
+        let mut body = json!({"type": kind, "msg_id": 7, "in_reply_to": 3});
+        body.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        let expected = json!({"src": "n1", "dest": "c1", "body": body});
+        assert_eq!(reply(payload), expected);
+    }
+
+    #[test]
+    fn json_payloads_take_their_kind_from_the_type_field() {
+        assert_eq!(json!({"type": "echo_ok", "echo": "a"}).kind(), "echo_ok");
+        assert_eq!(json!({"echo": "a"}).kind(), "unknown");
+        assert_eq!(json!({"type": 5}).kind(), "unknown");
+        let a = json!({"type": "made_up"}).kind();
+        assert!(std::ptr::eq(a, json!({"type": "made_up"}).kind()));
+        // Past the cap, new types are lumped together.
+        for i in 0..MAX_KINDS {
+            intern(&format!("filler_{}", i));
+        }
+        assert_eq!(intern("one_too_many"), "other");
+        assert_eq!(intern("made_up"), "made_up");
+    }
+
+    #[test]
+    fn init_ok_has_a_msg_id() {
+        assert_reply(InitPayload::InitOk, "init_ok", json!({}));
+    }
+
+    #[test]
+    fn reply_shapes() {
+        let echo = "hi".to_string();
+        assert_reply(Payload::EchoOk { echo }, "echo_ok", json!({"echo": "hi"}));
+        let id = "n1-1".to_string();
+        assert_reply(
+            Payload::GenerateOk { id },
+            "generate_ok",
+            json!({"id": "n1-1"}),
+        );
+        let read = Payload::ReadOk {
+            messages: Some(vec![1.into(), 2.into()]),
+            value: None,
+            chunk: None,
+            total_chunks: None,
+        };
+        assert_reply(read, "read_ok", json!({"messages": [1, 2]}));
+        let read = Payload::ReadOk {
+            messages: None,
+            value: Some(5.into()),
+            chunk: None,
+            total_chunks: None,
+        };
+        assert_reply(read, "read_ok", json!({"value": 5}));
+        assert_reply(Payload::WriteOk, "write_ok", json!({}));
+        assert_reply(Payload::CasOk, "cas_ok", json!({}));
+        let error = Payload::Error {
+            code: ErrorCode::KeyDoesNotExist as usize,
+            text: "no key 1".into(),
+        };
+        assert_reply(error, "error", json!({"code": 20, "text": "no key 1"}));
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn broadcast_reply_shapes() {
+        assert_reply(Payload::TopologyOk, "topology_ok", json!({}));
+        assert_reply(Payload::BroadcastOk, "broadcast_ok", json!({}));
+        let read = Payload::ReadOk {
+            messages: Some(vec![1.into(), 2.into()]),
+            value: None,
+            chunk: Some(0),
+            total_chunks: Some(2),
+        };
+        let fields = json!({"messages": [1, 2], "chunk": 0, "total_chunks": 2});
+        assert_reply(read, "read_ok", fields);
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn counter_reply_shapes() {
+        assert_reply(Payload::AddOk, "add_ok", json!({}));
+        assert_reply(Payload::RemoveOk, "remove_ok", json!({}));
+    }
+
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn kafka_reply_shapes() {
+        assert_reply(
+            Payload::SendOk { offset: 4 },
+            "send_ok",
+            json!({"offset": 4}),
+        );
+        let msgs = HashMap::from([("k".to_string(), vec![[4, 10], [5, 11]])]);
+        let fields = json!({"msgs": {"k": [[4, 10], [5, 11]]}});
+        assert_reply(Payload::PollOk { msgs }, "poll_ok", fields);
+        assert_reply(Payload::CommitOffsetsOk, "commit_offsets_ok", json!({}));
+        let offsets = HashMap::from([("k".to_string(), 5)]);
+        let fields = json!({"offsets": {"k": 5}});
+        assert_reply(
+            Payload::ListCommittedOffsetsOk { offsets },
+            "list_committed_offsets_ok",
+            fields,
+        );
+    }
+
+    #[cfg(feature = "txn")]
+    #[test]
+    fn txn_triples_round_trip() {
+        let wire = json!({"type": "txn", "txn": [["r", 1, null], ["w", 1, 5], ["w", 2, [1, "a"]]]});
+        let Payload::Txn { txn } = serde_json::from_value(wire.clone()).unwrap() else {
+            panic!("not a txn");
+        };
+        assert_eq!(
+            txn,
+            vec![
+                ("r".to_string(), 1, None),
+                ("w".to_string(), 1, Some(5.into())),
+                ("w".to_string(), 2, Some(json!([1, "a"]))),
+            ]
+        );
+        assert_eq!(serde_json::to_value(Payload::Txn { txn }).unwrap(), wire);
+
+        let txn = vec![("r".to_string(), 1, Some(5.into()))];
+        assert_reply(
+            Payload::TxnOk { txn },
+            "txn_ok",
+            json!({"txn": [["r", 1, 5]]}),
+        );
+    }
+
+    #[cfg(feature = "txn")]
+    #[test]
+    fn malformed_triples_are_rejected() {
+        for txn in [
+            json!([["r", 1]]),
+            json!([["r", "k", null]]),
+            json!([[1, 1, null]]),
+        ] {
+            let body = json!({"type": "txn", "txn": txn});
+            let message = json!({"src": "c1", "dest": "n1", "body": body});
+            let message: Message<Value> = serde_json::from_value(message).unwrap();
+            let error = message.parse_body::<Payload>().unwrap_err();
+            assert_eq!(error.code(), ErrorCode::MalformedRequest, "{}", txn);
+        }
+    }
+}
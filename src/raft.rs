@@ -0,0 +1,406 @@
+//! Enough of Raft to serve `lin-kv` from the nodes themselves: leader
+//! election by vote, log replication with the prev-entry check, and commit
+//! once a majority holds an entry from the current term. Client reads,
+//! writes and cases all go through the log; followers relay them to the
+//! leader. No snapshots or membership changes, and the log lives in memory.
+
+use crate::kv::{self, KvStore};
+use crate::log;
+use crate::node::{Node, election_timeout};
+use crate::protocol::{Body, ErrorCode, ErrorReply, MsgId, NodeId, Payload};
+use crate::runtime::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Most entries sent in one `append_entries`.
+const MAX_BATCH: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogEntry {
+    pub term: usize,
+    /// A client's read, write or cas.
+    pub op: Payload,
+    /// Who asked, answered once the entry is applied, and only by `leader`,
+    /// the node that appended it.
+    pub client: Option<(NodeId, MsgId)>,
+    pub leader: NodeId,
+}
+
+#[derive(Debug)]
+pub enum Role {
+    Follower,
+    Candidate {
+        votes: BTreeSet<NodeId>,
+    },
+    Leader {
+        next_index: HashMap<NodeId, usize>,
+        match_index: HashMap<NodeId, usize>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Raft {
+    pub term: usize,
+    pub voted_for: Option<NodeId>,
+    /// Entry `i`, counting from 1, is `log[i - 1]`.
+    pub log: Vec<LogEntry>,
+    pub commit_index: usize,
+    pub last_applied: usize,
+    pub role: Role,
+    pub leader: Option<NodeId>,
+    pub last_heard: Instant,
+    pub timeout: Duration,
+    pub store: KvStore,
+}
+impl Raft {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            last_heard: now,
+            timeout: election_timeout(),
+            store: KvStore::new(),
+        }
+    }
+    fn last_index(&self) -> usize {
+        self.log.len()
+    }
+    fn term_at(&self, index: usize) -> usize {
+        match index {
+            0 => 0,
+            _ => self.log.get(index - 1).map_or(0, |entry| entry.term),
+        }
+    }
+    fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+    /// Follows any newer term it hears of, as a follower with no vote cast.
+    fn observe_term(&mut self, term: usize) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = Role::Follower;
+        }
+    }
+    fn reset_timer(&mut self, now: Instant) {
+        self.last_heard = now;
+        self.timeout = election_timeout();
+    }
+}
+
+impl Node {
+    fn majority(&self) -> usize {
+        self.node_ids.len() / 2 + 1
+    }
+    fn peers(&self) -> Vec<NodeId> {
+        self.node_ids
+            .iter()
+            .filter(|n| **n != self.id)
+            .cloned()
+            .collect()
+    }
+
+    /// Appends a client's request to the log if this node leads, relays it
+    /// to the leader if there is one, and refuses it otherwise.
+    pub fn raft_submit(
+        &mut self,
+        src: &NodeId,
+        msg_id: Option<MsgId>,
+        op: Payload,
+    ) -> anyhow::Result<()> {
+        if self.raft.is_leader() {
+            self.raft.log.push(LogEntry {
+                term: self.raft.term,
+                op,
+                client: msg_id.map(|msg_id| (src.clone(), msg_id)),
+                leader: self.id.clone(),
+            });
+            self.advance_commit()?;
+            return self.replicate_log();
+        }
+        let Some(leader) = self.raft.leader.clone() else {
+            return Err(ErrorReply::new(ErrorCode::TemporarilyUnavailable, "no leader").into());
+        };
+        self.relay(src, msg_id, &leader, op)
+    }
+
+    /// Handles Raft's own messages between nodes.
+    pub fn handle_raft(
+        &mut self,
+        ctx: &mut Context,
+        src: &NodeId,
+        payload: Payload,
+    ) -> anyhow::Result<()> {
+        let now = ctx.now();
+        match payload {
+            Payload::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                let raft = &mut self.raft;
+                raft.observe_term(term);
+                let up_to_date = (last_log_term, last_log_index)
+                    >= (raft.term_at(raft.last_index()), raft.last_index());
+                let granted = term == raft.term
+                    && up_to_date
+                    && raft.voted_for.as_ref().is_none_or(|v| *v == candidate);
+                if granted {
+                    raft.voted_for = Some(candidate);
+                    raft.reset_timer(now);
+                }
+                ctx.reply(Payload::RequestVoteOk {
+                    term: raft.term,
+                    granted,
+                })?;
+            }
+            Payload::RequestVoteOk { term, granted } => {
+                self.raft.observe_term(term);
+                let majority = self.majority();
+                let won = match &mut self.raft.role {
+                    Role::Candidate { votes } if granted && term == self.raft.term => {
+                        votes.insert(src.clone());
+                        votes.len() >= majority
+                    }
+                    _ => false,
+                };
+                if won {
+                    self.become_leader()?;
+                }
+            }
+            Payload::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                let raft = &mut self.raft;
+                raft.observe_term(term);
+                if term < raft.term {
+                    return ctx.reply(Payload::AppendEntriesOk {
+                        term: raft.term,
+                        success: false,
+                        last_index: raft.last_index(),
+                    });
+                }
+                raft.role = Role::Follower;
+                raft.leader = Some(leader);
+                raft.reset_timer(now);
+                if prev_log_index > raft.last_index()
+                    || raft.term_at(prev_log_index) != prev_log_term
+                {
+                    // Hints where to back up to, so a far-behind follower
+                    // doesn't cost one round trip per entry.
+                    let last_index = raft.last_index().min(prev_log_index.saturating_sub(1));
+                    return ctx.reply(Payload::AppendEntriesOk {
+                        term: raft.term,
+                        success: false,
+                        last_index,
+                    });
+                }
+                let matched = prev_log_index + entries.len();
+                for (index, entry) in (prev_log_index + 1..).zip(entries) {
+                    if index <= raft.last_index() && raft.term_at(index) != entry.term {
+                        raft.log.truncate(index - 1);
+                    }
+                    if index > raft.last_index() {
+                        raft.log.push(entry);
+                    }
+                }
+                // A stale or reordered append may match less than is
+                // already committed; commit never moves back.
+                raft.commit_index = raft.commit_index.max(leader_commit.min(matched));
+                ctx.reply(Payload::AppendEntriesOk {
+                    term: self.raft.term,
+                    success: true,
+                    last_index: matched,
+                })?;
+                self.apply_committed()?;
+            }
+            Payload::AppendEntriesOk {
+                term,
+                success,
+                last_index,
+            } => {
+                self.raft.observe_term(term);
+                if term != self.raft.term {
+                    return Ok(());
+                }
+                let Role::Leader {
+                    next_index,
+                    match_index,
+                } = &mut self.raft.role
+                else {
+                    return Ok(());
+                };
+                if success {
+                    let matched = match_index.entry(src.clone()).or_default();
+                    *matched = (*matched).max(last_index);
+                    next_index.insert(src.clone(), *matched + 1);
+                    self.advance_commit()?;
+                } else {
+                    let next = next_index.entry(src.clone()).or_insert(1);
+                    *next = (*next - 1).min(last_index + 1).max(1);
+                    self.send_append(src)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> anyhow::Result<()> {
+        log!(Info, "raft_leader", term = self.raft.term);
+        let next = self.raft.last_index() + 1;
+        let peers = self.peers();
+        self.raft.role = Role::Leader {
+            next_index: peers.iter().map(|n| (n.clone(), next)).collect(),
+            match_index: peers.iter().map(|n| (n.clone(), 0)).collect(),
+        };
+        self.raft.leader = Some(self.id.clone());
+        self.replicate_log()
+    }
+
+    fn start_election(&mut self, now: Instant) -> anyhow::Result<()> {
+        let raft = &mut self.raft;
+        raft.term += 1;
+        raft.voted_for = Some(self.id.clone());
+        raft.leader = None;
+        raft.role = Role::Candidate {
+            votes: BTreeSet::from([self.id.clone()]),
+        };
+        raft.reset_timer(now);
+        log!(Info, "raft_election", term = raft.term);
+        let body = Payload::RequestVote {
+            term: raft.term,
+            candidate: self.id.clone(),
+            last_log_index: raft.last_index(),
+            last_log_term: raft.term_at(raft.last_index()),
+        };
+        if self.majority() == 1 {
+            return self.become_leader();
+        }
+        for n in self.peers() {
+            let msg_id = self.next_msg_id();
+            self.output
+                .send(&self.id, &n, Body::request(msg_id, body.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// Sends every follower the entries it's missing, or an empty heartbeat.
+    fn replicate_log(&mut self) -> anyhow::Result<()> {
+        for n in self.peers() {
+            self.send_append(&n)?;
+        }
+        Ok(())
+    }
+
+    fn send_append(&mut self, peer: &NodeId) -> anyhow::Result<()> {
+        let Role::Leader { next_index, .. } = &self.raft.role else {
+            return Ok(());
+        };
+        let next = next_index.get(peer).copied().unwrap_or(1).max(1);
+        let prev_log_index = next - 1;
+        let end = self.raft.last_index().min(prev_log_index + MAX_BATCH);
+        let body = Payload::AppendEntries {
+            term: self.raft.term,
+            leader: self.id.clone(),
+            prev_log_index,
+            prev_log_term: self.raft.term_at(prev_log_index),
+            entries: self.raft.log[prev_log_index.min(end)..end].to_vec(),
+            leader_commit: self.raft.commit_index,
+        };
+        let msg_id = self.next_msg_id();
+        self.output
+            .send(&self.id, peer, Body::request(msg_id, body))
+    }
+
+    /// Commits the newest entry of this term a majority has, and with it
+    /// everything before.
+    fn advance_commit(&mut self) -> anyhow::Result<()> {
+        let Role::Leader { match_index, .. } = &self.raft.role else {
+            return Ok(());
+        };
+        let majority = self.majority();
+        let committed = (self.raft.commit_index + 1..=self.raft.last_index())
+            .rev()
+            .find(|&index| {
+                self.raft.term_at(index) == self.raft.term
+                    && 1 + match_index.values().filter(|&&m| m >= index).count() >= majority
+            });
+        if let Some(index) = committed {
+            self.raft.commit_index = index;
+            self.apply_committed()?;
+        }
+        Ok(())
+    }
+
+    /// Applies committed entries in order, answering the clients of those
+    /// this node appended.
+    fn apply_committed(&mut self) -> anyhow::Result<()> {
+        while self.raft.last_applied < self.raft.commit_index {
+            self.raft.last_applied += 1;
+            let entry = &self.raft.log[self.raft.last_applied - 1];
+            // Every replica applies the same entries in the same order, so
+            // their dedup tables agree on which ones are repeats.
+            let cached = entry
+                .client
+                .as_ref()
+                .and_then(|(client, msg_id)| self.dedup.get(client, *msg_id))
+                .cloned();
+            let reply = match cached {
+                Some(reply) => reply,
+                None => {
+                    let reply = kv::apply(&mut self.raft.store, &entry.op);
+                    match &entry.client {
+                        Some(_) if matches!(entry.op, Payload::Read { .. }) => (),
+                        Some((client, msg_id)) => self.dedup.insert(client, *msg_id, reply.clone()),
+                        None => (),
+                    }
+                    reply
+                }
+            };
+            let Some((client, msg_id)) = entry.client.clone() else {
+                continue;
+            };
+            if entry.leader != self.id {
+                continue;
+            }
+            let body = Body {
+                msg_id: Some(self.next_msg_id()),
+                in_reply_to: Some(msg_id),
+                payload: reply,
+            };
+            self.output.send(&self.id, &client, body)?;
+        }
+        Ok(())
+    }
+}
+
+/// Heartbeats from the leader, elections from everyone else once the
+/// leader has been quiet too long.
+pub fn raft_tick(node: &mut Node, ctx: &mut Context) -> anyhow::Result<()> {
+    if node.workload != crate::node::Workload::LinKv {
+        return Ok(());
+    }
+    let now = ctx.now();
+    if node.raft.is_leader() {
+        return node.replicate_log();
+    }
+    if now.saturating_duration_since(node.raft.last_heard) >= node.raft.timeout {
+        return node.start_election(now);
+    }
+    Ok(())
+}
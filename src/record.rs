@@ -0,0 +1,295 @@
+//! Session recording: every message a node receives or sends, appended to a
+//! JSONL file for post-mortem analysis. Each line is
+//! `{"at_us":..,"dir":"in"|"out","msg":{..}}`, with `msg` the message exactly
+//! as it crossed the wire and `at_us` microseconds since recording started,
+//! plus `"trace":..` when the message belongs to a traced request.
+//!
+//! [`replay`] drives a node from a recording's inbound messages and reports
+//! how its output differs from what was recorded. It seeds the node's RNG
+//! and, with `MAELLE_REPLAY_REALTIME`, keeps the original timing, but timers
+//! still fire on the wall clock: if they interleave with messages
+//! differently than they did, the node's own msg_ids shift, and recorded
+//! replies to its rpcs may no longer line up with what it's waiting for.
+
+use crate::log;
+use crate::node::seed_random;
+use crate::protocol::{Message, MsgId, NodeId};
+use crate::runtime::{Context, Handler, Output, env_or, lock, run_with};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex, mpsc},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// One recorded line, as read back by a replay.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub at_us: u64,
+    pub dir: Direction,
+    pub msg: Message<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<String>,
+}
+
+enum Record {
+    Line(String),
+    /// Acknowledged once every line queued before it is on disk.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Appends to the recording from a buffered writer on its own thread, so
+/// recording never waits on the disk. Cheap to clone.
+#[derive(Clone)]
+pub struct Recorder {
+    tx: mpsc::Sender<Record>,
+    started: Instant,
+}
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (tx, rx) = mpsc::channel();
+        let path = path.display().to_string();
+        std::thread::spawn(move || {
+            let mut file = BufWriter::new(file);
+            for record in rx {
+                let written = match record {
+                    Record::Line(line) => file.write_all(line.as_bytes()),
+                    Record::Flush(done) => {
+                        let flushed = file.flush();
+                        let _ = done.send(());
+                        flushed
+                    }
+                };
+                if let Err(e) = written {
+                    log!(Error, "record_failed", path = path, error = e);
+                    return;
+                }
+            }
+            let _ = file.flush();
+        });
+        Ok(Self {
+            tx,
+            started: Instant::now(),
+        })
+    }
+
+    /// Records one wire line, which must be a complete JSON message.
+    pub fn record(&self, dir: Direction, line: &str, trace: Option<&str>) {
+        let dir = match dir {
+            Direction::In => "in",
+            Direction::Out => "out",
+        };
+        let trace = trace
+            .map(|trace| format!(",\"trace\":{}", Value::from(trace)))
+            .unwrap_or_default();
+        let entry = format!(
+            "{{\"at_us\":{},\"dir\":\"{}\",\"msg\":{}{}}}\n",
+            self.started.elapsed().as_micros(),
+            dir,
+            line.trim_end(),
+            trace
+        );
+        let _ = self.tx.send(Record::Line(entry));
+    }
+
+    /// Waits until everything recorded so far is on disk.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.tx.send(Record::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+/// Reads every entry of the recording at `path`.
+pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Vec<Entry>> {
+    let file = std::fs::File::open(path)?;
+    let mut entries = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("bad recording line {}: {}", n + 1, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Feeds the inbound messages recorded at `path` to the node `make` builds,
+/// echoing its output to `out`, then logs every difference from the
+/// recorded output. Set `MAELLE_REPLAY_REALTIME=true` to deliver messages
+/// at their recorded offsets instead of all at once.
+pub fn replay<H: Handler>(
+    path: impl AsRef<Path>,
+    mut out: impl Write + Send + 'static,
+    make: impl FnOnce(&Context) -> H,
+) -> anyhow::Result<()> {
+    let entries = load(path)?;
+    let realtime = env_or("MAELLE_REPLAY_REALTIME", false);
+    let (inbound, recorded): (Vec<Entry>, Vec<Entry>) = entries
+        .into_iter()
+        .partition(|entry| entry.dir == Direction::In);
+    let started = Instant::now();
+    let input = inbound.into_iter().map(move |entry| {
+        if realtime {
+            let at = Duration::from_micros(entry.at_us);
+            std::thread::sleep(at.saturating_sub(started.elapsed()));
+        }
+        serde_json::to_string(&entry.msg).map_err(std::io::Error::from)
+    });
+
+    let produced = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&produced);
+    let output = Output::spawn(move |line| {
+        if let Ok(m) = serde_json::from_str::<Message<Value>>(&line) {
+            if let Ok(mut produced) = sink.lock() {
+                produced.push(m);
+            }
+        }
+        out.write_all(line.as_bytes())?;
+        out.flush()
+    });
+    seed_random(0);
+    run_with(input, output, make)?;
+
+    let recorded: Vec<Message<Value>> = recorded.into_iter().map(|entry| entry.msg).collect();
+    let produced = std::mem::take(&mut *lock(&produced));
+    report(&diff(&recorded, &produced));
+    Ok(())
+}
+
+/// How a replay's output differs from the recording.
+#[derive(Debug, Default)]
+pub struct Diff {
+    /// Recorded replies the replay never sent.
+    pub missing_replies: Vec<Message<Value>>,
+    /// Replies the replay sent to requests that weren't answered before.
+    pub extra_replies: Vec<Message<Value>>,
+    /// Replies with a different payload: (recorded, replayed).
+    pub changed_replies: Vec<(Message<Value>, Message<Value>)>,
+    /// Replies identical but for their own msg_id.
+    pub renumbered: usize,
+    /// Other recorded messages the replay didn't send, and vice versa.
+    pub missing: Vec<Message<Value>>,
+    pub extra: Vec<Message<Value>>,
+}
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.missing_replies.is_empty()
+            && self.extra_replies.is_empty()
+            && self.changed_replies.is_empty()
+            && self.missing.is_empty()
+            && self.extra.is_empty()
+    }
+}
+
+/// Matches replies by who they answer, and everything else by destination
+/// and payload regardless of order or msg_id.
+pub fn diff(recorded: &[Message<Value>], produced: &[Message<Value>]) -> Diff {
+    type Key = (NodeId, MsgId);
+    fn split(messages: &[Message<Value>]) -> (HashMap<Key, &Message<Value>>, Vec<&Message<Value>>) {
+        let mut replies = HashMap::new();
+        let mut others = Vec::new();
+        for m in messages {
+            match m.body.in_reply_to {
+                Some(id) => {
+                    replies.insert((m.dest.clone(), id), m);
+                }
+                None => others.push(m),
+            }
+        }
+        (replies, others)
+    }
+    fn unsolicited(m: &Message<Value>) -> String {
+        format!("{} {}", m.dest, m.body.payload)
+    }
+
+    let (recorded_replies, recorded_others) = split(recorded);
+    let (mut produced_replies, produced_others) = split(produced);
+    let mut diff = Diff::default();
+    let mut keys: Vec<&Key> = recorded_replies.keys().collect();
+    keys.sort();
+    for key in keys {
+        let was = recorded_replies[key];
+        match produced_replies.remove(key) {
+            None => diff.missing_replies.push(was.clone()),
+            Some(now) if now.body.payload != was.body.payload => {
+                diff.changed_replies.push((was.clone(), now.clone()))
+            }
+            Some(now) if now.body.msg_id != was.body.msg_id => diff.renumbered += 1,
+            Some(_) => {}
+        }
+    }
+    let mut extra: Vec<Message<Value>> = produced_replies.into_values().cloned().collect();
+    extra.sort_by_key(|m| (m.dest.clone(), m.body.in_reply_to));
+    diff.extra_replies = extra;
+
+    let mut unmatched: HashMap<String, Vec<&Message<Value>>> = HashMap::new();
+    for m in produced_others {
+        unmatched.entry(unsolicited(m)).or_default().push(m);
+    }
+    for m in recorded_others {
+        let matched = unmatched
+            .get_mut(&unsolicited(m))
+            .and_then(|same| same.pop());
+        if matched.is_none() {
+            diff.missing.push(m.clone());
+        }
+    }
+    let mut extra: Vec<Message<Value>> = unmatched.into_values().flatten().cloned().collect();
+    extra.sort_by_key(unsolicited);
+    diff.extra = extra;
+    diff
+}
+
+fn report(diff: &Diff) {
+    let show = |m: &Message<Value>| serde_json::to_string(m).unwrap_or_default();
+    for m in &diff.missing_replies {
+        log!(Warn, "replay_missing_reply", recorded = show(m));
+    }
+    for m in &diff.extra_replies {
+        log!(Warn, "replay_extra_reply", replayed = show(m));
+    }
+    for (was, now) in &diff.changed_replies {
+        log!(
+            Warn,
+            "replay_changed_reply",
+            recorded = show(was),
+            replayed = show(now)
+        );
+    }
+    for m in &diff.missing {
+        log!(Warn, "replay_missing", recorded = show(m));
+    }
+    for m in &diff.extra {
+        log!(Warn, "replay_extra", replayed = show(m));
+    }
+    log!(
+        Info,
+        "replay_done",
+        same = diff.is_empty(),
+        missing_replies = diff.missing_replies.len(),
+        extra_replies = diff.extra_replies.len(),
+        changed_replies = diff.changed_replies.len(),
+        renumbered = diff.renumbered,
+        missing = diff.missing.len(),
+        extra = diff.extra.len(),
+    );
+}
@@ -0,0 +1,46 @@
+//! Consistent hashing of keys onto nodes. Every node builds the same ring
+//! from `node_ids` alone, so they all agree on who owns a key without
+//! talking about it. Each node sits on the ring at several points (virtual
+//! nodes) to even out how many keys land on each.
+
+use crate::protocol::NodeId;
+use std::collections::BTreeMap;
+
+pub struct Ring {
+    points: BTreeMap<u64, NodeId>,
+}
+impl Ring {
+    pub fn new(node_ids: &[NodeId], vnodes: usize) -> Self {
+        let mut points = BTreeMap::new();
+        for node in node_ids {
+            for i in 0..vnodes.max(1) {
+                points.insert(hash(&format!("{}#{}", node, i)), node.clone());
+            }
+        }
+        Self { points }
+    }
+
+    /// The first node at or after `key`'s point, wrapping around; `None`
+    /// only for a ring with no nodes.
+    pub fn owner(&self, key: &str) -> Option<&NodeId> {
+        self.points
+            .range(hash(key)..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node)
+    }
+}
+
+/// FNV-1a with a splitmix64 finish, so nearby strings like `n1#0` and
+/// `n1#1` still spread over the ring. Stable across builds, unlike std's
+/// hashers.
+fn hash(s: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in s.bytes() {
+        h ^= byte as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
@@ -0,0 +1,2012 @@
+//! The process around a node: init handshake, read loop, message output,
+//! and the [`Handler`] trait workloads plug into.
+//!
+//! Handlers never do IO themselves: [`Output::send`] only queues a line for
+//! the writer thread, so a slow stdout stalls the node only once the queue
+//! is full.
+//!
+//! A handler sees the runtime only through its [`Context`]: its ids, the
+//! clock, sends, replies, rpcs and [`Context::schedule`]. The same handler
+//! runs unchanged under [`crate::sim`] and [`crate::testnet`], which stand
+//! in for it without any IO.
+
+use crate::kv::KvError;
+use crate::log;
+use crate::middleware::{HandlerResult, Logging, Metrics, Middleware};
+use crate::protocol::{
+    AdminPayload, Body, BodyError, ErrorCode, ErrorReply, Fault, InitPayload, Kind, Message, MsgId,
+    NodeId, Payload, StatsSnapshot,
+};
+use crate::record::{Direction, Recorder};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::{
+    any::Any,
+    cell::Cell,
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{BufRead, BufReader, Read, Write},
+    ops::ControlFlow,
+    sync::{
+        Arc, Mutex, MutexGuard, Once, OnceLock, RwLock,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
+};
+
+/// One node's efficiency counters. Each [`Output`] has its own, shared by
+/// its clones, so any thread sending for the node can record into them and
+/// `stats` can be answered from them.
+pub(crate) struct Stats {
+    pub(crate) client_ops: AtomicU64,
+    pub(crate) messages_sent: AtomicU64,
+    /// Bytes of the messages in `messages_sent`, newlines included.
+    bytes_sent: AtomicU64,
+    pub(crate) retries: AtomicU64,
+    pub(crate) gossip_rounds: AtomicU64,
+    /// Messages read but not yet taken up by the dispatcher.
+    inbox_depth: AtomicUsize,
+    received: ByType,
+    sent: ByType,
+    started: Instant,
+}
+
+/// Room for every payload type plus every [`MAX_KINDS`] type seen on the
+/// wire, with space to spare so probes stay short.
+const BY_TYPE_SLOTS: usize = 1024;
+
+/// Message counts keyed by the `&'static str` a payload names its type
+/// with, in an open-addressed table of atomics: counting takes no lock and
+/// allocates nothing.
+struct ByType {
+    slots: Box<[(OnceLock<&'static str>, AtomicU64)]>,
+}
+impl ByType {
+    fn new() -> Self {
+        Self {
+            slots: (0..BY_TYPE_SLOTS)
+                .map(|_| (OnceLock::new(), AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+    fn count(&self, kind: &'static str) {
+        // FNV-1a: types are short, and this runs for every message.
+        let hash = kind.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x100_0000_01b3)
+        });
+        let len = self.slots.len();
+        for probe in 0..len {
+            let (name, n) = &self.slots[(hash as usize).wrapping_add(probe) % len];
+            if *name.get_or_init(|| kind) == kind {
+                n.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+    fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.slots
+            .iter()
+            .filter_map(|(name, n)| Some((name.get()?.to_string(), n.load(Ordering::Relaxed))))
+            .collect()
+    }
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            client_ops: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            gossip_rounds: AtomicU64::new(0),
+            inbox_depth: AtomicUsize::new(0),
+            received: ByType::new(),
+            sent: ByType::new(),
+            started: Instant::now(),
+        }
+    }
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Counts one message of type `kind` in `by_type`, unless it's admin
+    /// traffic.
+    fn count(by_type: &ByType, kind: &'static str) {
+        if !AdminPayload::TYPES.contains(&kind) {
+            by_type.count(kind);
+        }
+    }
+    /// Counts one message read of type `kind`.
+    pub(crate) fn count_received(&self, kind: &'static str) {
+        Self::count(&self.received, kind);
+    }
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        let client_ops = self.client_ops.load(Ordering::Relaxed);
+        let messages_sent = self.messages_sent.load(Ordering::Relaxed);
+        StatsSnapshot {
+            client_ops,
+            messages_sent,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            gossip_rounds: self.gossip_rounds.load(Ordering::Relaxed),
+            messages_per_op: messages_sent as f64 / client_ops.max(1) as f64,
+            received: self.received.snapshot(),
+            sent: self.sent.snapshot(),
+            pending_rpcs: 0,
+            inbox_depth: self.inbox_depth.load(Ordering::Relaxed),
+            uptime_ms: self.started.elapsed().as_millis() as u64,
+            handler: Value::Null,
+        }
+    }
+    pub(crate) fn report(&self) {
+        let s = self.snapshot();
+        log!(
+            Info,
+            "stats",
+            client_ops = s.client_ops,
+            messages_sent = s.messages_sent,
+            bytes_sent = s.bytes_sent,
+            retries = s.retries,
+            gossip_rounds = s.gossip_rounds,
+            msgs_per_op = format!("{:.2}", s.messages_per_op),
+            inbox_depth = s.inbox_depth,
+        );
+    }
+}
+
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn error_reply(e: &anyhow::Error) -> (ErrorCode, String) {
+    if let Some(reply) = e.downcast_ref::<ErrorReply>() {
+        return (reply.code, reply.text.clone());
+    }
+    if let Some(e) = e.downcast_ref::<BodyError>() {
+        return (e.code(), e.to_string());
+    }
+    match e.downcast_ref::<RpcError>() {
+        Some(RpcError::Timeout) => return (ErrorCode::Timeout, e.to_string()),
+        Some(RpcError::Error { code, text }) => {
+            return (
+                ErrorCode::from_code(*code).unwrap_or(ErrorCode::Crash),
+                text.clone(),
+            );
+        }
+        _ => (),
+    }
+    let code = match e.downcast_ref::<KvError>() {
+        Some(KvError::KeyNotFound(_)) => ErrorCode::KeyDoesNotExist,
+        Some(KvError::CasMismatch(_)) => ErrorCode::PreconditionFailed,
+        Some(KvError::Service { code, .. }) => {
+            ErrorCode::from_code(*code).unwrap_or(ErrorCode::Crash)
+        }
+        Some(KvError::Timeout) => ErrorCode::Timeout,
+        _ => ErrorCode::Crash,
+    };
+    (code, format!("{:#}", e))
+}
+
+/// A workload. The runtime hands it every incoming message except replies
+/// to its own [`Context::rpc`] and [`Context::rpc_then`] calls, one message
+/// at a time.
+pub trait Handler: Send + 'static {
+    type Payload: Serialize + DeserializeOwned + Kind + Send + 'static;
+
+    fn handle(&mut self, ctx: &mut Context, msg: Message<Self::Payload>) -> anyhow::Result<()>;
+
+    /// Background tasks and how often to run each; a zero period leaves
+    /// the task idle until it's set to something else.
+    fn periodic(&self) -> Vec<(Period, Task<Self>)> {
+        Vec::new()
+    }
+
+    /// Applies a `configure` request's settings, returning the values taken
+    /// up. Unknown settings are a [`ErrorCode::NotSupported`] error, with
+    /// nothing applied.
+    fn configure(
+        &mut self,
+        settings: &HashMap<String, Value>,
+    ) -> anyhow::Result<BTreeMap<String, Value>> {
+        match settings.keys().min() {
+            Some(key) => Err(ErrorReply::new(
+                ErrorCode::NotSupported,
+                format!("unknown setting {:?}", key),
+            )
+            .into()),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Workload-specific gauges included in `stats` replies.
+    fn stats(&self) -> Value {
+        Value::Null
+    }
+
+    /// A JSON snapshot of the handler's state for `dump_state` replies.
+    fn dump_state(&self) -> Value {
+        Value::Null
+    }
+
+    /// The node this one currently considers leader, for `leader` replies.
+    fn leader(&self) -> Option<NodeId> {
+        None
+    }
+
+    /// The topology as this node sees it, in Graphviz DOT, for
+    /// `dump_topology` replies; `None` if it has none.
+    fn topology_dot(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether there's nothing left to send, so the runtime can exit as soon
+    /// as the input ends rather than waiting out the grace period.
+    fn quiescent(&self) -> bool {
+        true
+    }
+}
+
+pub type Task<H> = fn(&mut H, &mut Context) -> anyhow::Result<()>;
+
+/// How often a periodic task runs, shared between whoever tunes it and the
+/// timer loop, which reads it afresh each time it schedules the task.
+#[derive(Clone, Debug)]
+pub struct Period(Arc<RwLock<Duration>>);
+impl Period {
+    pub fn new(interval: Duration) -> Self {
+        Self(Arc::new(RwLock::new(interval)))
+    }
+    pub fn get(&self) -> Duration {
+        *self
+            .0
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    pub fn set(&self, interval: Duration) {
+        *self
+            .0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = interval;
+    }
+    pub fn is_zero(&self) -> bool {
+        self.get().is_zero()
+    }
+}
+impl From<Duration> for Period {
+    fn from(interval: Duration) -> Self {
+        Self::new(interval)
+    }
+}
+
+/// The longest a timer loop goes without rereading its periods, so one
+/// that's changed, or woken from zero, takes effect about this soon.
+pub(crate) const PERIOD_RECHECK: Duration = Duration::from_millis(100);
+
+/// The time source for timeouts, retries, timers and timestamped ids, so a
+/// simulation or test can run them on virtual time. Time-dependent code
+/// reads the clock it was given, never the system's.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    /// Milliseconds since the Unix epoch, for ids that embed a timestamp.
+    fn unix_ms(&self) -> u64;
+    /// Blocks until `at`; a virtual clock just moves there.
+    fn sleep_until(&self, at: Instant);
+}
+
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn unix_ms(&self) -> u64 {
+        crate::node::now_ms()
+    }
+    fn sleep_until(&self, at: Instant) {
+        std::thread::sleep(at.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// An rpc waiting on its reply, which is given up on at `expires`.
+struct PendingRpc {
+    dest: NodeId,
+    expires: Instant,
+    tx: mpsc::Sender<Message<Value>>,
+}
+type PendingRpcs = Arc<Mutex<HashMap<MsgId, PendingRpc>>>;
+
+/// What a handler does with the reply to a [`Context::rpc_then`], or with
+/// its timing out, once the dispatcher gets to it.
+pub type Then<H> = Box<
+    dyn FnOnce(&mut H, &mut Context, Result<Message<Value>, RpcError>) -> anyhow::Result<()> + Send,
+>;
+
+/// A [`Context::rpc_then`] waiting on its reply: the [`Then`] of whichever
+/// handler made it, and the request it was handling, which the continuation
+/// answers as if it still were.
+struct PendingThen {
+    dest: NodeId,
+    expires: Instant,
+    incoming: Option<Message<()>>,
+    trace: Option<String>,
+    then: Box<dyn Any + Send>,
+}
+type PendingThens = Arc<Mutex<HashMap<MsgId, PendingThen>>>;
+/// Messages a handler has [scheduled](Context::schedule) for itself, with
+/// when each is due.
+type Delayed = Arc<Mutex<Vec<(Instant, Message<Value>)>>>;
+type Chain = Arc<Mutex<Vec<Box<dyn Middleware>>>>;
+
+/// The runtime as seen from a handler: who this node is, and how to talk
+/// to the rest of the cluster. Cheap to clone.
+#[derive(Clone)]
+pub struct Context {
+    pub node_id: NodeId,
+    pub node_ids: Vec<NodeId>,
+    msg_ids: Arc<AtomicU64>,
+    rpcs: PendingRpcs,
+    thens: PendingThens,
+    output: Output,
+    clock: Arc<dyn Clock>,
+    delayed: Delayed,
+    middleware: Chain,
+    incoming: Option<Message<()>>,
+}
+impl Context {
+    pub fn new(
+        node_id: NodeId,
+        node_ids: Vec<NodeId>,
+        output: Output,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            node_id,
+            node_ids,
+            msg_ids: Arc::new(AtomicU64::new(0)),
+            rpcs: Arc::new(Mutex::new(HashMap::new())),
+            thens: Arc::new(Mutex::new(HashMap::new())),
+            output,
+            clock,
+            delayed: Arc::new(Mutex::new(Vec::new())),
+            middleware: Arc::new(Mutex::new(vec![
+                Box::new(Logging::default()),
+                Box::new(Metrics),
+            ])),
+            incoming: None,
+        }
+    }
+    pub fn self_id(&self) -> &NodeId {
+        &self.node_id
+    }
+    /// Runs `middleware` around every message handled from now on, after
+    /// the ones already added; see [`crate::middleware`].
+    pub fn add_middleware(&self, middleware: impl Middleware) {
+        if let Ok(mut chain) = self.middleware.lock() {
+            chain.push(Box::new(middleware));
+        }
+    }
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+    /// Where this node's messages go, for handlers that send outside of a
+    /// `Context` call.
+    pub fn output(&self) -> Output {
+        self.output.clone()
+    }
+    /// This node's efficiency counters.
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.output.stats
+    }
+    /// The node-wide msg_id counter, for handlers that allocate ids outside
+    /// of a `Context` call.
+    pub fn msg_ids(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.msg_ids)
+    }
+    pub fn next_msg_id(&self) -> MsgId {
+        MsgId(self.msg_ids.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+    /// Sends `payload` under a fresh msg_id, which is returned.
+    pub fn send<P: Serialize + Kind>(&self, dest: &NodeId, payload: P) -> anyhow::Result<MsgId> {
+        let msg_id = self.next_msg_id();
+        self.output
+            .send(&self.node_id, dest, Body::request(msg_id, payload))?;
+        Ok(msg_id)
+    }
+    /// Hands `payload` back to the handler once `delay` has passed on the
+    /// node's clock, as a message from this node to itself.
+    pub fn schedule<P: Serialize + Kind>(&self, delay: Duration, payload: P) -> anyhow::Result<()> {
+        let m = Message {
+            src: self.node_id.clone(),
+            dest: self.node_id.clone(),
+            body: Body::new(serde_json::to_value(payload)?),
+        };
+        lock(&self.delayed).push((self.now() + delay, m));
+        Ok(())
+    }
+    /// When the next scheduled message is due, if any is.
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        let delayed = self.delayed.lock().ok()?;
+        delayed.iter().map(|(at, _)| *at).min()
+    }
+    /// Takes the scheduled messages due by `now`, or all of them, earliest
+    /// first.
+    pub(crate) fn take_scheduled(&self, now: Option<Instant>) -> Vec<(Instant, Message<Value>)> {
+        let Ok(mut delayed) = self.delayed.lock() else {
+            return Vec::new();
+        };
+        let (mut due, later) = std::mem::take(&mut *delayed)
+            .into_iter()
+            .partition(|(at, _)| now.is_none_or(|now| *at <= now));
+        *delayed = later;
+        due.sort_by_key(|(at, _)| *at);
+        due
+    }
+    /// The trace id of the client request being handled, or of the one
+    /// that caused the node message being handled. Everything sent while
+    /// handling it, other than to clients, carries it along.
+    pub fn trace(&self) -> Option<String> {
+        log::trace()
+    }
+    /// Runs `f` under `trace`, e.g. to resend a message from a timer as
+    /// part of the request it was first sent for.
+    pub fn in_trace<T>(&self, trace: Option<String>, f: impl FnOnce() -> T) -> T {
+        let outer = log::trace();
+        log::set_trace(trace);
+        let result = f();
+        log::set_trace(outer);
+        result
+    }
+    /// Answers the message being handled, see [`Message::into_reply`].
+    pub fn reply<P: Serialize + Kind>(&self, payload: P) -> anyhow::Result<()> {
+        let Some(incoming) = self.incoming.clone() else {
+            anyhow::bail!("no message to reply to");
+        };
+        let reply = incoming.into_reply(&mut || self.next_msg_id(), payload);
+        self.output.send(&reply.src, &reply.dest, reply.body)
+    }
+    /// Sends `payload` as a request; its reply is delivered on the returned
+    /// channel instead of to the handler.
+    pub fn rpc<P: Serialize + Kind>(
+        &self,
+        dest: &NodeId,
+        payload: P,
+    ) -> anyhow::Result<mpsc::Receiver<Message<Value>>> {
+        self.start_rpc(dest, payload).map(|(_, rx)| rx)
+    }
+    fn start_rpc<P: Serialize + Kind>(
+        &self,
+        dest: &NodeId,
+        payload: P,
+    ) -> anyhow::Result<(MsgId, mpsc::Receiver<Message<Value>>)> {
+        let msg_id = self.next_msg_id();
+        let (tx, rx) = mpsc::channel();
+        let pending = PendingRpc {
+            dest: dest.clone(),
+            expires: self.now() + pending_ttl(),
+            tx,
+        };
+        lock(&self.rpcs).insert(msg_id, pending);
+        if let Err(e) = self
+            .output
+            .send(&self.node_id, dest, Body::request(msg_id, payload))
+        {
+            lock(&self.rpcs).remove(&msg_id);
+            return Err(e);
+        }
+        // The caller is about to wait on the reply.
+        self.output.write_now();
+        Ok((msg_id, rx))
+    }
+    /// Sends `payload` as a request and blocks until its reply arrives. If
+    /// none does within `timeout`, the pending entry is dropped so a late
+    /// reply goes to the handler instead.
+    pub fn rpc_with_timeout<P: Serialize + Kind>(
+        &self,
+        dest: &NodeId,
+        payload: P,
+        timeout: Duration,
+    ) -> Result<Message<Value>, RpcError> {
+        let (msg_id, rx) = self.start_rpc(dest, payload).map_err(RpcError::Send)?;
+        match rx.recv_timeout(timeout) {
+            Ok(reply) => rpc_result(reply),
+            Err(_) => {
+                if let Ok(mut rpcs) = self.rpcs.lock() {
+                    rpcs.remove(&msg_id);
+                }
+                Err(RpcError::Timeout)
+            }
+        }
+    }
+    /// Sends `payload` as a request without waiting on it: `then` runs on
+    /// the dispatcher once the reply arrives, or once `timeout` passes
+    /// without one, so everything else carries on meanwhile. It runs as
+    /// part of the request being handled now, which it can still
+    /// [reply](Context::reply) to; an error it returns is reported to that
+    /// request's sender.
+    pub fn rpc_then<H: Handler, P: Serialize + Kind>(
+        &self,
+        dest: &NodeId,
+        payload: P,
+        timeout: Duration,
+        then: impl FnOnce(&mut H, &mut Context, Result<Message<Value>, RpcError>) -> anyhow::Result<()>
+        + Send
+        + 'static,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.next_msg_id();
+        let then: Then<H> = Box::new(then);
+        let pending = PendingThen {
+            dest: dest.clone(),
+            expires: self.now() + timeout,
+            incoming: self.incoming.clone(),
+            trace: log::trace(),
+            then: Box::new(then),
+        };
+        lock(&self.thens).insert(msg_id, pending);
+        if let Err(e) = self
+            .output
+            .send(&self.node_id, dest, Body::request(msg_id, payload))
+        {
+            lock(&self.thens).remove(&msg_id);
+            return Err(e);
+        }
+        Ok(())
+    }
+    /// Passes `m` to the rpc waiting on it, or returns it if there is none.
+    pub(crate) fn route_reply(&self, m: Message<Value>) -> anyhow::Result<Option<Message<Value>>> {
+        let Some(in_reply_to) = m.body.in_reply_to else {
+            return Ok(Some(m));
+        };
+        match lock(&self.rpcs).remove(&in_reply_to) {
+            Some(pending) => {
+                let _ = pending.tx.send(m);
+                Ok(None)
+            }
+            None => Ok(Some(m)),
+        }
+    }
+    /// Takes the continuation waiting on `m`, if `m` answers an
+    /// [`rpc_then`](Context::rpc_then).
+    fn take_then(&self, m: &Message<Value>) -> Option<PendingThen> {
+        let in_reply_to = m.body.in_reply_to?;
+        lock(&self.thens).remove(&in_reply_to)
+    }
+    /// Takes the continuations whose timeout has passed.
+    fn take_expired_thens(&self) -> Vec<(MsgId, PendingThen)> {
+        let now = self.now();
+        let mut thens = lock(&self.thens);
+        let mut ids: Vec<MsgId> = thens
+            .iter()
+            .filter(|(_, pending)| pending.expires <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        ids.into_iter()
+            .filter_map(|id| thens.remove(&id).map(|pending| (id, pending)))
+            .collect()
+    }
+    /// Gives up on rpcs that have waited past [`pending_ttl`], answering
+    /// each with a timeout error in case anyone is still waiting on it.
+    /// A zero TTL keeps them until their reply comes.
+    fn expire_rpcs(&self) -> anyhow::Result<()> {
+        if pending_ttl().is_zero() {
+            return Ok(());
+        }
+        let now = self.now();
+        let expired: Vec<(MsgId, PendingRpc)> = {
+            let mut rpcs = lock(&self.rpcs);
+            let ids: Vec<MsgId> = rpcs
+                .iter()
+                .filter(|(_, pending)| pending.expires <= now)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| rpcs.remove(&id).map(|pending| (id, pending)))
+                .collect()
+        };
+        for (msg_id, pending) in expired {
+            log!(Info, "rpc_expired", dest = pending.dest, msg_id = msg_id);
+            let payload = Payload::Error {
+                code: ErrorCode::Timeout as usize,
+                text: "no reply before the rpc expired".to_string(),
+            };
+            let _ = pending.tx.send(Message {
+                src: pending.dest,
+                dest: self.node_id.clone(),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: Some(msg_id),
+                    payload: serde_json::to_value(payload)?,
+                },
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    Timeout,
+    /// The peer answered with an `error` message.
+    Error {
+        code: usize,
+        text: String,
+    },
+    Send(anyhow::Error),
+}
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "timed out waiting for rpc reply"),
+            RpcError::Error { code, text } => write!(f, "rpc error {}: {}", code, text),
+            RpcError::Send(e) => write!(f, "failed to send rpc: {:#}", e),
+        }
+    }
+}
+impl std::error::Error for RpcError {}
+
+/// An rpc's reply, or the error it answered with.
+fn rpc_result(reply: Message<Value>) -> Result<Message<Value>, RpcError> {
+    if reply.body.payload.get("type").and_then(Value::as_str) != Some("error") {
+        return Ok(reply);
+    }
+    let field = |name| reply.body.payload.get(name);
+    Err(RpcError::Error {
+        code: field("code").and_then(Value::as_u64).unwrap_or(0) as usize,
+        text: field("text")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
+
+/// The msg_id of a request, i.e. a message that carries one and isn't itself
+/// a reply; errors while handling it are reported back to the sender.
+pub(crate) fn request_msg_id<P>(body: &Body<P>) -> Option<MsgId> {
+    match body.in_reply_to {
+        Some(_) => None,
+        None => body.msg_id,
+    }
+}
+
+/// Locks `mutex`, taking it back from a handler that panicked holding it.
+/// The panic was already answered (see [`guarded`]); refusing every later
+/// lock would leave the node running but unable to do anything.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        log!(Warn, "lock_recovered");
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Reads up to and answers the `init` handshake, returning the context it
+/// establishes and any messages that arrived before it, in order.
+fn init(
+    input: &mut impl Iterator<Item = std::io::Result<String>>,
+    output: Output,
+) -> anyhow::Result<(Context, Vec<Message<Value>>)> {
+    let mut backlog = Vec::new();
+    loop {
+        let Some(line) = input.next() else {
+            anyhow::bail!("input closed before init");
+        };
+        let line = line?;
+        let m: Message<Value> = match serde_json::from_str(&line) {
+            Ok(m) => m,
+            Err(e) => {
+                log!(Error, "malformed", error = e, raw = line.trim_end());
+                continue;
+            }
+        };
+        output.record(Direction::In, &line, None);
+        if !is_init(&m) {
+            backlog.push(m);
+            continue;
+        }
+        let init = match m.parse_body::<InitPayload>() {
+            Ok(init) => init,
+            Err(e) => {
+                log!(Error, "malformed_init", error = e, raw = line.trim_end());
+                continue;
+            }
+        };
+        if let InitPayload::Init { node_id, node_ids } = init.body.payload {
+            log::set_node(&node_id);
+            let ctx = Context::new(node_id, node_ids, output, Arc::new(SystemClock));
+            ctx.output
+                .send(&ctx.node_id, &m.src, init_ok(&ctx, m.body.msg_id))?;
+            return Ok((ctx, backlog));
+        }
+    }
+}
+
+fn is_init(m: &Message<Value>) -> bool {
+    m.body.payload.get("type").and_then(Value::as_str) == Some("init")
+}
+
+/// Like every other reply, `init_ok` carries its own msg_id from the shared
+/// counter.
+fn init_ok(ctx: &Context, in_reply_to: Option<MsgId>) -> Body<InitPayload> {
+    Body {
+        msg_id: Some(ctx.next_msg_id()),
+        in_reply_to,
+        payload: InitPayload::InitOk,
+    }
+}
+
+/// Per-node counts of messages sent between nodes, as far as this node has
+/// heard. Carried on node-to-node messages in a `vclock` field that clients
+/// never see, so recordings can be put in causal order.
+pub type VectorClock = BTreeMap<String, usize>;
+
+const VCLOCK: &str = "vclock";
+
+/// A payload as sent, with the [`TRACE`] and [`VCLOCK`] fields alongside
+/// its own.
+#[derive(Serialize)]
+struct Stamped<'a, P> {
+    #[serde(flatten)]
+    payload: &'a P,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vclock: Option<VectorClock>,
+}
+
+/// The body field carrying a request's trace id between nodes and to
+/// services. See [`Context::trace`].
+const TRACE: &str = "trace";
+
+/// Write coalescing for [`Output::to_writer`]: lines are held until
+/// `bytes` of them are waiting or `window` has passed since the first, and
+/// then written at once. On with `MAELLE_COALESCE_BYTES` over 0, with the
+/// window from `MAELLE_COALESCE_MS` (default 1).
+#[derive(Clone, Copy, Debug)]
+pub struct Coalesce {
+    pub bytes: usize,
+    pub window: Duration,
+}
+impl Coalesce {
+    pub fn from_env() -> Option<Self> {
+        let bytes = env_or("MAELLE_COALESCE_BYTES", 0);
+        (bytes > 0).then(|| Self {
+            bytes,
+            window: Duration::from_millis(env_or("MAELLE_COALESCE_MS", 1)),
+        })
+    }
+}
+
+/// Lines queued for output before senders start blocking, from
+/// `MAELLE_OUTBOX`.
+fn outbox_capacity() -> usize {
+    env_or("MAELLE_OUTBOX", 1024)
+}
+
+enum Outgoing {
+    /// `urgent` lines, i.e. those to clients, are written ahead of the
+    /// rest. `seq` is the line's place among everything sent to `dest`.
+    Line {
+        line: String,
+        urgent: bool,
+        dest: NodeId,
+        seq: u64,
+    },
+    /// Takes the place of a line to `dest` that failed to encode, so the
+    /// lines after it aren't held back for good.
+    Skip { dest: NodeId, seq: u64 },
+    /// Acknowledged once every line queued before it has been written,
+    /// bar any still waiting on an earlier line to their destination.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Urgent lines written in a row while other lines wait, from
+/// `MAELLE_URGENT_BURST`; after that one other line goes out, so a stream of
+/// replies can't hold back gossip forever.
+fn urgent_burst() -> usize {
+    env_or("MAELLE_URGENT_BURST", 8).max(1)
+}
+
+/// What [`Queues`] hands the writer once its turn comes.
+enum Ready {
+    Line(String),
+    Flush(mpsc::Sender<()>),
+}
+
+/// One destination's lines in the order they were sent: the next `seq`
+/// due, and whatever reached the writer ahead of it.
+#[derive(Default)]
+struct InOrder {
+    next: u64,
+    early: BTreeMap<u64, Outgoing>,
+}
+
+/// The [`Fault`]s an [`Output`] is under.
+#[derive(Default)]
+struct Faults {
+    /// How many more lines to each peer to discard.
+    drop_next: BTreeMap<NodeId, usize>,
+    delay: BTreeMap<NodeId, Duration>,
+}
+impl Faults {
+    fn apply(&mut self, fault: Fault) {
+        match fault {
+            Fault::DropNext { count, peer } => {
+                let left = self.drop_next.entry(peer).or_default();
+                *left = left.saturating_add(count);
+                self.drop_next.retain(|_, left| *left > 0);
+            }
+            Fault::Delay { peer, ms: 0 } => {
+                self.delay.remove(&peer);
+            }
+            Fault::Delay { peer, ms } => {
+                self.delay.insert(peer, Duration::from_millis(ms));
+            }
+            Fault::Clear => *self = Self::default(),
+        }
+    }
+    fn active(&self) -> Vec<Fault> {
+        let drops = self.drop_next.iter().map(|(peer, count)| Fault::DropNext {
+            count: *count,
+            peer: peer.clone(),
+        });
+        let delays = self.delay.iter().map(|(peer, delay)| Fault::Delay {
+            peer: peer.clone(),
+            ms: delay.as_millis() as u64,
+        });
+        drops.chain(delays).collect()
+    }
+    /// Whether the next line to `dest` is to be discarded, counting it off
+    /// if so.
+    fn drops(&mut self, dest: &NodeId) -> bool {
+        let Some(left) = self.drop_next.get_mut(dest) else {
+            return false;
+        };
+        *left -= 1;
+        if *left == 0 {
+            self.drop_next.remove(dest);
+        }
+        true
+    }
+}
+
+/// What the writer thread has taken off the queue but not written yet,
+/// urgent lines apart from everything else. A line that overtook an
+/// earlier one to the same destination on its way here waits in `order`
+/// until that one arrives, so each destination sees its lines in the order
+/// they were sent while different destinations interleave freely.
+struct Queues {
+    urgent: VecDeque<String>,
+    normal: VecDeque<Ready>,
+    order: HashMap<NodeId, InOrder>,
+    burst: usize,
+    /// Urgent lines popped since the last other one.
+    streak: usize,
+    faults: Arc<Mutex<Faults>>,
+    /// Lines a [`Fault::Delay`] is holding, in order for each destination,
+    /// with when each is due out and whether it's urgent.
+    delayed: BTreeMap<NodeId, VecDeque<(Instant, bool, String)>>,
+}
+impl Queues {
+    fn new(burst: usize, faults: Arc<Mutex<Faults>>) -> Self {
+        Self {
+            urgent: VecDeque::new(),
+            normal: VecDeque::new(),
+            order: HashMap::new(),
+            burst,
+            streak: 0,
+            faults,
+            delayed: BTreeMap::new(),
+        }
+    }
+    /// Lines ready to write; those waiting on an earlier one don't count.
+    fn len(&self) -> usize {
+        self.urgent.len() + self.normal.len()
+    }
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn push(&mut self, outgoing: Outgoing) {
+        let (dest, seq) = match outgoing {
+            Outgoing::Flush(done) => return self.normal.push_back(Ready::Flush(done)),
+            Outgoing::Line { ref dest, seq, .. } | Outgoing::Skip { ref dest, seq } => {
+                (dest.clone(), seq)
+            }
+        };
+        let order = self.order.entry(dest.clone()).or_default();
+        order.early.insert(seq, outgoing);
+        let mut lines = Vec::new();
+        while let Some(next) = order.early.remove(&order.next) {
+            order.next += 1;
+            if let Outgoing::Line { line, urgent, .. } = next {
+                lines.push((urgent, line));
+            }
+        }
+        for (urgent, line) in lines {
+            self.admit(&dest, urgent, line);
+        }
+    }
+    /// Queues `line`, the next to `dest`, unless a fault discards or holds
+    /// it.
+    fn admit(&mut self, dest: &NodeId, urgent: bool, line: String) {
+        let delay = {
+            let mut faults = lock(&self.faults);
+            if faults.drops(dest) {
+                log!(Info, "fault_dropped", dest = dest);
+                return;
+            }
+            faults.delay.get(dest).copied()
+        };
+        match self.delayed.get_mut(dest) {
+            // Behind what's held, even once the delay is lifted.
+            Some(held) => {
+                held.push_back((Instant::now() + delay.unwrap_or_default(), urgent, line))
+            }
+            None => match delay {
+                Some(delay) => {
+                    let held = VecDeque::from([(Instant::now() + delay, urgent, line)]);
+                    self.delayed.insert(dest.clone(), held);
+                }
+                None => self.enqueue(urgent, line),
+            },
+        }
+    }
+    fn enqueue(&mut self, urgent: bool, line: String) {
+        if urgent {
+            self.urgent.push_back(line);
+        } else {
+            self.normal.push_back(Ready::Line(line));
+        }
+    }
+    /// Queues the held lines due out by `now`, and all those to a peer
+    /// that's no longer delayed.
+    fn release(&mut self, now: Instant) {
+        if self.delayed.is_empty() {
+            return;
+        }
+        let delay = lock(&self.faults).delay.clone();
+        let mut due = Vec::new();
+        self.delayed.retain(|dest, held| {
+            while let Some((at, ..)) = held.front() {
+                if *at > now && delay.contains_key(dest) {
+                    break;
+                }
+                due.extend(held.pop_front().map(|(_, urgent, line)| (urgent, line)));
+            }
+            !held.is_empty()
+        });
+        for (urgent, line) in due {
+            self.enqueue(urgent, line);
+        }
+    }
+    /// When the next held line is due out.
+    fn next_release(&self) -> Option<Instant> {
+        self.delayed
+            .values()
+            .filter_map(|held| held.front().map(|(at, ..)| *at))
+            .min()
+    }
+    fn pop(&mut self) -> Option<Ready> {
+        if !self.urgent.is_empty() && (self.normal.is_empty() || self.streak < self.burst) {
+            return self.pop_urgent().map(Ready::Line);
+        }
+        self.streak = 0;
+        self.normal.pop_front()
+    }
+    fn pop_urgent(&mut self) -> Option<String> {
+        let line = self.urgent.pop_front()?;
+        self.streak += 1;
+        Some(line)
+    }
+}
+
+/// The writer thread's end of an [`Output`]: the sink, and the lines held
+/// back for [`Coalesce`].
+struct Writer<S> {
+    sink: S,
+    coalesce: Option<Coalesce>,
+    held: String,
+    deadline: Option<Instant>,
+}
+impl<S: FnMut(String) -> bool> Writer<S> {
+    /// Writes `line`, or holds it back if coalescing, returning whether the
+    /// sink still takes writes.
+    fn write(&mut self, line: String) -> bool {
+        let Some(coalesce) = self.coalesce else {
+            return (self.sink)(line);
+        };
+        self.held.push_str(&line);
+        let now = Instant::now();
+        let deadline = *self.deadline.get_or_insert(now + coalesce.window);
+        if self.held.len() < coalesce.bytes && now < deadline {
+            return true;
+        }
+        self.write_held()
+    }
+    fn write_held(&mut self) -> bool {
+        self.deadline = None;
+        if self.held.is_empty() {
+            return true;
+        }
+        (self.sink)(std::mem::take(&mut self.held))
+    }
+}
+
+/// A node's outgoing messages, serialized one per line and handed to a
+/// writer thread through a bounded queue. Messages to one destination are
+/// written in the order their sends began, whichever threads they came
+/// from; replies to clients jump ahead of whatever else the writer has
+/// waiting. Cheap to clone.
+#[derive(Clone)]
+pub struct Output {
+    tx: mpsc::SyncSender<Outgoing>,
+    /// Set once the sink stops accepting writes (e.g. a broken pipe);
+    /// there's no one left to talk to, so the dispatcher stops.
+    failed: Arc<AtomicBool>,
+    recorder: Option<Recorder>,
+    vclock: Arc<Mutex<VectorClock>>,
+    /// How many client requests this node has started a trace for.
+    traces: Arc<AtomicUsize>,
+    /// The next `seq` to give a line to each destination.
+    seqs: Arc<Mutex<HashMap<NodeId, u64>>>,
+    faults: Arc<Mutex<Faults>>,
+    stats: Arc<Stats>,
+}
+impl Output {
+    /// Writes each line with a single `write_all` from the one writer
+    /// thread, so lines sent through any number of clones never interleave.
+    /// With [`Coalesce`] configured, lines are held back and written several
+    /// to a `write_all` instead.
+    pub fn to_writer(os: impl Write + Send + 'static) -> Self {
+        Self::to_writer_with(os, Coalesce::from_env())
+    }
+    /// [`Output::to_writer`], coalescing as given rather than as configured.
+    pub fn to_writer_with(mut os: impl Write + Send + 'static, coalesce: Option<Coalesce>) -> Self {
+        let sink = move |lines: String| {
+            os.write_all(lines.as_bytes())?;
+            os.flush()
+        };
+        Self::start(sink, coalesce)
+    }
+    /// Hands each line, newline included, to `sink` on the writer thread.
+    pub fn spawn(sink: impl FnMut(String) -> std::io::Result<()> + Send + 'static) -> Self {
+        Self::start(sink, None)
+    }
+    fn start(
+        mut sink: impl FnMut(String) -> std::io::Result<()> + Send + 'static,
+        coalesce: Option<Coalesce>,
+    ) -> Self {
+        let (tx, rx) = mpsc::sync_channel(outbox_capacity());
+        let failed = Arc::new(AtomicBool::new(false));
+        let faults = Arc::new(Mutex::new(Faults::default()));
+        let output = Self {
+            tx,
+            failed: Arc::clone(&failed),
+            recorder: None,
+            vclock: Arc::new(Mutex::new(VectorClock::new())),
+            traces: Arc::new(AtomicUsize::new(0)),
+            seqs: Arc::new(Mutex::new(HashMap::new())),
+            faults: Arc::clone(&faults),
+            stats: Arc::new(Stats::new()),
+        };
+        let capacity = outbox_capacity();
+        std::thread::spawn(move || {
+            let mut writer = Writer {
+                sink: |lines: String| match sink(lines) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log!(Error, "output_closed", error = e);
+                        failed.store(true, Ordering::Relaxed);
+                        false
+                    }
+                },
+                coalesce,
+                held: String::new(),
+                deadline: None,
+            };
+            let mut queues = Queues::new(urgent_burst(), faults);
+            loop {
+                queues.release(Instant::now());
+                if queues.is_empty() {
+                    let wake = writer
+                        .deadline
+                        .into_iter()
+                        .chain(queues.next_release())
+                        .min();
+                    let outgoing = match wake {
+                        None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                        Some(wake) => {
+                            rx.recv_timeout(wake.saturating_duration_since(Instant::now()))
+                        }
+                    };
+                    match outgoing {
+                        Ok(outgoing) => queues.push(outgoing),
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            let elapsed = writer
+                                .deadline
+                                .is_some_and(|deadline| deadline <= Instant::now());
+                            if elapsed && !writer.write_held() {
+                                return;
+                            }
+                            continue;
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            writer.write_held();
+                            return;
+                        }
+                    }
+                }
+                // Take in whatever else is waiting, so a reply queued behind
+                // a backlog is seen before the backlog is written.
+                while queues.len() < capacity {
+                    let Ok(outgoing) = rx.try_recv() else {
+                        break;
+                    };
+                    queues.push(outgoing);
+                }
+                let written = match queues.pop() {
+                    Some(Ready::Line(line)) => writer.write(line),
+                    Some(Ready::Flush(done)) => {
+                        let mut written = true;
+                        while let Some(line) = queues.pop_urgent() {
+                            written = written && writer.write(line);
+                        }
+                        written = written && writer.write_held();
+                        let _ = done.send(());
+                        written
+                    }
+                    None => true,
+                };
+                if !written {
+                    return;
+                }
+            }
+        });
+        output
+    }
+    /// Has the writer write out whatever it's holding back, without waiting
+    /// for it to; e.g. before blocking on a reply that can't come until the
+    /// request is out.
+    pub fn write_now(&self) {
+        let (done, _) = mpsc::channel();
+        let _ = self.tx.try_send(Outgoing::Flush(done));
+    }
+    /// Also records every line sent, and every line received by the
+    /// node this output belongs to, with `recorder`.
+    pub fn recording(self, recorder: Recorder) -> Self {
+        Self {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+    pub(crate) fn record(&self, dir: Direction, line: &str, trace: Option<&str>) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(dir, line, trace);
+        }
+    }
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+    /// Applies `fault` to the lines written from now on, returning every
+    /// fault then in effect. Delays are on the system clock, whatever the
+    /// node's [`Clock`].
+    pub fn inject(&self, fault: Fault) -> Vec<Fault> {
+        let active = {
+            let mut faults = lock(&self.faults);
+            faults.apply(fault);
+            faults.active()
+        };
+        // Wakes the writer to let out what a lifted delay was holding.
+        self.write_now();
+        active
+    }
+    /// This node's vector clock as of now.
+    pub fn vclock(&self) -> VectorClock {
+        self.vclock
+            .lock()
+            .map(|clock| clock.clone())
+            .unwrap_or_default()
+    }
+    /// Takes the vector clock off a message from another node and merges it
+    /// into ours, so handlers never see it. A client's request instead gets
+    /// a fresh trace id, which [`dispatch`] takes off again.
+    pub(crate) fn observe(&self, m: &mut Message<Value>) {
+        if m.src.is_client() && m.body.msg_id.is_some() {
+            let n = self.traces.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(payload) = m.body.payload.as_object_mut() {
+                payload.insert(TRACE.to_string(), format!("{}-{}", m.dest, n).into());
+            }
+        }
+        if !m.src.is_node() {
+            return;
+        }
+        let Some(theirs) = m
+            .body
+            .payload
+            .as_object_mut()
+            .and_then(|payload| payload.remove(VCLOCK))
+        else {
+            return;
+        };
+        let Ok(theirs) = serde_json::from_value::<VectorClock>(theirs) else {
+            return;
+        };
+        if let Ok(mut ours) = self.vclock.lock() {
+            for (node, tick) in theirs {
+                let entry = ours.entry(node).or_default();
+                *entry = (*entry).max(tick);
+            }
+        }
+    }
+    /// Queues a message as one complete line. Blocks only when the writer
+    /// has fallen a full queue behind.
+    pub fn send<P: Serialize + Kind>(
+        &self,
+        src: &NodeId,
+        dest: &NodeId,
+        body: Body<P>,
+    ) -> anyhow::Result<()> {
+        if dest.is_node() {
+            Stats::incr(&self.stats.messages_sent);
+        }
+        // Taken first, so a send that's slow to encode still goes out
+        // ahead of a later one to the same destination.
+        let seq = {
+            let mut seqs = lock(&self.seqs);
+            let next = seqs.entry(dest.clone()).or_default();
+            *next += 1;
+            *next - 1
+        };
+        let line = match self.encode(src, dest, body) {
+            Ok(line) => line,
+            Err(e) => {
+                let skip = Outgoing::Skip {
+                    dest: dest.clone(),
+                    seq,
+                };
+                let _ = self.tx.send(skip);
+                return Err(e);
+            }
+        };
+        // Everything to a client is urgent, not only replies, so that
+        // lines to one destination all take the same queue and stay in
+        // order.
+        let line = Outgoing::Line {
+            line,
+            urgent: dest.is_client(),
+            dest: dest.clone(),
+            seq,
+        };
+        let line = match self.tx.try_send(line) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::TrySendError::Full(line)) => {
+                log!(Warn, "output_backpressure", dest = dest);
+                line
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                anyhow::bail!("output writer has stopped")
+            }
+        };
+        self.tx
+            .send(line)
+            .map_err(|_| anyhow::anyhow!("output writer has stopped"))
+    }
+    /// `body` from `src` to `dest` as one line, newline included, with the
+    /// trace and vector clock added and the send logged and recorded.
+    fn encode<P: Serialize + Kind>(
+        &self,
+        src: &NodeId,
+        dest: &NodeId,
+        body: Body<P>,
+    ) -> anyhow::Result<String> {
+        let trace = log::trace();
+        let kind = body.payload.kind();
+        let vclock = match (dest.is_node(), self.vclock.lock()) {
+            (true, Ok(mut clock)) => {
+                let tick = clock.entry(src.to_string()).or_default();
+                *tick = tick.saturating_add(1);
+                Some(clock.clone())
+            }
+            _ => None,
+        };
+        let m = Message {
+            src: src.clone(),
+            dest: dest.clone(),
+            body: Body {
+                msg_id: body.msg_id,
+                in_reply_to: body.in_reply_to,
+                payload: Stamped {
+                    payload: &body.payload,
+                    // Clients get exactly the fields their protocol defines.
+                    trace: trace.as_deref().filter(|_| !dest.is_client()),
+                    vclock,
+                },
+            },
+        };
+        let mut line = Vec::new();
+        serde_json::to_writer(&mut line, &m)?;
+        let mut line = String::from_utf8(line)?;
+        Stats::count(&self.stats.sent, kind);
+        log!(
+            Debug,
+            "send",
+            dest = dest,
+            r#type = kind,
+            msg_id = log::opt(body.msg_id),
+            in_reply_to = log::opt(body.in_reply_to),
+        );
+        self.record(Direction::Out, &line, trace.as_deref());
+        line.push('\n');
+        if dest.is_node() {
+            self.stats
+                .bytes_sent
+                .fetch_add(line.len() as u64, Ordering::Relaxed);
+        }
+        Ok(line)
+    }
+    /// Waits until everything queued so far has been written, and
+    /// recorded if recording.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.tx.send(Outgoing::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.flush();
+        }
+    }
+}
+
+/// Index of a periodic task in the list the dispatcher was started with.
+pub type TimerId = usize;
+
+/// Everything the dispatcher reacts to, in the order it happened.
+pub enum Event {
+    Message(Message<Value>),
+    Tick(TimerId),
+    Eof,
+}
+
+/// Reads stdin on its own thread. Replies to a pending [`Context::rpc`] are
+/// routed straight to the waiting caller, so a handler blocked on one never
+/// needs the dispatcher to make progress.
+///
+/// The queue to the dispatcher is bounded ([`inbox_capacity`]): once it's
+/// full the reader stops taking input, and Maelstrom is held back by the
+/// pipe filling up rather than the node by its memory. A reply read only
+/// after the queue drains can't reach its rpc before then, so an rpc made
+/// with the queue full may wait out its timeout.
+fn spawn_reader(
+    input: impl Iterator<Item = std::io::Result<String>> + Send + 'static,
+    ctx: Context,
+    events: mpsc::SyncSender<Event>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in input {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    log!(Error, "unreadable_input", error = e);
+                    continue;
+                }
+                Err(e) => {
+                    log!(Error, "read_failed", error = e);
+                    break;
+                }
+            };
+            let Some(m) = accept_line(&ctx, &line) else {
+                continue;
+            };
+            ctx.output.stats.inbox_depth.fetch_add(1, Ordering::Relaxed);
+            if events.send(Event::Message(m)).is_err() {
+                return;
+            }
+        }
+        let _ = events.send(Event::Eof);
+    })
+}
+
+/// Parses one input line, answering it if it's malformed and handing it to
+/// its caller if it's an rpc reply. Returns what's left to dispatch.
+pub(crate) fn accept_line(ctx: &Context, line: &str) -> Option<Message<Value>> {
+    let mut m: Message<Value> = match serde_json::from_str(line) {
+        Ok(m) => m,
+        Err(e) => {
+            reject_malformed(ctx, line, e);
+            return None;
+        }
+    };
+    ctx.output.observe(&mut m);
+    let trace = m.body.payload.get(TRACE).and_then(Value::as_str);
+    ctx.output.record(Direction::In, line, trace);
+    let kind = m.body.payload.kind();
+    ctx.stats().count_received(kind);
+    log!(
+        Debug,
+        "recv",
+        src = m.src,
+        r#type = kind,
+        msg_id = log::opt(m.body.msg_id),
+        in_reply_to = log::opt(m.body.in_reply_to),
+        trace = log::opt(trace),
+    );
+    match ctx.route_reply(m) {
+        Ok(m) => m,
+        Err(e) => {
+            log!(Error, "route_failed", error = format!("{:#}", e));
+            None
+        }
+    }
+}
+
+/// How much of a malformed line is logged and quoted back to its sender.
+const MALFORMED_EXCERPT: usize = 200;
+
+/// Answers a line that isn't a message with a malformed-request error, if
+/// enough of it can be made out to say who sent it and which request it
+/// was; otherwise it's only logged.
+fn reject_malformed(ctx: &Context, line: &str, e: serde_json::Error) {
+    let excerpt = excerpt(line, MALFORMED_EXCERPT);
+    log!(Error, "malformed", error = e, raw = excerpt);
+    let (src, msg_id) = match serde_json::from_str::<Value>(line) {
+        Ok(value) => (
+            value.get("src").and_then(Value::as_str).map(NodeId::from),
+            value.pointer("/body/msg_id").and_then(Value::as_u64),
+        ),
+        Err(_) => (
+            scan_field(line, "src").map(NodeId::from),
+            scan_field(line, "msg_id").and_then(|id| id.parse().ok()),
+        ),
+    };
+    if let (Some(src), Some(in_reply_to)) = (src, msg_id) {
+        send_error(
+            ctx,
+            &src,
+            MsgId(in_reply_to),
+            ErrorCode::MalformedRequest,
+            format!("{}; input: {}", e, excerpt),
+        );
+    }
+}
+
+/// The first `max` bytes of `line`, cut back to a char boundary, marked
+/// when something was cut.
+fn excerpt(line: &str, max: usize) -> String {
+    if line.len() <= max {
+        return line.to_string();
+    }
+    let mut end = max;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &line[..end])
+}
+
+/// What follows the first `"key":` in `line`: a string's contents or a run
+/// of digits. Enough to answer input too broken to parse.
+fn scan_field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let quoted = format!("{:?}", key);
+    let at = line.find(&quoted)? + quoted.len();
+    let value = line[at..].trim_start().strip_prefix(':')?.trim_start();
+    if let Some(string) = value.strip_prefix('"') {
+        return string.split_once('"').map(|(contents, _)| contents);
+    }
+    let end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    (end > 0).then(|| &value[..end])
+}
+
+/// Sends a tick for each timer whenever its interval elapses. A timer whose
+/// last tick is still queued is skipped rather than piling up behind a slow
+/// handler.
+fn spawn_timers(
+    clock: Arc<dyn Clock>,
+    periods: Vec<Period>,
+    queued: Arc<Vec<AtomicBool>>,
+    events: mpsc::SyncSender<Event>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        if periods.is_empty() {
+            return;
+        }
+        // Each task is next due a period after it was last due, with
+        // the period as it is by then.
+        let mut last: Vec<Instant> = vec![clock.now(); periods.len()];
+        loop {
+            let now = clock.now();
+            let next = periods
+                .iter()
+                .zip(&last)
+                .filter(|(period, _)| !period.is_zero())
+                .map(|(period, last)| *last + period.get())
+                .min()
+                .unwrap_or(now + PERIOD_RECHECK);
+            clock.sleep_until(next.min(now + PERIOD_RECHECK));
+            let now = clock.now();
+            for (id, last) in last.iter_mut().enumerate() {
+                let interval = periods[id].get();
+                let due = *last + interval;
+                if interval.is_zero() || due > now {
+                    continue;
+                }
+                // A loop running late catches up by one run, not many.
+                *last = now
+                    .checked_sub(interval)
+                    .map_or(due, |floor| due.max(floor));
+                if queued[id].swap(true, Ordering::Relaxed) {
+                    continue;
+                }
+                if events.send(Event::Tick(id)).is_err() {
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// How often rpcs are checked for having expired.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to keep running timers after stdin closes so retries and
+/// batches still go out, from `MAELLE_GRACE_MS`.
+fn grace_period() -> Duration {
+    Duration::from_millis(env_or("MAELLE_GRACE_MS", 1000))
+}
+
+/// How long a sent request is kept waiting on its reply, from
+/// `MAELLE_PENDING_TTL_MS`: rpcs and the node's retried and gossip
+/// callbacks alike, bar the replication of txn writes, which nothing else
+/// would repair. Replies lost to a partition would otherwise leave their
+/// entries behind forever. Zero keeps them indefinitely.
+pub fn pending_ttl() -> Duration {
+    Duration::from_millis(env_or("MAELLE_PENDING_TTL_MS", 60_000))
+}
+
+fn expire_rpcs<H: Handler>(handler: &mut H, ctx: &mut Context) -> anyhow::Result<()> {
+    for (msg_id, pending) in ctx.take_expired_thens() {
+        log!(Info, "rpc_expired", dest = pending.dest, msg_id = msg_id);
+        resume(handler, ctx, pending, Err(RpcError::Timeout));
+    }
+    ctx.expire_rpcs()
+}
+
+/// The handler's periodic tasks and, after them, the runtime's own: the
+/// stats report and the expiry of rpcs nothing answered. [`run_with`] and
+/// the [simulator](crate::sim) both run this list, so a node ticks the same
+/// in either.
+pub(crate) fn tasks<H: Handler>(handler: &H) -> Vec<(Period, Task<H>)> {
+    let mut tasks = handler.periodic();
+    tasks.push((stats_interval().into(), report_stats::<H>));
+    tasks.push((EXPIRY_INTERVAL.into(), expire_rpcs::<H>));
+    tasks
+}
+
+/// How many messages the reader may get ahead of the dispatcher, from
+/// `MAELLE_INBOX_CAPACITY`.
+fn inbox_capacity() -> usize {
+    env_or("MAELLE_INBOX_CAPACITY", 4096).max(1)
+}
+
+fn stats_interval() -> Duration {
+    Duration::from_millis(env_or("MAELLE_STATS_MS", 5000))
+}
+
+fn report_stats<H>(_: &mut H, ctx: &mut Context) -> anyhow::Result<()> {
+    ctx.output.stats.report();
+    Ok(())
+}
+
+/// Logs a message that couldn't be handled and, if it was a request, tells
+/// the sender why.
+fn report_failure(ctx: &Context, src: &NodeId, request_id: Option<MsgId>, e: &anyhow::Error) {
+    log!(
+        Warn,
+        "handler_failed",
+        src = src,
+        msg_id = log::opt(request_id),
+        error = format!("{:#}", e),
+    );
+    if let Some(in_reply_to) = request_id {
+        let (code, text) = error_reply(e);
+        send_error(ctx, src, in_reply_to, code, text);
+    }
+}
+
+fn send_error(ctx: &Context, dest: &NodeId, in_reply_to: MsgId, code: ErrorCode, text: String) {
+    let body = Body {
+        msg_id: Some(ctx.next_msg_id()),
+        in_reply_to: Some(in_reply_to),
+        payload: Payload::Error {
+            code: code as usize,
+            text,
+        },
+    };
+    let _ = ctx.output.send(&ctx.node_id, dest, body);
+}
+
+/// Splits a byte stream into its JSON values, each re-serialized onto one
+/// line for [`run_with`] and the recorder, however the sender laid them out.
+/// A value that doesn't parse is passed on as it came, along with the rest
+/// of its line, for the reader to reject; the stream carries on after it.
+pub struct JsonStream<R> {
+    reader: Counted<R>,
+}
+impl<R: BufRead> JsonStream<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: Counted {
+                inner: reader,
+                read: 0,
+                seen: Vec::new(),
+            },
+        }
+    }
+}
+impl<R: BufRead> Iterator for JsonStream<R> {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.reader.read;
+            self.reader.seen.clear();
+            // A fresh deserializer each time, since one gives up for good
+            // after its first error.
+            let mut values =
+                serde_json::Deserializer::from_reader(&mut self.reader).into_iter::<Value>();
+            let value = values.next()?;
+            let offset = start + values.byte_offset() as u64;
+            match value {
+                Ok(value) => return Some(Ok(value.to_string())),
+                Err(e) if e.is_io() => return Some(Err(e.into())),
+                Err(e) => {
+                    log!(Debug, "malformed_input", error = e, offset = offset);
+                    if !e.is_eof() {
+                        if let Err(e) = self.reader.skip_line() {
+                            return Some(Err(e));
+                        }
+                    }
+                    let raw = String::from_utf8_lossy(&self.reader.seen);
+                    let raw = raw.trim();
+                    if !raw.is_empty() {
+                        return Some(Ok(raw.to_string()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Counts the bytes taken from `inner`, for [`JsonStream`]'s offsets, and
+/// keeps those of the value being read in case it doesn't parse.
+struct Counted<R> {
+    inner: R,
+    read: u64,
+    seen: Vec<u8>,
+}
+impl<R: BufRead> Counted<R> {
+    fn skip_line(&mut self) -> std::io::Result<()> {
+        let skipped = self.inner.read_until(b'\n', &mut self.seen)?;
+        self.read += skipped as u64;
+        Ok(())
+    }
+}
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        self.seen.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Runs the handler `make` builds, reading messages from `input` and
+/// writing them to `out` (in a binary, stdin and stdout); see [`run_with`].
+/// With `MAELLE_RECORD` set to a path, a [session recording](crate::record)
+/// is appended there too; with `MAELLE_REPLAY` set, one is
+/// [replayed](crate::record::replay) instead of reading `input`.
+pub fn run<H: Handler>(
+    input: impl Read + Send + 'static,
+    out: impl Write + Send + 'static,
+    make: impl FnOnce(&Context) -> H,
+) -> anyhow::Result<()> {
+    if let Ok(path) = std::env::var("MAELLE_REPLAY") {
+        return crate::record::replay(path, out, make);
+    }
+    let mut output = Output::to_writer(out);
+    if let Ok(path) = std::env::var("MAELLE_RECORD") {
+        let recorder = Recorder::create(&path)
+            .map_err(|e| anyhow::anyhow!("can't record to {}: {}", path, e))?;
+        output = output.recording(recorder);
+    }
+    run_with(JsonStream::new(BufReader::new(input)), output, make)
+}
+
+/// Runs the handler `make` builds until `input` is exhausted, plus a grace
+/// period for outstanding work. Messages and timer ticks are merged into one
+/// stream of [`Event`]s and handled one at a time, so the handler is never
+/// shared.
+pub fn run_with<H: Handler>(
+    mut input: impl Iterator<Item = std::io::Result<String>> + Send + 'static,
+    output: Output,
+    make: impl FnOnce(&Context) -> H,
+) -> anyhow::Result<()> {
+    let (ctx, backlog) = init(&mut input, output)?;
+    let mut handler = make(&ctx);
+
+    let tasks = tasks(&handler);
+
+    // Room for the backlog too, which is queued before anything drains it.
+    let (events, inbox) = mpsc::sync_channel(inbox_capacity().max(backlog.len()));
+    let queued: Arc<Vec<AtomicBool>> =
+        Arc::new(tasks.iter().map(|_| AtomicBool::new(false)).collect());
+    for m in backlog {
+        ctx.output.stats.inbox_depth.fetch_add(1, Ordering::Relaxed);
+        events.send(Event::Message(m))?;
+    }
+    spawn_reader(input, ctx.clone(), events.clone());
+    spawn_timers(
+        ctx.clock(),
+        tasks.iter().map(|(period, _)| period.clone()).collect(),
+        Arc::clone(&queued),
+        events,
+    );
+
+    let mut deadline: Option<Instant> = None;
+    while !ctx.output.failed() {
+        let wake = deadline.into_iter().chain(ctx.next_due()).min();
+        let event = match wake {
+            None => inbox
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            Some(wake) => inbox.recv_timeout(wake.saturating_duration_since(ctx.now())),
+        };
+        for (_, m) in ctx.take_scheduled(Some(ctx.now())) {
+            dispatch(&mut handler, &ctx, m);
+        }
+        let event = match event {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout)
+                if deadline.is_none_or(|deadline| ctx.now() < deadline) =>
+            {
+                continue;
+            }
+            Err(_) => break,
+        };
+        match event {
+            Event::Message(m) => {
+                ctx.output.stats.inbox_depth.fetch_sub(1, Ordering::Relaxed);
+                dispatch(&mut handler, &ctx, m)
+            }
+            Event::Tick(id) => {
+                queued[id].store(false, Ordering::Relaxed);
+                let mut ctx = ctx.clone();
+                if let Err(e) = guarded(|| (tasks[id].1)(&mut handler, &mut ctx)) {
+                    log!(Warn, "task_failed", timer = id, error = format!("{:#}", e));
+                }
+            }
+            Event::Eof => deadline = Some(ctx.now() + grace_period()),
+        }
+        if deadline.is_some() && handler.quiescent() && lock(&ctx.thens).is_empty() {
+            break;
+        }
+    }
+
+    ctx.output.stats.report();
+    ctx.output.flush();
+
+    Ok(())
+}
+
+/// Parses `m` for the handler and handles it, reporting any failure back to
+/// the sender.
+pub(crate) fn dispatch<H: Handler>(handler: &mut H, ctx: &Context, mut m: Message<Value>) {
+    let trace = m
+        .body
+        .payload
+        .as_object_mut()
+        .and_then(|payload| payload.remove(TRACE))
+        .and_then(|trace| trace.as_str().map(String::from));
+    log::set_trace(trace);
+    dispatch_traced(handler, ctx, m);
+    log::set_trace(None);
+}
+
+fn dispatch_traced<H: Handler>(handler: &mut H, ctx: &Context, m: Message<Value>) {
+    if let Some(pending) = ctx.take_then(&m) {
+        return resume(handler, ctx, pending, rpc_result(m));
+    }
+    if is_init(&m) {
+        // Already initialized; a repeated init just gets the same answer.
+        let _ = ctx
+            .output
+            .send(&ctx.node_id, &m.src, init_ok(ctx, m.body.msg_id));
+        return;
+    }
+    let kind = m.body.payload.get("type").and_then(Value::as_str);
+    if kind.is_some_and(|kind| AdminPayload::TYPES.contains(&kind)) {
+        match m.parse_body::<AdminPayload>() {
+            Ok(admin) => answer_admin(handler, ctx, admin),
+            Err(e) => report_failure(ctx, &m.src, request_msg_id(&m.body), &e.into()),
+        }
+        return;
+    }
+    // Taken out while it runs, so a middleware can add another.
+    let mut chain = ctx
+        .middleware
+        .lock()
+        .map(|mut chain| std::mem::take(&mut *chain))
+        .unwrap_or_default();
+    let mut ran = 0;
+    let mut answer = None;
+    for middleware in chain.iter_mut() {
+        ran += 1;
+        if let ControlFlow::Break(reply) = middleware.before(ctx, &m) {
+            answer = Some(reply);
+            break;
+        }
+    }
+    let mut ctx = ctx.clone();
+    ctx.incoming = Some(m.headers());
+    let result = match answer {
+        Some(Some(reply)) => ctx.reply(reply),
+        Some(None) => Ok(()),
+        None => handle(handler, &mut ctx, &m),
+    };
+    if let Err(e) = &result {
+        report_failure(&ctx, &m.src, request_msg_id(&m.body), e);
+    }
+    for middleware in chain[..ran].iter_mut().rev() {
+        middleware.after(&ctx, &m, &result);
+    }
+    if let Ok(mut added) = ctx.middleware.lock() {
+        chain.append(&mut added);
+        *added = chain;
+    };
+}
+
+/// Runs the continuation of an [`rpc_then`](Context::rpc_then) with its
+/// outcome, back in the request and trace it was made under.
+fn resume<H: Handler>(
+    handler: &mut H,
+    ctx: &Context,
+    pending: PendingThen,
+    result: Result<Message<Value>, RpcError>,
+) {
+    let Ok(then) = pending.then.downcast::<Then<H>>() else {
+        return;
+    };
+    let outer = log::trace();
+    log::set_trace(pending.trace);
+    let mut ctx = ctx.clone();
+    ctx.incoming = pending.incoming;
+    if let Err(e) = guarded(|| then(handler, &mut ctx, result)) {
+        match &ctx.incoming {
+            Some(m) => report_failure(&ctx, &m.src, request_msg_id(&m.body), &e),
+            None => log!(Warn, "continuation_failed", error = format!("{:#}", e)),
+        }
+    }
+    log::set_trace(outer);
+}
+
+/// Parses `m` for the handler and hands it over.
+fn handle<H: Handler>(handler: &mut H, ctx: &mut Context, m: &Message<Value>) -> HandlerResult {
+    let parsed = m.parse_body::<H::Payload>().inspect_err(|_| {
+        let raw = serde_json::to_string(m).unwrap_or_default();
+        log!(Error, "rejected", src = m.src, raw = raw);
+    })?;
+    guarded(|| handler.handle(ctx, parsed))
+}
+
+/// Answers `stats`, `dump_state` and the like outside the workload and
+/// its accounting, in one dispatcher turn.
+fn answer_admin<H: Handler>(handler: &mut H, ctx: &Context, m: Message<AdminPayload>) {
+    let reply = match m.body.payload {
+        AdminPayload::Configure { ref settings } => match guarded(|| handler.configure(settings)) {
+            Ok(settings) => {
+                log!(
+                    Info,
+                    "configured",
+                    settings = serde_json::to_string(&settings).unwrap_or_default()
+                );
+                AdminPayload::ConfigureOk { settings }
+            }
+            Err(e) => return report_failure(ctx, &m.src, m.body.msg_id, &e),
+        },
+        AdminPayload::InjectFault { ref fault } => {
+            log!(
+                Info,
+                "fault_injected",
+                fault = serde_json::to_string(fault).unwrap_or_default()
+            );
+            AdminPayload::InjectFaultOk {
+                faults: ctx.output().inject(fault.clone()),
+            }
+        }
+        AdminPayload::Stats => {
+            let mut stats = ctx.output.stats.snapshot();
+            stats.pending_rpcs = lock(&ctx.rpcs).len() + lock(&ctx.thens).len();
+            stats.handler = handler.stats();
+            AdminPayload::StatsOk { stats }
+        }
+        AdminPayload::DumpState => AdminPayload::DumpStateOk {
+            state: handler.dump_state(),
+            vclock: ctx.output.vclock(),
+        },
+        AdminPayload::Leader => AdminPayload::LeaderOk {
+            leader: handler.leader(),
+        },
+        AdminPayload::DumpTopology => match handler.topology_dot() {
+            Some(dot) => AdminPayload::DumpTopologyOk { dot },
+            None => {
+                let e = ErrorReply::new(ErrorCode::NotSupported, "no topology to dump").into();
+                return report_failure(ctx, &m.src, m.body.msg_id, &e);
+            }
+        },
+        AdminPayload::StatsOk { .. }
+        | AdminPayload::DumpStateOk { .. }
+        | AdminPayload::LeaderOk { .. }
+        | AdminPayload::ConfigureOk { .. }
+        | AdminPayload::InjectFaultOk { .. }
+        | AdminPayload::DumpTopologyOk { .. } => return,
+    };
+    let mut ctx = ctx.clone();
+    ctx.incoming = Some(m.headers());
+    if let Err(e) = ctx.reply(reply) {
+        log!(Warn, "admin_reply_failed", error = format!("{:#}", e));
+    }
+}
+
+/// Runs `f`, turning a panic into an error so that one bad message is
+/// answered with a crash reply instead of taking the node down. The panic
+/// itself is logged where it happened, backtrace and all.
+pub(crate) fn guarded<T>(f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(log_panics);
+    let outer = GUARDED.replace(true);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    GUARDED.set(outer);
+    result.unwrap_or_else(|panic| {
+        Err(anyhow::anyhow!(
+            "handler panicked: {}",
+            panic_reason(&*panic)
+        ))
+    })
+}
+
+thread_local! {
+    /// Whether this thread is inside [`guarded`], whose panics are ours to
+    /// report.
+    static GUARDED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Has panics inside [`guarded`] logged as an `error` line carrying the
+/// backtrace. Every other panic, and those too with errors not logged, goes
+/// to whatever hook was set before, so the embedding program's or test
+/// harness's handling is left as it was.
+fn log_panics() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if !GUARDED.get() || !log::enabled(log::Level::Error) {
+            return previous(info);
+        }
+        log!(
+            Error,
+            "panic",
+            reason = panic_reason(info.payload()),
+            location = log::opt(info.location()),
+            backtrace = std::backtrace::Backtrace::force_capture(),
+        );
+    }));
+}
+
+fn panic_reason(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown cause".to_string())
+}
@@ -0,0 +1,598 @@
+//! Deterministic simulation: every node runs on the calling thread against a
+//! virtual clock, and a seeded RNG picks message latencies (and so delivery
+//! order) and injected drops and duplicates. Running again with the seed a
+//! failure printed replays the same interleaving.
+//!
+//! The build has no property-testing crate to lean on, so exploring many
+//! schedules means sweeping seeds: build a `Sim` per seed, drive it, and
+//! report [`Sim::seed`] for the first one whose outcome is wrong, after
+//! shrinking the schedule it drew while the failure reproduces (as
+//! `tests/property.rs` does).
+//!
+//! Replays are only exact if handlers send in a deterministic order, e.g.
+//! not straight out of a `HashMap` iteration.
+//!
+//! Nodes run the same periodic tasks as under
+//! [`run_with`](crate::runtime::run_with), rpc expiry included, so an
+//! unanswered [`Context::rpc_then`] times out on the virtual clock. Nothing
+//! else runs while a handler waits on a [`Context::rpc`] reply, so handlers
+//! should use `rpc_then` here. There's no kv service in the simulator,
+//! though, so the kv-backed workloads need a
+//! [`TestNet`](crate::testnet::TestNet).
+//!
+//! [`Sim::deliver_line`] is the entry point for fuzzing the input path; the
+//! `deliver_line` target in `fuzz/` drives it (`cargo fuzz run
+//! deliver_line`).
+
+use crate::checker::History;
+use crate::log;
+use crate::node::{now_ms, random_u64, seed_random};
+use crate::protocol::{Body, Kind, Message, MsgId, NodeId};
+use crate::runtime::{
+    Clock, Context, Handler, Output, PERIOD_RECHECK, Period, Task, accept_line, dispatch, env_or,
+    guarded, tasks,
+};
+use crate::testnet::CLIENT;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Time that only moves when the simulation, or a test holding it, says
+/// so. It starts at the real time it was created at and never goes back.
+pub struct VirtualClock {
+    base: Instant,
+    base_unix_ms: u64,
+    elapsed_ns: AtomicU64,
+}
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self {
+            base: Instant::now(),
+            base_unix_ms: now_ms(),
+            elapsed_ns: AtomicU64::new(0),
+        }
+    }
+    /// Time since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_ns.load(Ordering::Relaxed))
+    }
+    pub fn advance(&self, by: Duration) {
+        self.advance_to(self.elapsed() + by);
+    }
+    fn advance_to(&self, at: Duration) {
+        self.elapsed_ns
+            .fetch_max(at.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+    fn unix_ms(&self) -> u64 {
+        self.base_unix_ms + self.elapsed().as_millis() as u64
+    }
+    fn sleep_until(&self, at: Instant) {
+        self.advance_to(at.saturating_duration_since(self.base));
+    }
+}
+
+/// Faults injected on messages from one node to another.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Link {
+    /// Probability that a message is lost.
+    pub drop: f64,
+    /// Probability that a delivered message arrives twice.
+    pub duplicate: f64,
+    /// Added to every delivery's latency.
+    pub delay: Duration,
+    /// Drawn from instead of [`Sim::latency`].
+    pub latency: Option<Latency>,
+}
+
+const LONGEST_LATENCY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long a message takes to be delivered, drawn afresh for each from the
+/// simulation's seeded RNG.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Anywhere from the first to the second, inclusive.
+    Uniform(Duration, Duration),
+    /// Log-normal around `median`, with `sigma` the standard deviation of
+    /// its logarithm: mostly close to the median, with a long tail of slow
+    /// deliveries, cut off at a day.
+    LogNormal {
+        median: Duration,
+        sigma: f64,
+    },
+}
+impl Latency {
+    /// `fixed:MS`, `uniform:MIN_MS:MAX_MS` or `lognormal:MEDIAN_MS:SIGMA`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let mut parts = name.split(':');
+        let kind = parts.next()?;
+        let mut ms = || parts.next()?.parse().ok().map(Duration::from_millis);
+        let latency = match kind {
+            "fixed" => Latency::Fixed(ms()?),
+            "uniform" => Latency::Uniform(ms()?, ms()?),
+            "lognormal" => Latency::LogNormal {
+                median: ms()?,
+                sigma: parts
+                    .next()?
+                    .parse()
+                    .ok()
+                    .filter(|sigma: &f64| sigma.is_finite() && *sigma >= 0.0)?,
+            },
+            _ => return None,
+        };
+        parts.next().is_none().then_some(latency)
+    }
+    pub fn sample(&self) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform(min, max) => {
+                let spread = max.saturating_sub(min).as_nanos() as u64;
+                min + Duration::from_nanos(random_u64() % spread.saturating_add(1))
+            }
+            Latency::LogNormal { median, sigma } => {
+                // Box-Muller, with the first sample kept off zero.
+                let u1 = 1.0 - unit();
+                let u2 = unit();
+                let normal = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+                Duration::try_from_secs_f64(median.as_secs_f64() * (sigma * normal).exp())
+                    .map_or(LONGEST_LATENCY, |latency| latency.min(LONGEST_LATENCY))
+            }
+        }
+    }
+}
+
+enum SimEvent {
+    Deliver(Message<Value>),
+    Tick {
+        node: usize,
+        timer: usize,
+        generation: u64,
+    },
+}
+
+/// An event due at `at`; `seq` breaks ties in scheduling order.
+struct Scheduled {
+    at: Duration,
+    seq: u64,
+    event: SimEvent,
+}
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+impl Eq for Scheduled {}
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// When to next look at a task last run at `last`, as the runtime's timer
+/// loop would: when it's due, or sooner to notice its period changing.
+fn next_tick(period: &Period, last: Duration, now: Duration) -> Duration {
+    let recheck = now + PERIOD_RECHECK;
+    match period.get() {
+        interval if interval.is_zero() => recheck,
+        interval => (last + interval).min(recheck),
+    }
+}
+
+struct SimNode<H: Handler> {
+    handler: H,
+    ctx: Context,
+    sent: Arc<Mutex<Vec<String>>>,
+    tasks: Vec<(Period, Task<H>)>,
+    /// When each task last ran, or was last due if it never has.
+    last_run: Vec<Duration>,
+    /// Bumped on each [`Sim::restart`], so ticks for the old tasks lapse.
+    generation: u64,
+}
+
+/// Builds node `id` with `make`, its output collected for the network.
+fn start_node<H: Handler>(
+    id: &NodeId,
+    ids: &[NodeId],
+    clock: &Arc<VirtualClock>,
+    make: impl FnOnce(&Context) -> H,
+) -> SimNode<H> {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&sent);
+    let output = Output::spawn(move |line| {
+        if let Ok(mut sent) = sink.lock() {
+            sent.push(line);
+        }
+        Ok(())
+    });
+    let ctx = Context::new(id.clone(), ids.to_vec(), output, clock.clone());
+    let handler = make(&ctx);
+    let tasks = tasks(&handler);
+    SimNode {
+        handler,
+        ctx,
+        sent,
+        last_run: vec![clock.elapsed(); tasks.len()],
+        tasks,
+        generation: 0,
+    }
+}
+
+pub struct Sim<H: Handler> {
+    seed: u64,
+    clock: Arc<VirtualClock>,
+    nodes: Vec<SimNode<H>>,
+    index: HashMap<NodeId, usize>,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+    /// Delivery delays on links without one of their own.
+    pub latency: Latency,
+    links: HashMap<(NodeId, NodeId), Link>,
+    /// Messages sent from one node to another so far, lost ones included.
+    traffic: HashMap<(NodeId, NodeId), usize>,
+    outside: VecDeque<Message<Value>>,
+    next_msg_id: u64,
+    history: History,
+}
+
+/// The seed from `MAELLE_SIM_SEED`, or a fresh one to print on failure.
+pub fn seed_from_env() -> u64 {
+    env_or("MAELLE_SIM_SEED", random_u64())
+}
+
+impl<H: Handler> Sim<H> {
+    /// Builds one node per id with `make`, already initialized, and seeds
+    /// this thread's RNG with `seed`.
+    pub fn new(node_ids: &[&str], seed: u64, make: impl Fn(&Context) -> H) -> Self {
+        log!(Info, "sim_seed", seed = seed);
+        seed_random(seed);
+        let clock = Arc::new(VirtualClock::new());
+        let ids: Vec<NodeId> = node_ids.iter().map(|id| NodeId::from(*id)).collect();
+        let nodes: Vec<SimNode<H>> = ids
+            .iter()
+            .map(|id| start_node(id, &ids, &clock, &make))
+            .collect();
+        let mut sim = Self {
+            seed,
+            clock,
+            index: ids.into_iter().enumerate().map(|(i, id)| (id, i)).collect(),
+            nodes,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            latency: Latency::Uniform(Duration::from_millis(1), Duration::from_millis(50)),
+            links: HashMap::new(),
+            traffic: HashMap::new(),
+            outside: VecDeque::new(),
+            next_msg_id: 0,
+            history: History::default(),
+        };
+        for node in 0..sim.nodes.len() {
+            sim.schedule_tasks(node);
+        }
+        sim
+    }
+
+    /// Replaces node `id` with a fresh one from `make`, as if its process
+    /// were killed and started again: whatever it held in memory is gone,
+    /// and messages still in flight to it reach the new one.
+    pub fn restart(&mut self, id: &str, make: impl FnOnce(&Context) -> H) {
+        let Some(&node) = self.index.get(id) else {
+            return;
+        };
+        let ids = self.nodes[node].ctx.node_ids.clone();
+        let generation = self.nodes[node].generation + 1;
+        self.nodes[node] = SimNode {
+            generation,
+            ..start_node(&NodeId::from(id), &ids, &self.clock, make)
+        };
+        self.schedule_tasks(node);
+        self.collect(node);
+    }
+
+    fn schedule_tasks(&mut self, node: usize) {
+        let now = self.now();
+        let generation = self.nodes[node].generation;
+        for timer in 0..self.nodes[node].tasks.len() {
+            let at = next_tick(&self.nodes[node].tasks[timer].0, now, now);
+            self.schedule(
+                at,
+                SimEvent::Tick {
+                    node,
+                    timer,
+                    generation,
+                },
+            );
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Virtual time since the simulation started.
+    pub fn now(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// Sets the faults on messages from `src` to `dest`.
+    pub fn link(&mut self, src: &str, dest: &str, link: Link) {
+        self.links.insert((src.into(), dest.into()), link);
+    }
+
+    /// Drops everything between `a` and `b`, both ways.
+    pub fn partition(&mut self, a: &str, b: &str) {
+        let cut = Link {
+            drop: 1.0,
+            ..Link::default()
+        };
+        self.link(a, b, cut);
+        self.link(b, a, cut);
+    }
+
+    /// Clears the faults between `a` and `b`.
+    pub fn heal(&mut self, a: &str, b: &str) {
+        self.links.remove(&(a.into(), b.into()));
+        self.links.remove(&(b.into(), a.into()));
+    }
+
+    /// Sends `payload` from client [`CLIENT`] to `dest` and returns its msg_id.
+    /// Client traffic is never faulted.
+    pub fn send(&mut self, dest: &str, payload: impl Serialize) -> anyhow::Result<MsgId> {
+        self.next_msg_id += 1;
+        let msg_id = MsgId(self.next_msg_id);
+        let m = Message {
+            src: CLIENT.into(),
+            dest: dest.into(),
+            body: Body::request(msg_id, serde_json::to_value(payload)?),
+        };
+        self.history
+            .invoke(self.now(), &m.dest, msg_id, &m.body.payload);
+        let at = self.now() + self.latency.sample();
+        self.schedule(at, SimEvent::Deliver(m));
+        Ok(msg_id)
+    }
+
+    /// Sends `payload` and runs until its reply arrives, or `timeout` of
+    /// virtual time passes.
+    pub fn request(
+        &mut self,
+        dest: &str,
+        payload: impl Serialize,
+        timeout: Duration,
+    ) -> anyhow::Result<Message<Value>> {
+        let msg_id = self.send(dest, payload)?;
+        let deadline = self.now() + timeout;
+        let is_reply = |m: &Message<Value>| m.dest == CLIENT && m.body.in_reply_to == Some(msg_id);
+        loop {
+            if let Some(i) = self.outside.iter().position(is_reply) {
+                if let Some(m) = self.outside.remove(i) {
+                    return Ok(m);
+                }
+            }
+            if !self.step_until(deadline) {
+                anyhow::bail!(
+                    "no reply from {} to msg {} (seed {})",
+                    dest,
+                    msg_id,
+                    self.seed
+                );
+            }
+        }
+    }
+
+    /// Hands node `id` one raw input line, as if read from its stdin, and
+    /// runs it through the same parsing and dispatch. Meant for fuzzing:
+    /// whatever the bytes, the node logs or answers with an error but never
+    /// panics.
+    pub fn deliver_line(&mut self, id: &str, line: &str) {
+        let Some(&node) = self.index.get(id) else {
+            return;
+        };
+        let SimNode { handler, ctx, .. } = &mut self.nodes[node];
+        if let Some(m) = accept_line(ctx, line) {
+            dispatch(handler, ctx, m);
+        }
+        self.collect(node);
+    }
+
+    /// The next message that left the cluster, if any.
+    pub fn recv(&mut self) -> Option<Message<Value>> {
+        self.outside.pop_front()
+    }
+
+    /// Every request [`send`](Self::send) made of the nodes and every
+    /// reply to one so far, for [`crate::checker`].
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Hands `m` outside the cluster, noting it in the history if it's a
+    /// reply to the client.
+    fn leave(&mut self, m: Message<Value>) {
+        if let (true, Some(in_reply_to)) = (m.dest == CLIENT, m.body.in_reply_to) {
+            self.history
+                .complete(self.now(), in_reply_to, &m.body.payload);
+        }
+        self.outside.push_back(m);
+    }
+
+    /// Runs every event due within the next `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.now() + duration;
+        while self.step_until(until) {}
+        self.clock.advance_to(until);
+    }
+
+    /// Handles the next event if it's due by `until`; false if there is none.
+    fn step_until(&mut self, until: Duration) -> bool {
+        let due = self
+            .queue
+            .peek()
+            .is_some_and(|Reverse(next)| next.at <= until);
+        if !due {
+            return false;
+        }
+        let Some(Reverse(next)) = self.queue.pop() else {
+            return false;
+        };
+        self.clock.advance_to(next.at);
+        match next.event {
+            SimEvent::Deliver(mut m) => {
+                let Some(&node) = self.index.get(&m.dest) else {
+                    self.leave(m);
+                    return true;
+                };
+                let SimNode { handler, ctx, .. } = &mut self.nodes[node];
+                ctx.output().observe(&mut m);
+                ctx.stats().count_received(m.body.payload.kind());
+                if let Ok(Some(m)) = ctx.route_reply(m) {
+                    dispatch(handler, ctx, m);
+                }
+                self.collect(node);
+            }
+            SimEvent::Tick {
+                node,
+                timer,
+                generation,
+            } => {
+                // A task of a node since restarted.
+                if generation != self.nodes[node].generation {
+                    return true;
+                }
+                let SimNode {
+                    handler,
+                    ctx,
+                    tasks,
+                    last_run,
+                    ..
+                } = &mut self.nodes[node];
+                let (period, task) = (tasks[timer].0.clone(), tasks[timer].1);
+                let interval = period.get();
+                // Only woken to reread a period that may have changed.
+                if interval.is_zero() || next.at < last_run[timer] + interval {
+                    let at = next_tick(&period, last_run[timer], next.at);
+                    let tick = SimEvent::Tick {
+                        node,
+                        timer,
+                        generation,
+                    };
+                    self.schedule(at, tick);
+                    return true;
+                }
+                last_run[timer] = next.at;
+                let mut ctx = ctx.clone();
+                if let Err(e) = guarded(|| task(handler, &mut ctx)) {
+                    log!(
+                        Warn,
+                        "task_failed",
+                        timer = timer,
+                        error = format!("{:#}", e)
+                    );
+                }
+                self.collect(node);
+                let at = next_tick(&period, next.at, next.at);
+                let tick = SimEvent::Tick {
+                    node,
+                    timer,
+                    generation,
+                };
+                self.schedule(at, tick);
+            }
+        }
+        true
+    }
+
+    /// Puts what `node` sent onto the simulated network, and what it
+    /// scheduled for itself onto the queue.
+    fn collect(&mut self, node: usize) {
+        let now = self.clock.now();
+        for (at, m) in self.nodes[node].ctx.take_scheduled(None) {
+            let at = self.now() + at.saturating_duration_since(now);
+            self.schedule(at, SimEvent::Deliver(m));
+        }
+        self.nodes[node].ctx.output().flush();
+        let sent = match self.nodes[node].sent.lock() {
+            Ok(mut sent) => std::mem::take(&mut *sent),
+            Err(_) => return,
+        };
+        for line in sent {
+            let Ok(m) = serde_json::from_str::<Message<Value>>(&line) else {
+                continue;
+            };
+            if !self.index.contains_key(&m.dest) {
+                self.leave(m);
+                continue;
+            }
+            *self
+                .traffic
+                .entry((m.src.clone(), m.dest.clone()))
+                .or_default() += 1;
+            let link = self
+                .links
+                .get(&(m.src.clone(), m.dest.clone()))
+                .copied()
+                .unwrap_or_default();
+            if chance(link.drop) {
+                continue;
+            }
+            let latency = link.latency.unwrap_or(self.latency);
+            if chance(link.duplicate) {
+                let at = self.now() + link.delay + latency.sample();
+                self.schedule(at, SimEvent::Deliver(m.clone()));
+            }
+            let at = self.now() + link.delay + latency.sample();
+            self.schedule(at, SimEvent::Deliver(m));
+        }
+    }
+
+    fn schedule(&mut self, at: Duration, event: SimEvent) {
+        self.seq += 1;
+        self.queue.push(Reverse(Scheduled {
+            at,
+            seq: self.seq,
+            event,
+        }));
+    }
+
+    /// How many messages `src` has sent `dest`, lost ones included.
+    pub fn sent(&self, src: &str, dest: &str) -> usize {
+        let key = (NodeId::from(src), NodeId::from(dest));
+        self.traffic.get(&key).copied().unwrap_or(0)
+    }
+
+    /// The handler of node `id`, to inspect its state directly.
+    pub fn node(&self, id: &str) -> Option<&H> {
+        self.index.get(id).map(|&i| &self.nodes[i].handler)
+    }
+}
+
+fn chance(probability: f64) -> bool {
+    probability > 0.0 && unit() < probability
+}
+
+/// A sample in [0, 1).
+fn unit() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,299 @@
+//! An in-process stand-in for Maelstrom: runs several nodes on their own
+//! threads, routes their messages to each other by `dest`, and lets the
+//! caller play every other party, client `c1` included.
+//!
+//! Each node keeps its own stats, but they all share the process's log
+//! tagging, so logs are only meaningful for the cluster as a whole.
+//!
+//! [`TestNet::serve_kv`] stands in for Maelstrom's `lin-kv` and `seq-kv`
+//! with a [`FakeKv`], so kv-backed workloads run in-process too.
+
+use crate::kv::{self, LIN_KV, SEQ_KV};
+use crate::node::random_u64;
+use crate::protocol::{Body, ErrorCode, Message, MsgId, NodeId, Payload};
+use crate::runtime::{Context, Handler, Output, lock, run_with};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, mpsc},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// The client id a [`TestNet`] sends requests as.
+pub const CLIENT: &str = "c1";
+
+type Inputs = Arc<Mutex<HashMap<NodeId, mpsc::Sender<String>>>>;
+/// Stand-in services by name, e.g. `lin-kv`.
+type Services = Arc<Mutex<HashMap<NodeId, mpsc::Sender<Message<Value>>>>>;
+
+pub struct TestNet {
+    inputs: Inputs,
+    services: Services,
+    kv_history: Arc<Mutex<Vec<KvOp>>>,
+    /// Messages to anyone who isn't a node, in the order they were sent.
+    outside: mpsc::Receiver<Message<Value>>,
+    unclaimed: VecDeque<Message<Value>>,
+    nodes: Vec<JoinHandle<anyhow::Result<()>>>,
+    next_msg_id: u64,
+}
+impl TestNet {
+    /// Starts one node per id, each built by `make`, and completes the init
+    /// handshake with all of them.
+    pub fn start<H, F>(node_ids: &[&str], make: F) -> anyhow::Result<Self>
+    where
+        H: Handler,
+        F: Fn(&Context) -> H + Clone + Send + 'static,
+    {
+        let inputs: Inputs = Arc::new(Mutex::new(HashMap::new()));
+        let services: Services = Arc::new(Mutex::new(HashMap::new()));
+        let (outgoing, routed) = mpsc::channel::<String>();
+        let (to_outside, outside) = mpsc::channel();
+        let mut nodes = Vec::new();
+        for id in node_ids {
+            let (tx, rx) = mpsc::channel::<String>();
+            lock(&inputs).insert(NodeId::from(*id), tx);
+            let outgoing = outgoing.clone();
+            let output = Output::spawn(move |line| {
+                outgoing
+                    .send(line)
+                    .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+            });
+            let make = make.clone();
+            nodes.push(std::thread::spawn(move || {
+                run_with(rx.into_iter().map(Ok), output, make)
+            }));
+        }
+        let router_inputs = Arc::clone(&inputs);
+        let router_services = Arc::clone(&services);
+        std::thread::spawn(move || route(routed, router_inputs, router_services, to_outside));
+
+        let mut net = Self {
+            inputs,
+            services,
+            kv_history: Arc::new(Mutex::new(Vec::new())),
+            outside,
+            unclaimed: VecDeque::new(),
+            nodes,
+            next_msg_id: 0,
+        };
+        let all: Vec<&str> = node_ids.to_vec();
+        for id in node_ids {
+            let init = serde_json::json!({"type": "init", "node_id": id, "node_ids": all});
+            net.request(id, init, Duration::from_secs(5))?;
+        }
+        Ok(net)
+    }
+
+    /// Sends `payload` from [`CLIENT`] to `dest` and returns its msg_id.
+    pub fn send(&mut self, dest: &str, payload: impl Serialize) -> anyhow::Result<MsgId> {
+        self.next_msg_id += 1;
+        let msg_id = MsgId(self.next_msg_id);
+        self.deliver(CLIENT, dest, Body::request(msg_id, payload))?;
+        Ok(msg_id)
+    }
+
+    /// Delivers a message from any outside party, e.g. a fake `seq-kv`
+    /// answering a node's request.
+    pub fn deliver<P: Serialize>(
+        &self,
+        src: &str,
+        dest: &str,
+        body: Body<P>,
+    ) -> anyhow::Result<()> {
+        let line = serde_json::to_string(&Message {
+            src: src.into(),
+            dest: dest.into(),
+            body,
+        })?;
+        let inputs = lock(&self.inputs);
+        let Some(input) = inputs.get(dest) else {
+            anyhow::bail!("no node {}", dest);
+        };
+        input
+            .send(line)
+            .map_err(|_| anyhow::anyhow!("node {} has stopped", dest))
+    }
+
+    /// The next message addressed outside the cluster, waiting up to
+    /// `timeout` for one.
+    pub fn recv(&mut self, timeout: Duration) -> Option<Message<Value>> {
+        self.unclaimed
+            .pop_front()
+            .or_else(|| self.outside.recv_timeout(timeout).ok())
+    }
+
+    /// Sends `payload` and waits for the reply to it; anything else that
+    /// arrives meanwhile stays queued for [`recv`](Self::recv).
+    pub fn request(
+        &mut self,
+        dest: &str,
+        payload: impl Serialize,
+        timeout: Duration,
+    ) -> anyhow::Result<Message<Value>> {
+        let msg_id = self.send(dest, payload)?;
+        let deadline = Instant::now() + timeout;
+        let is_reply = |m: &Message<Value>| m.dest == CLIENT && m.body.in_reply_to == Some(msg_id);
+        if let Some(i) = self.unclaimed.iter().position(is_reply) {
+            if let Some(m) = self.unclaimed.remove(i) {
+                return Ok(m);
+            }
+        }
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let Ok(m) = self.outside.recv_timeout(left) else {
+                anyhow::bail!("no reply from {} to msg {}", dest, msg_id);
+            };
+            if is_reply(&m) {
+                return Ok(m);
+            }
+            self.unclaimed.push_back(m);
+        }
+    }
+
+    /// Answers requests to `lin-kv` and `seq-kv` with `kv` from now on.
+    pub fn serve_kv(&mut self, kv: FakeKv) -> anyhow::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut services = lock(&self.services);
+        services.insert(LIN_KV.into(), tx.clone());
+        services.insert(SEQ_KV.into(), tx);
+        let inputs = Arc::clone(&self.inputs);
+        let history = Arc::clone(&self.kv_history);
+        std::thread::spawn(move || kv.serve(rx, inputs, history));
+        Ok(())
+    }
+
+    /// Every operation the [`FakeKv`] has answered, in the order it applied
+    /// them.
+    pub fn kv_history(&self) -> anyhow::Result<Vec<KvOp>> {
+        Ok(lock(&self.kv_history).clone())
+    }
+
+    /// Closes every node's input and waits for them to finish.
+    pub fn shutdown(self) -> anyhow::Result<()> {
+        lock(&self.inputs).clear();
+        for node in self.nodes {
+            node.join()
+                .map_err(|_| anyhow::anyhow!("node thread panicked"))??;
+        }
+        Ok(())
+    }
+}
+
+/// Forwards each line to the node or service it's addressed to, and
+/// everything else to the outside.
+fn route(
+    routed: mpsc::Receiver<String>,
+    inputs: Inputs,
+    services: Services,
+    outside: mpsc::Sender<Message<Value>>,
+) {
+    for line in routed {
+        let Ok(m) = serde_json::from_str::<Message<Value>>(&line) else {
+            continue;
+        };
+        let input = inputs
+            .lock()
+            .ok()
+            .and_then(|inputs| inputs.get(&m.dest).cloned());
+        if let Some(input) = input {
+            let _ = input.send(line);
+            continue;
+        }
+        let service = services
+            .lock()
+            .ok()
+            .and_then(|services| services.get(&m.dest).cloned());
+        match service {
+            Some(service) => {
+                let _ = service.send(m);
+            }
+            None => {
+                let _ = outside.send(m);
+            }
+        }
+    }
+}
+
+/// One request a [`FakeKv`] answered.
+#[derive(Clone, Debug)]
+pub struct KvOp {
+    pub service: NodeId,
+    pub client: NodeId,
+    pub request: Payload,
+    pub reply: Payload,
+    /// When the request arrived and when its reply was handed back.
+    pub invoked: Instant,
+    pub completed: Instant,
+}
+
+/// An in-memory `lin-kv`/`seq-kv`: read, write and cas over one store per
+/// service, with Maelstrom's error codes. Requests are applied one at a
+/// time in arrival order, so both services are linearizable; `seq-kv`'s
+/// stale reads aren't modelled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FakeKv {
+    /// How long each reply takes to arrive.
+    pub latency: Duration,
+    /// Probability that a request fails with a temporarily-unavailable
+    /// error instead of being applied.
+    pub failure_rate: f64,
+}
+impl FakeKv {
+    fn serve(
+        self,
+        requests: mpsc::Receiver<Message<Value>>,
+        inputs: Inputs,
+        history: Arc<Mutex<Vec<KvOp>>>,
+    ) {
+        let mut stores = HashMap::new();
+        let mut next_msg_id = 0;
+        for m in requests {
+            let invoked = Instant::now();
+            let Ok(request) = m.parse_body::<Payload>() else {
+                continue;
+            };
+            let Some(msg_id) = request.body.msg_id else {
+                continue;
+            };
+            let request = request.body.payload;
+            let failed = self.failure_rate > 0.0
+                && ((random_u64() >> 11) as f64 / (1u64 << 53) as f64) < self.failure_rate;
+            let reply = if failed {
+                kv::error(ErrorCode::TemporarilyUnavailable, "injected failure")
+            } else {
+                kv::apply(stores.entry(m.dest.clone()).or_default(), &request)
+            };
+            std::thread::sleep(self.latency);
+            next_msg_id += 1;
+            let body = Body {
+                msg_id: Some(MsgId(next_msg_id)),
+                in_reply_to: Some(msg_id),
+                payload: &reply,
+            };
+            let line = serde_json::to_string(&Message {
+                src: m.dest.clone(),
+                dest: m.src.clone(),
+                body,
+            });
+            let input = inputs
+                .lock()
+                .ok()
+                .and_then(|inputs| inputs.get(&m.src).cloned());
+            if let (Ok(line), Some(input)) = (line, input) {
+                let _ = input.send(line);
+            }
+            if let Ok(mut history) = history.lock() {
+                history.push(KvOp {
+                    service: m.dest,
+                    client: m.src,
+                    request,
+                    reply,
+                    invoked,
+                    completed: Instant::now(),
+                });
+            }
+        }
+    }
+}
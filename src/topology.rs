@@ -0,0 +1,328 @@
+//! Graphs every node derives identically from the sorted `node_ids`, for
+//! [`TopologyMode`](crate::node::TopologyMode). Each generator takes the
+//! node count and returns the undirected edges between positions in the
+//! sorted ids, lower position first, without duplicates; [`adjacency`]
+//! turns them into the neighbor map broadcast works from, and [`to_dot`]
+//! into a picture of it.
+
+use crate::protocol::NodeId;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Each node links to the next `fanout` after it in breadth-first order.
+pub fn tree(n: usize, fanout: usize) -> Vec<(usize, usize)> {
+    let fanout = fanout.max(1);
+    (1..n).map(|i| ((i - 1) / fanout, i)).collect()
+}
+
+pub fn star(n: usize) -> Vec<(usize, usize)> {
+    (1..n).map(|i| (0, i)).collect()
+}
+
+/// Consecutive runs of `size` form clusters represented by their first;
+/// the representatives are all linked, the rest only to their own.
+pub fn clusters(n: usize, size: usize) -> Vec<(usize, usize)> {
+    let size = size.max(1);
+    let representatives: Vec<usize> = (0..n).step_by(size).collect();
+    let mut edges: Vec<(usize, usize)> = representatives
+        .iter()
+        .enumerate()
+        .flat_map(|(i, a)| representatives[i + 1..].iter().map(move |b| (*a, *b)))
+        .collect();
+    edges.extend((0..n).filter(|i| i % size != 0).map(|i| (i - i % size, i)));
+    edges
+}
+
+/// The nodes laid out row by row in a square as close to `n` as fits, each
+/// linked to the ones beside and below it; at most 4 neighbors apiece.
+pub fn grid(n: usize) -> Vec<(usize, usize)> {
+    let side = (1..).find(|side| side * side >= n).unwrap_or(1);
+    let mut edges = Vec::new();
+    for i in 0..n {
+        if (i + 1) % side != 0 && i + 1 < n {
+            edges.push((i, i + 1));
+        }
+        if i + side < n {
+            edges.push((i, i + side));
+        }
+    }
+    edges
+}
+
+/// A cycle through every node, plus `chords` shortcuts from each to the
+/// nodes 2, 4, 8... places on, as in Chord's finger tables: at most
+/// `2 + 2 * chords` neighbors apiece, and a diameter logarithmic in `n`
+/// once `chords` reaches log2 of it.
+pub fn ring(n: usize, chords: usize) -> Vec<(usize, usize)> {
+    let mut edges = BTreeSet::new();
+    for i in 0..n {
+        let offsets = std::iter::once(1).chain((1..=chords.min(63)).map(|j| 1usize << j));
+        for offset in offsets.take_while(|offset| *offset < n) {
+            edges.insert(edge(i, (i + offset) % n));
+        }
+    }
+    edges.into_iter().filter(|(a, b)| a != b).collect()
+}
+
+/// Tries at a random graph before falling back to a circulant one.
+const RANDOM_ATTEMPTS: usize = 100;
+
+/// A connected graph where every node has `degree` neighbors, drawn from
+/// `seed`; one node has one fewer when `n * degree` is odd, and `degree`
+/// is raised to 2, since nothing less connects more than two nodes, and
+/// capped at `n - 1`. Should the draws keep failing, e.g. for a
+/// `degree` close to `n`, each node is linked to its `degree / 2` nearest
+/// on a ring instead (and across it, for an odd `degree` and even `n`).
+pub fn random_regular(n: usize, degree: usize, seed: u64) -> Vec<(usize, usize)> {
+    let degree = degree.max(2).min(n.saturating_sub(1));
+    let mut rng = SplitMix(seed);
+    for _ in 0..RANDOM_ATTEMPTS {
+        if let Some(edges) = pair_up(n, degree, &mut rng) {
+            if is_connected(n, &edges) {
+                return edges;
+            }
+        }
+    }
+    let mut edges = BTreeSet::new();
+    for i in 0..n {
+        for offset in 1..=degree / 2 {
+            edges.insert(edge(i, (i + offset) % n));
+        }
+        if degree % 2 == 1 && n.is_multiple_of(2) {
+            edges.insert(edge(i, (i + n / 2) % n));
+        }
+    }
+    edges.into_iter().filter(|(a, b)| a != b).collect()
+}
+
+/// Pairs off `degree` stubs per node at random, each with one that makes
+/// neither a loop nor a repeated edge; `None` if it paints itself into a
+/// corner.
+fn pair_up(n: usize, degree: usize, rng: &mut SplitMix) -> Option<Vec<(usize, usize)>> {
+    let mut stubs: Vec<usize> = (0..n)
+        .flat_map(|i| std::iter::repeat_n(i, degree))
+        .collect();
+    if stubs.len() % 2 == 1 {
+        stubs.pop();
+    }
+    let mut edges = BTreeSet::new();
+    while let Some(a) = stubs.pop() {
+        let candidates: Vec<usize> = (0..stubs.len())
+            .filter(|&i| stubs[i] != a && !edges.contains(&edge(a, stubs[i])))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let b = stubs.swap_remove(candidates[rng.below(candidates.len())]);
+        edges.insert(edge(a, b));
+    }
+    Some(edges.into_iter().collect())
+}
+
+/// Whether every node can reach every other along `edges`.
+pub fn is_connected(n: usize, edges: &[(usize, usize)]) -> bool {
+    let mut neighbors = vec![Vec::new(); n];
+    for &(a, b) in edges {
+        neighbors[a].push(b);
+        neighbors[b].push(a);
+    }
+    let mut seen = vec![false; n];
+    let mut queue: VecDeque<usize> = (0..n.min(1)).collect();
+    while let Some(i) = queue.pop_front() {
+        if std::mem::replace(&mut seen[i], true) {
+            continue;
+        }
+        queue.extend(neighbors[i].iter().filter(|&&j| !seen[j]));
+    }
+    seen.into_iter().all(|seen| seen)
+}
+
+/// The neighbors of each of `ids` along `edges`, as sorted positions in
+/// `ids` index them.
+pub fn adjacency(ids: &[NodeId], edges: &[(usize, usize)]) -> HashMap<NodeId, Vec<NodeId>> {
+    let mut topology: HashMap<NodeId, Vec<NodeId>> =
+        ids.iter().map(|id| (id.clone(), Vec::new())).collect();
+    for &(a, b) in edges {
+        topology
+            .entry(ids[a].clone())
+            .or_default()
+            .push(ids[b].clone());
+        topology
+            .entry(ids[b].clone())
+            .or_default()
+            .push(ids[a].clone());
+    }
+    topology
+}
+
+/// `topology` as an undirected Graphviz graph of `ids` and the links
+/// between them, `this` node highlighted and its links labeled with the
+/// round-trip times in `rtt`. Anything not a node, e.g. a client, is left
+/// out.
+pub fn to_dot(
+    ids: &[NodeId],
+    topology: &HashMap<NodeId, Vec<NodeId>>,
+    this: Option<&NodeId>,
+    rtt: &HashMap<NodeId, Duration>,
+) -> String {
+    let mut nodes: BTreeSet<&NodeId> = ids.iter().collect();
+    let mut edges: BTreeMap<(&NodeId, &NodeId), Option<Duration>> = BTreeMap::new();
+    for (a, neighbors) in topology {
+        for b in neighbors {
+            if !a.is_node() || !b.is_node() || a == b {
+                continue;
+            }
+            nodes.extend([a, b]);
+            let rtt = match this {
+                Some(this) if this == a => rtt.get(b).copied(),
+                Some(this) if this == b => rtt.get(a).copied(),
+                _ => None,
+            };
+            edges.insert((a.min(b), a.max(b)), rtt);
+        }
+    }
+    let mut dot = String::from("graph topology {\n");
+    for node in nodes.into_iter().filter(|node| node.is_node()) {
+        let style = if Some(node) == this {
+            " [style=filled, fillcolor=lightblue]"
+        } else {
+            ""
+        };
+        let _ = writeln!(dot, "  \"{}\"{};", node, style);
+    }
+    for ((a, b), rtt) in edges {
+        let _ = match rtt {
+            Some(rtt) => writeln!(
+                dot,
+                "  \"{}\" -- \"{}\" [label=\"{}ms\"];",
+                a,
+                b,
+                rtt.as_millis()
+            ),
+            None => writeln!(dot, "  \"{}\" -- \"{}\";", a, b),
+        };
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn edge(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+/// SplitMix64: every node must draw the same graph from the same seed,
+/// whatever its own random stream is doing.
+struct SplitMix(u64);
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: std::ops::RangeInclusive<usize> = 1..=25;
+
+    fn degrees(n: usize, edges: &[(usize, usize)]) -> Vec<usize> {
+        let mut degrees = vec![0; n];
+        for &(a, b) in edges {
+            degrees[a] += 1;
+            degrees[b] += 1;
+        }
+        degrees
+    }
+
+    /// Edges run lower position first, within `n`, once each.
+    fn assert_well_formed(n: usize, edges: &[(usize, usize)]) {
+        assert!(edges.iter().all(|&(a, b)| a < b && b < n), "{:?}", edges);
+        let unique: BTreeSet<_> = edges.iter().collect();
+        assert_eq!(unique.len(), edges.len(), "{:?}", edges);
+        assert!(is_connected(n, edges), "{} nodes: {:?}", n, edges);
+    }
+
+    #[test]
+    fn trees_are_connected_and_acyclic() {
+        for n in SIZES {
+            for fanout in 1..=4 {
+                let edges = tree(n, fanout);
+                assert_well_formed(n, &edges);
+                // Connected with n - 1 edges leaves no room for a cycle.
+                assert_eq!(edges.len(), n - 1, "{} nodes, fanout {}", n, fanout);
+                assert!(degrees(n, &edges).iter().all(|d| *d <= fanout + 1));
+            }
+            assert_eq!(star(n).len(), n - 1);
+            assert_well_formed(n, &star(n));
+        }
+    }
+
+    #[test]
+    fn clusters_are_connected() {
+        for n in SIZES {
+            for size in 1..=6 {
+                assert_well_formed(n, &clusters(n, size));
+            }
+        }
+    }
+
+    #[test]
+    fn grids_have_at_most_four_neighbors() {
+        for n in SIZES {
+            let edges = grid(n);
+            assert_well_formed(n, &edges);
+            assert!(degrees(n, &edges).iter().all(|d| *d <= 4), "{} nodes", n);
+        }
+    }
+
+    #[test]
+    fn rings_have_at_most_two_neighbors_plus_two_a_chord() {
+        for n in SIZES {
+            for chords in 0..=4 {
+                let edges = ring(n, chords);
+                assert_well_formed(n, &edges);
+                let degrees = degrees(n, &edges);
+                assert!(degrees.iter().all(|d| *d <= 2 + 2 * chords), "{} nodes", n);
+            }
+        }
+    }
+
+    #[test]
+    fn random_graphs_are_regular() {
+        for n in SIZES {
+            for degree in 1..=5 {
+                for seed in 0..4 {
+                    let edges = random_regular(n, degree, seed);
+                    assert_well_formed(n, &edges);
+                    let degree = degree.max(2).min(n - 1);
+                    let mut degrees = degrees(n, &edges);
+                    degrees.sort();
+                    let context = format!("{} nodes, degree {}, seed {}", n, degree, seed);
+                    if n * degree % 2 == 1 {
+                        assert_eq!(degrees[0], degree - 1, "{}", context);
+                        degrees.remove(0);
+                    }
+                    assert!(degrees.iter().all(|d| *d == degree), "{}", context);
+                    assert_eq!(edges, random_regular(n, degree, seed), "{}", context);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn adjacency_links_both_ways() {
+        let ids: Vec<NodeId> = ["n1", "n2", "n3"].into_iter().map(NodeId::from).collect();
+        let topology = adjacency(&ids, &star(3));
+        assert_eq!(topology["n1"], vec![NodeId::from("n2"), NodeId::from("n3")]);
+        assert_eq!(topology["n2"], vec![NodeId::from("n1")]);
+        assert_eq!(topology["n3"], vec![NodeId::from("n1")]);
+    }
+}
@@ -0,0 +1,171 @@
+//! Write-ahead log: with `MAELLE_WAL` set to a path, every change to the
+//! broadcast values, g-set elements, counter and kafka logs is appended
+//! there as a JSON line, and a node that starts up over an existing log
+//! replays it first, so a restart (e.g. Maelstrom's crash nemesis) keeps
+//! what was acknowledged.
+//!
+//! Lines go through a buffered writer on its own thread and are fsynced
+//! every `MAELLE_WAL_FSYNC_MS` (0 for every line); a crash loses whatever
+//! was written since the last fsync. Each entry carries the resulting
+//! state rather than the change, so replaying one twice does no harm.
+//! OR-set and txn state aren't logged.
+
+use crate::log;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::mpsc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op")]
+#[serde(rename_all = "snake_case")]
+pub enum WalEntry {
+    Message {
+        value: Value,
+    },
+    Element {
+        value: Value,
+    },
+    /// `node`'s totals in the pn-counter after the change.
+    Counter {
+        node: String,
+        increments: u64,
+        decrements: u64,
+    },
+    Append {
+        key: String,
+        offset: usize,
+        msg: usize,
+    },
+    /// The committed offset for `key` after the change.
+    Commit {
+        key: String,
+        offset: usize,
+    },
+}
+
+pub struct Wal {
+    tx: Option<mpsc::Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+impl Wal {
+    /// Opens the log at `path` for appending, first cutting off a final
+    /// line left half-written by a crash.
+    pub fn open(path: impl AsRef<Path>, fsync_every: Duration) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        drop_partial_line(&mut file)?;
+        let (tx, rx) = mpsc::channel::<String>();
+        let path = path.display().to_string();
+        let writer = std::thread::spawn(move || {
+            let mut file = BufWriter::new(file);
+            let mut synced = Instant::now();
+            let mut dirty = false;
+            loop {
+                let written = match rx.recv_timeout(fsync_every.max(Duration::from_millis(1))) {
+                    Ok(line) => {
+                        dirty = true;
+                        file.write_all(line.as_bytes())
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => Ok(()),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        let _ = sync(&mut file);
+                        return;
+                    }
+                };
+                let written = written.and_then(|()| {
+                    if dirty && synced.elapsed() >= fsync_every {
+                        dirty = false;
+                        synced = Instant::now();
+                        return sync(&mut file);
+                    }
+                    Ok(())
+                });
+                if let Err(e) = written {
+                    log!(Error, "wal_failed", path = path, error = e);
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            tx: Some(tx),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn append(&self, entry: &WalEntry) {
+        let Ok(mut line) = serde_json::to_string(entry) else {
+            return;
+        };
+        line.push('\n');
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(line);
+        }
+    }
+}
+impl Drop for Wal {
+    /// Waits for everything appended to be written and fsynced.
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn sync(file: &mut BufWriter<File>) -> std::io::Result<()> {
+    file.flush()?;
+    file.get_ref().sync_data()
+}
+
+fn drop_partial_line(file: &mut File) -> std::io::Result<()> {
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    let complete = contents
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    if complete < contents.len() {
+        log!(
+            Warn,
+            "wal_truncated",
+            dropped_bytes = contents.len() - complete
+        );
+        file.set_len(complete as u64)?;
+    }
+    Ok(())
+}
+
+/// Reads every entry of the log at `path`, or none if there's no log yet.
+/// A final line that doesn't parse is taken to be one a crash cut short and
+/// skipped; anywhere else it's an error.
+pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Vec<WalEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let lines: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()?;
+    let mut entries = Vec::new();
+    for (n, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if n + 1 == lines.len() => {}
+            Err(e) => anyhow::bail!("bad wal line {}: {}", n + 1, e),
+        }
+    }
+    Ok(entries)
+}
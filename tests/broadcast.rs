@@ -0,0 +1,301 @@
+//! The broadcast workload's edge cases, in the simulator.
+#![cfg(feature = "broadcast")]
+
+use maelle::node::{Node, Workload, random_u64};
+use maelle::protocol::{NodeId, Payload};
+use maelle::sim::Sim;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const IDS: [&str; 3] = ["n0", "n1", "n2"];
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn cluster(seed: u64) -> Sim<Node> {
+    Sim::new(&IDS, seed, |ctx| Node::new(ctx, Workload::Broadcast))
+}
+
+fn broadcast(sim: &mut Sim<Node>, dest: &str, message: Value) {
+    let broadcast = Payload::Broadcast {
+        message,
+        stamp: None,
+    };
+    let reply = sim.request(dest, broadcast, TIMEOUT).unwrap();
+    let reply = reply.parse_body::<Payload>().unwrap().body.payload;
+    assert!(matches!(reply, Payload::BroadcastOk), "{:?}", reply);
+}
+
+fn read(sim: &mut Sim<Node>, dest: &str) -> Vec<Value> {
+    let reply = sim.request(dest, Payload::Read { key: None }, TIMEOUT);
+    match reply.unwrap().parse_body::<Payload>().unwrap().body.payload {
+        Payload::ReadOk {
+            messages: Some(messages),
+            ..
+        } => messages,
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn broadcast_before_any_topology_reaches_everyone() {
+    let mut sim = cluster(27);
+    broadcast(&mut sim, "n0", 1.into());
+    sim.run_for(Duration::from_secs(2));
+    for id in IDS {
+        assert_eq!(read(&mut sim, id), vec![Value::from(1)], "{}", id);
+    }
+}
+
+#[test]
+fn topology_without_our_own_entry_falls_back_to_everyone() {
+    let mut sim = cluster(28);
+    let n = |id: &str| NodeId::from(id);
+    // n1 and n2 are listed as each other's neighbors, and n0 not at all.
+    let topology: HashMap<NodeId, Vec<NodeId>> =
+        HashMap::from([(n("n1"), vec![n("n2")]), (n("n2"), vec![n("n1")])]);
+    for id in IDS {
+        let topology = Payload::Topology {
+            topology: topology.clone(),
+        };
+        let reply = sim.request(id, topology, TIMEOUT).unwrap();
+        let reply = reply.parse_body::<Payload>().unwrap().body.payload;
+        assert!(matches!(reply, Payload::TopologyOk), "{}: {:?}", id, reply);
+    }
+    broadcast(&mut sim, "n0", 1.into());
+    sim.run_for(Duration::from_secs(2));
+    for id in IDS {
+        assert_eq!(read(&mut sim, id), vec![Value::from(1)], "{}", id);
+    }
+}
+
+#[test]
+fn reads_list_values_in_the_same_order_every_time() {
+    let mut sim = cluster(29);
+    let mut values: Vec<Value> = (0..40).map(|i| Value::from(i * 7 % 40)).collect();
+    values.extend([json!("b"), json!("a"), json!({"k": 1}), json!(-3)]);
+    for (i, value) in values.iter().enumerate() {
+        let dest = IDS[(random_u64() as usize + i) % IDS.len()];
+        broadcast(&mut sim, dest, value.clone());
+    }
+    sim.run_for(Duration::from_secs(2));
+
+    let first = read(&mut sim, "n0");
+    assert_eq!(first.len(), values.len());
+    let integers: Vec<i64> = first.iter().filter_map(Value::as_i64).collect();
+    assert!(integers.is_sorted(), "{:?}", integers);
+    for id in IDS {
+        for _ in 0..3 {
+            assert_eq!(read(&mut sim, id), first, "{}", id);
+        }
+    }
+}
+
+#[test]
+fn a_lost_value_is_pulled_in_when_the_next_shows_the_gap() {
+    use maelle::node::{NodeConfig, RetryPolicy};
+    use maelle::sim::Link;
+
+    // Nothing but a pull can repair the loss: no retries in time, and no
+    // gossip or anti-entropy rounds.
+    let never = Duration::from_secs(3600);
+    let config = NodeConfig {
+        retry_policy: RetryPolicy {
+            base: never,
+            max_interval: never,
+            ..RetryPolicy::from_env()
+        },
+        gossip_interval: Duration::ZERO,
+        sync_interval: Duration::ZERO,
+        ..NodeConfig::from_env(Workload::Broadcast)
+    };
+    let mut sim = Sim::new(&["n0", "n1"], 57, |ctx| {
+        Node::with_config(ctx, config.clone())
+    });
+    let lost = Link {
+        drop: 1.0,
+        ..Link::default()
+    };
+    sim.link("n0", "n1", lost);
+    broadcast(&mut sim, "n0", 1.into());
+    sim.run_for(Duration::from_millis(200));
+    assert!(read(&mut sim, "n1").is_empty());
+
+    sim.heal("n0", "n1");
+    broadcast(&mut sim, "n0", 2.into());
+    sim.run_for(Duration::from_secs(1));
+    assert_eq!(read(&mut sim, "n1"), vec![Value::from(1), Value::from(2)]);
+    let contiguous = sim
+        .node("n1")
+        .and_then(|node| node.origins.get("n0"))
+        .map(|log| log.contiguous);
+    assert_eq!(contiguous, Some(2));
+}
+
+#[test]
+fn a_restarted_node_catches_up_from_its_neighbors() {
+    use maelle::node::NodeConfig;
+
+    // No gossip or anti-entropy rounds, so only catch-up can refill it.
+    let config = NodeConfig {
+        gossip_interval: Duration::ZERO,
+        sync_interval: Duration::ZERO,
+        ..NodeConfig::from_env(Workload::Broadcast)
+    };
+    let make = |ctx: &_| Node::with_config(ctx, config.clone());
+    let mut sim = Sim::new(&IDS, 66, make);
+    for message in 0..20 {
+        broadcast(&mut sim, IDS[message % IDS.len()], message.into());
+    }
+    sim.run_for(Duration::from_secs(1));
+    let all = read(&mut sim, "n0");
+    assert_eq!(all.len(), 20);
+
+    sim.restart("n1", make);
+    assert!(sim.node("n1").unwrap().messages.is_empty());
+    sim.run_for(Duration::from_secs(1));
+    assert_eq!(read(&mut sim, "n1"), all, "seed {}", sim.seed());
+
+    // And it's back in the cluster for what comes next.
+    broadcast(&mut sim, "n1", 20.into());
+    sim.run_for(Duration::from_secs(1));
+    for id in IDS {
+        assert_eq!(read(&mut sim, id).len(), 21, "{}", id);
+    }
+}
+
+#[test]
+fn a_slow_link_stretches_only_that_neighbors_interval() {
+    use maelle::node::{NodeConfig, Pacing};
+    use maelle::sim::{Latency, Link};
+
+    let pacing = Pacing {
+        rtt_multiple: 4.0,
+        floor: Duration::from_millis(50),
+        ceiling: Duration::from_secs(5),
+    };
+    let config = NodeConfig {
+        pacing: Some(pacing),
+        ..NodeConfig::from_env(Workload::Broadcast)
+    };
+    let mut sim = Sim::new(&IDS, 91, |ctx| Node::with_config(ctx, config.clone()));
+    sim.latency = Latency::Fixed(Duration::from_millis(5));
+    let slow = Link {
+        latency: Some(Latency::Fixed(Duration::from_millis(200))),
+        ..Link::default()
+    };
+    sim.link("n0", "n2", slow);
+    sim.link("n2", "n0", slow);
+    for message in 0..30 {
+        broadcast(&mut sim, IDS[message % IDS.len()], message.into());
+        sim.run_for(Duration::from_millis(100));
+    }
+    sim.run_for(Duration::from_secs(3));
+
+    let interval =
+        |sim: &Sim<Node>, id: &str, peer: &str| sim.node(id).unwrap().gossip_interval_for(peer);
+    // A 400ms round trip, four times over; the rest sit at the floor.
+    assert!(interval(&sim, "n0", "n2") >= Duration::from_millis(1200));
+    assert!(interval(&sim, "n2", "n0") >= Duration::from_millis(1200));
+    assert_eq!(interval(&sim, "n0", "n1"), pacing.floor);
+    assert_eq!(interval(&sim, "n1", "n0"), pacing.floor);
+    assert_eq!(interval(&sim, "n1", "n2"), pacing.floor);
+    assert_eq!(interval(&sim, "n2", "n1"), pacing.floor);
+    for id in IDS {
+        assert_eq!(read(&mut sim, id).len(), 30, "{}", id);
+    }
+}
+
+#[test]
+fn swapping_the_topology_mid_broadcast_still_converges() {
+    use maelle::topology;
+
+    let ids = ["n0", "n1", "n2", "n3", "n4"];
+    let mut sim = Sim::new(&ids, 94, |ctx| Node::new(ctx, Workload::Broadcast));
+    let node_ids: Vec<NodeId> = ids.iter().map(|id| NodeId::from(*id)).collect();
+    let set_topology = |sim: &mut Sim<Node>, edges: &[(usize, usize)]| {
+        let topology = topology::adjacency(&node_ids, edges);
+        for id in ids {
+            let topology = Payload::Topology {
+                topology: topology.clone(),
+            };
+            sim.send(id, topology).unwrap();
+        }
+    };
+    set_topology(&mut sim, &topology::tree(ids.len(), 1));
+    sim.run_for(Duration::from_millis(100));
+    for message in 0..40 {
+        if message == 20 {
+            // Sent without waiting, so it lands among broadcasts in flight.
+            set_topology(&mut sim, &topology::star(ids.len()));
+        }
+        let dest = ids[message % ids.len()];
+        let broadcast = Payload::Broadcast {
+            message: message.into(),
+            stamp: None,
+        };
+        sim.send(dest, broadcast).unwrap();
+        sim.run_for(Duration::from_millis(10));
+    }
+    sim.run_for(Duration::from_secs(5));
+
+    let expected: Vec<Value> = (0..40).map(Value::from).collect();
+    for id in ids {
+        assert_eq!(read(&mut sim, id), expected, "{} (seed {})", id, sim.seed());
+        // Nothing left waiting on a neighbor it no longer has.
+        assert!(sim.node(id).unwrap().callbacks.is_empty(), "{}", id);
+    }
+}
+
+#[test]
+fn held_acks_ride_on_batches_and_save_messages() {
+    use maelle::node::NodeConfig;
+    use maelle::topology;
+
+    let ids: Vec<String> = (0..9).map(|i| format!("n{}", i)).collect();
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    let node_ids: Vec<NodeId> = ids.iter().map(|id| NodeId::from(*id)).collect();
+    let grid = topology::adjacency(&node_ids, &topology::grid(ids.len()));
+    let run = |ack_delay: Duration| {
+        let config = NodeConfig {
+            batch_window: Duration::from_millis(20),
+            ack_delay,
+            ..NodeConfig::from_env(Workload::Broadcast)
+        };
+        let mut sim = Sim::new(&ids, 92, |ctx| Node::with_config(ctx, config.clone()));
+        for id in &ids {
+            let topology = Payload::Topology {
+                topology: grid.clone(),
+            };
+            sim.request(id, topology, TIMEOUT).unwrap();
+        }
+        let before: usize = pairs(&ids).map(|(a, b)| sim.sent(a, b)).sum();
+        for message in 0..100 {
+            broadcast(&mut sim, ids[message % ids.len()], message.into());
+            sim.run_for(Duration::from_millis(10));
+        }
+        sim.run_for(Duration::from_secs(3));
+        let expected: Vec<Value> = (0..100).map(Value::from).collect();
+        for id in &ids {
+            assert_eq!(read(&mut sim, id), expected, "{} with {:?}", id, ack_delay);
+            // Held acks settle callbacks just as replies do.
+            assert!(sim.node(id).unwrap().callbacks.is_empty(), "{}", id);
+        }
+        pairs(&ids).map(|(a, b)| sim.sent(a, b)).sum::<usize>() - before
+    };
+    let replied = run(Duration::ZERO);
+    let held = run(Duration::from_millis(50));
+    // Each batch that goes back anyway carries the acks for the ones that
+    // came in: at least a tenth fewer messages between nodes.
+    assert!(
+        held * 10 < replied * 9,
+        "{} messages with acks held, {} without",
+        held,
+        replied
+    );
+}
+
+/// Every ordered pair of distinct nodes.
+fn pairs<'a>(ids: &'a [&'a str]) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+    ids.iter()
+        .flat_map(move |a| ids.iter().filter(move |b| a != *b).map(move |b| (*a, *b)))
+}
@@ -0,0 +1,53 @@
+//! Command-line flags, and which workloads take them.
+
+use maelle::builder::NodeBuilder;
+
+fn build(args: &str) -> anyhow::Result<maelle::node::NodeConfig> {
+    NodeBuilder::new()
+        .args(args.split_whitespace().map(String::from))
+        .build()
+}
+
+#[test]
+fn flags_for_another_workload_are_refused() {
+    let e = build("echo --gossip-ms 5 --topology star")
+        .unwrap_err()
+        .to_string();
+    assert!(e.contains("--gossip-ms only applies to"), "{}", e);
+    assert!(e.contains("--topology only applies to broadcast"), "{}", e);
+    let e = build("echo --id-strategy snowflake")
+        .unwrap_err()
+        .to_string();
+    assert!(
+        e.contains("--id-strategy only applies to unique-ids"),
+        "{}",
+        e
+    );
+}
+
+#[test]
+fn flags_for_every_workload_go_with_any() {
+    build("echo --log-level off --retry-base-ms 50").unwrap();
+    build("unique-ids --id-strategy snowflake").unwrap();
+}
+
+#[cfg(feature = "broadcast")]
+#[test]
+fn a_workload_given_after_its_flags_still_takes_them() {
+    use maelle::node::{TopologyMode, Workload};
+
+    let config = build("--topology star --gossip-ms 5 --workload broadcast").unwrap();
+    assert_eq!(config.workload, Workload::Broadcast);
+    assert_eq!(config.topology_mode, TopologyMode::Star);
+    let e = build("broadcast --sync-ms 100 --workload echo").unwrap_err();
+    assert!(e.to_string().contains("--sync-ms"), "{}", e);
+}
+
+#[cfg(feature = "counter")]
+#[test]
+fn gossip_flags_go_with_every_gossiping_workload() {
+    build("g-set --gossip-ms 200 --gossip-floor-ms 50").unwrap();
+    build("g-counter --gossip-ms 200").unwrap();
+    let e = build("kv-counter --gossip-ms 200").unwrap_err();
+    assert!(e.to_string().contains("--gossip-ms"), "{}", e);
+}
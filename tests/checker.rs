@@ -0,0 +1,133 @@
+//! The history checks, over histories written out by hand.
+
+use maelle::checker::{History, check_broadcast, check_counter};
+use maelle::protocol::{MsgId, NodeId};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+struct Client {
+    history: History,
+    next_msg_id: u64,
+    time: Duration,
+}
+impl Client {
+    fn new() -> Self {
+        Self {
+            history: History::default(),
+            next_msg_id: 0,
+            time: Duration::ZERO,
+        }
+    }
+    fn invoke(&mut self, node: &str, payload: Value) -> MsgId {
+        self.next_msg_id += 1;
+        self.time += Duration::from_millis(1);
+        let msg_id = MsgId(self.next_msg_id);
+        self.history
+            .invoke(self.time, &NodeId::from(node), msg_id, &payload);
+        msg_id
+    }
+    fn complete(&mut self, msg_id: MsgId, payload: Value) {
+        self.time += Duration::from_millis(1);
+        self.history.complete(self.time, msg_id, &payload);
+    }
+    fn call(&mut self, node: &str, payload: Value, reply: Value) {
+        let msg_id = self.invoke(node, payload);
+        self.complete(msg_id, reply);
+    }
+    fn add(&mut self, node: &str, delta: i64) -> MsgId {
+        self.invoke(node, json!({"type": "add", "delta": delta}))
+    }
+    fn read_counter(&mut self, node: &str, value: i64) {
+        self.call(
+            node,
+            json!({"type": "read"}),
+            json!({"type": "read_ok", "value": value}),
+        );
+    }
+}
+
+fn error(code: u64) -> Value {
+    json!({"type": "error", "code": code, "text": "failed"})
+}
+
+#[test]
+fn counter_sums_acknowledged_adds() {
+    let mut client = Client::new();
+    let add = client.add("n1", 5);
+    client.complete(add, json!({"type": "add_ok"}));
+    let add = client.add("n2", -2);
+    client.complete(add, json!({"type": "add_ok"}));
+    client.read_counter("n1", 3);
+    client.read_counter("n2", 3);
+    assert!(check_counter(&client.history).is_ok());
+
+    client.read_counter("n2", 5);
+    let violation = check_counter(&client.history).unwrap_err();
+    assert!(violation.reason.contains("n2"), "{}", violation);
+}
+
+#[test]
+fn counter_timeouts_and_crashes_may_or_may_not_count() {
+    for code in [0, 13] {
+        for read in [1, 11] {
+            let mut client = Client::new();
+            let add = client.add("n1", 1);
+            client.complete(add, json!({"type": "add_ok"}));
+            let add = client.add("n1", 10);
+            client.complete(add, error(code));
+            client.read_counter("n1", read);
+            assert!(
+                check_counter(&client.history).is_ok(),
+                "code {}, read {}",
+                code,
+                read
+            );
+        }
+    }
+}
+
+#[test]
+fn counter_definite_failures_never_count() {
+    let mut client = Client::new();
+    let add = client.add("n1", 1);
+    client.complete(add, json!({"type": "add_ok"}));
+    let add = client.add("n1", 10);
+    client.complete(add, error(11));
+    client.read_counter("n1", 11);
+    assert!(check_counter(&client.history).is_err());
+}
+
+#[test]
+fn broadcast_reads_hold_every_acknowledged_value() {
+    let mut client = Client::new();
+    client.call(
+        "n1",
+        json!({"type": "broadcast", "message": 1}),
+        json!({"type": "broadcast_ok"}),
+    );
+    client.call(
+        "n1",
+        json!({"type": "read"}),
+        json!({"type": "read_ok", "messages": [1]}),
+    );
+    client.call(
+        "n2",
+        json!({"type": "read"}),
+        json!({"type": "read_ok", "messages": []}),
+    );
+    let violation = check_broadcast(&client.history).unwrap_err();
+    assert!(violation.reason.contains("n2"), "{}", violation);
+    assert!(!violation.ops.is_empty());
+
+    client.call(
+        "n2",
+        json!({"type": "read"}),
+        json!({"type": "read_ok", "messages": [1, 2]}),
+    );
+    let violation = check_broadcast(&client.history).unwrap_err();
+    assert!(
+        violation.reason.contains("never broadcast"),
+        "{}",
+        violation
+    );
+}
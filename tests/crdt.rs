@@ -0,0 +1,78 @@
+//! The counter and set workloads converge over gossip, in the simulator.
+#![cfg(feature = "counter")]
+
+use maelle::checker::check_counter;
+use maelle::node::{Node, Workload, random_u64};
+use maelle::protocol::Payload;
+use maelle::sim::Sim;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+const IDS: [&str; 3] = ["n0", "n1", "n2"];
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn cluster(workload: Workload, seed: u64) -> Sim<Node> {
+    Sim::new(&IDS, seed, |ctx| Node::new(ctx, workload))
+}
+
+fn read(sim: &mut Sim<Node>, id: &str) -> Option<Value> {
+    let reply = sim.request(id, Payload::Read { key: None }, TIMEOUT).ok()?;
+    match reply.parse_body::<Payload>().ok()?.body.payload {
+        Payload::ReadOk { value, .. } => value,
+        _ => None,
+    }
+}
+
+#[test]
+fn counter_sums_adds_from_every_node() {
+    let mut sim = cluster(Workload::Counter, 2);
+    let mut sum = 0;
+    for i in 0..60 {
+        let delta = (random_u64() % 21) as i64 - 10;
+        sum += delta;
+        let element = None;
+        sim.send(IDS[i % IDS.len()], Payload::Add { delta, element })
+            .unwrap();
+        sim.run_for(Duration::from_millis(20));
+    }
+    sim.run_for(Duration::from_secs(2));
+    for id in IDS {
+        assert_eq!(
+            read(&mut sim, id),
+            Some(sum.into()),
+            "{} (seed {})",
+            id,
+            sim.seed()
+        );
+    }
+    check_counter(sim.history()).unwrap_or_else(|violation| panic!("{}", violation));
+}
+
+#[test]
+fn g_set_converges_on_every_node() {
+    let mut sim = cluster(Workload::GSet, 12);
+    let mut added = BTreeSet::new();
+    for i in 0..60 {
+        // Values of every shape, some added more than once.
+        let element = match random_u64() % 4 {
+            0 => Value::from(random_u64() % 20),
+            1 => Value::from(format!("s{}", random_u64() % 20)),
+            2 => serde_json::json!({"k": random_u64() % 5}),
+            _ => serde_json::json!([i % 3, null]),
+        };
+        added.insert(element.to_string());
+        let element = Some(element);
+        sim.send(IDS[i % IDS.len()], Payload::Add { delta: 0, element })
+            .unwrap();
+        sim.run_for(Duration::from_millis(20));
+    }
+    sim.run_for(Duration::from_secs(2));
+    for id in IDS {
+        let elements: BTreeSet<String> = match read(&mut sim, id) {
+            Some(Value::Array(elements)) => elements.iter().map(Value::to_string).collect(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(elements, added, "{} (seed {})", id, sim.seed());
+    }
+}
@@ -0,0 +1,53 @@
+//! Leader election by heartbeat, in the simulator.
+
+use maelle::node::{Node, NodeConfig, Workload};
+use maelle::protocol::NodeId;
+use maelle::sim::Sim;
+use std::time::Duration;
+
+const IDS: [&str; 5] = ["n0", "n1", "n2", "n3", "n4"];
+const TICK: Duration = Duration::from_millis(100);
+/// Two of the longest election timeouts, at the default of one to two
+/// seconds.
+const TICKS: usize = 40;
+
+/// The leader all of `among` follow, if they agree on one.
+fn agreed(sim: &Sim<Node>, among: &[&str]) -> Option<(usize, NodeId)> {
+    let mut views = among.iter().filter_map(|id| sim.node(id)).map(|node| {
+        let election = &node.election;
+        (election.term, election.leader.clone())
+    });
+    let first = views.next()?;
+    views.all(|view| view == first).then_some(first)
+}
+
+#[test]
+fn a_new_leader_takes_over_within_the_timeout() {
+    let config = NodeConfig {
+        heartbeat_interval: TICK,
+        ..NodeConfig::from_env(Workload::Echo)
+    };
+    let mut sim = Sim::new(&IDS, 60, |ctx| Node::with_config(ctx, config.clone()));
+    sim.run_for(TICK * 10);
+    assert_eq!(agreed(&sim, &IDS), Some((0, NodeId::from("n0"))));
+
+    // Kill the leader, as far as everyone else can tell.
+    for id in &IDS[1..] {
+        sim.partition("n0", id);
+    }
+    let rest = &IDS[1..];
+    for tick in 0..TICKS {
+        sim.run_for(TICK);
+        if let Some((term, leader)) = agreed(&sim, rest) {
+            if leader != "n0" {
+                assert!(term > 0);
+                assert!(rest.contains(&leader.as_str()));
+                // And it stays put while it keeps heartbeating.
+                sim.run_for(TICK * 30);
+                assert_eq!(agreed(&sim, rest), Some((term, leader)));
+                return;
+            }
+        }
+        assert!(tick < TICKS - 1, "no new leader (seed {})", sim.seed());
+    }
+}
@@ -0,0 +1,183 @@
+//! Handlers that fail or panic, and messages nothing was waiting for: the
+//! node answers what it can and carries on, in the simulator.
+
+use maelle::node::{Node, Workload};
+use maelle::protocol::{ErrorCode, ErrorReply, Message, Payload};
+use maelle::runtime::{Context, Handler, RpcError};
+use maelle::sim::Sim;
+use serde_json::{Value, json};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn payload(reply: Message<Value>) -> Payload {
+    reply.parse_body::<Payload>().unwrap().body.payload
+}
+
+fn error_code(reply: Message<Value>) -> usize {
+    match payload(reply) {
+        Payload::Error { code, .. } => code,
+        other => panic!("{:?}", other),
+    }
+}
+
+fn echo(sim: &mut Sim<impl Handler>, dest: &str, echo: &str) -> Message<Value> {
+    let echo = Payload::Echo { echo: echo.into() };
+    sim.request(dest, echo, TIMEOUT).unwrap()
+}
+
+fn assert_echoes(sim: &mut Sim<impl Handler>, dest: &str) {
+    match payload(echo(sim, dest, "still here")) {
+        Payload::EchoOk { echo } => assert_eq!(echo, "still here"),
+        other => panic!("{:?}", other),
+    }
+}
+
+/// Echoes, unless told to fail or to panic.
+struct Fragile;
+impl Handler for Fragile {
+    type Payload = Payload;
+    fn handle(&mut self, ctx: &mut Context, m: Message) -> anyhow::Result<()> {
+        let Payload::Echo { echo } = m.body.payload else {
+            return Ok(());
+        };
+        match echo.as_str() {
+            "unavailable" => Err(ErrorReply::new(ErrorCode::TemporarilyUnavailable, "busy").into()),
+            "fail" => anyhow::bail!("something broke"),
+            "panic" => panic!("handler panicked"),
+            _ => ctx.reply(Payload::EchoOk { echo }),
+        }
+    }
+}
+
+#[test]
+fn failed_handlers_get_error_replies() {
+    let mut sim = Sim::new(&["n1"], 41, |_| Fragile);
+    let reply = echo(&mut sim, "n1", "unavailable");
+    assert_eq!(
+        error_code(reply),
+        ErrorCode::TemporarilyUnavailable as usize
+    );
+    let reply = echo(&mut sim, "n1", "fail");
+    assert_eq!(error_code(reply), ErrorCode::Crash as usize);
+    assert_echoes(&mut sim, "n1");
+}
+
+#[test]
+fn bad_requests_get_error_replies() {
+    let mut sim = Sim::new(&["n1"], 42, |ctx| Node::new(ctx, Workload::Echo));
+    let reply = sim.request("n1", json!({"type": "echo"}), TIMEOUT).unwrap();
+    assert_eq!(error_code(reply), ErrorCode::MalformedRequest as usize);
+    let reply = sim.request("n1", json!({"type": "nonsense"}), TIMEOUT);
+    assert_eq!(error_code(reply.unwrap()), ErrorCode::NotSupported as usize);
+    assert_echoes(&mut sim, "n1");
+}
+
+#[test]
+fn a_panic_is_a_crash_and_the_node_carries_on() {
+    let mut sim = Sim::new(&["n1"], 86, |_| Fragile);
+    let reply = echo(&mut sim, "n1", "panic");
+    assert_eq!(error_code(reply), ErrorCode::Crash as usize);
+    assert_echoes(&mut sim, "n1");
+    let reply = echo(&mut sim, "n1", "panic");
+    assert_eq!(error_code(reply), ErrorCode::Crash as usize);
+    assert_echoes(&mut sim, "n1");
+}
+
+#[cfg(feature = "broadcast")]
+#[test]
+fn duplicate_and_stray_broadcast_oks_are_ignored() {
+    use maelle::protocol::NodeId;
+    use maelle::sim::Link;
+    use maelle::topology;
+
+    let ids = ["n0", "n1", "n2"];
+    let mut sim = Sim::new(&ids, 25, |ctx| Node::new(ctx, Workload::Broadcast));
+    let node_ids: Vec<NodeId> = ids.into_iter().map(NodeId::from).collect();
+    let adjacency = topology::adjacency(&node_ids, &topology::star(ids.len()));
+    for id in ids {
+        let topology = Payload::Topology {
+            topology: adjacency.clone(),
+        };
+        sim.request(id, topology, TIMEOUT).unwrap();
+    }
+    // Everything between the nodes, acks included, arrives twice.
+    for a in ids {
+        for b in ids.into_iter().filter(|b| *b != a) {
+            let twice = Link {
+                duplicate: 1.0,
+                ..Link::default()
+            };
+            sim.link(a, b, twice);
+        }
+    }
+    for message in 0..10 {
+        let broadcast = Payload::Broadcast {
+            message: message.into(),
+            stamp: None,
+        };
+        sim.request(ids[message % ids.len()], broadcast, TIMEOUT)
+            .unwrap();
+    }
+    // And an ack for something never sent, twice over.
+    let stray = json!({"src": "n1", "dest": "n0", "body": {
+        "type": "broadcast_ok", "msg_id": 1_000, "in_reply_to": 999,
+    }});
+    sim.deliver_line("n0", &stray.to_string());
+    sim.deliver_line("n0", &stray.to_string());
+    sim.run_for(Duration::from_secs(2));
+
+    for id in ids {
+        let reply = sim.request(id, Payload::Read { key: None }, TIMEOUT);
+        match payload(reply.unwrap()) {
+            Payload::ReadOk {
+                messages: Some(messages),
+                ..
+            } => assert_eq!(messages.len(), 10, "{}: {:?}", id, messages),
+            other => panic!("{:?}", other),
+        }
+    }
+}
+
+/// Passes a client's echo on to `n2` and answers with how that went.
+struct Relay;
+impl Handler for Relay {
+    type Payload = Payload;
+    fn handle(&mut self, ctx: &mut Context, m: Message) -> anyhow::Result<()> {
+        let Payload::Echo { echo } = m.body.payload else {
+            return Ok(());
+        };
+        if !m.src.is_client() {
+            return ctx.reply(Payload::EchoOk { echo });
+        }
+        let echo = Payload::Echo { echo };
+        let timeout = Duration::from_secs(3);
+        ctx.rpc_then(&"n2".into(), echo, timeout, |_: &mut Self, ctx, reply| {
+            let echo = match reply {
+                Ok(_) => "relayed",
+                Err(RpcError::Timeout) => "timed out",
+                Err(e) => panic!("{:?}", e),
+            };
+            ctx.reply(Payload::EchoOk { echo: echo.into() })
+        })
+    }
+}
+
+#[test]
+fn an_unanswered_rpc_times_out_on_the_virtual_clock() {
+    let mut sim = Sim::new(&["n1", "n2"], 76, |_| Relay);
+    sim.partition("n1", "n2");
+    sim.send("n1", Payload::Echo { echo: "hi".into() }).unwrap();
+    sim.run_for(Duration::from_secs(2));
+    assert!(sim.recv().is_none(), "answered before the timeout");
+    sim.run_for(Duration::from_secs(2));
+    let answered = |reply: Option<Message<Value>>| match payload(reply.unwrap()) {
+        Payload::EchoOk { echo } => echo,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(answered(sim.recv()), "timed out");
+
+    sim.heal("n1", "n2");
+    let reply = echo(&mut sim, "n1", "hi");
+    assert_eq!(answered(Some(reply)), "relayed");
+}
@@ -0,0 +1,50 @@
+//! The `generate` workload's id strategies.
+
+use maelle::node::{Node, SNOWFLAKE_SEQUENCE_MASK, Snowflake, Workload};
+use maelle::protocol::NodeId;
+use maelle::runtime::{Context, Output, SystemClock};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[test]
+fn counter_ids_are_unique_across_threads() {
+    let ids = vec![NodeId::from("n1"), NodeId::from("n2")];
+    let output = Output::spawn(|_| Ok(()));
+    let ctx = Context::new(ids[0].clone(), ids, output, Arc::new(SystemClock));
+    let node = Node::new(&ctx, Workload::UniqueIds);
+    let generated: Vec<String> = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..8)
+            .map(|_| scope.spawn(|| (0..1_250).map(|_| node.gen_unique_id()).collect::<Vec<_>>()))
+            .collect();
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect()
+    });
+    assert_eq!(generated.len(), 10_000);
+    let unique: HashSet<&String> = generated.iter().collect();
+    assert_eq!(unique.len(), generated.len());
+}
+
+#[test]
+fn snowflakes_survive_the_clock_stepping_back() {
+    let mut snowflake = Snowflake::new(3);
+    // A second of ids, then the clock is set back half a second and runs
+    // on from there; one millisecond is busy enough to use up its sequence.
+    let mut times: Vec<u64> = (0..1_000).map(|ms| 1_700_000_000_000 + ms).collect();
+    times.extend((500..1_500).map(|ms| 1_700_000_000_000 + ms));
+    times.extend(std::iter::repeat_n(
+        1_700_000_000_400,
+        SNOWFLAKE_SEQUENCE_MASK as usize * 2,
+    ));
+    let mut clock = times
+        .into_iter()
+        .chain(std::iter::repeat(1_700_000_001_600));
+
+    let mut last = 0;
+    for _ in 0..10_000 {
+        let id = snowflake.next(|| clock.next().unwrap());
+        assert!(id > last, "{} after {}", id, last);
+        last = id;
+    }
+}
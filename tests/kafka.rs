@@ -0,0 +1,50 @@
+//! The kafka-style log workload kept in the node itself, in the simulator.
+#![cfg(feature = "kafka")]
+
+use maelle::node::{Node, Workload, random_u64};
+use maelle::protocol::Payload;
+use maelle::sim::Sim;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn committed(sim: &mut Sim<Node>, keys: &[&str]) -> HashMap<String, usize> {
+    let keys = keys.iter().map(|key| key.to_string()).collect();
+    let list = Payload::ListCommittedOffsets { keys };
+    let reply = sim.request("n1", list, TIMEOUT).unwrap();
+    match reply.parse_body::<Payload>().unwrap().body.payload {
+        Payload::ListCommittedOffsetsOk { offsets } => offsets,
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn commits_out_of_order_never_move_back() {
+    let mut sim = Sim::new(&["n1"], 6, |ctx| Node::new(ctx, Workload::Echo));
+    let mut highest: HashMap<String, usize> = HashMap::new();
+    for i in 0..200 {
+        let key = ["a", "b"][i % 2];
+        let offset = (random_u64() % 50) as usize;
+        // From c1, or from a second client with its own msg_ids.
+        if random_u64().is_multiple_of(2) {
+            let offsets = HashMap::from([(key.to_string(), offset)]);
+            sim.request("n1", Payload::CommitOffsets { offsets }, TIMEOUT)
+                .unwrap();
+        } else {
+            let commit = json!({"src": "c2", "dest": "n1", "body": {
+                "type": "commit_offsets", "msg_id": i, "offsets": {key: offset},
+            }});
+            sim.deliver_line("n1", &commit.to_string());
+        }
+        let highest = highest.entry(key.to_string()).or_default();
+        *highest = offset.max(*highest);
+
+        let offsets = committed(&mut sim, &[key]);
+        assert_eq!(offsets.get(key), Some(&*highest), "seed {}", sim.seed());
+    }
+    // Keys never committed are left out, not given as zero.
+    let offsets = committed(&mut sim, &["a", "never"]);
+    assert!(!offsets.contains_key("never"));
+}
@@ -0,0 +1,156 @@
+//! The kv-backed workloads against a [`FakeKv`], on a [`TestNet`]: their
+//! kv requests wait on the dispatcher rather than blocking it.
+#![cfg(any(feature = "counter", feature = "kafka"))]
+
+use maelle::node::{Node, Workload};
+use maelle::protocol::{Message, Payload};
+use maelle::testnet::{FakeKv, TestNet};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn start(ids: &[&str], workload: Workload, latency: Duration) -> TestNet {
+    let mut net = TestNet::start(ids, move |ctx| Node::new(ctx, workload)).unwrap();
+    let kv = FakeKv {
+        latency,
+        ..FakeKv::default()
+    };
+    net.serve_kv(kv).unwrap();
+    net
+}
+
+fn payload(reply: Message<serde_json::Value>) -> Payload {
+    reply.parse_body::<Payload>().unwrap().body.payload
+}
+
+#[cfg(feature = "counter")]
+#[test]
+fn echo_is_answered_while_adds_wait_on_the_kv() {
+    let latency = Duration::from_millis(20);
+    let mut net = start(&["n1"], Workload::KvCounter, latency);
+    let adds: Vec<_> = (1..=5)
+        .map(|delta| {
+            let element = None;
+            net.send("n1", Payload::Add { delta, element }).unwrap()
+        })
+        .collect();
+    let echo = Payload::Echo {
+        echo: "still here".into(),
+    };
+    let reply = net.request("n1", echo, TIMEOUT).unwrap();
+    assert!(matches!(payload(reply), Payload::EchoOk { .. }));
+    // Blocking, the echo would wait on every add's read and cas.
+    assert!(
+        net.recv(Duration::ZERO).is_none(),
+        "an add finished before the echo"
+    );
+
+    let mut acked = 0;
+    while acked < adds.len() {
+        let reply = net.recv(TIMEOUT).expect("every add is answered");
+        if reply.body.in_reply_to.is_some_and(|id| adds.contains(&id)) {
+            assert!(matches!(payload(reply), Payload::AddOk));
+            acked += 1;
+        }
+    }
+    let reply = net.request("n1", Payload::Read { key: None }, TIMEOUT);
+    match payload(reply.unwrap()) {
+        Payload::ReadOk { value, .. } => assert_eq!(value, Some(15.into())),
+        other => panic!("{:?}", other),
+    }
+    net.shutdown().unwrap();
+}
+
+#[cfg(feature = "counter")]
+#[test]
+fn concurrent_adds_from_several_nodes_all_count() {
+    let ids = ["n1", "n2", "n3"];
+    let mut net = start(&ids, Workload::KvCounter, Duration::from_millis(1));
+    let mut adds = Vec::new();
+    for i in 0..30 {
+        let element = None;
+        let add = Payload::Add { delta: 1, element };
+        adds.push(net.send(ids[i % ids.len()], add).unwrap());
+    }
+    let mut acked = 0;
+    while acked < adds.len() {
+        let reply = net.recv(TIMEOUT).expect("every add is answered");
+        if reply.body.in_reply_to.is_some_and(|id| adds.contains(&id)) {
+            acked += 1;
+        }
+    }
+    for id in ids {
+        let reply = net.request(id, Payload::Read { key: None }, TIMEOUT);
+        match payload(reply.unwrap()) {
+            Payload::ReadOk { value, .. } => assert_eq!(value, Some(30.into()), "{}", id),
+            other => panic!("{:?}", other),
+        }
+    }
+    net.shutdown().unwrap();
+}
+
+#[cfg(feature = "kafka")]
+#[test]
+fn kafka_logs_and_offsets_live_in_the_kv() {
+    use std::collections::HashMap;
+
+    let mut net = start(&["n1", "n2"], Workload::KvKafka, Duration::ZERO);
+    for (i, msg) in [10, 11, 12].into_iter().enumerate() {
+        let dest = ["n1", "n2"][i % 2];
+        let send = Payload::Send {
+            key: "k".into(),
+            msg,
+        };
+        match payload(net.request(dest, send, TIMEOUT).unwrap()) {
+            Payload::SendOk { offset } => assert_eq!(offset, i),
+            other => panic!("{:?}", other),
+        }
+    }
+    let poll = Payload::Poll {
+        offsets: HashMap::from([("k".to_string(), 1), ("empty".to_string(), 0)]),
+    };
+    match payload(net.request("n2", poll, TIMEOUT).unwrap()) {
+        Payload::PollOk { msgs } => {
+            assert_eq!(msgs["k"], vec![[1, 11], [2, 12]]);
+            assert!(msgs["empty"].is_empty());
+        }
+        other => panic!("{:?}", other),
+    }
+    let commit = Payload::CommitOffsets {
+        offsets: HashMap::from([("k".to_string(), 2)]),
+    };
+    let reply = net.request("n1", commit, TIMEOUT).unwrap();
+    assert!(matches!(payload(reply), Payload::CommitOffsetsOk));
+    let list = Payload::ListCommittedOffsets {
+        keys: vec!["k".into(), "empty".into()],
+    };
+    match payload(net.request("n2", list, TIMEOUT).unwrap()) {
+        Payload::ListCommittedOffsetsOk { offsets } => {
+            assert_eq!(offsets, HashMap::from([("k".to_string(), 2)]));
+        }
+        other => panic!("{:?}", other),
+    }
+    net.shutdown().unwrap();
+}
+
+#[cfg(feature = "kafka")]
+#[test]
+fn kafka_commits_in_the_kv_never_move_back() {
+    use std::collections::HashMap;
+
+    let mut net = start(&["n1", "n2"], Workload::KvKafka, Duration::ZERO);
+    for (dest, offset) in [("n1", 5), ("n2", 3), ("n1", 4), ("n2", 7), ("n1", 6)] {
+        let commit = Payload::CommitOffsets {
+            offsets: HashMap::from([("k".to_string(), offset)]),
+        };
+        net.request(dest, commit, TIMEOUT).unwrap();
+    }
+    let list = Payload::ListCommittedOffsets {
+        keys: vec!["k".into()],
+    };
+    match payload(net.request("n2", list, TIMEOUT).unwrap()) {
+        Payload::ListCommittedOffsetsOk { offsets } => assert_eq!(offsets["k"], 7),
+        other => panic!("{:?}", other),
+    }
+    net.shutdown().unwrap();
+}
@@ -0,0 +1,109 @@
+//! The distributions simulated deliveries draw their latency from.
+
+use maelle::node::{Node, Workload, seed_random};
+use maelle::protocol::Payload;
+use maelle::sim::{Latency, Sim};
+use std::time::Duration;
+
+const SAMPLES: usize = 10_000;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+/// `SAMPLES` draws from `latency`, sorted.
+fn sorted_samples(latency: Latency, seed: u64) -> Vec<Duration> {
+    seed_random(seed);
+    let mut samples: Vec<Duration> = (0..SAMPLES).map(|_| latency.sample()).collect();
+    samples.sort();
+    samples
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+#[test]
+fn names_parse_to_their_distributions() {
+    let parse = Latency::from_name;
+    assert!(matches!(parse("fixed:5"), Some(Latency::Fixed(d)) if d == ms(5)));
+    assert!(matches!(
+        parse("uniform:1:50"),
+        Some(Latency::Uniform(min, max)) if (min, max) == (ms(1), ms(50))
+    ));
+    assert!(matches!(
+        parse("lognormal:20:0.5"),
+        Some(Latency::LogNormal { median, sigma }) if median == ms(20) && sigma == 0.5
+    ));
+    for bad in [
+        "",
+        "fixed",
+        "fixed:5:6",
+        "uniform:1",
+        "lognormal:20:-1",
+        "lognormal:20:NaN",
+        "pareto:1",
+    ] {
+        assert!(parse(bad).is_none(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn fixed_is_always_the_same() {
+    let samples = sorted_samples(Latency::Fixed(ms(7)), 98);
+    assert!(samples.iter().all(|&sample| sample == ms(7)));
+}
+
+#[test]
+fn uniform_spreads_evenly_between_its_bounds() {
+    let samples = sorted_samples(Latency::Uniform(ms(10), ms(30)), 98);
+    assert!(samples[0] >= ms(10) && samples[SAMPLES - 1] <= ms(30));
+    let median = percentile(&samples, 0.5);
+    assert!(median > ms(19) && median < ms(21), "{:?}", median);
+    // Each quarter of the range gets about a quarter of the draws.
+    let below_15 = samples.iter().filter(|&&sample| sample < ms(15)).count();
+    assert!((2_250..2_750).contains(&below_15), "{}", below_15);
+    // An empty range is just its one value.
+    let samples = sorted_samples(Latency::Uniform(ms(4), ms(4)), 98);
+    assert!(samples.iter().all(|&sample| sample == ms(4)));
+}
+
+#[test]
+fn log_normal_centers_on_its_median_with_a_long_tail() {
+    let median = ms(20);
+    let samples = sorted_samples(Latency::LogNormal { median, sigma: 0.5 }, 98);
+    let p50 = percentile(&samples, 0.5);
+    assert!(p50 > ms(19) && p50 < ms(21), "{:?}", p50);
+    // Slow by a factor of e^(2.33 sigma) at the 99th percentile, about
+    // 3.2x, while as fast draws are only as much under.
+    let p99 = percentile(&samples, 0.99);
+    assert!(p99 > ms(55) && p99 < ms(75), "{:?}", p99);
+    let p01 = percentile(&samples, 0.01);
+    assert!(p01 > ms(5) && p01 < ms(8), "{:?}", p01);
+    assert!(p99 - p50 > (p50 - p01) * 3);
+
+    // No spread at all is the median every time.
+    let samples = sorted_samples(Latency::LogNormal { median, sigma: 0.0 }, 98);
+    assert!(samples.iter().all(|&sample| sample == median));
+    // And a wild one is cut off at a day rather than overflowing.
+    let day = Duration::from_secs(24 * 60 * 60);
+    let samples = sorted_samples(
+        Latency::LogNormal {
+            median,
+            sigma: 50.0,
+        },
+        98,
+    );
+    assert_eq!(samples[SAMPLES - 1], day);
+}
+
+#[test]
+fn the_sim_delivers_after_the_latency_drawn() {
+    let mut sim = Sim::new(&["n1"], 98, |ctx| Node::new(ctx, Workload::Echo));
+    sim.latency = Latency::Fixed(ms(15));
+    let echo = Payload::Echo { echo: "hi".into() };
+    let sent_at = sim.now();
+    sim.request("n1", echo, Duration::from_secs(1)).unwrap();
+    // The reply leaves the cluster as soon as it's sent.
+    assert_eq!(sim.now() - sent_at, ms(15));
+}
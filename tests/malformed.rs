@@ -0,0 +1,126 @@
+//! A corpus of broken input lines, of the kind the fuzz target turns up,
+//! fed to a node in the simulator: each gets an error reply, and none
+//! brings the node down.
+
+use maelle::node::{Node, Workload};
+use maelle::protocol::{ErrorCode, Message, Payload};
+use maelle::sim::Sim;
+use serde_json::Value;
+use std::time::Duration;
+
+/// A request from c1 to n1 with `body` spliced in after its msg_id.
+fn request(msg_id: u64, body: &str) -> String {
+    format!(
+        r#"{{"src":"c1","dest":"n1","body":{{"msg_id":{},{}}}}}"#,
+        msg_id, body
+    )
+}
+
+/// Lines that are wrong in some way but still show who sent them and which
+/// request they were, with that request's msg_id.
+fn corpus() -> Vec<(u64, String)> {
+    let huge = "x".repeat(1 << 20);
+    let deep = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+    #[allow(unused_mut)]
+    let mut corpus = vec![
+        // Cut off halfway, and cut off in a huge string.
+        (
+            1,
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"type":"echo","echo":"#.to_string(),
+        ),
+        (
+            2,
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"msg_id":2,"echo":"{}"#,
+                huge
+            ),
+        ),
+        (3, request(3, r#""type":"echo","echo":5"#)),
+        (4, request(4, r#""type":"echo""#)),
+        (5, request(5, &format!(r#""type":"{}""#, huge))),
+        (6, request(6, r#""type":null"#)),
+        (7, request(7, r#""type":"read","key":1e400"#)),
+        (8, request(8, r#""type":"echo","echo":"\ud800""#)),
+        (
+            9,
+            r#"{"src":"c1","dest":"n1","dest":"n1","body":{"msg_id":9,"type":"echo","echo":"a"}}"#
+                .to_string(),
+        ),
+        (
+            10,
+            format!(
+                r#"{{"src":"c1","body":{{"msg_id":10,"type":"echo","echo":{}}},"dest":"n1"}}"#,
+                deep
+            ),
+        ),
+    ];
+    #[cfg(feature = "kafka")]
+    corpus.extend([
+        (11, request(11, r#""type":"send","key":"k","msg":-1"#)),
+        (
+            12,
+            request(12, r#""type":"send","key":"k","msg":18446744073709551616"#),
+        ),
+    ]);
+    #[cfg(feature = "counter")]
+    corpus.push((
+        13,
+        request(13, r#""type":"add","delta":9223372036854775808"#),
+    ));
+    corpus
+}
+
+#[test]
+fn every_broken_line_gets_an_error_reply() {
+    let mut sim = Sim::new(&["n1"], 50, |ctx| Node::new(ctx, Workload::Echo));
+    for (msg_id, line) in corpus() {
+        sim.deliver_line("n1", &line);
+        let reply = sim
+            .recv()
+            .unwrap_or_else(|| panic!("no reply to {}", msg_id));
+        assert!(sim.recv().is_none());
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.in_reply_to.map(|id| id.0), Some(msg_id));
+        let payload = reply.parse_body::<Payload>().unwrap().body.payload;
+        let Payload::Error { code, .. } = payload else {
+            panic!("{}: {:?}", msg_id, payload);
+        };
+        let expected = [ErrorCode::MalformedRequest, ErrorCode::NotSupported];
+        assert!(
+            expected.iter().any(|expected| *expected as usize == code),
+            "{}: code {}",
+            msg_id,
+            code
+        );
+    }
+    assert_still_echoes(&mut sim);
+}
+
+#[test]
+fn lines_with_no_one_to_answer_are_dropped() {
+    let mut sim = Sim::new(&["n1"], 50, |ctx| Node::new(ctx, Workload::Echo));
+    for line in [
+        "",
+        "\u{0}",
+        "null",
+        "[]",
+        "{}",
+        "{{{{",
+        r#"{"src":"c1"}"#,
+        r#"{"msg_id":-1,"src":"c1","dest":"n1","body":{"type":"echo"}}"#,
+        r#"{"src":12,"dest":"n1","body":{"msg_id":1,"type":"echo"}}"#,
+    ] {
+        sim.deliver_line("n1", line);
+        assert!(sim.recv().is_none(), "{:?}", line);
+    }
+    assert_still_echoes(&mut sim);
+}
+
+fn assert_still_echoes(sim: &mut Sim<Node>) {
+    let echo = Payload::Echo {
+        echo: "still here".into(),
+    };
+    let reply: Message<Value> = sim.request("n1", echo, Duration::from_secs(1)).unwrap();
+    let payload = reply.parse_body::<Payload>().unwrap().body.payload;
+    assert!(matches!(payload, Payload::EchoOk { .. }), "{:?}", payload);
+}
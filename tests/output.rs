@@ -0,0 +1,116 @@
+//! Lines sent through [`Output`]: from many threads at once, and to clients
+//! ahead of the rest.
+
+use maelle::protocol::{Body, NodeId};
+use maelle::runtime::{Coalesce, Output};
+use serde_json::{Value, json};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const THREADS: usize = 16;
+const SENDS: usize = 2_000;
+
+/// Takes a few bytes a write, so only a `write_all` gets a whole line out.
+#[derive(Clone, Default)]
+struct Trickle(Arc<Mutex<Vec<u8>>>);
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(7);
+        self.0.lock().unwrap().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends from every thread at once and returns what was written.
+fn hammer(make: impl Fn(Trickle) -> Output) -> String {
+    let written = Trickle::default();
+    let output = make(written.clone());
+    std::thread::scope(|scope| {
+        for thread in 0..THREADS {
+            let output = output.clone();
+            scope.spawn(move || {
+                let src = NodeId::from("n1");
+                // To clients and nodes both, which the writer orders apart.
+                let dest = NodeId::from(if thread % 2 == 0 { "c1" } else { "n2" });
+                for i in 0..SENDS {
+                    let payload = json!({"type": "echo", "echo": format!("{}\n{}", thread, i)});
+                    output.send(&src, &dest, Body::new(payload)).unwrap();
+                }
+            });
+        }
+    });
+    output.flush();
+    let bytes = written.0.lock().unwrap().clone();
+    String::from_utf8(bytes).unwrap()
+}
+
+fn assert_one_object_a_line(written: &str) {
+    assert!(written.ends_with('\n'));
+    let mut seen = vec![0; THREADS];
+    for line in written.lines() {
+        let mut values = serde_json::Deserializer::from_str(line).into_iter::<Value>();
+        let value = values.next().unwrap().unwrap();
+        assert!(values.next().is_none(), "{}", line);
+        let echo = value["body"]["echo"].as_str().unwrap();
+        let (thread, i) = echo.split_once('\n').unwrap();
+        let thread: usize = thread.parse().unwrap();
+        // And each thread's in the order it sent them.
+        assert_eq!(i.parse::<usize>().unwrap(), seen[thread], "{}", line);
+        seen[thread] += 1;
+    }
+    assert_eq!(seen, vec![SENDS; THREADS]);
+}
+
+#[test]
+fn lines_from_many_threads_never_interleave() {
+    let written = hammer(|sink| Output::to_writer_with(sink, None));
+    assert_one_object_a_line(&written);
+}
+
+#[test]
+fn coalesced_lines_never_interleave() {
+    let coalesce = Coalesce {
+        bytes: 4096,
+        window: Duration::from_millis(1),
+    };
+    let written = hammer(|sink| Output::to_writer_with(sink, Some(coalesce)));
+    assert_one_object_a_line(&written);
+}
+
+#[test]
+fn a_client_reply_overtakes_a_backlog_to_nodes() {
+    // Holds the writer in its first write until the backlog is queued.
+    let (open, gate) = std::sync::mpsc::channel::<()>();
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&written);
+    let output = Output::spawn(move |line| {
+        if sink.lock().unwrap().is_empty() {
+            let _ = gate.recv();
+        }
+        sink.lock().unwrap().push(line);
+        Ok(())
+    });
+    let src = NodeId::from("n1");
+    for i in 0..500 {
+        let gossip = json!({"type": "gossip", "i": i});
+        output
+            .send(&src, &NodeId::from("n2"), Body::new(gossip))
+            .unwrap();
+    }
+    let reply = json!({"type": "read_ok"});
+    output
+        .send(&src, &NodeId::from("c1"), Body::new(reply))
+        .unwrap();
+    open.send(()).unwrap();
+    output.flush();
+
+    let written = written.lock().unwrap();
+    assert_eq!(written.len(), 501);
+    let at = written.iter().position(|line| line.contains("read_ok"));
+    // Behind only what was already being written when it was sent.
+    assert!(at.is_some_and(|at| at <= 2), "{:?}", at);
+}
@@ -0,0 +1,43 @@
+//! Which panics the runtime reports itself and which it leaves to the hook
+//! that was there before. In a test binary of its own, as the panic hook is
+//! the whole process's.
+
+use maelle::protocol::{ErrorCode, Message, Payload};
+use maelle::runtime::{Context, Handler};
+use maelle::sim::Sim;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Panics the hook set before the runtime's was handed.
+static PASSED_ON: AtomicUsize = AtomicUsize::new(0);
+
+struct Panicky;
+impl Handler for Panicky {
+    type Payload = Payload;
+    fn handle(&mut self, _: &mut Context, _: Message) -> anyhow::Result<()> {
+        panic!("handler panicked")
+    }
+}
+
+#[test]
+fn only_handler_panics_bypass_the_previous_hook() {
+    std::panic::set_hook(Box::new(|_| {
+        PASSED_ON.fetch_add(1, Ordering::Relaxed);
+    }));
+    let mut sim = Sim::new(&["n1"], 86, |_| Panicky);
+    let echo = Payload::Echo { echo: "hi".into() };
+    let reply = sim.request("n1", echo, Duration::from_secs(1)).unwrap();
+    match reply.parse_body::<Payload>().unwrap().body.payload {
+        Payload::Error { code, .. } => assert_eq!(code, ErrorCode::Crash as usize),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(PASSED_ON.load(Ordering::Relaxed), 0);
+
+    // Outside a handler, on this thread and on another, the runtime has
+    // nothing to answer, so the panic is the previous hook's.
+    let _ = std::panic::catch_unwind(|| panic!("not a handler's"));
+    assert_eq!(PASSED_ON.load(Ordering::Relaxed), 1);
+    let _ = std::thread::spawn(|| panic!("nor this")).join();
+    assert_eq!(PASSED_ON.load(Ordering::Relaxed), 2);
+    drop(std::panic::take_hook());
+}
@@ -0,0 +1,312 @@
+//! Seed sweeps in the simulator: each seed draws a cluster and a schedule
+//! of client requests and partitions, and the run is then checked against
+//! the client's history, which a failure prints the ops of. A failing
+//! schedule is shrunk first, dropping steps and partitions for as long as
+//! it still fails, and the smallest one found is printed with its seed;
+//! `MAELLE_SIM_SEED` replays just that seed, and `MAELLE_SWEEP_SEEDS` sets
+//! how many are swept otherwise.
+#![cfg(any(feature = "broadcast", feature = "counter"))]
+
+use maelle::node::{Node, random_u64, seed_random};
+use maelle::protocol::Payload;
+use maelle::sim::Sim;
+use std::fmt::Debug;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn seeds(default: u64) -> Vec<u64> {
+    let var = |name| std::env::var(name).ok().and_then(|v| v.parse().ok());
+    match var("MAELLE_SIM_SEED") {
+        Some(seed) => vec![seed],
+        None => (0..var("MAELLE_SWEEP_SEEDS").unwrap_or(default)).collect(),
+    }
+}
+
+/// A value below `n`, from the simulation's RNG so it replays with the seed.
+fn below(n: usize) -> usize {
+    (random_u64() % n as u64) as usize
+}
+
+fn node_names(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("n{}", i)).collect()
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Churn {
+    Cut(usize, usize),
+    Heal(usize, usize),
+}
+
+/// One client request, to node `node`, after whatever `churn` does to the
+/// network, and `wait_ms` before the next.
+#[derive(Clone, Debug)]
+struct Step<Op> {
+    churn: Option<Churn>,
+    node: usize,
+    op: Op,
+    wait_ms: u64,
+}
+
+/// What a seed draws: a cluster, the links between its nodes, and the
+/// steps run against it.
+#[derive(Clone, Debug)]
+struct Schedule<Op> {
+    nodes: usize,
+    links: Vec<(usize, usize)>,
+    steps: Vec<Step<Op>>,
+}
+
+impl<Op: Clone> Schedule<Op> {
+    /// `count` steps over `links`, `op` drawing each one's request. Now
+    /// and then a step cuts a link, or heals one of those cut so far.
+    fn draw(
+        nodes: usize,
+        links: Vec<(usize, usize)>,
+        count: usize,
+        mut op: impl FnMut(usize) -> Op,
+    ) -> Self {
+        let mut cut = Vec::new();
+        let steps = (0..count)
+            .map(|i| {
+                let churn = match below(10) {
+                    0 => {
+                        let (a, b) = links[below(links.len())];
+                        cut.push((a, b));
+                        Some(Churn::Cut(a, b))
+                    }
+                    1 if !cut.is_empty() => {
+                        let (a, b) = cut.swap_remove(below(cut.len()));
+                        Some(Churn::Heal(a, b))
+                    }
+                    _ => None,
+                };
+                Step {
+                    churn,
+                    node: below(nodes),
+                    op: op(i),
+                    wait_ms: below(100) as u64,
+                }
+            })
+            .collect();
+        Self {
+            nodes,
+            links,
+            steps,
+        }
+    }
+
+    /// Schedules one step smaller than this: with a run of steps left out,
+    /// from half of them down to one, then with one step's partition or
+    /// wait taken away.
+    fn shrunk(&self) -> Vec<Self> {
+        let mut smaller = Vec::new();
+        let len = self.steps.len();
+        let mut size = len / 2;
+        while size > 0 {
+            for start in (0..len).step_by(size) {
+                let mut schedule = self.clone();
+                schedule.steps.drain(start..(start + size).min(len));
+                smaller.push(schedule);
+            }
+            size /= 2;
+        }
+        for i in 0..len {
+            if self.steps[i].churn.is_some() {
+                let mut schedule = self.clone();
+                schedule.steps[i].churn = None;
+                smaller.push(schedule);
+            }
+            if self.steps[i].wait_ms > 0 {
+                let mut schedule = self.clone();
+                schedule.steps[i].wait_ms = 0;
+                smaller.push(schedule);
+            }
+        }
+        smaller
+    }
+
+    /// Runs every step against `sim`, `send` making each request, then
+    /// heals every link.
+    fn run(
+        &self,
+        sim: &mut Sim<Node>,
+        names: &[&str],
+        mut send: impl FnMut(&mut Sim<Node>, &str, &Op) -> Result<(), String>,
+    ) -> Result<(), String> {
+        for step in &self.steps {
+            match step.churn {
+                Some(Churn::Cut(a, b)) => sim.partition(names[a], names[b]),
+                Some(Churn::Heal(a, b)) => sim.heal(names[a], names[b]),
+                None => {}
+            }
+            send(sim, names[step.node], &step.op)?;
+            sim.run_for(Duration::from_millis(step.wait_ms));
+        }
+        for &(a, b) in &self.links {
+            sim.heal(names[a], names[b]);
+        }
+        Ok(())
+    }
+}
+
+/// Runs `check` on the schedule `draw` makes of every seed. The first that
+/// fails is shrunk while it keeps failing, and reported with its seed.
+fn sweep<Op: Clone + Debug>(
+    default_seeds: u64,
+    draw: impl Fn(u64) -> Schedule<Op>,
+    check: impl Fn(u64, &Schedule<Op>) -> Result<(), String>,
+) {
+    for seed in seeds(default_seeds) {
+        seed_random(seed);
+        let schedule = draw(seed);
+        let Err(failure) = check(seed, &schedule) else {
+            continue;
+        };
+        let drawn = schedule.steps.len();
+        let mut smallest = (schedule, failure);
+        while let Some(smaller) = smallest
+            .0
+            .shrunk()
+            .into_iter()
+            .find_map(|schedule| check(seed, &schedule).err().map(|e| (schedule, e)))
+        {
+            smallest = smaller;
+        }
+        let (schedule, failure) = smallest;
+        panic!(
+            "seed {} (MAELLE_SIM_SEED={}): {}\nshrunk from {} steps to {:?}",
+            seed, seed, failure, drawn, schedule
+        );
+    }
+}
+
+#[cfg(feature = "broadcast")]
+mod broadcast {
+    use super::*;
+    use maelle::checker::check_broadcast;
+    use maelle::node::Workload;
+    use maelle::protocol::NodeId;
+    use maelle::topology;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    /// The smallest and largest clusters drawn; a default sweep draws each
+    /// size once.
+    const NODES: std::ops::RangeInclusive<usize> = 3..=25;
+
+    /// A random tree over `n` nodes, plus up to `n / 2` more edges.
+    fn connected_graph(n: usize) -> Vec<(usize, usize)> {
+        let mut edges: BTreeSet<(usize, usize)> = (1..n).map(|i| (below(i), i)).collect();
+        for _ in 0..n / 2 {
+            let (a, b) = (below(n), below(n));
+            if a != b {
+                edges.insert((a.min(b), a.max(b)));
+            }
+        }
+        edges.into_iter().collect()
+    }
+
+    fn draw(seed: u64) -> Schedule<u64> {
+        let sizes = NODES.end() - NODES.start() + 1;
+        let n = NODES.start() + seed as usize % sizes;
+        Schedule::draw(n, connected_graph(n), 10 + below(30), |i| i as u64)
+    }
+
+    fn converges(seed: u64, schedule: &Schedule<u64>) -> Result<(), String> {
+        let ids = node_names(schedule.nodes);
+        let names: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let mut sim = Sim::new(&names, seed, |ctx| Node::new(ctx, Workload::Broadcast));
+        let node_ids: Vec<NodeId> = names.iter().map(|id| NodeId::from(*id)).collect();
+        let adjacency = topology::adjacency(&node_ids, &schedule.links);
+        for id in &names {
+            let topology = Payload::Topology {
+                topology: adjacency.clone(),
+            };
+            sim.request(id, topology, TIMEOUT)
+                .map_err(|e| format!("{:#}", e))?;
+        }
+
+        schedule.run(&mut sim, &names, |sim, dest, message| {
+            let broadcast = Payload::Broadcast {
+                message: (*message).into(),
+                stamp: None,
+            };
+            sim.send(dest, broadcast).map_err(|e| format!("{:#}", e))?;
+            Ok(())
+        })?;
+        sim.run_for(Duration::from_secs(30));
+
+        let mut reads = Vec::new();
+        for id in &names {
+            let reply = sim
+                .request(id, Payload::Read { key: None }, TIMEOUT)
+                .map_err(|e| format!("{:#}", e))?;
+            reads.push((*id, reply));
+        }
+        check_broadcast(sim.history()).map_err(|violation| violation.to_string())?;
+        // The check excuses broadcasts left unanswered; none should be.
+        let expected: BTreeSet<u64> = schedule.steps.iter().map(|step| step.op).collect();
+        for (id, reply) in reads {
+            let got: BTreeSet<u64> = reply.body.payload["messages"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_u64)
+                .collect();
+            if got != expected {
+                let missing: Vec<_> = expected.difference(&got).collect();
+                let n = schedule.nodes;
+                return Err(format!("{} of {} nodes misses {:?}", id, n, missing));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_converges_through_partitions() {
+        let sizes = NODES.end() - NODES.start() + 1;
+        sweep(sizes as u64, draw, converges);
+    }
+}
+
+#[cfg(feature = "counter")]
+mod counter {
+    use super::*;
+    use maelle::checker::check_counter;
+    use maelle::node::Workload;
+
+    fn draw(seed: u64) -> Schedule<i64> {
+        let n = 2 + seed as usize % 6;
+        let links: Vec<(usize, usize)> = (0..n)
+            .flat_map(|a| (a + 1..n).map(move |b| (a, b)))
+            .collect();
+        Schedule::draw(n, links, 10 + below(50), |_| below(21) as i64 - 10)
+    }
+
+    fn sums(seed: u64, schedule: &Schedule<i64>) -> Result<(), String> {
+        let ids = node_names(schedule.nodes);
+        let names: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let mut sim = Sim::new(&names, seed, |ctx| Node::new(ctx, Workload::Counter));
+        schedule.run(&mut sim, &names, |sim, dest, delta| {
+            let add = Payload::Add {
+                delta: *delta,
+                element: None,
+            };
+            sim.send(dest, add).map_err(|e| format!("{:#}", e))?;
+            Ok(())
+        })?;
+        sim.run_for(Duration::from_secs(10));
+
+        for id in &names {
+            sim.request(id, Payload::Read { key: None }, TIMEOUT)
+                .map_err(|e| format!("{:#}", e))?;
+        }
+        check_counter(sim.history()).map_err(|violation| violation.to_string())
+    }
+
+    #[test]
+    fn counter_sums_through_partitions() {
+        sweep(20, draw, sums);
+    }
+}
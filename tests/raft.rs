@@ -0,0 +1,150 @@
+//! `lin-kv` served by the nodes' own Raft, in the simulator.
+
+use maelle::node::{Node, Workload};
+use maelle::protocol::{NodeId, Payload};
+use maelle::sim::Sim;
+use serde_json::{Value, json};
+use std::time::Duration;
+
+const IDS: [&str; 5] = ["n0", "n1", "n2", "n3", "n4"];
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn cluster(seed: u64) -> Sim<Node> {
+    Sim::new(&IDS, seed, |ctx| Node::new(ctx, Workload::LinKv))
+}
+
+/// Runs until some node leads, and returns it.
+fn leader(sim: &mut Sim<Node>, among: &[&str]) -> NodeId {
+    for _ in 0..100 {
+        sim.run_for(Duration::from_millis(100));
+        let leaders: Vec<NodeId> = among
+            .iter()
+            .filter_map(|id| sim.node(id))
+            .filter(|node| node.raft.leader.as_ref() == Some(&node.id))
+            .map(|node| node.id.clone())
+            .collect();
+        // A deposed leader may not have heard yet; the newest term wins.
+        if let Some(leader) = leaders
+            .into_iter()
+            .max_by_key(|id| sim.node(id).map(|node| node.raft.term))
+        {
+            return leader;
+        }
+    }
+    panic!("no leader among {:?} (seed {})", among, sim.seed());
+}
+
+fn write(sim: &mut Sim<Node>, dest: &str, value: i64) -> Payload {
+    let reply = sim
+        .request(
+            dest,
+            Payload::Write {
+                key: 0.into(),
+                value: value.into(),
+            },
+            TIMEOUT,
+        )
+        .and_then(|m| Ok(m.parse_body::<Payload>()?.body.payload));
+    reply.unwrap_or_else(|e| panic!("write to {}: {:#}", dest, e))
+}
+
+fn read(sim: &mut Sim<Node>, dest: &str) -> Option<Value> {
+    let reply = sim
+        .request(
+            dest,
+            Payload::Read {
+                key: Some(0.into()),
+            },
+            TIMEOUT,
+        )
+        .ok()?;
+    match reply.parse_body::<Payload>().ok()?.body.payload {
+        Payload::ReadOk { value, .. } => value,
+        _ => None,
+    }
+}
+
+#[test]
+fn majority_side_of_a_partition_keeps_serving() {
+    let mut sim = cluster(61);
+    let old = leader(&mut sim, &IDS);
+    assert!(matches!(write(&mut sim, &old, 1), Payload::WriteOk));
+
+    // Cut the leader off from everyone; the rest elect one of their own.
+    for id in IDS {
+        if id != old.as_str() {
+            sim.partition(&old, id);
+        }
+    }
+    let rest: Vec<&str> = IDS.into_iter().filter(|id| *id != old.as_str()).collect();
+    let new = leader(&mut sim, &rest);
+    assert_ne!(new, old);
+    assert!(matches!(write(&mut sim, &new, 2), Payload::WriteOk));
+    // The old leader can't get a majority for what it's given.
+    assert!(
+        sim.request(
+            &old,
+            Payload::Write {
+                key: 0.into(),
+                value: 3.into(),
+            },
+            Duration::from_secs(2),
+        )
+        .is_err()
+    );
+
+    for id in IDS {
+        sim.heal(&old, id);
+    }
+    sim.run_for(Duration::from_secs(3));
+    for id in IDS {
+        assert_eq!(
+            read(&mut sim, id),
+            Some(2.into()),
+            "{} (seed {})",
+            id,
+            sim.seed()
+        );
+    }
+}
+
+/// An `append_entries` from `n1` as leader of term 1, of writes `entries`
+/// after `prev_log_index`.
+fn append(msg_id: u64, prev_log_index: usize, entries: &[i64], leader_commit: usize) -> String {
+    let entries: Vec<Value> = entries
+        .iter()
+        .map(|value| {
+            json!({
+                "term": 1,
+                "op": {"type": "write", "key": 0, "value": value},
+                "client": null,
+                "leader": "n1",
+            })
+        })
+        .collect();
+    let body = json!({
+        "type": "append_entries",
+        "msg_id": msg_id,
+        "term": 1,
+        "leader": "n1",
+        "prev_log_index": prev_log_index,
+        "prev_log_term": if prev_log_index == 0 { 0 } else { 1 },
+        "entries": entries,
+        "leader_commit": leader_commit,
+    });
+    json!({"src": "n1", "dest": "n0", "body": body}).to_string()
+}
+
+#[test]
+fn commit_index_never_moves_back() {
+    let mut sim = cluster(62);
+    let commit_index = |sim: &Sim<Node>| sim.node("n0").map(|node| node.raft.commit_index);
+    sim.deliver_line("n0", &append(1, 0, &[1, 2, 3], 3));
+    assert_eq!(commit_index(&sim), Some(3));
+    // A late retry of an earlier batch, sent after the leader committed
+    // more: it matches only the first entry, which mustn't undo the rest.
+    sim.deliver_line("n0", &append(2, 0, &[1], 5));
+    assert_eq!(commit_index(&sim), Some(3));
+    sim.deliver_line("n0", &append(3, 3, &[4, 5], 5));
+    assert_eq!(commit_index(&sim), Some(5));
+}
@@ -0,0 +1,225 @@
+//! Retried messages and the sweep that gives up on them, in the simulator.
+#![cfg(any(feature = "broadcast", feature = "txn"))]
+
+use maelle::node::{Callback, Node, Workload};
+#[cfg(feature = "broadcast")]
+use maelle::protocol::NodeId;
+use maelle::protocol::Payload;
+use maelle::runtime::pending_ttl;
+use maelle::sim::Sim;
+#[cfg(feature = "broadcast")]
+use maelle::topology;
+use std::time::Duration;
+
+const IDS: [&str; 3] = ["n0", "n1", "n2"];
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Unacknowledged messages `id` still has to resend.
+fn pending(sim: &Sim<Node>, id: &str) -> usize {
+    sim.node(id).map_or(0, |node| {
+        node.callbacks
+            .values()
+            .filter(|callback| matches!(callback, Callback::Pending { .. }))
+            .count()
+    })
+}
+
+fn cut_off(sim: &mut Sim<Node>, id: &str) {
+    for other in IDS {
+        if other != id {
+            sim.partition(id, other);
+        }
+    }
+}
+
+#[cfg(feature = "broadcast")]
+fn broadcast_cluster(seed: u64) -> Sim<Node> {
+    let mut sim = Sim::new(&IDS, seed, |ctx| Node::new(ctx, Workload::Broadcast));
+    let ids: Vec<NodeId> = IDS.iter().map(|id| NodeId::from(*id)).collect();
+    let topology = topology::adjacency(&ids, &topology::star(ids.len()));
+    for id in IDS {
+        let topology = Payload::Topology {
+            topology: topology.clone(),
+        };
+        sim.request(id, topology, TIMEOUT).unwrap();
+    }
+    sim
+}
+
+#[cfg(feature = "broadcast")]
+#[test]
+fn unanswered_broadcasts_are_reclaimed_after_the_ttl() {
+    let mut sim = broadcast_cluster(76);
+    cut_off(&mut sim, "n0");
+    for message in 0..200 {
+        let broadcast = Payload::Broadcast {
+            message: message.into(),
+            stamp: None,
+        };
+        sim.request("n0", broadcast, TIMEOUT).unwrap();
+    }
+    assert!(pending(&sim, "n0") > 0);
+    sim.run_for(pending_ttl() / 2);
+    assert!(pending(&sim, "n0") > 0, "expired early");
+    sim.run_for(pending_ttl());
+    assert_eq!(pending(&sim, "n0"), 0, "seed {}", sim.seed());
+}
+
+/// How many times `id` has sent what's still unacknowledged to `dest`.
+#[cfg(feature = "broadcast")]
+fn attempts(sim: &Sim<Node>, id: &str, dest: &str) -> Vec<u32> {
+    sim.node(id).map_or(Vec::new(), |node| {
+        node.callbacks
+            .values()
+            .filter_map(|callback| match callback {
+                Callback::Pending {
+                    dest: to, attempts, ..
+                } if to == dest => Some(*attempts),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+#[cfg(feature = "broadcast")]
+#[test]
+fn broadcasts_are_retried_until_acknowledged() {
+    let mut sim = broadcast_cluster(18);
+    sim.partition("n0", "n1");
+    let broadcast = Payload::Broadcast {
+        message: 7.into(),
+        stamp: None,
+    };
+    sim.request("n0", broadcast, TIMEOUT).unwrap();
+    sim.run_for(Duration::from_secs(2));
+    let before = attempts(&sim, "n0", "n1");
+    assert_eq!(before.len(), 1, "seed {}", sim.seed());
+    sim.run_for(Duration::from_secs(2));
+    let after = attempts(&sim, "n0", "n1");
+    assert!(after[0] > before[0], "not retried: {:?}", after);
+
+    // Once acknowledged it's dropped, leaving nothing to send again.
+    sim.heal("n0", "n1");
+    sim.run_for(Duration::from_secs(2));
+    assert!(attempts(&sim, "n0", "n1").is_empty());
+    let has = |sim: &Sim<Node>| {
+        sim.node("n1")
+            .is_some_and(|node| node.messages.contains(&7.into()))
+    };
+    assert!(has(&sim));
+    sim.run_for(Duration::from_secs(5));
+    assert!(attempts(&sim, "n0", "n1").is_empty());
+    assert_eq!(pending(&sim, "n0"), 0);
+}
+
+#[cfg(feature = "txn")]
+#[test]
+fn replicated_writes_outlive_the_ttl() {
+    let mut sim = Sim::new(&IDS, 77, |ctx| Node::new(ctx, Workload::Echo));
+    cut_off(&mut sim, "n0");
+    let write = ("w".to_string(), 1, Some(5.into()));
+    sim.request("n0", Payload::Txn { txn: vec![write] }, TIMEOUT)
+        .unwrap();
+    sim.run_for(pending_ttl() * 2);
+    assert_eq!(pending(&sim, "n0"), IDS.len() - 1);
+
+    for id in IDS {
+        sim.heal("n0", id);
+    }
+    sim.run_for(Duration::from_secs(10));
+    for id in IDS {
+        let value = sim
+            .node(id)
+            .and_then(|node| node.registers.get(&1))
+            .map(|register| register.value.clone());
+        assert_eq!(value, Some(5.into()), "{} (seed {})", id, sim.seed());
+    }
+    assert_eq!(pending(&sim, "n0"), 0);
+}
+
+/// Retries are due by the node's [`Clock`](maelle::runtime::Clock), which
+/// in the simulator only moves when told to: a long backoff passes
+/// without the wait.
+#[cfg(feature = "broadcast")]
+#[test]
+fn the_retry_deadline_passes_on_the_virtual_clock() {
+    use maelle::node::{NodeConfig, RetryPolicy};
+    use maelle::sim::Link;
+    use std::time::Instant;
+
+    // Well inside the pending TTL, which would otherwise drop the broadcast.
+    let backoff = Duration::from_secs(20);
+    let config = NodeConfig {
+        retry_policy: RetryPolicy {
+            base: backoff,
+            max_interval: backoff,
+            jitter: 0.0,
+            ..RetryPolicy::from_env()
+        },
+        gossip_interval: Duration::ZERO,
+        sync_interval: Duration::ZERO,
+        ..NodeConfig::from_env(Workload::Broadcast)
+    };
+    let started = Instant::now();
+    let mut sim = Sim::new(&["n0", "n1"], 75, |ctx| {
+        Node::with_config(ctx, config.clone())
+    });
+    // One way only: n1's pings still reach n0, so it's never suspected
+    // and its retries held back.
+    let lost = Link {
+        drop: 1.0,
+        ..Link::default()
+    };
+    sim.link("n0", "n1", lost);
+    let broadcast = Payload::Broadcast {
+        message: 7.into(),
+        stamp: None,
+    };
+    sim.request("n0", broadcast, TIMEOUT).unwrap();
+    let first = attempts(&sim, "n0", "n1");
+    assert_eq!(first.len(), 1);
+    sim.run_for(backoff / 2);
+    assert_eq!(attempts(&sim, "n0", "n1"), first, "retried early");
+    sim.run_for(backoff);
+    assert!(attempts(&sim, "n0", "n1")[0] > first[0], "not retried");
+    assert!(started.elapsed() < backoff / 10);
+}
+
+/// Once the failure detector suspects a neighbor, what's sent to it comes
+/// down to a probe and a batch a ping interval, plus anti-entropy, however
+/// many values are broadcast; and it's caught up once it answers again.
+#[cfg(feature = "broadcast")]
+#[test]
+fn a_dead_neighbor_costs_a_probe_not_a_retry_per_value() {
+    let mut sim = Sim::new(&IDS, 62, |ctx| Node::new(ctx, Workload::Broadcast));
+    cut_off(&mut sim, "n2");
+    // Long enough to be suspected.
+    sim.run_for(Duration::from_secs(5));
+    let dead = |sim: &Sim<Node>| sim.node("n0").unwrap().health_of("n2");
+    assert_eq!(dead(&sim), maelle::node::Health::Suspected);
+
+    let before = sim.sent("n0", "n2");
+    let values = 100;
+    for message in 0..values {
+        let broadcast = Payload::Broadcast {
+            message: message.into(),
+            stamp: None,
+        };
+        sim.request("n0", broadcast, TIMEOUT).unwrap();
+        sim.run_for(Duration::from_millis(100));
+    }
+    sim.run_for(Duration::from_secs(10));
+    let to_dead = sim.sent("n0", "n2") - before;
+    // 20 seconds: a ping and a batch a second, and anti-entropy rounds.
+    assert!(to_dead <= 60, "{} messages to a dead neighbor", to_dead);
+    assert_eq!(attempts(&sim, "n0", "n2").iter().max(), Some(&0));
+
+    for id in IDS {
+        sim.heal("n2", id);
+    }
+    sim.run_for(Duration::from_secs(5));
+    assert_eq!(dead(&sim), maelle::node::Health::Alive);
+    let has = sim.node("n2").unwrap().messages.len();
+    assert_eq!(has, values, "seed {}", sim.seed());
+    assert_eq!(pending(&sim, "n0"), 0);
+}
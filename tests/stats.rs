@@ -0,0 +1,63 @@
+//! Each node's `stats`, after an exchange whose traffic is known.
+#![cfg(feature = "broadcast")]
+
+use maelle::node::{Node, NodeConfig, Workload};
+use maelle::protocol::{AdminPayload, Payload, StatsSnapshot};
+use maelle::sim::Sim;
+use serde_json::json;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn stats(sim: &mut Sim<Node>, id: &str) -> StatsSnapshot {
+    let reply = sim.request(id, AdminPayload::Stats, TIMEOUT).unwrap();
+    serde_json::from_value(reply.body.payload["stats"].clone()).unwrap()
+}
+
+#[test]
+fn stats_count_each_nodes_own_traffic() {
+    let config = NodeConfig {
+        gossip_interval: Duration::ZERO,
+        sync_interval: Duration::ZERO,
+        ..NodeConfig::from_env(Workload::Broadcast)
+    };
+    let mut sim = Sim::new(&["n0", "n1"], 26, |ctx| {
+        Node::with_config(ctx, config.clone())
+    });
+    let topology = json!({"n0": ["n1"], "n1": ["n0"]});
+    for id in ["n0", "n1"] {
+        let topology = json!({"type": "topology", "topology": topology});
+        sim.request(id, topology, TIMEOUT).unwrap();
+    }
+    for message in 0..3 {
+        let broadcast = Payload::Broadcast {
+            message: message.into(),
+            stamp: None,
+        };
+        sim.request("n0", broadcast, TIMEOUT).unwrap();
+    }
+    sim.run_for(Duration::from_millis(500));
+    let n0 = stats(&mut sim, "n0");
+    let n1 = stats(&mut sim, "n1");
+
+    // n0 took the three broadcasts and the client's topology, n1 only its
+    // topology: each node counts what it handled, not the cluster's total.
+    assert_eq!((n0.client_ops, n1.client_ops), (4, 1));
+    assert_eq!(n0.sent.get("broadcast"), Some(&3));
+    assert_eq!(n0.received.get("broadcast_ok"), Some(&3));
+    assert_eq!(n1.received.get("broadcast"), Some(&3));
+    assert_eq!(n1.sent.get("broadcast_ok"), Some(&3));
+    assert_eq!(n1.sent.get("broadcast"), None);
+    // Only messages to nodes: the three broadcasts or their acks, and the
+    // catch-up each node asked of the other and answered. Replies to the
+    // client aren't among them.
+    assert_eq!(n0.sent.get("broadcast_ok"), Some(&3));
+    assert_eq!((n0.messages_sent, n1.messages_sent), (5, 5));
+    assert_eq!(n0.retries + n1.retries, 0);
+
+    // Asking again counts nothing new: admin traffic is left out.
+    let again = stats(&mut sim, "n0");
+    assert_eq!(again.sent, n0.sent);
+    assert_eq!(again.received, n0.received);
+    assert!(!n0.received.contains_key("stats"));
+}
@@ -0,0 +1,163 @@
+//! Whole nodes end to end, over a [`TestNet`] or straight through
+//! [`run_with`].
+
+use maelle::node::{Node, Workload};
+use maelle::protocol::{Message, Payload};
+use maelle::runtime::{Output, run_with};
+use maelle::testnet::TestNet;
+use serde_json::{Value, json};
+use std::sync::mpsc;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn payload(reply: Message<Value>) -> Payload {
+    reply.parse_body::<Payload>().unwrap().body.payload
+}
+
+#[test]
+fn init_is_answered_first_with_its_own_msg_id() {
+    let lines = [
+        // Anything ahead of init waits for it.
+        json!({"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 1, "echo": "early"}}),
+        json!({"src": "c0", "dest": "n1", "body": {
+            "type": "init", "msg_id": 7, "node_id": "n1", "node_ids": ["n1", "n2"],
+        }}),
+    ];
+    let (tx, rx) = mpsc::channel();
+    let output = Output::spawn(move |line| {
+        tx.send(line)
+            .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+    });
+    let input = lines.map(|line| Ok(line.to_string())).into_iter();
+    run_with(input, output, |ctx| Node::new(ctx, Workload::Echo)).unwrap();
+
+    let sent: Vec<Value> = rx
+        .try_iter()
+        .map(|line| serde_json::from_str(&line).unwrap())
+        .collect();
+    assert_eq!(sent.len(), 2, "{:?}", sent);
+    assert_eq!(sent[0]["dest"], "c0");
+    assert_eq!(sent[0]["body"]["type"], "init_ok");
+    assert_eq!(sent[0]["body"]["in_reply_to"], 7);
+    let init_ok = sent[0]["body"]["msg_id"]
+        .as_u64()
+        .expect("init_ok has a msg_id");
+    assert_eq!(sent[1]["src"], "n1");
+    assert_eq!(sent[1]["body"]["type"], "echo_ok");
+    assert_eq!(sent[1]["body"]["in_reply_to"], 1);
+    assert_eq!(sent[1]["body"]["echo"], "early");
+    assert!(sent[1]["body"]["msg_id"].as_u64() > Some(init_ok));
+}
+
+#[test]
+fn echo_round_trip() {
+    let mut net = TestNet::start(&["n1", "n2"], |ctx| Node::new(ctx, Workload::Echo)).unwrap();
+    for (i, dest) in ["n1", "n2", "n1"].into_iter().enumerate() {
+        let echo = format!("hello {}", i);
+        let reply = net
+            .request(dest, Payload::Echo { echo: echo.clone() }, TIMEOUT)
+            .unwrap();
+        assert_eq!(reply.src, dest);
+        assert!(reply.body.msg_id.is_some());
+        match payload(reply) {
+            Payload::EchoOk { echo: back } => assert_eq!(back, echo),
+            other => panic!("{:?}", other),
+        }
+    }
+    net.shutdown().unwrap();
+}
+
+#[cfg(feature = "broadcast")]
+mod broadcast {
+    use super::*;
+    use maelle::protocol::{AdminPayload, NodeId};
+    use maelle::topology;
+    use std::collections::{BTreeSet, HashMap};
+    use std::time::Instant;
+
+    const IDS: [&str; 5] = ["n1", "n2", "n3", "n4", "n5"];
+
+    fn start() -> TestNet {
+        TestNet::start(&IDS, |ctx| Node::new(ctx, Workload::Broadcast)).unwrap()
+    }
+
+    fn set_topology(net: &mut TestNet, topology: &HashMap<NodeId, Vec<NodeId>>) {
+        for id in IDS {
+            let topology = Payload::Topology {
+                topology: topology.clone(),
+            };
+            let reply = net.request(id, topology, TIMEOUT).unwrap();
+            assert!(matches!(payload(reply), Payload::TopologyOk), "{}", id);
+        }
+    }
+
+    fn broadcast(net: &mut TestNet, dest: &str, message: u64) {
+        let broadcast = Payload::Broadcast {
+            message: message.into(),
+            stamp: None,
+        };
+        let reply = net.request(dest, broadcast, TIMEOUT).unwrap();
+        assert!(matches!(payload(reply), Payload::BroadcastOk), "{}", dest);
+    }
+
+    fn read(net: &mut TestNet, dest: &str) -> BTreeSet<u64> {
+        let reply = net.request(dest, Payload::Read { key: None }, TIMEOUT);
+        match payload(reply.unwrap()) {
+            Payload::ReadOk {
+                messages: Some(messages),
+                ..
+            } => messages.iter().filter_map(Value::as_u64).collect(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    /// Reads every node until all of them hold `expected`.
+    fn assert_converges(net: &mut TestNet, expected: &BTreeSet<u64>) {
+        let deadline = Instant::now() + TIMEOUT;
+        for id in IDS {
+            loop {
+                let got = read(net, id);
+                if &got == expected {
+                    break;
+                }
+                assert!(Instant::now() < deadline, "{} has {:?}", id, got);
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+
+    #[test]
+    fn broadcasts_reach_all_five_nodes() {
+        let mut net = start();
+        let ids: Vec<NodeId> = IDS.into_iter().map(NodeId::from).collect();
+        set_topology(&mut net, &topology::adjacency(&ids, &topology::grid(5)));
+        let expected: BTreeSet<u64> = (0..50).collect();
+        for message in &expected {
+            broadcast(&mut net, IDS[*message as usize % IDS.len()], *message);
+        }
+        assert_converges(&mut net, &expected);
+        net.shutdown().unwrap();
+    }
+
+    #[test]
+    fn values_travel_along_the_given_topology() {
+        // A line, so a value from one end only gets to the other by way of
+        // every node between.
+        let mut net = start();
+        let ids: Vec<NodeId> = IDS.into_iter().map(NodeId::from).collect();
+        set_topology(&mut net, &topology::adjacency(&ids, &topology::tree(5, 1)));
+        let reply = net.request("n3", AdminPayload::DumpTopology, TIMEOUT);
+        let reply = reply.unwrap().parse_body::<AdminPayload>().unwrap();
+        let AdminPayload::DumpTopologyOk { dot } = reply.body.payload else {
+            panic!("{:?}", reply.body.payload);
+        };
+        assert_eq!(dot.matches(" -- ").count(), 4, "{}", dot);
+        assert!(dot.contains("\"n2\" -- \"n3\""), "{}", dot);
+
+        broadcast(&mut net, "n1", 1);
+        broadcast(&mut net, "n5", 5);
+        assert_converges(&mut net, &BTreeSet::from([1, 5]));
+        net.shutdown().unwrap();
+    }
+}
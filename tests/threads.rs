@@ -0,0 +1,77 @@
+//! The runtime's thread count under load. Alone in its file, since the
+//! count is of the whole test process.
+#![cfg(target_os = "linux")]
+
+use maelle::node::{Node, Workload};
+use maelle::runtime::{Output, run_with};
+use serde_json::{Value, json};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MESSAGES: u64 = 50_000;
+
+/// Keeps what's written to it.
+struct Lines(Arc<Mutex<Vec<u8>>>);
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn threads() -> usize {
+    std::fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+#[test]
+fn thread_count_stays_bounded() {
+    let init = json!({"src": "c0", "dest": "n1", "body": {
+        "type": "init", "msg_id": 0, "node_id": "n1", "node_ids": ["n1"],
+    }});
+    let echoes = (1..=MESSAGES).map(|msg_id| {
+        let body = json!({"type": "echo", "msg_id": msg_id, "echo": format!("m{}", msg_id)});
+        json!({"src": "c1", "dest": "n1", "body": body}).to_string()
+    });
+    let input = std::iter::once(init.to_string()).chain(echoes).map(Ok);
+
+    let before = threads();
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = thread::spawn({
+        let done = Arc::clone(&done);
+        move || {
+            let mut peak = 0;
+            while !done.load(Ordering::Relaxed) {
+                peak = peak.max(threads());
+                thread::sleep(Duration::from_millis(5));
+            }
+            peak
+        }
+    });
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let output = Output::to_writer(Lines(Arc::clone(&written)));
+    run_with(input, output, |ctx| Node::new(ctx, Workload::Echo)).unwrap();
+    done.store(true, Ordering::Relaxed);
+    let peak = sampler.join().unwrap();
+
+    // The init_ok, then an echo_ok for every echo, in order.
+    let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+    let replies: Vec<Value> = written
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["body"].take())
+        .collect();
+    assert_eq!(replies.len() as u64, MESSAGES + 1);
+    assert_eq!(replies[0]["type"], "init_ok");
+    for (msg_id, reply) in (1..=MESSAGES).zip(&replies[1..]) {
+        assert_eq!(reply["type"], "echo_ok", "{}", reply);
+        assert_eq!(reply["in_reply_to"], msg_id, "{}", reply);
+        assert_eq!(reply["echo"], format!("m{}", msg_id), "{}", reply);
+    }
+    // The sampler, the reader, the timers and the writer, with some slack.
+    assert!(peak <= before + 8, "{} threads, from {}", peak, before);
+}
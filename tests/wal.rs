@@ -0,0 +1,70 @@
+//! A node restarted over its write-ahead log, in the simulator.
+#![cfg(feature = "broadcast")]
+
+use maelle::node::{Node, NodeConfig, Workload};
+use maelle::protocol::Payload;
+use maelle::sim::Sim;
+use serde_json::Value;
+use std::io::Write;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn start(wal: &str, seed: u64) -> Sim<Node> {
+    let config = NodeConfig {
+        wal: Some(wal.to_string()),
+        ..NodeConfig::from_env(Workload::Broadcast)
+    };
+    Sim::new(&["n1"], seed, |ctx| Node::with_config(ctx, config.clone()))
+}
+
+fn read(sim: &mut Sim<Node>) -> Vec<Value> {
+    let reply = sim.request("n1", Payload::Read { key: None }, TIMEOUT);
+    match reply.unwrap().parse_body::<Payload>().unwrap().body.payload {
+        Payload::ReadOk {
+            messages: Some(messages),
+            ..
+        } => messages,
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn restarted_node_reads_what_it_had() {
+    let path = std::env::temp_dir().join(format!("maelle-wal-{}.jsonl", std::process::id()));
+    let wal = path.to_str().unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    let mut sim = start(wal, 65);
+    for message in 0..25 {
+        let broadcast = Payload::Broadcast {
+            message: Value::from(message),
+            stamp: None,
+        };
+        sim.request("n1", broadcast, TIMEOUT).unwrap();
+    }
+    let before = read(&mut sim);
+    assert_eq!(before.len(), 25);
+    drop(sim);
+    // Killed halfway through writing one more.
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(br#"{"kind":"message","val"#).unwrap();
+    drop(file);
+
+    let mut sim = start(wal, 66);
+    assert_eq!(read(&mut sim), before);
+    // And it carries on logging after the torn line.
+    let broadcast = Payload::Broadcast {
+        message: Value::from(25),
+        stamp: None,
+    };
+    sim.request("n1", broadcast, TIMEOUT).unwrap();
+    drop(sim);
+    let mut sim = start(wal, 67);
+    assert_eq!(read(&mut sim).len(), 26);
+    drop(sim);
+    let _ = std::fs::remove_file(&path);
+}